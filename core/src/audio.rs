@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::commands::{After, ffmpeg, MediaInfo, Session};
+use crate::commands::ffmpeg::{AAC, FLAC, OPUS};
+use crate::commands::ffprobe::Stream;
+use crate::dash::{self, AlreadyProcessed, Overrides};
+use crate::error::ConvError;
+use crate::queue::Request;
+use crate::sessions::Sessions;
+use crate::{PREVIEW_DIR, SETTINGS};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Aac,
+    Opus,
+    Flac,
+}
+
+impl Default for AudioFormat {
+    fn default() -> Self {
+        AudioFormat::Aac
+    }
+}
+
+impl AudioFormat {
+    // Streams already in the format are copied rather than encoded again
+    fn codec_name(&self) -> &'static str {
+        match self {
+            AudioFormat::Aac => "aac",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Aac => "m4a",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    fn default_bitrate(&self) -> Option<isize> {
+        match self {
+            AudioFormat::Aac => Some(256_000),
+            AudioFormat::Opus => Some(160_000),
+            AudioFormat::Flac => None,
+        }
+    }
+}
+
+// Where the audio for a source will be written, a directory named like its package would be
+pub fn audio_dir(info: &MediaInfo, overrides: &Overrides) -> PathBuf {
+    let base = if overrides.preview_seconds.is_some() { *PREVIEW_DIR } else { SETTINGS.dirs.audio.as_path() };
+    base.join(dash::package_name(info))
+}
+
+// The audio streams to extract, by stream index as in the media details, or else those in the
+// configured audio languages
+fn audio_streams<'a>(info: &'a MediaInfo, tracks: Option<&[isize]>) -> Result<Vec<&'a Stream>, String> {
+    let audio = dash::wanted_audio_streams(&info.raw.streams, &SETTINGS.audio_languages);
+    let tracks = match tracks {
+        Some(t) if t.is_empty() => return Err("No tracks were given".to_string()),
+        Some(t) => t,
+        None if audio.is_empty() => return Err("The source has no audio".to_string()),
+        None => return Ok(audio),
+    };
+    tracks.iter().map(|t| info.raw.streams.iter()
+        .find(|s| s.index == *t && s.codec_type == "audio")
+        .ok_or_else(|| format!("Stream {} isn't an audio stream", t)))
+        .collect()
+}
+
+// Checks the overrides which make sense for audio alone, returning a message suitable for the client
+pub fn validate(overrides: &Overrides) -> Result<(), String> {
+    overrides.validate()?;
+    if overrides.split_chapters.unwrap_or(false) {
+        return Err("Chapters can only be split when packaging".to_string());
+    }
+    if overrides.encryption.is_some() || overrides.clearkey.is_some() {
+        return Err("Only packages can be encrypted".to_string());
+    }
+    Ok(())
+}
+
+// Extracts each of the source's audio streams to a file of its own, for concerts, talks and the like
+// which are listened to rather than watched. The files are named after the stream's index and
+// language, and written alongside the directory they end up in until all of them are done.
+pub async fn exec_audio_conv(state: Arc<Sessions>, file: PathBuf, format: AudioFormat, tracks: Option<&[isize]>,
+                                    overrides: &Overrides, owner: Option<String>) -> Result<String, ConvError> {
+    let session = audio_session(&state, Uuid::new_v4(), file.clone(), format, tracks, overrides, owner).await?;
+    let request = Request::Audio { file, format, tracks: tracks.map(<[isize]>::to_vec), overrides: overrides.clone() };
+    dash::launch(&state, session, request).await
+}
+
+pub(crate) async fn audio_session(state: &Arc<Sessions>, id: Uuid, file: PathBuf, format: AudioFormat, tracks: Option<&[isize]>,
+                                  overrides: &Overrides, owner: Option<String>) -> Result<Session, ConvError> {
+    let mut info = MediaInfo::get(&file).await?;
+    let out_dir = audio_dir(&info, overrides);
+    if out_dir.exists() && overrides.preview_seconds.is_none() && !overrides.force.unwrap_or(false) {
+        return Err(AlreadyProcessed { name: dash::package_name(&info) }.into());
+    }
+
+    let streams = audio_streams(&info, tracks).map_err(|reason| ConvError::Probe { path: file.clone(), reason })?;
+
+    let staged = dash::staging_dir(&out_dir, id);
+    std::fs::create_dir_all(&staged)?;
+
+    let mut stages = streams.into_iter().map(|s| {
+        let mut c = ffmpeg::Config::new(file.clone());
+        c.video_disabled()
+            .subtitle_disabled()
+            .tracks(Some(s.index));
+        if let Some(s) = overrides.start() {
+            c.start(s);
+        }
+        if let Some(e) = overrides.end() {
+            c.end(e);
+        }
+        if let Some(p) = overrides.preview_seconds {
+            c.duration(Duration::from_secs(p));
+        }
+        if s.codec_name != format.codec_name() || overrides.audio_bitrate.is_some() {
+            c.audio_encoder(match format {
+                AudioFormat::Aac => AAC,
+                AudioFormat::Opus => OPUS,
+                AudioFormat::Flac => FLAC,
+            });
+            if let Some(b) = overrides.audio_bitrate.or_else(|| format.default_bitrate()) {
+                c.audio_bitrate(b);
+            }
+        }
+        let name = match s.language() {
+            Some(l) => format!("{:02}.{}.{}", s.index, l, format.extension()),
+            None => format!("{:02}.{}", s.index, format.extension()),
+        };
+        c.out(staged.join(name));
+        c
+    }).collect::<Vec<_>>().into_iter();
+
+    info.duration = overrides.output_duration(info.duration);
+    // There's at least one, audio_streams never gives none
+    let mut session = Session::new(id, Box::new(stages.next().unwrap()), Arc::new(RwLock::new(info)));
+    // Every stream is read from the source, so none waits for another
+    for c in stages {
+        session.chain_after(c, After::Stages(vec![]));
+    }
+    session.output(out_dir.clone())
+        .on_success(move || dash::swap_in(&staged, &out_dir))
+        .owner(owner)
+        .events(state.events.clone());
+    Ok(session)
+}
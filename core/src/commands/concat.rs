@@ -0,0 +1,70 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::commands::{CommandLine, MediaCommandConfig, long_path, path_bytes, Tool};
+use crate::error::ConvError;
+
+// Joins several inputs end to end with ffmpeg's concat demuxer, without re-encoding. The inputs are
+// read from a list file which must be written with write_list before the command runs.
+pub struct Config {
+    list: PathBuf,
+    out_file: PathBuf,
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        let mut cmd = CommandLine::tool(Tool::Ffmpeg);
+        cmd.arg("-y")
+            .arg("-f")
+            .arg("concat")
+            // Allows absolute paths in the list
+            .arg("-safe")
+            .arg("0")
+            .arg("-i")
+            .path(&self.list)
+            .arg("-progress")
+            .arg("-")
+            .arg("-map")
+            .arg("0")
+            .arg("-c")
+            .arg("copy")
+            .path(&self.out_file);
+
+        Ok(cmd)
+    }
+
+    fn name(&self) -> String {
+        "Join sources".to_string()
+    }
+
+    fn reports_progress(&self) -> bool {
+        true
+    }
+}
+
+impl Config {
+    pub fn new(list: PathBuf, out_file: PathBuf) -> Self {
+        Config {
+            list,
+            out_file,
+        }
+    }
+}
+
+// Written as raw bytes so names which aren't valid UTF-8 are passed through untouched
+pub fn write_list(list: &Path, inputs: &[PathBuf]) -> io::Result<()> {
+    let mut contents = vec![];
+    for i in inputs {
+        contents.extend_from_slice(b"file '");
+        for &b in &path_bytes(&long_path(i)) {
+            // Quotes end the quoted string, are escaped, then a new quoted string begins
+            if b == b'\'' {
+                contents.extend_from_slice(b"'\\''");
+            } else {
+                contents.push(b);
+            }
+        }
+        contents.extend_from_slice(b"'\n");
+    }
+    std::fs::write(list, contents)
+}
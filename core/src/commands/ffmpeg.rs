@@ -0,0 +1,532 @@
+use core::result::Result::{Err, Ok};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::commands::{CommandLine, MediaCommandConfig, SessionError, Tool};
+use crate::commands::ffmpeg::Encoder::{Audio, Subtitle, Video};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::WORK_DIR;
+use crate::error::ConvError;
+
+pub struct Config {
+    video: CodecOpts,
+    audio: CodecOpts,
+    subtitle: CodecOpts,
+    file: PathBuf,
+    out_file: Option<PathBuf>,
+    tracks: Vec<isize>,
+    burn_subtitle: Option<isize>,
+    keyframe_interval: Option<Duration>,
+    fragmented: bool,
+    fragment_duration: Option<Duration>,
+    faststart: bool,
+    duration: Option<Duration>,
+    start: Option<Duration>,
+    end: Option<Duration>,
+    can_fail: bool,
+}
+
+pub struct CodecOpts {
+    encoder: Encoder,
+    bitrate: isize,
+    enabled: bool,
+    crf: isize,
+    channels: isize,
+    colour_8_bit: bool,
+    max_height: isize,
+    fps: isize,
+    gop: isize,
+}
+
+#[derive(PartialEq)]
+pub enum Encoder {
+    Video(VideoEncoder),
+    Audio(AudioEncoder),
+    Subtitle(SubtitleEncoder),
+    None,
+}
+
+type VideoEncoder = &'static str;
+
+pub const X264: VideoEncoder = "libx264";
+#[allow(dead_code)]
+pub const X265: VideoEncoder = "libx265";
+pub const X264_NVENC: VideoEncoder = "h264_nvenc";
+pub const X265_NVENC: VideoEncoder = "hevc_nvenc";
+
+// Maps a user supplied encoder name onto one of the supported video encoders
+pub fn video_encoder_from_name(name: &str) -> Option<VideoEncoder> {
+    match name {
+        "x264" | "libx264" => Some(X264),
+        "x265" | "libx265" => Some(X265),
+        "x264_nvenc" | "h264_nvenc" => Some(X264_NVENC),
+        "x265_nvenc" | "hevc_nvenc" => Some(X265_NVENC),
+        _ => None
+    }
+}
+
+
+type AudioEncoder = &'static str;
+
+pub const AAC: AudioEncoder = "aac";
+pub const EAC3: AudioEncoder = "eac3";
+pub const OPUS: AudioEncoder = "libopus";
+pub const FLAC: AudioEncoder = "flac";
+
+
+type SubtitleEncoder = &'static str;
+
+pub const WEB_VTT: SubtitleEncoder = "webvtt";
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        self.validate()?;
+
+        let mut cmd = CommandLine::tool(Tool::Ffmpeg);
+
+        // As input options these seek on the source's timeline, and are frame accurate when transcoding
+        if let Some(start) = self.start {
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", start.as_secs_f64()));
+        }
+        if let Some(end) = self.end {
+            cmd.arg("-to")
+                .arg(format!("{:.3}", end.as_secs_f64()));
+        }
+
+        cmd.arg("-i")
+            .path(&self.file)
+            .arg("-y")
+            // .arg("-v")
+            // .arg("quiet")
+            .arg("-progress")
+            .arg("-");
+
+        if self.video.enabled {
+            let enc = match self.video.encoder {
+                Video(x) => x,
+                Encoder::None => "copy",
+                _ => unreachable!()
+            };
+
+            cmd.arg("-c:v")
+                .arg(enc);
+
+            if self.video.bitrate > -1 {
+                cmd.arg("-b:v")
+                    .arg(self.video.bitrate.to_string());
+            }
+
+            if self.video.gop > -1 {
+                cmd.arg("-g")
+                    .arg(self.video.gop.to_string());
+            }
+
+            if let Some(d) = self.keyframe_interval {
+                cmd.arg("-force_key_frames")
+                    .arg(format!("expr:gte(t,n_forced*{:.3})", d.as_secs_f64()));
+            }
+
+            let mut filters = vec![];
+            if self.video.fps > -1 {
+                filters.push(format!("fps={}", self.video.fps));
+            }
+            if self.video.max_height > -1 {
+                filters.push(format!("scale=-2:'min(ih,{})'", self.video.max_height));
+            }
+            if self.video.colour_8_bit {
+                filters.push("format=yuv420p".to_string());
+            }
+            if let Some(idx) = self.burn_subtitle {
+                // Overlaying needs a filter graph with both inputs, the rest of the filters then run
+                // on the composited output
+                filters.insert(0, format!("[0:v:0][0:{}]overlay", idx));
+                cmd.arg("-filter_complex")
+                    .arg(filters.join(",") + "[v]")
+                    .arg("-map")
+                    .arg("[v]");
+            } else if !filters.is_empty() {
+                cmd.arg("-vf")
+                    .arg(filters.join(","));
+            }
+
+            if self.video.crf > -1 {
+                cmd.arg("-crf")
+                    .arg(self.video.crf.to_string());
+            }
+        } else {
+            cmd.arg("-vn");
+        }
+
+        if self.audio.enabled {
+            let enc = match self.audio.encoder {
+                Audio(x) => x,
+                Encoder::None => "copy",
+                _ => unreachable!()
+            };
+
+            cmd.arg("-c:a")
+                .arg(enc);
+
+            if self.audio.bitrate > -1 {
+                cmd.arg("-b:a")
+                    .arg(self.audio.bitrate.to_string());
+            }
+
+            if self.audio.channels > -1 {
+                cmd.arg("-ac")
+                    .arg(self.audio.channels.to_string());
+            }
+        } else {
+            cmd.arg("-an");
+        }
+
+        if self.subtitle.enabled {
+            let enc = match self.subtitle.encoder {
+                Subtitle(x) => x,
+                Encoder::None => "copy",
+                _ => unreachable!()
+            };
+
+            cmd.arg("-c:s")
+                .arg(enc);
+        } else {
+            cmd.arg("-sn");
+        }
+
+        if let Some(d) = self.duration {
+            cmd.arg("-t")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+
+        for t in &self.tracks {
+            cmd.arg("-map")
+                .arg("0:".to_string() + &*t.to_string());
+        }
+
+        // A fragmented MP4 is written front to back, so it can go into a pipe
+        if self.fragmented {
+            cmd.arg("-movflags")
+                .arg("+frag_keyframe+empty_moov+default_base_moof");
+            if let Some(d) = self.fragment_duration {
+                cmd.arg("-frag_duration")
+                    .arg(d.as_micros().to_string());
+            }
+        } else if self.faststart {
+            cmd.arg("-movflags")
+                .arg("+faststart");
+        }
+
+        cmd.path(self.out_path());
+
+        Ok(cmd)
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        match self.video.encoder {
+            Audio(_) | Subtitle(_) => Err(InvalidCommandConfig("video cannot have an audio or subtitle encoder")),
+            Video(_) | Encoder::None => Ok(())
+        }?;
+        match self.audio.encoder {
+            Video(_) | Subtitle(_) => Err(InvalidCommandConfig("audio cannot have an video or subtitle encoder")),
+            Audio(_) | Encoder::None => Ok(())
+        }?;
+        match self.subtitle.encoder {
+            Audio(_) | Video(_) => Err(InvalidCommandConfig("subtitle cannot have an audio or video encoder")),
+            Subtitle(_) | Encoder::None => Ok(())
+        }?;
+
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if start >= end {
+                return Err(InvalidCommandConfig("start must be before the end"));
+            }
+        }
+
+        if !self.video.enabled && !self.audio.enabled && !self.subtitle.enabled {
+            return Err(InvalidCommandConfig("no streams are enabled"));
+        }
+
+        if self.audio.crf > -1 || self.subtitle.crf > -1 {
+            return Err(InvalidCommandConfig("audio and subtitles cannot have a crf"));
+        }
+
+        if self.audio.max_height > -1 || self.subtitle.max_height > -1
+            || self.audio.fps > -1 || self.subtitle.fps > -1 || self.audio.gop > -1 || self.subtitle.gop > -1 {
+            return Err(InvalidCommandConfig("audio and subtitles cannot have a max height, fps or gop"));
+        }
+
+        if (self.video.bitrate > -1 || self.video.crf > -1 || self.video.max_height > -1
+            || self.video.fps > -1 || self.video.gop > -1) && self.video.encoder == Encoder::None {
+            return Err(InvalidCommandConfig("bitrate, crf, max height, fps and gop cannot be set without an encoder"));
+        }
+
+        if self.burn_subtitle.is_some() && (!self.video.enabled || self.video.encoder == Encoder::None) {
+            return Err(InvalidCommandConfig("subtitles can only be burned in when encoding video"));
+        }
+
+        if self.fragmented && self.faststart {
+            return Err(InvalidCommandConfig("a fragmented MP4 has no index to move to the start"));
+        }
+
+        if self.keyframe_interval.is_some() && (!self.video.enabled || self.video.encoder == Encoder::None) {
+            return Err(InvalidCommandConfig("keyframes can only be placed when encoding video"));
+        }
+
+        Ok(())
+    }
+
+    fn can_fail(&self) -> bool {
+        self.can_fail
+    }
+
+    fn name(&self) -> String {
+        let idx = self.tracks.get(0).cloned().unwrap_or(0);
+        if self.video.enabled && self.video.fps > -1 {
+            "Encode trick play video".to_string()
+        } else if self.video.enabled {
+            "Encode video".to_string()
+        } else if self.audio.enabled {
+            format!("Encode audio stream {}", idx)
+        } else {
+            format!("Extract subtitle stream {}", idx)
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        let copy = |o: &CodecOpts| o.encoder == Encoder::None;
+        if self.video.enabled && copy(&self.video) {
+            1.0
+        } else if self.video.enabled && self.video.fps > -1 {
+            3.0
+        } else if self.video.enabled {
+            10.0
+        } else if self.audio.enabled && copy(&self.audio) {
+            0.5
+        } else if self.audio.enabled {
+            1.0
+        } else {
+            0.1
+        }
+    }
+
+    fn reports_progress(&self) -> bool {
+        true
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        vec![self.file.clone()]
+    }
+
+    fn outputs(&self) -> Vec<PathBuf> {
+        vec![self.out_path()]
+    }
+}
+
+#[allow(dead_code)]
+impl Config {
+    fn out_path(&self) -> PathBuf {
+        self.out_file.clone().unwrap_or({
+            let mut base = WORK_DIR.to_path_buf();
+            let mut stem = self.file.file_stem().unwrap().to_os_string();
+            stem.push({
+                let idx = self.tracks.get(0).cloned().unwrap_or(0);
+                if self.video.enabled {
+                    format!("-split-vid-{}.mp4", idx)
+                } else if self.audio.enabled {
+                    format!("-split-aud-{}.mp4", idx)
+                } else {
+                    format!("-split-sub-{}.vtt", idx)
+                }
+            });
+            base.push(stem);
+            base
+        })
+    }
+
+    pub fn new(file: PathBuf) -> Self {
+        Config {
+            file,
+            out_file: None,
+            tracks: vec![],
+            burn_subtitle: None,
+            keyframe_interval: None,
+            fragmented: false,
+            fragment_duration: None,
+            faststart: false,
+            duration: None,
+            start: None,
+            end: None,
+            video: CodecOpts {
+                encoder: Encoder::None,
+                bitrate: -1,
+                enabled: true,
+                crf: -1,
+                channels: -1,
+                colour_8_bit: false,
+                max_height: -1,
+                fps: -1,
+                gop: -1,
+            },
+            audio: CodecOpts {
+                encoder: Encoder::None,
+                bitrate: -1,
+                enabled: true,
+                crf: -1,
+                channels: -1,
+                colour_8_bit: false,
+                max_height: -1,
+                fps: -1,
+                gop: -1,
+            },
+            subtitle: CodecOpts {
+                encoder: Encoder::None,
+                bitrate: -1,
+                enabled: true,
+                crf: -1,
+                channels: -1,
+                colour_8_bit: false,
+                max_height: -1,
+                fps: -1,
+                gop: -1,
+            },
+            can_fail: false,
+        }
+    }
+
+    pub fn out(&mut self, out: PathBuf) -> &mut Self {
+        self.out_file = Some(out);
+        self
+    }
+
+    pub fn crf(&mut self, crf: isize) -> &mut Self {
+        self.video.crf = crf;
+        self
+    }
+
+    pub fn video_bitrate(&mut self, b: isize) -> &mut Self {
+        self.video.bitrate = b;
+        self
+    }
+
+    pub fn audio_bitrate(&mut self, b: isize) -> &mut Self {
+        self.audio.bitrate = b;
+        self
+    }
+
+    pub fn video_encoder(&mut self, e: VideoEncoder) -> &mut Self {
+        self.video.encoder = Video(e);
+        self
+    }
+
+    pub fn audio_encoder(&mut self, e: AudioEncoder) -> &mut Self {
+        self.audio.encoder = Audio(e);
+        self
+    }
+
+    pub fn subtitle_encoder(&mut self, e: SubtitleEncoder) -> &mut Self {
+        self.subtitle.encoder = Subtitle(e);
+        self
+    }
+
+    pub fn video_disabled(&mut self) -> &mut Self {
+        self.video.enabled = false;
+        self
+    }
+
+    pub fn audio_disabled(&mut self) -> &mut Self {
+        self.audio.enabled = false;
+        self
+    }
+
+    pub fn subtitle_disabled(&mut self) -> &mut Self {
+        self.subtitle.enabled = false;
+        self
+    }
+
+    pub fn audio_channels(&mut self, channels: isize) -> &mut Self {
+        self.audio.channels = channels;
+        self
+    }
+
+    pub fn tracks<T>(&mut self, tracks: T) -> &mut Self
+        where
+            T: IntoIterator<Item=isize>,
+    {
+        self.tracks.extend(tracks);
+        self
+    }
+
+    pub fn max_height(&mut self, height: isize) -> &mut Self {
+        self.video.max_height = height;
+        self
+    }
+
+    pub fn fps(&mut self, fps: isize) -> &mut Self {
+        self.video.fps = fps;
+        self
+    }
+
+    // Maximum distance between keyframes, 1 makes every frame a keyframe
+    pub fn gop(&mut self, gop: isize) -> &mut Self {
+        self.video.gop = gop;
+        self
+    }
+
+    // Forces a keyframe at least this often, so segments of this length can be cut
+    pub fn keyframe_interval(&mut self, d: Duration) -> &mut Self {
+        self.keyframe_interval = Some(d);
+        self
+    }
+
+    // Writes a fragmented MP4, with a fragment starting at each keyframe
+    pub fn fragmented(&mut self) -> &mut Self {
+        self.fragmented = true;
+        self
+    }
+
+    // Fragments at least this often as well as at keyframes
+    pub fn fragment_duration(&mut self, d: Duration) -> &mut Self {
+        self.fragmented = true;
+        self.fragment_duration = Some(d);
+        self
+    }
+
+    // Moves the index to the start of the file once it's written, so players can start before
+    // they have all of it
+    pub fn faststart(&mut self) -> &mut Self {
+        self.faststart = true;
+        self
+    }
+
+    // Only convert the first part of the input
+    pub fn duration(&mut self, d: Duration) -> &mut Self {
+        self.duration = Some(d);
+        self
+    }
+
+    pub fn start(&mut self, start: Duration) -> &mut Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn end(&mut self, end: Duration) -> &mut Self {
+        self.end = Some(end);
+        self
+    }
+
+    pub fn burn_subtitle(&mut self, track: isize) -> &mut Self {
+        self.burn_subtitle = Some(track);
+        self
+    }
+
+    pub fn colour_8_bit(&mut self) -> &mut Self {
+        self.video.colour_8_bit = true;
+        self
+    }
+
+    pub fn can_fail(&mut self) -> &mut Self {
+        self.can_fail = true;
+        self
+    }
+}
+
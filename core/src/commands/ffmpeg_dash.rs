@@ -0,0 +1,461 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::commands::{CommandLine, MediaCommandConfig, SessionError, Tool};
+use crate::commands::ffmpeg::{WEB_VTT, X264};
+use crate::commands::mp4dash::MANIFEST;
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::error::ConvError;
+
+// Encodes and packages in a single ffmpeg run using its dash muxer, so there are no intermediate
+// files and Bento4 isn't needed. The muxer can't carry text, so subtitles are converted alongside
+// into the package and added to the manifest afterwards by add_subtitles.
+pub struct Config {
+    file: PathBuf,
+    out_dir: Option<PathBuf>,
+    mpd_name: String,
+    video: Option<Video>,
+    audios: Vec<Audio>,
+    subtitles: Vec<Subtitle>,
+    duration: Option<Duration>,
+    start: Option<Duration>,
+    end: Option<Duration>,
+    hls: bool,
+    segment_duration: Option<Duration>,
+    fragment_duration: Option<Duration>,
+    low_latency: bool,
+}
+
+pub struct Video {
+    index: isize,
+    // Copied when None
+    encoder: Option<&'static str>,
+    crf: Option<isize>,
+    bitrate: Option<isize>,
+    max_height: Option<isize>,
+    colour_8_bit: bool,
+    burn_subtitle: Option<isize>,
+    trick_play: bool,
+}
+
+pub struct Audio {
+    index: isize,
+    // Copied when None
+    encoder: Option<&'static str>,
+    channels: Option<isize>,
+    bitrate: Option<isize>,
+    language: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Subtitle {
+    index: isize,
+    language: Option<String>,
+    role: Option<&'static str>,
+}
+
+impl Video {
+    pub fn new(index: isize) -> Self {
+        Video {
+            index,
+            encoder: None,
+            crf: None,
+            bitrate: None,
+            max_height: None,
+            colour_8_bit: false,
+            burn_subtitle: None,
+            trick_play: false,
+        }
+    }
+
+    pub fn encoder(&mut self, e: &'static str) -> &mut Self {
+        self.encoder = Some(e);
+        self
+    }
+
+    pub fn crf(&mut self, crf: isize) -> &mut Self {
+        self.crf = Some(crf);
+        self
+    }
+
+    pub fn bitrate(&mut self, b: isize) -> &mut Self {
+        self.bitrate = Some(b);
+        self
+    }
+
+    pub fn max_height(&mut self, height: isize) -> &mut Self {
+        self.max_height = Some(height);
+        self
+    }
+
+    pub fn colour_8_bit(&mut self) -> &mut Self {
+        self.colour_8_bit = true;
+        self
+    }
+
+    pub fn burn_subtitle(&mut self, track: isize) -> &mut Self {
+        self.burn_subtitle = Some(track);
+        self
+    }
+
+    // Adds a keyframe only rendition marked as the trick mode of the main one
+    pub fn trick_play(&mut self) -> &mut Self {
+        self.trick_play = true;
+        self
+    }
+}
+
+impl Audio {
+    pub fn new(index: isize, language: Option<String>) -> Self {
+        Audio { index, encoder: None, channels: None, bitrate: None, language }
+    }
+
+    pub fn encoder(&mut self, e: &'static str) -> &mut Self {
+        self.encoder = Some(e);
+        self
+    }
+
+    pub fn channels(&mut self, channels: isize) -> &mut Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    pub fn bitrate(&mut self, b: isize) -> &mut Self {
+        self.bitrate = Some(b);
+        self
+    }
+}
+
+impl Subtitle {
+    pub fn new(index: isize, language: Option<String>) -> Self {
+        Subtitle { index, language, role: None }
+    }
+
+    // A DASH role from the urn:mpeg:dash:role:2011 scheme e.g. "main" or "forced-subtitle"
+    pub fn role(&mut self, role: &'static str) -> &mut Self {
+        self.role = Some(role);
+        self
+    }
+
+    fn file_name(&self) -> String {
+        format!("sub-{}.vtt", self.index)
+    }
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        self.validate()?;
+        let out_dir = self.out_dir.as_ref().ok_or(InvalidCommandConfig("no output directory was given"))?;
+
+        let mut cmd = CommandLine::tool(Tool::Ffmpeg);
+
+        if let Some(start) = self.start {
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", start.as_secs_f64()));
+        }
+        if let Some(end) = self.end {
+            cmd.arg("-to")
+                .arg(format!("{:.3}", end.as_secs_f64()));
+        }
+
+        cmd.arg("-i")
+            .path(&self.file)
+            .arg("-y")
+            .arg("-progress")
+            .arg("-");
+
+        // Output streams are numbered in the order they're mapped, which the adaptation sets refer to
+        let mut sets = vec![];
+        let mut stream = 0;
+        if let Some(v) = &self.video {
+            let mut filters = vec![];
+            if let Some(h) = v.max_height {
+                filters.push(format!("scale=-2:'min(ih,{})'", h));
+            }
+            if v.colour_8_bit {
+                filters.push("format=yuv420p".to_string());
+            }
+            if let Some(idx) = v.burn_subtitle {
+                filters.insert(0, format!("[0:{}][0:{}]overlay", v.index, idx));
+                cmd.arg("-filter_complex")
+                    .arg(filters.join(",") + "[v]")
+                    .arg("-map")
+                    .arg("[v]");
+            } else {
+                cmd.arg("-map")
+                    .arg(format!("0:{}", v.index));
+                if !filters.is_empty() {
+                    cmd.arg("-filter:v:0")
+                        .arg(filters.join(","));
+                }
+            }
+            cmd.arg("-c:v:0")
+                .arg(v.encoder.unwrap_or("copy"));
+            if let Some(crf) = v.crf {
+                cmd.arg("-crf:v:0")
+                    .arg(crf.to_string());
+            }
+            if let Some(b) = v.bitrate {
+                cmd.arg("-b:v:0")
+                    .arg(b.to_string());
+            }
+            // Segments can only start on a keyframe
+            if let (Some(d), Some(_)) = (self.segment_duration, v.encoder) {
+                cmd.arg("-force_key_frames:v:0")
+                    .arg(format!("expr:gte(t,n_forced*{:.3})", d.as_secs_f64()));
+            }
+            sets.push(format!("id=0,streams={}", stream));
+            stream += 1;
+
+            if v.trick_play {
+                cmd.arg("-map")
+                    .arg(format!("0:{}", v.index))
+                    .arg("-filter:v:1")
+                    .arg("fps=1,scale=-2:'min(ih,360)',format=yuv420p")
+                    .arg("-c:v:1")
+                    .arg(X264)
+                    .arg("-g:v:1")
+                    .arg("1")
+                    .arg("-crf:v:1")
+                    .arg("28");
+                sets.push(format!("id=1,trick_id=0,streams={}", stream));
+                stream += 1;
+            }
+        }
+
+        for (i, a) in self.audios.iter().enumerate() {
+            cmd.arg("-map")
+                .arg(format!("0:{}", a.index))
+                .arg(format!("-c:a:{}", i))
+                .arg(a.encoder.unwrap_or("copy"));
+            if let Some(b) = a.bitrate {
+                cmd.arg(format!("-b:a:{}", i))
+                    .arg(b.to_string());
+            }
+            if let Some(c) = a.channels {
+                cmd.arg(format!("-ac:a:{}", i))
+                    .arg(c.to_string());
+            }
+            if let Some(l) = &a.language {
+                cmd.arg(format!("-metadata:s:a:{}", i))
+                    .arg(format!("language={}", l));
+            }
+            // Each audio stream gets a set of its own so players can choose between them
+            sets.push(format!("id={},streams={}", sets.len(), stream));
+            stream += 1;
+        }
+
+        self.duration_arg(&mut cmd);
+        cmd.arg("-f")
+            .arg("dash")
+            .arg("-adaptation_sets")
+            .arg(sets.join(" "))
+            .arg("-use_template")
+            .arg("1")
+            .arg("-use_timeline")
+            .arg("1");
+        if let Some(d) = self.segment_duration {
+            cmd.arg("-seg_duration")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+        if let Some(d) = self.fragment_duration {
+            cmd.arg("-frag_type")
+                .arg("duration")
+                .arg("-frag_duration")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+        // Low latency DASH writes availabilityTimeOffset, which needs the segments written as
+        // they're produced
+        if self.low_latency {
+            cmd.arg("-streaming")
+                .arg("1")
+                .arg("-ldash")
+                .arg("1");
+        }
+        // The muxer names the master playlist itself, and only for the audio and video
+        if self.hls {
+            cmd.arg("-hls_playlist")
+                .arg("1");
+        }
+        cmd.path(out_dir.join(&self.mpd_name));
+
+        // Each subtitle is a further output of the same run
+        for s in &self.subtitles {
+            cmd.arg("-map")
+                .arg(format!("0:{}", s.index))
+                .arg("-c:s")
+                .arg(WEB_VTT);
+            self.duration_arg(&mut cmd);
+            cmd.path(out_dir.join(s.file_name()));
+        }
+
+        Ok(cmd)
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if start >= end {
+                return Err(InvalidCommandConfig("start must be before the end"));
+            }
+        }
+
+        if self.video.is_none() && self.audios.is_empty() {
+            return Err(InvalidCommandConfig("no streams are enabled"));
+        }
+
+        if let Some(v) = &self.video {
+            if (v.crf.is_some() || v.bitrate.is_some() || v.max_height.is_some() || v.burn_subtitle.is_some())
+                && v.encoder.is_none() {
+                return Err(InvalidCommandConfig("bitrate, crf, max height and burnt in subtitles cannot be set without an encoder"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        if self.video.as_ref().map_or(false, |v| v.encoder.is_some()) {
+            "Encode and package".to_string()
+        } else {
+            "Package".to_string()
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        match &self.video {
+            Some(v) if v.encoder.is_some() => 10.0,
+            Some(v) if v.trick_play => 3.0,
+            _ => 1.0,
+        }
+    }
+
+    fn reports_progress(&self) -> bool {
+        true
+    }
+}
+
+impl Config {
+    pub fn new(file: PathBuf) -> Self {
+        Config {
+            file,
+            out_dir: None,
+            mpd_name: MANIFEST.to_string(),
+            video: None,
+            audios: vec![],
+            subtitles: vec![],
+            duration: None,
+            start: None,
+            end: None,
+            hls: false,
+            segment_duration: None,
+            fragment_duration: None,
+            low_latency: false,
+        }
+    }
+
+    pub fn mpd_name(&mut self, name: &str) -> &mut Self {
+        self.mpd_name = name.to_string();
+        self
+    }
+
+    // Also writes HLS playlists for the same segments
+    pub fn hls(&mut self) -> &mut Self {
+        self.hls = true;
+        self
+    }
+
+    pub fn segment_duration(&mut self, d: Duration) -> &mut Self {
+        self.segment_duration = Some(d);
+        self
+    }
+
+    // Splits segments into fragments of this length, by default each segment is one fragment
+    pub fn fragment_duration(&mut self, d: Duration) -> &mut Self {
+        self.fragment_duration = Some(d);
+        self
+    }
+
+    pub fn low_latency(&mut self) -> &mut Self {
+        self.low_latency = true;
+        self
+    }
+
+    pub fn out_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.out_dir = Some(dir);
+        self
+    }
+
+    pub fn video(&mut self, video: Video) -> &mut Self {
+        self.video = Some(video);
+        self
+    }
+
+    pub fn audio(&mut self, audio: Audio) -> &mut Self {
+        self.audios.push(audio);
+        self
+    }
+
+    pub fn subtitle(&mut self, subtitle: Subtitle) -> &mut Self {
+        self.subtitles.push(subtitle);
+        self
+    }
+
+    // Only convert the first part of the input
+    pub fn duration(&mut self, d: Duration) -> &mut Self {
+        self.duration = Some(d);
+        self
+    }
+
+    pub fn start(&mut self, start: Duration) -> &mut Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn end(&mut self, end: Duration) -> &mut Self {
+        self.end = Some(end);
+        self
+    }
+
+    pub fn subtitles(&self) -> Vec<Subtitle> {
+        self.subtitles.clone()
+    }
+
+    fn duration_arg(&self, cmd: &mut CommandLine) {
+        if let Some(d) = self.duration {
+            cmd.arg("-t")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+    }
+}
+
+// Adds the subtitles converted next to the manifest to it, as side loaded WebVTT files
+pub fn add_subtitles(manifest: &Path, subtitles: &[Subtitle]) -> io::Result<()> {
+    if subtitles.is_empty() {
+        return Ok(());
+    }
+    let out_dir = manifest.parent().unwrap_or(manifest);
+    let mut mpd = std::fs::read_to_string(manifest)?;
+    let end = mpd.rfind("</Period>")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the manifest has no period"))?;
+
+    let mut sets = String::new();
+    for s in subtitles.iter().filter(|s| out_dir.join(s.file_name()).exists()) {
+        let lang = s.language.as_ref()
+            .map(|l| format!(" lang=\"{}\"", escape(l)))
+            .unwrap_or_default();
+        sets.push_str(&format!("\t\t<AdaptationSet contentType=\"text\" mimeType=\"text/vtt\"{}>\n", lang));
+        if let Some(r) = s.role {
+            sets.push_str(&format!("\t\t\t<Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>\n", r));
+        }
+        sets.push_str(&format!("\t\t\t<Representation id=\"sub-{}\" bandwidth=\"256\">\n", s.index));
+        sets.push_str(&format!("\t\t\t\t<BaseURL>{}</BaseURL>\n", s.file_name()));
+        sets.push_str("\t\t\t</Representation>\n\t\t</AdaptationSet>\n");
+    }
+    mpd.insert_str(end, &sets);
+    std::fs::write(manifest, mpd)
+}
+
+fn escape(v: &str) -> String {
+    v.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{SETTINGS, vtt};
+use crate::commands::Tool;
+use crate::error::ConvError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FFProbeResponse {
+    pub streams: Vec<Stream>,
+    pub format: Format,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    pub start_time: String,
+    pub end_time: String,
+    pub tags: Option<Tags>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Format {
+    // Absent for some TS and AVI sources, see FFProbeResponse::duration
+    pub duration: Option<String>,
+    // Bytes, absent for some streamed inputs
+    pub size: Option<String>,
+    // Container metadata such as show and season_number, the keys' case varies between containers
+    pub tags: Option<HashMap<String, String>>,
+}
+
+impl Format {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.as_ref()?.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stream {
+    pub index: isize,
+    pub codec_name: String,
+    pub codec_type: String,
+    pub channels: Option<isize>,
+    pub channel_layout: Option<String>,
+    pub width: Option<isize>,
+    pub height: Option<isize>,
+    pub pix_fmt: Option<String>,
+    pub profile: Option<String>,
+    pub color_transfer: Option<String>,
+    pub duration: Option<String>,
+    pub nb_frames: Option<String>,
+    pub tags: Option<Tags>,
+    pub disposition: Option<Disposition>,
+    // Everything else ffprobe reported, passed through to clients as is
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Disposition {
+    #[serde(default)]
+    pub default: u8,
+    #[serde(default)]
+    pub forced: u8,
+    #[serde(default)]
+    pub attached_pic: u8,
+    #[serde(default)]
+    pub dub: u8,
+    #[serde(default)]
+    pub original: u8,
+    #[serde(default)]
+    pub comment: u8,
+    #[serde(default)]
+    pub hearing_impaired: u8,
+    #[serde(default)]
+    pub visual_impaired: u8,
+}
+
+impl FFProbeResponse {
+    // The container's duration, or the longest stream's when the container doesn't say
+    pub fn duration(&self) -> Option<Duration> {
+        let secs = |d: &str| d.parse().ok().filter(|d: &f64| d.is_finite() && *d > 0.0).map(Duration::from_secs_f64);
+        self.format.duration.as_deref().and_then(secs).or_else(|| {
+            self.streams.iter()
+                .filter_map(|s| s.duration.as_deref().and_then(secs)
+                    .or_else(|| s.tags.as_ref()?.duration.as_deref().and_then(vtt::parse_timestamp)))
+                .max()
+        })
+    }
+
+    // The number of frames in the first video stream, when the container records it
+    pub fn frames(&self) -> Option<u64> {
+        self.streams.iter()
+            .find(|s| s.codec_type == "video" && !s.is_attached_pic())?
+            .nb_frames.as_deref()?
+            .parse().ok()
+            .filter(|f| *f > 0)
+    }
+}
+
+impl Stream {
+    // The stream's ISO 639-2 language, ignoring the 'undetermined' placeholder
+    pub fn language(&self) -> Option<String> {
+        self.tags.as_ref()
+            .and_then(|t| t.language.clone())
+            .filter(|l| l != "und")
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.disposition.as_ref().map_or(false, |d| d.default == 1)
+    }
+
+    pub fn is_forced(&self) -> bool {
+        self.disposition.as_ref().map_or(false, |d| d.forced == 1)
+    }
+
+    pub fn is_attached_pic(&self) -> bool {
+        self.disposition.as_ref().map_or(false, |d| d.attached_pic == 1)
+    }
+
+    // Bits per colour component, going by the pixel format, e.g. 10 for yuv420p10le
+    pub fn bit_depth(&self) -> Option<u8> {
+        let fmt = self.pix_fmt.as_deref()?;
+        let fmt = fmt.strip_suffix("le").or_else(|| fmt.strip_suffix("be")).unwrap_or(fmt);
+        match fmt.rsplit_once('p')?.1 {
+            "" => Some(8),
+            bits => bits.parse().ok(),
+        }
+    }
+
+    // PQ (HDR10 and Dolby Vision) and HLG are the transfer functions used for HDR
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.color_transfer.as_deref(), Some("smpte2084") | Some("arib-std-b67"))
+    }
+
+    // In bits per second. Matroska only has it in the tags, and only when the muxer wrote statistics.
+    pub fn bit_rate(&self) -> Option<isize> {
+        self.extra.get("bit_rate")
+            .and_then(|b| b.as_str())
+            .or_else(|| self.tags.as_ref().and_then(|t| t.bit_rate.as_deref()))
+            .and_then(|b| b.parse().ok())
+    }
+
+    // Image based subtitles can't be converted to WebVTT, only burned into the video
+    pub fn is_bitmap_subtitle(&self) -> bool {
+        self.codec_type == "subtitle" && matches!(&*self.codec_name,
+            "hdmv_pgs_subtitle" | "dvd_subtitle" | "dvb_subtitle" | "xsub")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub language: Option<String>,
+    // Matroska keeps stream lengths here, as hh:mm:ss.fffffffff
+    #[serde(rename = "DURATION")]
+    pub duration: Option<String>,
+    // And bitrates, in bits per second
+    #[serde(rename = "BPS", alias = "BPS-eng")]
+    pub bit_rate: Option<String>,
+}
+
+// Gives up after the probe timeout, as ffprobe can hang on some broken files and remote sources
+pub async fn get_info(file: &Path) -> Result<FFProbeResponse, ConvError> {
+    let out = Command::new(Tool::Ffprobe.path())
+        .args(Tool::Ffprobe.extra_args())
+        .arg("-v")
+        .arg("error")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_streams")
+        .arg("-show_chapters")
+        .arg("-show_entries")
+        .arg("format=duration,size:format_tags")
+        .arg(file)
+        .kill_on_drop(true)
+        .output();
+    let out = tokio::time::timeout(Duration::from_secs(SETTINGS.probe_timeout), out).await
+        .map_err(|_| ConvError::Probe { path: file.to_path_buf(), reason: "ffprobe timed out".to_string() })?
+        .map_err(|source| ConvError::Spawn { program: "ffprobe", source })?;
+    if !out.status.success() {
+        return Err(ConvError::Exit {
+            program: "ffprobe",
+            status: out.status,
+            stderr: String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        });
+    }
+
+    debug!("{:?}", std::str::from_utf8(&out.stdout));
+
+    let mut parsed: FFProbeResponse = serde_json::from_slice(&out.stdout)
+        .map_err(|e| ConvError::Probe { path: file.to_path_buf(), reason: e.to_string() })?;
+    if parsed.duration().is_none() {
+        match scan_duration(file).await {
+            Some(d) => parsed.format.duration = Some(format!("{:.6}", d.as_secs_f64())),
+            None => warn!("Could not work out the length of {:?}", file),
+        }
+    }
+    Ok(parsed)
+}
+
+// Finds the length by reading through every packet without decoding, which is as quick as reading the
+// file. Only used when nothing in the headers gives the length.
+async fn scan_duration(file: &Path) -> Option<Duration> {
+    debug!("Scanning {:?} for its length", file);
+    let out = Command::new(Tool::Ffmpeg.path())
+        .args(Tool::Ffmpeg.extra_args())
+        .arg("-v")
+        .arg("quiet")
+        .arg("-nostdin")
+        .arg("-i")
+        .arg(file)
+        .arg("-map")
+        .arg("0")
+        .arg("-c")
+        .arg("copy")
+        .arg("-f")
+        .arg("null")
+        .arg("-progress")
+        .arg("-")
+        .arg("-")
+        .kill_on_drop(true)
+        .output();
+    let out = tokio::time::timeout(Duration::from_secs(SETTINGS.probe_timeout), out).await.ok()?.ok()?;
+
+    String::from_utf8_lossy(&out.stdout).lines()
+        .filter_map(|l| l.strip_prefix("out_time_us="))
+        .filter_map(|t| t.parse().ok())
+        .last()
+        .filter(|t| *t > 0)
+        .map(Duration::from_micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::commands::ffprobe::{get_info, Stream};
+
+    #[actix_rt::test]
+    async fn parse() {
+        println!("{:?}", get_info(Path::new("1.mkv")).await.unwrap())
+    }
+
+    #[test]
+    fn bit_depth() {
+        let stream = |pix_fmt: &str| serde_json::from_value::<Stream>(serde_json::json!({
+            "index": 0,
+            "codec_name": "hevc",
+            "codec_type": "video",
+            "pix_fmt": pix_fmt,
+        })).unwrap();
+
+        assert_eq!(stream("yuv420p").bit_depth(), Some(8));
+        assert_eq!(stream("yuv420p10le").bit_depth(), Some(10));
+        assert_eq!(stream("yuv444p12be").bit_depth(), Some(12));
+        assert_eq!(stream("nv12").bit_depth(), None);
+    }
+
+    #[test]
+    fn bit_rate() {
+        let stream = |v: serde_json::Value| serde_json::from_value::<Stream>(v).unwrap();
+
+        assert_eq!(stream(serde_json::json!({
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+            "bit_rate": "128000",
+        })).bit_rate(), Some(128_000));
+        assert_eq!(stream(serde_json::json!({
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+            "tags": { "BPS-eng": "192000" },
+        })).bit_rate(), Some(192_000));
+        assert_eq!(stream(serde_json::json!({
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+        })).bit_rate(), None);
+    }
+}
\ No newline at end of file
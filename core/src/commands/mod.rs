@@ -0,0 +1,2029 @@
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Write};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use derive_more::{Display, Error};
+use futures::{FutureExt, StreamExt};
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::sync::RwLock as AsyncRwLock;
+use tracing::{debug, error, info, info_span, trace};
+use tracing_futures::Instrument;
+use uuid::Uuid;
+
+use crate::commands::ffprobe::FFProbeResponse;
+use crate::{filename, LOG_DIR, metrics, probe_cache, reaper, runtime, SETTINGS};
+use crate::filename::ParsedName;
+use crate::commands::SessionError::{AlreadyStarted, InvalidCommandConfig};
+use crate::error::ConvError;
+
+pub mod concat;
+pub mod ffprobe;
+pub mod ffmpeg;
+pub mod ffmpeg_dash;
+pub mod mp4fragment;
+pub mod mp4dash;
+pub mod poster;
+pub mod remote;
+pub mod shaka;
+pub mod thumbnails;
+
+#[derive(Display, Debug, Error)]
+pub enum SessionError {
+    #[display(fmt = "The session has already been started")]
+    AlreadyStarted,
+    #[display(fmt = "The command has ended up with an impossible configuration: {}", _0)]
+    InvalidCommandConfig(#[error(not(source))] &'static str),
+}
+
+// A program and its arguments. Unlike a Command it can be looked into, to show users what's run.
+#[derive(Debug, Clone)]
+pub struct CommandLine {
+    program: OsString,
+    args: Vec<OsString>,
+    // The tool the program is, so a worker can run it from where it has it
+    tool: Option<Tool>,
+    // How many of the arguments come with the tool as it's run here, such as its extra arguments,
+    // which a worker puts its own in place of
+    prefix: usize,
+    // Which of the arguments are keys, left out wherever the command is shown
+    secrets: Vec<usize>,
+}
+
+// Shown in place of secret arguments
+const REDACTED: &str = "[redacted]";
+
+impl CommandLine {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        CommandLine { program: program.as_ref().to_os_string(), args: vec![], tool: None, prefix: 0, secrets: vec![] }
+    }
+
+    // The tool where it's configured to be, starting with its extra arguments
+    pub fn tool(tool: Tool) -> Self {
+        let mut cmd = match tool {
+            // Can need running through Python
+            Tool::Mp4dash => mp4dash::launcher(),
+            _ => {
+                let mut cmd = CommandLine::new(tool.path());
+                cmd.args(tool.extra_args());
+                cmd
+            }
+        };
+        cmd.tool = Some(tool);
+        cmd.prefix = cmd.args.len();
+        cmd
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    // A file or directory, in the form programs can open whatever its length
+    pub fn path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.args.push(long_path(path.as_ref()).into_os_string());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(&mut self, args: I) -> &mut Self {
+        self.args.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    // An argument which is passed as it is but never logged, such as an encryption key
+    pub fn secret<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.secrets.push(self.args.len());
+        self.arg(arg)
+    }
+
+    // The arguments as they can be shown
+    fn shown_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().enumerate()
+            .map(move |(i, a)| if self.secrets.contains(&i) { OsStr::new(REDACTED) } else { a.as_os_str() })
+    }
+
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        cmd
+    }
+
+    // What the program is called in errors, the tool's name when it's one
+    fn program_name(&self) -> &'static str {
+        self.tool.map_or("the stage's command", Tool::name)
+    }
+
+    // Differs between commands which would write something different, keys and all
+    fn digest(&self) -> String {
+        let mut hash = Sha256::new();
+        for part in once(&self.program).chain(&self.args) {
+            hash.update(path_bytes(Path::new(part)));
+            hash.update([0]);
+        }
+        hex::encode(hash.finalize())
+    }
+}
+
+// The external programs commands run, see SETTINGS.tools
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tool {
+    Ffmpeg,
+    Ffprobe,
+    Mp4fragment,
+    Mp4dash,
+    // Shaka Packager
+    Packager,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 5] = [Tool::Ffmpeg, Tool::Ffprobe, Tool::Mp4fragment, Tool::Mp4dash, Tool::Packager];
+
+    pub fn from_name(name: &str) -> Option<Tool> {
+        Tool::ALL.iter().copied().find(|t| t.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Ffmpeg => "ffmpeg",
+            Tool::Ffprobe => "ffprobe",
+            Tool::Mp4fragment => "mp4fragment",
+            Tool::Mp4dash => "mp4dash",
+            Tool::Packager => "packager",
+        }
+    }
+
+    pub fn path(self) -> &'static Path {
+        SETTINGS.tools.path(self)
+    }
+
+    pub fn extra_args(self) -> &'static [String] {
+        SETTINGS.tools.extra_args(self)
+    }
+}
+
+// Arguments which aren't valid UTF-8 are shown lossily, they're still passed as they are. Secrets
+// are left out as they are when displayed.
+impl Serialize for CommandLine {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("CommandLine", 2)?;
+        s.serialize_field("program", &self.program.to_string_lossy())?;
+        s.serialize_field("args", &self.shown_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>())?;
+        s.end()
+    }
+}
+
+// As it would be typed into a shell, but for any secrets
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quote = |a: &OsStr| {
+            let a = a.to_string_lossy();
+            if !a.is_empty() && a.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=+,".contains(c)) {
+                a.into_owned()
+            } else {
+                format!("'{}'", a.replace('\'', "'\\''"))
+            }
+        };
+        write!(f, "{}", quote(&self.program))?;
+        for a in self.shown_args() {
+            write!(f, " {}", quote(a))?;
+        }
+        Ok(())
+    }
+}
+
+// Set once the server is shutting down. Sessions then stop before their next stage, and those
+// cancelled from then on are recorded as interrupted.
+pub static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub trait MediaCommandConfig {
+    // The program and arguments to run, which is all build needs
+    fn describe(&self) -> Result<CommandLine, ConvError>;
+    fn build(&self) -> Result<Command, ConvError> {
+        Ok(self.describe()?.command())
+    }
+    fn validate(&self) -> Result<(), SessionError> {
+        Ok(())
+    }
+    // Whether the session carries on without the stage's output when it fails
+    fn can_fail(&self) -> bool {
+        false
+    }
+    // What the stage does, for showing to users, by default the program it runs
+    fn name(&self) -> String {
+        self.describe()
+            .ok()
+            .and_then(|c| Path::new(&c.program).file_stem().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_default()
+    }
+    // Roughly how long the stage takes relative to the others, a full video encode being 10
+    fn weight(&self) -> f64 {
+        1.0
+    }
+    // Whether the command writes ffmpeg style progress to stdout, others only show as busy
+    fn reports_progress(&self) -> bool {
+        false
+    }
+    // The files the command reads and writes. A stage is skipped when all of its outputs are newer
+    // than its inputs, so commands which don't list their outputs always run.
+    fn inputs(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+    fn outputs(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+}
+
+// So stages which vary with the settings can be put together before being chained
+impl<T: MediaCommandConfig + ?Sized> MediaCommandConfig for Box<T> {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        (**self).describe()
+    }
+
+    fn build(&self) -> Result<Command, ConvError> {
+        (**self).build()
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        (**self).validate()
+    }
+
+    fn can_fail(&self) -> bool {
+        (**self).can_fail()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn weight(&self) -> f64 {
+        (**self).weight()
+    }
+
+    fn reports_progress(&self) -> bool {
+        (**self).reports_progress()
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        (**self).inputs()
+    }
+
+    fn outputs(&self) -> Vec<PathBuf> {
+        (**self).outputs()
+    }
+}
+
+// A stage as it would be run, stages are numbered from 1 as elsewhere in the API
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PlannedStage {
+    pub stage: usize,
+    pub name: String,
+    // The program and its arguments
+    #[schema(value_type = Object)]
+    pub command: CommandLine,
+    pub after: Vec<usize>,
+    pub can_fail: bool,
+    #[schema(value_type = Vec<String>)]
+    pub inputs: Vec<PathBuf>,
+    #[schema(value_type = Vec<String>)]
+    pub outputs: Vec<PathBuf>,
+}
+
+// Which of the stages added before it a stage has to wait for, by the order they were added.
+// Stages which don't depend on each other run at the same time, up to SETTINGS.stage_parallelism.
+#[derive(Clone, Debug)]
+pub enum After {
+    All,
+    Stages(Vec<usize>),
+}
+
+pub struct Session {
+    id: Uuid,
+    media_info: Arc<RwLock<MediaInfo>>,
+    progress: Arc<Progress>,
+    info: watch::Receiver<SessionInfoInt>,
+    commands: Vec<Box<dyn MediaCommandConfig + Send + Sync>>,
+    // What each command waits for
+    after: Vec<After>,
+    // Kept after the commands are handed over to run
+    stage_names: Vec<String>,
+    stage_weights: Vec<f64>,
+    stage_progress: Vec<bool>,
+    on_success: Vec<Box<dyn FnOnce() -> io::Result<()> + Send + Sync>>,
+    events: Option<broadcast::Sender<SessionEvent>>,
+    output: Option<PathBuf>,
+    // Made as the session starts and removed should it not complete, see make_dir
+    made: Option<PathBuf>,
+    owner: Option<String>,
+    work_dir: Option<PathBuf>,
+    work_lock: Option<File>,
+    parallelism: Option<usize>,
+    cancel: watch::Sender<bool>,
+    cancelled: watch::Receiver<bool>,
+    // The worker running the session, which sends its progress through reports, see remote
+    worker: Option<String>,
+    reports: Option<mpsc::UnboundedSender<remote::Update>>,
+    // Where lines logged are also sent once started, for a worker to pass on
+    forward: Option<mpsc::UnboundedSender<(Stream, String)>>,
+}
+
+// Lifecycle notifications for anyone watching the sessions
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(untagged)]
+pub enum SessionEvent {
+    Created { #[schema(value_type = String)] id: Uuid },
+    Stage { #[schema(value_type = String)] id: Uuid, stage: usize, max_stages: usize },
+    Progress(SessionInfo),
+    Completed { #[schema(value_type = String)] id: Uuid },
+    Failed { #[schema(value_type = String)] id: Uuid },
+    Cancelled { #[schema(value_type = String)] id: Uuid },
+    Interrupted { #[schema(value_type = String)] id: Uuid },
+}
+
+impl SessionEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::Created { .. } => "created",
+            SessionEvent::Stage { .. } => "stage",
+            SessionEvent::Progress(_) => "progress",
+            SessionEvent::Completed { .. } => "completed",
+            SessionEvent::Failed { .. } => "failed",
+            SessionEvent::Cancelled { .. } => "cancelled",
+            SessionEvent::Interrupted { .. } => "interrupted",
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        match self {
+            SessionEvent::Created { id }
+            | SessionEvent::Stage { id, .. }
+            | SessionEvent::Completed { id }
+            | SessionEvent::Failed { id }
+            | SessionEvent::Cancelled { id }
+            | SessionEvent::Interrupted { id } => *id,
+            // Progress carries the id as a string for the API
+            SessionEvent::Progress(info) => Uuid::parse_str(&info.id).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionInfoInt {
+    frame: usize,
+    fps: f64,
+    bitrate: f64,
+    total_size: usize,
+    time: Duration,
+    stdout: VecDeque<String>,
+    stderr: VecDeque<String>,
+    // Lines for the session's log file, see log_writer
+    log_file: Option<mpsc::UnboundedSender<String>>,
+    // The stage started last
+    stage: usize,
+    max_stages: usize,
+    // Which stages have finished
+    done: Vec<bool>,
+    // The running stage whose progress the figures above are for, from 0
+    lead: Option<usize>,
+    failed: bool,
+    complete: bool,
+    cancelled: bool,
+    interrupted: bool,
+    created: SystemTime,
+    started: Option<SystemTime>,
+    finished: Option<SystemTime>,
+    // When the lead stage started
+    stage_started: Option<Instant>,
+    error: Option<String>,
+    forward: Option<mpsc::UnboundedSender<(Stream, String)>>,
+}
+
+impl SessionInfoInt {
+    fn new() -> Self {
+        SessionInfoInt {
+            frame: 0,
+            fps: 0.0,
+            bitrate: 0.0,
+            total_size: 0,
+            time: Duration::from_secs(0),
+            stdout: VecDeque::new(),
+            stderr: VecDeque::new(),
+            log_file: None,
+            stage: 0,
+            max_stages: 1,
+            done: vec![],
+            lead: None,
+            failed: false,
+            complete: false,
+            cancelled: false,
+            interrupted: false,
+            created: SystemTime::now(),
+            started: None,
+            finished: None,
+            stage_started: None,
+            error: None,
+            forward: None,
+        }
+    }
+
+    fn state(&self) -> SessionState {
+        if self.interrupted {
+            SessionState::Interrupted
+        } else if self.cancelled {
+            SessionState::Cancelled
+        } else if self.failed {
+            SessionState::Failed
+        } else if self.complete {
+            SessionState::Complete
+        } else if self.started.is_some() {
+            SessionState::Running
+        } else {
+            SessionState::Pending
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finished = Some(SystemTime::now());
+        // Lets the log's writer finish once it has written what's left
+        self.log_file = None;
+    }
+
+    // A copy with only the latest lines of output, which is all the session info shows
+    fn snapshot(&self) -> Self {
+        SessionInfoInt {
+            frame: self.frame,
+            fps: self.fps,
+            bitrate: self.bitrate,
+            total_size: self.total_size,
+            time: self.time,
+            stdout: tail(&self.stdout),
+            stderr: tail(&self.stderr),
+            log_file: None,
+            stage: self.stage,
+            max_stages: self.max_stages,
+            done: self.done.clone(),
+            lead: self.lead,
+            failed: self.failed,
+            complete: self.complete,
+            cancelled: self.cancelled,
+            interrupted: self.interrupted,
+            created: self.created,
+            started: self.started,
+            finished: self.finished,
+            stage_started: self.stage_started,
+            error: self.error.clone(),
+            forward: None,
+        }
+    }
+
+    // Keeps the latest lines in memory and appends every line to the session's log file
+    fn log(&mut self, stream: Stream, line: String) {
+        if let Some(tx) = &self.log_file {
+            tx.send(format!("{}\t{}\n", stream.name(), line)).ok();
+        }
+        if let Some(tx) = &self.forward {
+            tx.send((stream, line.clone())).ok();
+        }
+        let buf = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+        buf.push_back(line);
+        while buf.len() > SETTINGS.session_log_lines {
+            buf.pop_front();
+        }
+    }
+}
+
+// A session's progress. Its tasks write through the lock and publish each change as a snapshot, so
+// reading a session's info never waits on them.
+struct Progress {
+    info: AsyncRwLock<SessionInfoInt>,
+    snapshot: watch::Sender<SessionInfoInt>,
+}
+
+impl Progress {
+    async fn update<F>(&self, f: F)
+        where F: FnOnce(&mut SessionInfoInt)
+    {
+        let mut info = self.info.write().await;
+        f(&mut info);
+        self.snapshot.broadcast(info.snapshot()).ok();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LogLine {
+    stream: &'static str,
+    line: String,
+}
+
+// Lines included with the session info, the rest are fetched through the logs endpoint
+const LOG_PREVIEW_LINES: usize = 20;
+
+fn log_path(id: Uuid) -> PathBuf {
+    LOG_DIR.join(format!("{}.log", id))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionState {
+    Pending,
+    Running,
+    Complete,
+    Failed,
+    Cancelled,
+    // Stopped by the server shutting down
+    Interrupted,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct SessionInfo {
+    id: String,
+    file_name: String,
+    owner: Option<String>,
+    // The worker running it, when it isn't run here
+    worker: Option<String>,
+    // Where the package is written
+    output: Option<String>,
+    state: SessionState,
+    // Seconds since the epoch
+    created: u64,
+    started: Option<u64>,
+    finished: Option<u64>,
+    percent_complete: f64,
+    // Names of every stage, in the order they run
+    stages: Vec<String>,
+    // The running stage can't tell how far through it is, so should be shown as busy rather than
+    // as a stalled percentage
+    indeterminate: bool,
+    stage: usize,
+    max_stages: usize,
+    failed: bool,
+    complete: bool,
+    cancelled: bool,
+    interrupted: bool,
+    // Why the session failed, when it's known
+    error: Option<String>,
+    detail: Option<SessionDetail>,
+    logs: SessionLog,
+}
+
+impl SessionInfo {
+    pub fn running(&self) -> bool {
+        !self.failed && !self.complete && !self.cancelled && !self.interrupted
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn started(&self) -> Option<u64> {
+        self.started
+    }
+
+    pub fn finished(&self) -> Option<u64> {
+        self.finished
+    }
+
+    pub fn percent_complete(&self) -> f64 {
+        self.percent_complete
+    }
+
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+
+    pub fn max_stages(&self) -> usize {
+        self.max_stages
+    }
+
+    pub fn indeterminate(&self) -> bool {
+        self.indeterminate
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct SessionLog {
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct SessionDetail {
+    frame: usize,
+    fps: f64,
+    bitrate: f64,
+    total_size: usize,
+    #[schema(value_type = Object)]
+    time: Duration,
+    #[schema(value_type = Option<Object>)]
+    length: Option<Duration>,
+    // How many seconds of media the stage gets through per second, 2.0 being twice real time
+    speed: Option<f64>,
+    #[schema(value_type = Option<Object>)]
+    stage_remaining: Option<Duration>,
+    // Assumes the stages left take as long as those so far, so is rough until a few have run
+    #[schema(value_type = Option<Object>)]
+    remaining: Option<Duration>,
+}
+
+impl Session {
+    pub fn new(id: Uuid, cmd: Box<dyn MediaCommandConfig + Send + Sync>, info: Arc<RwLock<MediaInfo>>) -> Self
+    {
+        let (snapshot, snapshot_rx) = watch::channel(SessionInfoInt::new());
+        let progress = Arc::new(Progress {
+            info: AsyncRwLock::new(SessionInfoInt::new()),
+            snapshot,
+        });
+        let (cancel, cancelled) = watch::channel(false);
+
+        Session {
+            id,
+            media_info: info,
+            progress,
+            info: snapshot_rx,
+            stage_names: vec![cmd.name()],
+            stage_weights: vec![cmd.weight()],
+            stage_progress: vec![cmd.reports_progress()],
+            commands: vec![cmd],
+            after: vec![After::Stages(vec![])],
+            on_success: vec![],
+            events: None,
+            output: None,
+            made: None,
+            owner: None,
+            work_dir: None,
+            work_lock: None,
+            parallelism: None,
+            cancel,
+            cancelled,
+            worker: None,
+            reports: None,
+            forward: None,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn get_info(&self) -> SessionInfo {
+        let media_info = &*self.media_info.read().unwrap();
+        let session_info = &*self.info.borrow();
+
+        let task_fraction = if session_info.complete {
+            1.0
+        } else {
+            match (media_info.duration, media_info.frames) {
+                (Some(d), _) => session_info.time.as_secs_f64() / d.as_secs_f64(),
+                (None, Some(f)) => session_info.frame as f64 / f as f64,
+                (None, None) => 0.0,
+            }
+        }.max(0.0).min(1.0);
+
+        // Stages count towards the total by how long they take, so the quick packaging stages don't
+        // make up most of the bar. Of the running stages only the lead can say how far it has got.
+        let total_weight: f64 = self.stage_weights.iter().sum();
+        let done_weight: f64 = self.stage_weights.iter().zip(&session_info.done)
+            .filter(|(_, d)| **d)
+            .map(|(w, _)| w)
+            .sum();
+        let stage_weight = session_info.lead
+            .filter(|_| !session_info.complete)
+            .and_then(|s| self.stage_weights.get(s))
+            .cloned()
+            .unwrap_or(0.0);
+        let indeterminate = session_info.state() == SessionState::Running && session_info.lead.is_none();
+        let overall_percent = if total_weight > 0.0 {
+            (done_weight + stage_weight * task_fraction) / total_weight * 100.0
+        } else {
+            0.0
+        };
+
+        let detail = if session_info.bitrate > 0.0 {
+            let speed = session_info.stage_started
+                .map(|t| t.elapsed().as_secs_f64())
+                .filter(|e| *e > 0.0 && session_info.time > Duration::from_secs(0))
+                .map(|e| session_info.time.as_secs_f64() / e);
+            let stage_remaining = match media_info.duration {
+                Some(d) => speed.map(|s| Duration::from_secs_f64(d.checked_sub(session_info.time).unwrap_or_default().as_secs_f64() / s)),
+                // Going by how far through the frames the stage is
+                None => session_info.stage_started
+                    .filter(|_| task_fraction > 0.0 && task_fraction < 1.0)
+                    .map(|t| Duration::from_secs_f64(t.elapsed().as_secs_f64() * (1.0 - task_fraction) / task_fraction)),
+            };
+            let remaining = session_info.started
+                .and_then(|t| t.elapsed().ok())
+                .filter(|_| overall_percent > 0.0 && overall_percent < 100.0)
+                .map(|e| Duration::from_secs_f64(e.as_secs_f64() * (100.0 - overall_percent) / overall_percent));
+
+            Some(SessionDetail {
+                frame: session_info.frame,
+                fps: session_info.fps,
+                bitrate: session_info.bitrate,
+                total_size: session_info.total_size,
+                time: session_info.time,
+                length: media_info.duration,
+                speed,
+                stage_remaining,
+                remaining,
+            })
+        } else {
+            None
+        };
+
+        SessionInfo {
+            id: self.id.to_string(),
+            file_name: media_info.file_title.clone(),
+            owner: self.owner.clone(),
+            worker: self.worker.clone(),
+            output: self.output.as_ref().map(|o| o.to_string_lossy().into_owned()),
+            state: session_info.state(),
+            created: epoch_secs(session_info.created),
+            started: session_info.started.map(epoch_secs),
+            finished: session_info.finished.map(epoch_secs),
+
+            percent_complete: overall_percent,
+            stages: self.stage_names.clone(),
+            indeterminate,
+            stage: session_info.stage,
+            max_stages: session_info.max_stages,
+
+            failed: session_info.failed,
+            complete: session_info.complete,
+            cancelled: session_info.cancelled,
+            interrupted: session_info.interrupted,
+            error: session_info.error.clone(),
+
+            logs: SessionLog {
+                stdout: session_info.stdout.iter().cloned().collect(),
+                stderr: session_info.stderr.iter().cloned().collect(),
+            },
+            detail,
+        }
+    }
+
+    // Adds a stage which runs once all of those before it have finished
+    pub fn chain<T: 'static>(&mut self, cmd: T) -> &mut Self
+        where T: MediaCommandConfig + Send + Sync
+    {
+        self.chain_after(cmd, After::All)
+    }
+
+    pub fn chain_after<T: 'static>(&mut self, cmd: T, after: After) -> &mut Self
+        where T: MediaCommandConfig + Send + Sync
+    {
+        self.after.push(after);
+        self.stage_names.push(cmd.name());
+        self.stage_weights.push(cmd.weight());
+        self.stage_progress.push(cmd.reports_progress());
+        self.commands.push(Box::new(cmd));
+        self
+    }
+
+    // Work done in-process once every command has finished successfully, e.g. writing extra files
+    // into the package
+    pub fn on_success<F: 'static>(&mut self, f: F) -> &mut Self
+        where F: FnOnce() -> io::Result<()> + Send + Sync
+    {
+        self.on_success.push(Box::new(f));
+        self
+    }
+
+    // Where the session's final product ends up
+    pub fn output(&mut self, dir: PathBuf) -> &mut Self {
+        self.output = Some(dir);
+        self
+    }
+
+    pub fn output_dir(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+
+    // A directory for commands which won't make it themselves. It's only made once the session
+    // starts, rather than while it's queued, and removed again should the session not complete.
+    pub fn make_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.made = Some(dir);
+        self
+    }
+
+    // Where intermediate files are written, the directory is removed once the session ends
+    pub fn work_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.work_dir = Some(dir);
+        self
+    }
+
+    // Held until the session ends, so nothing else uses the work dir in the meantime
+    pub fn lock_work_dir(&mut self, lock: File) -> &mut Self {
+        self.work_lock = Some(lock);
+        self
+    }
+
+    // Overrides SETTINGS.stage_parallelism, for stages which have to run together such as the ends
+    // of a pipe
+    pub fn parallelism(&mut self, n: usize) -> &mut Self {
+        self.parallelism = Some(n);
+        self
+    }
+
+    pub fn stage_names(&self) -> &[String] {
+        &self.stage_names
+    }
+
+    // What the session's commands have written, from the log file when there is one, along with how
+    // many lines there are altogether. Only the lines asked for are kept, from offset or the last
+    // tail, so long logs aren't read into memory whole. Reads from disk and waits on the session so
+    // shouldn't be called on the runtime's threads.
+    pub fn logs(&self, offset: Option<usize>, tail: Option<usize>, limit: Option<usize>) -> (Vec<LogLine>, usize) {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut total = 0;
+        let mut kept = VecDeque::new();
+        let mut keep = |line: (Option<Stream>, String)| {
+            match tail {
+                Some(tail) => {
+                    kept.push_back(line);
+                    if kept.len() > tail {
+                        kept.pop_front();
+                    }
+                }
+                None => {
+                    let start = offset.unwrap_or(0);
+                    if total >= start && total - start < limit {
+                        kept.push_back(line);
+                    }
+                }
+            }
+            total += 1;
+        };
+
+        match File::open(log_path(self.id)) {
+            Ok(f) => io::BufReader::new(f).lines()
+                .filter_map(|l| l.ok())
+                .for_each(|l| keep((None, l))),
+            Err(_) => {
+                let s = futures::executor::block_on(self.progress.info.read());
+                s.stdout.iter().map(|l| (Some(Stream::Stdout), l.clone()))
+                    .chain(s.stderr.iter().map(|l| (Some(Stream::Stderr), l.clone())))
+                    .for_each(&mut keep);
+            }
+        }
+
+        // Lines from the file start with the stream they came from
+        let lines = kept.into_iter().take(limit).map(|(stream, l)| match stream {
+            Some(stream) => LogLine { stream: stream.name(), line: l },
+            None => {
+                let mut parts = l.splitn(2, '\t');
+                let stream = match parts.next() {
+                    Some("stderr") => Stream::Stderr,
+                    _ => Stream::Stdout,
+                };
+                LogLine { stream: stream.name(), line: parts.next().unwrap_or_default().to_string() }
+            }
+        });
+        (lines.collect(), total)
+    }
+
+    pub fn media_info(&self) -> MediaInfo {
+        self.media_info.read().unwrap().clone()
+    }
+
+    pub fn events(&mut self, tx: broadcast::Sender<SessionEvent>) -> &mut Self {
+        self.events = Some(tx);
+        self
+    }
+
+    // The user who asked for the session, when auth is enabled
+    pub fn owner(&mut self, owner: Option<String>) -> &mut Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn get_owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    // Kills the running stage and skips the rest, the session is then marked as cancelled. A session
+    // which is still queued is marked straight away.
+    pub fn cancel(&mut self) {
+        if !self.is_queued() {
+            self.cancel.broadcast(true).ok();
+            if SHUTTING_DOWN.load(Ordering::SeqCst) {
+                self.abandon();
+            }
+            return;
+        }
+        self.commands.clear();
+        self.on_success.clear();
+        self.work_lock.take();
+        remove_work_dir(&self.work_dir);
+        let interrupted = SHUTTING_DOWN.load(Ordering::SeqCst);
+        {
+            let s = &mut *self.progress.info.write().now_or_never()
+                .expect("nothing else writes before the session starts");
+            if interrupted {
+                s.interrupted = true;
+            } else {
+                s.cancelled = true;
+            }
+            s.finish();
+            self.progress.snapshot.broadcast(s.snapshot()).ok();
+        }
+        let id = self.id;
+        if let Some(tx) = &self.events {
+            tx.send(if interrupted { SessionEvent::Interrupted { id } } else { SessionEvent::Cancelled { id } }).ok();
+        }
+    }
+
+    // Waiting to be started, see queue::dispatch
+    pub fn is_queued(&self) -> bool {
+        !self.commands.is_empty()
+    }
+
+    // The stages each stage waits for, from 0
+    fn dependencies(&self) -> Result<Vec<Vec<usize>>, SessionError> {
+        let after: Vec<Vec<usize>> = self.after.iter().enumerate().map(|(i, a)| match a {
+            After::All => (0..i).collect(),
+            After::Stages(stages) => stages.clone(),
+        }).collect();
+        if after.iter().enumerate().any(|(i, a)| a.iter().any(|&d| d >= i)) {
+            return Err(InvalidCommandConfig("stages can only wait on stages added before them"));
+        }
+        Ok(after)
+    }
+
+    // What starting the session would run, without running anything
+    pub fn plan(&self) -> Result<Vec<PlannedStage>, ConvError> {
+        if self.commands.is_empty() {
+            return Err(AlreadyStarted.into());
+        }
+        let after = self.dependencies()?;
+        self.commands.iter().zip(after).enumerate().map(|(i, (c, after))| {
+            Ok(PlannedStage {
+                stage: i + 1,
+                name: c.name(),
+                command: c.describe()?,
+                after: after.into_iter().map(|d| d + 1).collect(),
+                can_fail: c.can_fail(),
+                inputs: c.inputs(),
+                outputs: c.outputs(),
+            })
+        }).collect()
+    }
+
+    pub fn start(&mut self) -> Result<(), ConvError> {
+        if self.commands.is_empty() {
+            return Err(AlreadyStarted.into());
+        }
+        let after = self.dependencies()?;
+        let weights = self.stage_weights.clone();
+        let progress_stages = self.stage_progress.clone();
+        let parallelism = self.parallelism.unwrap_or_else(runtime::stage_parallelism).max(1);
+
+        let cmds = std::mem::replace(&mut self.commands, vec![]);
+        let cmds = cmds.iter().map(|c| {
+            let cmd = (c.describe()?, c.build()?);
+            Ok((cmd, c.can_fail(), c.reports_progress(), c.inputs(), c.outputs()))
+        }).collect::<Result<Vec<_>, ConvError>>()?;
+        let work_lock = self.work_lock.take();
+        let work_dir = self.work_dir.clone();
+        let progress = self.progress.clone();
+        let cancelled = self.cancelled.clone();
+        let id = self.id;
+        let ending = self.begin();
+
+        let span = info_span!("session", session_id = %id);
+        tokio::spawn(async move {
+            let _work_lock = work_lock;
+            let stages = cmds.len();
+            let mut waiting: Vec<_> = cmds.into_iter().map(Some).collect();
+            let mut started_at = vec![None; stages];
+            let mut running = FuturesUnordered::new();
+            let mut failed = false;
+
+            // Failing stops the other stages as cancelling does, without counting as a cancel
+            let (abort, aborted) = watch::channel(false);
+            let abort = Arc::new(abort);
+            {
+                let abort = abort.clone();
+                let mut cancelled = cancelled.clone();
+                tokio::spawn(async move {
+                    while let Some(c) = cancelled.recv().await {
+                        if c {
+                            abort.broadcast(true).ok();
+                            return;
+                        }
+                    }
+                });
+            }
+
+            loop {
+                // Nothing new is started once the server is shutting down, the running stages are
+                // left to finish or be killed as the shutdown mode says
+                let stopping = failed || *aborted.borrow() || SHUTTING_DOWN.load(Ordering::SeqCst);
+                while !stopping && running.len() < parallelism {
+                    let next = (0..stages).find(|&i| waiting[i].is_some()
+                        && after[i].iter().all(|&d| waiting[d].is_none() && started_at[d].is_none()));
+                    let i = match next {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    let ((line, cmd), can_fail, reports_progress, inputs, outputs) = waiting[i].take().unwrap();
+                    // Left from an earlier run of the same thing
+                    let digest = line.digest();
+                    if up_to_date(work_dir.as_deref(), &digest, &inputs, &outputs) {
+                        info!(stage = i + 1, "Skipping stage as its outputs are up to date");
+                        progress.update(|s| {
+                            s.done[i] = true;
+                            s.log(Stream::Stdout, format!("Stage {} skipped, its outputs are up to date", i + 1));
+                        }).await;
+                        continue;
+                    }
+                    info!(stage = i + 1, command = %line, "Spawning command");
+                    let now = Instant::now();
+                    started_at[i] = Some(now);
+                    let mut max_stages = 0;
+                    progress.update(|s| {
+                        s.log(Stream::Stdout, format!("Stage {}: {}", i + 1, line));
+                        s.stage = i + 1;
+                        max_stages = s.max_stages;
+                        // The heaviest stage which reports progress is the one shown
+                        if reports_progress && s.lead.map_or(true, |l| weights[l] < weights[i]) {
+                            s.lead = Some(i);
+                            s.stage_started = Some(now);
+                        }
+                    }).await;
+                    ending.notify(SessionEvent::Stage { id, stage: i + 1, max_stages });
+                    finished(work_dir.as_deref(), None, &outputs);
+                    // Whatever was at the other end of a pipe has already read or closed it, so
+                    // running the stage again can't work. Failures of optional stages are ignored
+                    // anyway so aren't worth waiting on.
+                    let retry = !can_fail && !inputs.iter().chain(&outputs).any(|p| is_pipe(p));
+                    let attempts = if retry { SETTINGS.retry.attempts } else { 0 };
+                    let stage = Self::run_stage(cmd, line.program_name(), i, reports_progress, attempts, progress.clone(), aborted.clone());
+                    running.push(stage.map(move |r| (i, can_fail, outputs, digest, r)));
+                }
+
+                let (i, can_fail, outputs, digest, (status, stalled)) = match running.next().await {
+                    Some(r) => r,
+                    None => break,
+                };
+                started_at[i] = None;
+                let lead = (0..stages)
+                    .filter(|&r| started_at[r].is_some() && progress_stages[r])
+                    .max_by(|&a, &b| weights[a].partial_cmp(&weights[b]).unwrap_or(std::cmp::Ordering::Equal));
+                progress.update(|s| {
+                    s.done[i] = true;
+                    if s.lead == Some(i) {
+                        s.lead = lead;
+                        s.stage_started = lead.and_then(|l| started_at[l]);
+                    }
+                }).await;
+                let succeeded = matches!(&status, Ok(s) if s.success());
+                // Whatever was half written mustn't be taken as done by a later run
+                if succeeded {
+                    finished(work_dir.as_deref(), Some(&digest), &outputs);
+                } else {
+                    for o in &outputs {
+                        std::fs::remove_file(o).ok();
+                    }
+                }
+                if stalled && !can_fail {
+                    progress.update(|s| s.error = Some(format!("Stage {} stalled with no progress", i + 1))).await;
+                }
+                if let (Err(e), false) = (&status, can_fail) {
+                    progress.update(|s| s.error = Some(format!("Stage {} could not be run: {}", i + 1, e))).await;
+                }
+                if !succeeded && !can_fail && !failed {
+                    failed = true;
+                    abort.broadcast(true).ok();
+                }
+            }
+
+            let outcome = if *cancelled.borrow() {
+                // Sessions are only cancelled while shutting down to stop them
+                if SHUTTING_DOWN.load(Ordering::SeqCst) { Outcome::Interrupted } else { Outcome::Cancelled }
+            } else if failed {
+                Outcome::Failed
+            } else if waiting.iter().any(|c| c.is_some()) {
+                Outcome::Interrupted
+            } else {
+                Outcome::Completed
+            };
+            ending.end(outcome).await;
+        }.instrument(span));
+        Ok(())
+    }
+
+    // Marks the session as started, giving what's needed to end it once its stages have run
+    fn begin(&mut self) -> Ending {
+        {
+            let s = &mut *self.progress.info.write().now_or_never()
+                .expect("nothing else writes before the session starts");
+            s.max_stages = self.stage_names.len();
+            s.done = vec![false; self.stage_names.len()];
+            s.started = Some(SystemTime::now());
+            s.log_file = match File::create(log_path(self.id)) {
+                Ok(f) => Some(log_writer(f)),
+                Err(e) => {
+                    error!("Could not create the session log: {}", e);
+                    None
+                }
+            };
+            s.forward = self.forward.take();
+            self.progress.snapshot.broadcast(s.snapshot()).ok();
+        }
+        if let Some(dir) = &self.made {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                error!("Could not create {:?}: {}", dir, e);
+            }
+        }
+
+        let ending = Ending {
+            id: self.id,
+            progress: self.progress.clone(),
+            work_dir: self.work_dir.clone(),
+            made: self.made.clone(),
+            on_success: std::mem::replace(&mut self.on_success, vec![]),
+            max_time: self.media_info.read().unwrap().duration,
+            started: Instant::now(),
+            events: self.events.clone(),
+        };
+        ending.notify(SessionEvent::Created { id: self.id });
+        metrics::SESSIONS_STARTED.inc();
+        metrics::SESSIONS_ACTIVE.inc();
+        ending
+    }
+
+    // Runs a stage, retrying it when it fails up to attempts more times. Gives up on retrying once
+    // the session is aborted, or when the command couldn't be run at all.
+    async fn run_stage(mut cmd: Command, program: &'static str, stage: usize, reports_progress: bool, attempts: u32,
+                       progress: Arc<Progress>, aborted: watch::Receiver<bool>) -> (Result<ExitStatus, ConvError>, bool) {
+        // Stages which don't report progress can legitimately go quiet for a long time
+        let stall_timeout = Some(SETTINGS.stall_timeout)
+            .filter(|t| *t > 0 && reports_progress)
+            .map(Duration::from_secs);
+        let mut attempt = 0;
+        loop {
+            let spawned = Self::spawn(&mut cmd, program, stage, progress.clone(), aborted.clone(), stall_timeout)
+                .instrument(info_span!("stage", stage = stage + 1, attempt))
+                .await;
+            let (status, stalled) = match spawned {
+                Ok(s) => s,
+                Err(e) => {
+                    let msg = format!("Stage {} could not be run: {}", stage + 1, e);
+                    error!("{}", msg);
+                    progress.update(|s| s.log(Stream::Stderr, msg)).await;
+                    return (Err(e), false);
+                }
+            };
+            if stalled {
+                let msg = format!("Stage {} stalled with no progress for {}s and was killed", stage + 1, SETTINGS.stall_timeout);
+                error!("{}", msg);
+                progress.update(|s| s.log(Stream::Stderr, msg)).await;
+            }
+            if status.success() || *aborted.borrow() || attempt >= attempts {
+                return (Ok(status), stalled);
+            }
+            attempt += 1;
+            let delay = Duration::from_secs(SETTINGS.retry.backoff.saturating_mul(1 << (attempt - 1).min(16)));
+            let msg = format!("Stage {} failed with {}, retrying in {}s (attempt {} of {})",
+                              stage + 1, status, delay.as_secs(), attempt, attempts);
+            info!("{}", msg);
+            progress.update(|s| s.log(Stream::Stderr, msg)).await;
+            let mut aborted = aborted.clone();
+            future::select(tokio::time::delay_for(delay), Box::pin(wait_cancelled(&mut aborted))).await;
+            if *aborted.borrow() {
+                return (Ok(status), stalled);
+            }
+        }
+    }
+
+    // Runs a command to completion, or until the session is cancelled or the command goes without
+    // output for longer than the stall timeout. Whether it stalled is returned with its status.
+    async fn spawn(cmd: &mut Command, program: &'static str, stage: usize, progress: Arc<Progress>, mut cancelled: watch::Receiver<bool>,
+                   stall_timeout: Option<Duration>) -> Result<(ExitStatus, bool), ConvError> {
+        cmd.stdout(Stdio::piped())
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        {
+            let niceness = runtime::niceness();
+            if niceness != 0 {
+                // Only runs in the child, between fork and exec
+                unsafe {
+                    cmd.pre_exec(move || {
+                        libc::nice(niceness);
+                        Ok(())
+                    });
+                }
+            }
+        }
+
+        let mut p = cmd.spawn().map_err(|source| ConvError::Spawn { program, source })?;
+        let pid = p.id();
+        reaper::record(pid);
+
+        let stdout = p.stdout.take().unwrap();
+        let stderr = p.stderr.take().unwrap();
+
+        let mut reader = BufReader::new(stdout);
+        let mut reader_err = BufReader::new(stderr);
+
+        let last_output = Arc::new(Mutex::new(Instant::now()));
+        let stdout_output = last_output.clone();
+        let progress_stdout = progress.clone();
+        let read_stdout = tokio::spawn(async move {
+            let mut local_buf = SessionInfoInt::new();
+            let mut line_buf = VecDeque::new();
+            let mut ctr = 0;
+
+            progress_stdout.update(|s| {
+                if s.lead == Some(stage) {
+                    s.frame = 0;
+                    s.fps = 0.0;
+                    s.bitrate = 0.0;
+                    s.total_size = 0;
+                    s.time = Default::default();
+                }
+            }).await;
+
+            let mut buf = vec![];
+            while let Some(line) = read_line(&mut reader, &mut buf).await? {
+                trace!("Line: {}", line);
+                *stdout_output.lock().unwrap() = Instant::now();
+                match line.split('=').collect::<Vec<_>>()[..] {
+                    ["frame", x] => local_buf.frame = x.parse().unwrap_or(local_buf.frame),
+                    ["fps", x] => local_buf.fps = x.parse().unwrap_or(local_buf.fps),
+                    ["bitrate", x] => local_buf.bitrate = x.chars().take(floor_usize(x.len() as isize - 7))
+                        .collect::<String>()
+                        .trim()
+                        .parse()
+                        .unwrap_or(local_buf.bitrate),
+                    ["total_size", x] => local_buf.total_size = x.trim().parse().unwrap_or(local_buf.total_size),
+                    ["out_time_us", x] => local_buf.time = Duration::from_micros(x.parse().unwrap_or_else(|_| local_buf.time.as_micros() as u64)),
+                    [_, _] => (),
+                    _ => {
+                        // Unknown line implies we want to know immediately
+                        line_buf.push_back(line);
+                        ctr = 25;
+                    }
+                }
+
+                // Limit updates to limit locks
+                if ctr > 24 {
+                    debug!("Local Buffer Write {:?}", local_buf);
+
+                    progress_stdout.update(|s| {
+                        // Other stages running alongside would otherwise overwrite each other
+                        if s.lead == Some(stage) {
+                            s.frame = local_buf.frame;
+                            s.fps = local_buf.fps;
+                            s.bitrate = local_buf.bitrate;
+                            s.total_size = local_buf.total_size;
+                            s.time = local_buf.time;
+                        }
+
+                        for line in line_buf.drain(..) {
+                            s.log(Stream::Stdout, line);
+                        }
+                    }).await;
+
+                    ctr = 0;
+                }
+                ctr += 1;
+            };
+
+            // Only ffmpeg reports fps, the final figure is the average for the stage
+            if local_buf.fps > 0.0 {
+                metrics::ENCODE_FPS.observe(local_buf.fps);
+            }
+            Ok::<_, io::Error>(())
+        }.in_current_span());
+
+        let read_stderr = tokio::spawn(async move {
+            let mut buf = vec![];
+            while let Some(line) = read_line(&mut reader_err, &mut buf).await? {
+                debug!(target: "ffmpeg", "{}", line);
+                progress.update(|s| s.log(Stream::Stderr, line)).await;
+            };
+            Ok::<_, io::Error>(())
+        }.in_current_span());
+
+        // Ensure the child process is spawned in the runtime so it can
+        // make progress on its own while we await for any output.
+        let (status, stopped) = tokio::spawn(async move {
+            let stop = future::select(Box::pin(wait_cancelled(&mut cancelled)), Box::pin(watchdog(last_output, stall_timeout)));
+            let (status, stopped) = match future::select(&mut p, stop).await {
+                Either::Left((status, _)) => (status, None),
+                Either::Right((stop, _)) => {
+                    p.kill().ok();
+                    (p.await, Some(matches!(stop, Either::Right(_))))
+                }
+            };
+            reaper::forget(pid);
+            (status, stopped)
+        }.in_current_span()).await.map_err(joined)?;
+        let status = status?;
+        info!("child status was: {}", status);
+        // Whatever a killed command started may still hold its output open, so the output is only
+        // read to the end when it exited by itself
+        if stopped.is_none() {
+            read_stdout.await.map_err(joined)??;
+            read_stderr.await.map_err(joined)??;
+        }
+        Ok((status, stopped.unwrap_or(false)))
+    }
+}
+
+// The next line of a command's output without its line ending. Bytes which aren't UTF-8, such as
+// tags ffmpeg passes on from a source, are replaced rather than ending the output.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf).await? == 0 {
+        return Ok(None);
+    }
+    let line = buf.strip_suffix(b"\n").unwrap_or(buf);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Ok(Some(String::from_utf8_lossy(line).into_owned()))
+}
+
+// Tasks only fail to join when they panic or the runtime is going away
+fn joined(e: tokio::task::JoinError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Completed,
+    Failed,
+    Cancelled,
+    // Stopped by the server shutting down, or by losing the worker running it
+    Interrupted,
+}
+
+// What's left to do once a session's stages have run, wherever they ran
+struct Ending {
+    id: Uuid,
+    progress: Arc<Progress>,
+    work_dir: Option<PathBuf>,
+    made: Option<PathBuf>,
+    on_success: Vec<Box<dyn FnOnce() -> io::Result<()> + Send + Sync>>,
+    max_time: Option<Duration>,
+    started: Instant,
+    events: Option<broadcast::Sender<SessionEvent>>,
+}
+
+impl Ending {
+    // Sending only fails when nobody is listening, which is fine
+    fn notify(&self, e: SessionEvent) {
+        if let Some(tx) = &self.events {
+            tx.send(e).ok();
+        }
+    }
+
+    async fn end(mut self, outcome: Outcome) {
+        let id = self.id;
+        match outcome {
+            Outcome::Cancelled | Outcome::Interrupted => {
+                let interrupted = outcome == Outcome::Interrupted;
+                remove_work_dir(&self.work_dir);
+                remove_work_dir(&self.made);
+                self.progress.update(|s| {
+                    if interrupted {
+                        s.interrupted = true;
+                    } else {
+                        s.cancelled = true;
+                    }
+                    s.finish();
+                }).await;
+                metrics::SESSIONS_ACTIVE.dec();
+                self.notify(if interrupted { SessionEvent::Interrupted { id } } else { SessionEvent::Cancelled { id } });
+            }
+            Outcome::Failed => {
+                // Left behind by default so the failing stage's inputs can be inspected
+                if !SETTINGS.keep_failed_intermediates {
+                    remove_work_dir(&self.work_dir);
+                }
+                remove_work_dir(&self.made);
+                self.progress.update(|s| {
+                    s.failed = true;
+                    s.finish();
+                }).await;
+                metrics::SESSIONS_FAILED.inc();
+                metrics::SESSIONS_ACTIVE.dec();
+                self.notify(SessionEvent::Failed { id });
+            }
+            Outcome::Completed => {
+                for f in std::mem::replace(&mut self.on_success, vec![]) {
+                    if let Err(e) = f() {
+                        error!("Post processing failed: {}", e);
+                        self.progress.update(|s| s.log(Stream::Stderr, format!("Post processing failed: {}", e))).await;
+                    }
+                }
+                remove_work_dir(&self.work_dir);
+                // Manually max out the time to ensure we're at 100%
+                let max_time = self.max_time;
+                self.progress.update(|s| {
+                    if let Some(t) = max_time {
+                        s.time = t;
+                    }
+                    s.complete = true;
+                    s.finish();
+                }).await;
+                metrics::SESSIONS_COMPLETED.inc();
+                metrics::SESSIONS_ACTIVE.dec();
+                metrics::SESSION_DURATION.observe(self.started.elapsed().as_secs_f64());
+                self.notify(SessionEvent::Completed { id });
+            }
+        }
+    }
+}
+
+// Appends the lines sent to it to a session's log file, writing on the blocking pool so output
+// doesn't hold up the executor. Whatever has built up while writing is written together.
+fn log_writer(file: File) -> mpsc::UnboundedSender<String> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let file = Arc::new(file);
+    tokio::spawn(async move {
+        while let Some(mut lines) = rx.recv().await {
+            while let Ok(line) = rx.try_recv() {
+                lines.push_str(&line);
+            }
+            let file = file.clone();
+            match tokio::task::spawn_blocking(move || (&*file).write_all(lines.as_bytes())).await.map_err(joined) {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) | Err(e) => error!("Could not write to the session log: {}", e),
+            }
+        }
+    });
+    tx
+}
+
+fn remove_work_dir(dir: &Option<PathBuf>) {
+    if let Some(dir) = dir {
+        match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => error!("Could not remove {:?}: {}", dir, e),
+            _ => (),
+        }
+    }
+}
+
+// Resolves once there has been no output for the timeout, never without one
+async fn watchdog(last_output: Arc<Mutex<Instant>>, timeout: Option<Duration>) {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return future::pending().await,
+    };
+    loop {
+        let quiet = last_output.lock().unwrap().elapsed();
+        if quiet >= timeout {
+            return;
+        }
+        tokio::time::delay_for(timeout - quiet).await;
+    }
+}
+
+// Whether a stage's outputs were all written since its inputs last changed, by the same command.
+// Outputs only count once the command writing them has succeeded, so ones a crash left half written
+// aren't taken as done, nor ones written with other settings. Only regular files in the work dir
+// count, a pipe is never finished with.
+fn up_to_date(work_dir: Option<&Path>, digest: &str, inputs: &[PathBuf], outputs: &[PathBuf]) -> bool {
+    let written_by = |o: &PathBuf| finished_record(work_dir, o)
+        .and_then(|r| std::fs::read_to_string(r).ok())
+        .map_or(false, |d| d == digest);
+    if !outputs.iter().all(written_by) {
+        return false;
+    }
+    let modified = |p: &PathBuf| p.metadata().ok()
+        .filter(|m| m.is_file())
+        .and_then(|m| m.modified().ok());
+    let inputs: Option<Vec<_>> = inputs.iter().map(modified).collect();
+    let outputs: Option<Vec<_>> = outputs.iter().map(modified).collect();
+    match (inputs, outputs) {
+        (Some(i), Some(o)) if !o.is_empty() => o.iter().min() >= i.iter().max(),
+        _ => false,
+    }
+}
+
+// Records the outputs as written by the command with the digest, or with None as not finished
+fn finished(work_dir: Option<&Path>, digest: Option<&str>, outputs: &[PathBuf]) {
+    for record in outputs.iter().filter_map(|o| finished_record(work_dir, o)) {
+        let res = match digest {
+            Some(digest) => std::fs::write(&record, digest),
+            None => std::fs::remove_file(&record),
+        };
+        match res {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => error!("Could not update {:?}: {}", record, e),
+            _ => (),
+        }
+    }
+}
+
+fn finished_record(work_dir: Option<&Path>, output: &Path) -> Option<PathBuf> {
+    let work_dir = work_dir.filter(|w| output.parent() == Some(*w))?;
+    Some(work_dir.join(format!(".{}.done", output.file_name()?.to_string_lossy())))
+}
+
+// Resolves once the session is cancelled, never if the session is dropped first
+async fn wait_cancelled(rx: &mut watch::Receiver<bool>) {
+    while let Some(cancelled) = rx.recv().await {
+        if cancelled {
+            return;
+        }
+    }
+    future::pending().await
+}
+
+#[derive(Serialize, Debug, Clone, Default, ToSchema)]
+pub struct MediaInfo {
+    pub id: String,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub meta_title: Option<String>,
+    pub file_title: String,
+    // Unknown for some broken sources, progress is then counted in frames
+    #[schema(value_type = Option<Object>)]
+    pub duration: Option<Duration>,
+    pub frames: Option<u64>,
+    pub width: Option<isize>,
+    pub height: Option<isize>,
+    pub video_profile: Option<String>,
+    pub bit_depth: Option<u8>,
+    pub hdr: bool,
+    pub audio_channels: Option<isize>,
+    pub channel_layout: Option<String>,
+    // In stream order, without repeats
+    pub audio_languages: Vec<String>,
+    pub subtitle_languages: Vec<String>,
+    // Show, season, episode and year going by the file's name
+    pub parsed: ParsedName,
+    pub size: u64,
+    // Seconds since the epoch
+    pub modified: u64,
+
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(skip)]
+    pub raw: FFProbeResponse,
+}
+
+fn tail(lines: &VecDeque<String>) -> VecDeque<String> {
+    lines.iter().skip(lines.len().saturating_sub(LOG_PREVIEW_LINES)).cloned().collect()
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn floor_usize(n: isize) -> usize {
+    if n < 0 {
+        0
+    } else {
+        n as usize
+    }
+}
+
+// Bytes read from each end of a file to fingerprint it
+const FINGERPRINT_SAMPLE: u64 = 64 * 1024;
+
+// The id the API uses to refer to a source file, taken from its size and contents so it stays the same
+// when the file is renamed or moved. Only the start and end are read, which is enough to tell media
+// apart while staying quick on large files. Identical copies share an id.
+pub fn fingerprint(file: &Path) -> io::Result<String> {
+    use std::io::{Read, Seek, SeekFrom};
+    use sha2::{Digest, Sha256};
+
+    let mut f = File::open(file)?;
+    let size = f.metadata()?.len();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    let mut buf = vec![];
+    (&mut f).take(FINGERPRINT_SAMPLE).read_to_end(&mut buf)?;
+    if size > FINGERPRINT_SAMPLE {
+        f.seek(SeekFrom::Start(size.saturating_sub(FINGERPRINT_SAMPLE).max(FINGERPRINT_SAMPLE)))?;
+        f.take(FINGERPRINT_SAMPLE).read_to_end(&mut buf)?;
+    }
+    hasher.update(&buf);
+    Ok(hex::encode(&hasher.finalize()[..16]))
+}
+
+// Identifies a file by its path byte for byte, so names which aren't valid UTF-8 survive. Used for
+// files which can't be fingerprinted, and still accepted from clients which stored these ids.
+pub fn media_id(file: &Path) -> String {
+    base64::encode_config(path_bytes(file), base64::URL_SAFE_NO_PAD)
+}
+
+// The inverse of media_id. Ids in the standard alphabet are accepted too.
+pub fn media_path(id: &str) -> Option<PathBuf> {
+    base64::decode_config(id, base64::URL_SAFE_NO_PAD)
+        .or_else(|_| base64::decode(id))
+        .ok()
+        .and_then(path_from_bytes)
+}
+
+#[cfg(unix)]
+fn is_pipe(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    path.metadata().map_or(false, |m| m.file_type().is_fifo())
+}
+
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+// Extensions Windows runs programs with, and Bento4's scripts have
+#[cfg(windows)]
+const PROGRAM_EXTENSIONS: [&str; 4] = ["exe", "bat", "cmd", "py"];
+
+// Where a program given by path or by name would be found, which on Windows may be with an extension
+pub fn find_program(program: &Path) -> Option<PathBuf> {
+    let candidates = |p: PathBuf| {
+        let mut candidates = vec![];
+        #[cfg(windows)]
+        if p.extension().is_none() {
+            candidates.extend(PROGRAM_EXTENSIONS.iter().map(|e| p.with_extension(e)));
+        }
+        candidates.push(p);
+        candidates
+    };
+    // A bare name is looked for on the PATH as it would be when run
+    if program.components().count() > 1 {
+        return candidates(program.to_path_buf()).into_iter().find(|p| p.is_file());
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths).flat_map(|d| candidates(d.join(program))).find(|p| p.is_file())
+}
+
+// Windows paths are limited to 260 characters unless given in the verbatim \\?\ form, which has to
+// be absolute with nothing like .. in it. Shares are \\?\UNC\server\share\... in that form.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    if path.as_os_str().len() < 260 {
+        return path.to_path_buf();
+    }
+    let absolute = match std::env::current_dir() {
+        Ok(d) if path.is_relative() => d.join(path),
+        _ => path.to_path_buf(),
+    };
+    let mut verbatim = OsString::new();
+    let mut parts: Vec<&OsStr> = vec![];
+    for c in absolute.components() {
+        match c {
+            Component::Prefix(p) => match p.kind() {
+                Prefix::Disk(d) => verbatim.push(format!("\\\\?\\{}:", d as char)),
+                Prefix::UNC(server, share) => {
+                    verbatim.push("\\\\?\\UNC\\");
+                    verbatim.push(server);
+                    verbatim.push("\\");
+                    verbatim.push(share);
+                }
+                // Already verbatim, or a device
+                _ => return path.to_path_buf(),
+            },
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => { parts.pop(); }
+            Component::Normal(n) => parts.push(n),
+        }
+    }
+    for p in parts {
+        verbatim.push("\\");
+        verbatim.push(p);
+    }
+    PathBuf::from(verbatim)
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Paths elsewhere are UTF-16 underneath, anything which can't be represented is lossily converted
+#[cfg(not(unix))]
+fn is_pipe(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+impl MediaInfo {
+    pub async fn get(file: &Path) -> Result<Self, ConvError> {
+        let meta = probe_cache::probe(&file).await?;
+
+        // Cover art is stored as a video stream too
+        let v = meta.streams.iter().find(|s| s.codec_type == "video" && !s.is_attached_pic());
+        let a = meta.streams.iter().find(|s| s.codec_type == "audio");
+        let languages = |codec_type: &str| meta.streams.iter()
+            .filter(|s| s.codec_type == codec_type)
+            .filter_map(|s| s.language())
+            .fold(vec![], |mut langs, l| {
+                if !langs.contains(&l) {
+                    langs.push(l);
+                }
+                langs
+            });
+        let stat = file.metadata().ok();
+
+        Ok(
+            MediaInfo {
+                id: probe_cache::fingerprint(file).unwrap_or_else(|_| media_id(file)),
+                video_codec: v.and_then(|v| v.codec_name.clone().into()),
+                audio_codec: a.and_then(|a| a.codec_name.clone().into()),
+                meta_title: v.and_then(|v| v.tags.as_ref().and_then(|v| v.title.clone())),
+                file_title: file.file_name().unwrap().to_string_lossy().into_owned(),
+                duration: meta.duration(),
+                frames: meta.frames(),
+                width: v.and_then(|v| v.width),
+                height: v.and_then(|v| v.height),
+                video_profile: v.and_then(|v| v.profile.clone()),
+                bit_depth: v.and_then(|v| v.bit_depth()),
+                hdr: v.map_or(false, |v| v.is_hdr()),
+                audio_channels: a.and_then(|a| a.channels),
+                channel_layout: a.and_then(|a| a.channel_layout.clone()),
+                audio_languages: languages("audio"),
+                subtitle_languages: languages("subtitle"),
+                parsed: filename::parse(&file.file_stem().unwrap_or_default().to_string_lossy()),
+                size: stat.as_ref().map_or(0, |m| m.len()),
+                modified: stat.and_then(|m| m.modified().ok()).map_or(0, epoch_secs),
+                path: file.to_path_buf(),
+                raw: meta,
+            }
+        )
+    }
+
+    pub fn text_subtitles(&self) -> impl Iterator<Item=&ffprobe::Stream> {
+        self.raw.streams.iter().filter(|s| s.codec_type == "subtitle" && !s.is_bitmap_subtitle())
+    }
+
+    pub fn bitmap_subtitles(&self) -> impl Iterator<Item=&ffprobe::Stream> {
+        self.raw.streams.iter().filter(|s| s.is_bitmap_subtitle())
+    }
+
+    pub fn dash_transcode_required(&self) -> bool {
+        match &self.video_codec {
+            Some(x) => x != "h264",
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::commands::{After, CommandLine, finished, MediaCommandConfig, MediaInfo, Session, SessionState,
+                          up_to_date};
+    use crate::error::ConvError;
+
+    // Writes when it starts and ends to a shared log, exiting with code
+    struct Stage {
+        stage: usize,
+        log: PathBuf,
+        code: i32,
+        can_fail: bool,
+        program: &'static str,
+    }
+
+    impl MediaCommandConfig for Stage {
+        fn describe(&self) -> Result<CommandLine, ConvError> {
+            let mut cmd = CommandLine::new(self.program);
+            cmd.arg("-c")
+                .arg(format!("echo start {0} >> \"$1\"; sleep 0.3; echo end {0} >> \"$1\"; exit {1}", self.stage, self.code))
+                .arg("sh")
+                .path(&self.log);
+            Ok(cmd)
+        }
+
+        fn can_fail(&self) -> bool {
+            self.can_fail
+        }
+
+        fn name(&self) -> String {
+            format!("stage {}", self.stage)
+        }
+    }
+
+    fn stage(log: &Path, stage: usize) -> Stage {
+        Stage { stage, log: log.to_path_buf(), code: 0, can_fail: false, program: "sh" }
+    }
+
+    fn session(first: Stage) -> Session {
+        Session::new(Uuid::new_v4(), Box::new(first), Arc::new(RwLock::new(MediaInfo::default())))
+    }
+
+    // Runs the session to the end, giving how it ended and what its stages wrote
+    async fn run(session: &mut Session, log: &Path) -> (SessionState, Vec<String>) {
+        session.start().unwrap();
+        while session.get_info().running() {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        let lines = std::fs::read_to_string(log).unwrap_or_default().lines().map(String::from).collect();
+        std::fs::remove_file(log).ok();
+        (session.get_info().state(), lines)
+    }
+
+    fn at(lines: &[String], line: &str) -> usize {
+        lines.iter().position(|l| l == line).unwrap_or_else(|| panic!("{} isn't in {:?}", line, lines))
+    }
+
+    // The most stages running at once
+    fn most_at_once(lines: &[String]) -> usize {
+        lines.iter().scan(0, |n, l| {
+            *n = if l.starts_with("start") { *n + 1 } else { *n - 1 };
+            Some(*n)
+        }).max().unwrap_or(0)
+    }
+
+    fn log_file() -> PathBuf {
+        std::env::temp_dir().join(format!("{}.log", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn secrets_not_shown() {
+        let mut cmd = CommandLine::new("packager");
+        cmd.arg("--keys")
+            .secret("key_id=00112233445566778899aabbccddeeff:key=ffeeddccbbaa99887766554433221100")
+            .arg("--mpd_output")
+            .arg("out dir/manifest.mpd");
+
+        assert_eq!(cmd.to_string(), "packager --keys '[redacted]' --mpd_output 'out dir/manifest.mpd'");
+        assert!(cmd.args[1].to_string_lossy().contains("ffeeddcc"));
+        assert_eq!(serde_json::to_value(&cmd).unwrap(), serde_json::json!({
+            "program": "packager",
+            "args": ["--keys", "[redacted]", "--mpd_output", "out dir/manifest.mpd"],
+        }));
+    }
+
+    #[test]
+    fn finished_stages() {
+        let work = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&work).unwrap();
+        let (input, output) = (work.join("media-concat.txt"), work.join("media-v0.mp4"));
+        std::fs::write(&input, "").unwrap();
+        std::fs::write(&output, "").unwrap();
+        let (inputs, outputs) = (vec![input], vec![output]);
+
+        // Written, but maybe not all of it
+        assert!(!up_to_date(Some(&work), "a", &inputs, &outputs));
+        finished(Some(&work), Some("a"), &outputs);
+        assert!(up_to_date(Some(&work), "a", &inputs, &outputs));
+        // By another command
+        assert!(!up_to_date(Some(&work), "b", &inputs, &outputs));
+        // Outside a work dir
+        assert!(!up_to_date(None, "a", &inputs, &outputs));
+        // Being written again
+        finished(Some(&work), None, &outputs);
+        assert!(!up_to_date(Some(&work), "a", &inputs, &outputs));
+
+        std::fs::remove_dir_all(&work).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn stage_order() {
+        let log = log_file();
+        let mut s = session(stage(&log, 0));
+        s.chain_after(stage(&log, 1), After::Stages(vec![0]))
+            .chain_after(stage(&log, 2), After::Stages(vec![0]))
+            .chain(stage(&log, 3))
+            .parallelism(2);
+        let (state, lines) = run(&mut s, &log).await;
+
+        assert_eq!(state, SessionState::Complete);
+        assert!(at(&lines, "end 0") < at(&lines, "start 1"));
+        assert!(at(&lines, "end 0") < at(&lines, "start 2"));
+        // Independent of each other, so run together
+        assert!(at(&lines, "start 2") < at(&lines, "end 1"));
+        assert!(at(&lines, "start 1") < at(&lines, "end 2"));
+        assert!(at(&lines, "end 1") < at(&lines, "start 3"));
+        assert!(at(&lines, "end 2") < at(&lines, "start 3"));
+    }
+
+    #[actix_rt::test]
+    async fn stage_parallelism() {
+        for &parallelism in &[1, 2] {
+            let log = log_file();
+            let mut s = session(stage(&log, 0));
+            for i in 1..4 {
+                s.chain_after(stage(&log, i), After::Stages(vec![]));
+            }
+            s.parallelism(parallelism);
+            let (state, lines) = run(&mut s, &log).await;
+
+            assert_eq!(state, SessionState::Complete);
+            assert_eq!(lines.len(), 8);
+            assert_eq!(most_at_once(&lines), parallelism);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn stage_failure() {
+        let log = log_file();
+        let mut s = session(stage(&log, 0));
+        s.chain(Stage { code: 1, ..stage(&log, 1) })
+            .chain_after(stage(&log, 2), After::Stages(vec![1]))
+            .parallelism(1);
+        let (state, lines) = run(&mut s, &log).await;
+
+        assert_eq!(state, SessionState::Failed);
+        assert_eq!(lines, ["start 0", "end 0", "start 1", "end 1"]);
+
+        // Stages which can fail don't hold up those waiting on them
+        let mut s = session(stage(&log, 0));
+        s.chain(Stage { code: 1, can_fail: true, ..stage(&log, 1) })
+            .chain_after(stage(&log, 2), After::Stages(vec![1]))
+            .parallelism(1);
+        let (state, lines) = run(&mut s, &log).await;
+
+        assert_eq!(state, SessionState::Complete);
+        assert_eq!(lines, ["start 0", "end 0", "start 1", "end 1", "start 2", "end 2"]);
+
+        // A program which can't be run fails its stage rather than leaving the session running
+        let mut s = session(stage(&log, 0));
+        s.chain(Stage { program: "/nonexistent/sh", ..stage(&log, 1) })
+            .chain(stage(&log, 2));
+        let (state, lines) = run(&mut s, &log).await;
+
+        assert_eq!(state, SessionState::Failed);
+        assert_eq!(lines, ["start 0", "end 0"]);
+    }
+
+    #[test]
+    fn stage_dependencies() {
+        let log = log_file();
+        let mut s = session(stage(&log, 0));
+        s.chain(stage(&log, 1))
+            .chain_after(stage(&log, 2), After::Stages(vec![0]));
+        let after: Vec<_> = s.plan().unwrap().into_iter().map(|p| p.after).collect();
+        assert_eq!(after, vec![vec![], vec![1], vec![1]]);
+
+        // Waiting on itself or a later stage could never start
+        s.chain_after(stage(&log, 3), After::Stages(vec![3]));
+        assert!(s.plan().is_err());
+        assert!(s.start().is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::commands::{CommandLine, find_program, long_path, MediaCommandConfig, SessionError, Tool};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::encryption::{DrmSystem, Encryption};
+use crate::settings::{DashProfile, SegmentAddressing, Tools};
+use crate::error::ConvError;
+use crate::SETTINGS;
+
+pub const MANIFEST: &str = "manifest.mpd";
+// Written next to the manifest in CMAF mode, referencing the same segments
+pub const HLS_PLAYLIST: &str = "master.m3u8";
+
+lazy_static! {
+    // Worked out once, the program and the arguments before ours
+    static ref LAUNCHER: CommandLine = match script(&SETTINGS.tools) {
+        Some(script) => {
+            let mut cmd = CommandLine::new(&SETTINGS.tools.python);
+            cmd.path(script)
+                .args(Tool::Mp4dash.extra_args());
+            cmd
+        }
+        // Found with its extension, as on Windows only .exe files are found by name
+        None => {
+            let mut cmd = CommandLine::new(find_program(Tool::Mp4dash.path()).unwrap_or_else(|| Tool::Mp4dash.path().to_path_buf()));
+            cmd.args(Tool::Mp4dash.extra_args());
+            cmd
+        }
+    };
+}
+
+pub(super) fn launcher() -> CommandLine {
+    LAUNCHER.clone()
+}
+
+// Bento4's mp4dash is a Python script, installed with a wrapper which runs it: a shell script, or on
+// Windows a batch file. Batch files are run through cmd, which mangles quoting and can't start in
+// a share, and the wrapper runs whichever Python comes first. So the script is run with tools.python
+// when tools.mp4dash is the script, or a wrapper the script can be found beside.
+pub(crate) fn script(tools: &Tools) -> Option<PathBuf> {
+    let found = find_program(&tools.mp4dash)?;
+    match found.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "py" => Some(found),
+        // Bento4's SDK has the wrappers in bin and the scripts in utils
+        "bat" | "cmd" => Some(found.parent()?.parent()?.join("utils").join("mp4-dash.py")).filter(|s| s.is_file()),
+        _ => None,
+    }
+}
+
+pub struct Config {
+    files: Vec<Track>,
+    out_dir: Option<PathBuf>,
+    force: bool,
+    hls: bool,
+    mpd_name: String,
+    profile: DashProfile,
+    addressing: SegmentAddressing,
+    number_padding: bool,
+    init_segment: Option<String>,
+    encryption: Option<Encryption>,
+}
+
+// An input to the packager along with the metadata mp4dash can't reliably read from the file itself
+pub struct Track {
+    pub(super) file: PathBuf,
+    pub(super) language: Option<String>,
+    pub(super) role: Option<&'static str>,
+    pub(super) trick_play: bool,
+}
+
+impl Track {
+    pub fn new(file: PathBuf, language: Option<String>) -> Self {
+        Track { file, language, role: None, trick_play: false }
+    }
+
+    // A DASH role from the urn:mpeg:dash:role:2011 scheme e.g. "main" or "alternate"
+    pub fn role(&mut self, role: &'static str) -> &mut Self {
+        self.role = Some(role);
+        self
+    }
+
+    // Only used to pick out the track type, the path itself is passed through untouched
+    pub(super) fn kind(&self) -> &'static str {
+        let file = self.file.to_string_lossy();
+        if file.contains("-sub-") {
+            "text"
+        } else if file.contains("-aud-") {
+            "audio"
+        } else {
+            "video"
+        }
+    }
+
+    // Marks a keyframe only rendition, advertised in the MPD with the DASH-IF trickmode property
+    // so players use it for fast-forward and rewind
+    pub fn trick_play(&mut self) -> &mut Self {
+        self.trick_play = true;
+        self
+    }
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        let mut cmd = CommandLine::tool(Tool::Mp4dash);
+
+        cmd.arg("-o")
+            .path(self.out_dir.as_ref().ok_or(InvalidCommandConfig("no output directory was given"))?);
+
+        if self.force {
+            cmd.arg("--force");
+        }
+
+        cmd.arg(format!("--mpd-name={}", self.mpd_name));
+
+        match self.profile {
+            DashProfile::Live => cmd.arg("--profiles=live"),
+            DashProfile::OnDemand => cmd.arg("--profiles=on-demand"),
+        };
+        match self.addressing {
+            SegmentAddressing::Timeline => { cmd.arg("--use-segment-timeline"); }
+            SegmentAddressing::List => { cmd.arg("--use-segment-list"); }
+            SegmentAddressing::Template => (),
+        }
+        if self.number_padding {
+            cmd.arg("--use-segment-template-number-padding");
+        }
+        if let Some(i) = &self.init_segment {
+            cmd.arg(format!("--init-segment={}", i));
+        }
+
+        if let Some(e) = &self.encryption {
+            let (id, key) = e.key_id.as_ref().zip(e.key.as_ref())
+                .ok_or(InvalidCommandConfig("mp4dash can only encrypt with a given key"))?;
+            cmd.arg("--encryption-key")
+                .secret(format!("{}:{}", id, key))
+                .arg(format!("--encryption-cenc-scheme={}", e.scheme.name()));
+            for system in &e.systems {
+                match system {
+                    DrmSystem::Widevine => cmd.arg("--widevine"),
+                    DrmSystem::PlayReady => cmd.arg("--playready"),
+                    DrmSystem::ClearKey => cmd.arg("--clearkey"),
+                };
+            }
+            if let Some(url) = &e.playready_license_url {
+                cmd.arg(format!("--playready-header=LA_URL:{}", url));
+            }
+            if let Some(url) = &e.clearkey_license_url {
+                cmd.arg(format!("--clearkey-license-uri={}", url));
+            }
+        }
+
+        if self.hls {
+            cmd.arg("--hls")
+                .arg(format!("--hls-master-playlist-name={}", HLS_PLAYLIST));
+        }
+
+        for track in &self.files {
+            let kind = track.kind();
+            let mut opts = vec![];
+            if kind == "text" {
+                opts.push("+format=webvtt".to_string());
+            }
+            if let Some(l) = &track.language {
+                if kind != "video" {
+                    opts.push(format!("+language={}", option_value(l)));
+                }
+            }
+            if let Some(r) = track.role {
+                opts.push(format!("+roles={}", r));
+            }
+            if track.trick_play {
+                opts.push("+trick_play=true".to_string());
+            }
+
+            if opts.is_empty() {
+                cmd.path(&track.file);
+            } else {
+                let mut arg = OsString::from(format!("[{}]", opts.join(",")));
+                arg.push(long_path(&track.file));
+                cmd.arg(arg);
+            }
+        }
+
+
+        Ok(cmd)
+    }
+
+    fn name(&self) -> String {
+        "Package".to_string()
+    }
+
+    fn weight(&self) -> f64 {
+        0.5
+    }
+}
+
+// Values can't contain the characters which delimit the [+key=value,...] options and there is no
+// escape syntax, so anything but letters, digits and hyphens is dropped
+pub(super) fn option_value(v: &str) -> String {
+    v.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').collect()
+}
+
+impl Config {
+    pub fn new<T>(files: T) -> Self
+        where T: IntoIterator<Item=Track>
+    {
+        Config {
+            files: files.into_iter().collect(),
+            out_dir: None,
+            force: false,
+            hls: false,
+            mpd_name: MANIFEST.to_string(),
+            profile: DashProfile::Live,
+            addressing: SegmentAddressing::Timeline,
+            number_padding: false,
+            init_segment: None,
+            encryption: None,
+        }
+    }
+
+    // Allows writing over an existing output directory
+    pub fn force(&mut self) -> &mut Self {
+        self.force = true;
+        self
+    }
+
+    // Also writes HLS playlists for the same segments
+    pub fn hls(&mut self) -> &mut Self {
+        self.hls = true;
+        self
+    }
+
+    pub fn mpd_name(&mut self, name: &str) -> &mut Self {
+        self.mpd_name = name.to_string();
+        self
+    }
+
+    pub fn profile(&mut self, profile: DashProfile) -> &mut Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn segment_addressing(&mut self, addressing: SegmentAddressing) -> &mut Self {
+        self.addressing = addressing;
+        self
+    }
+
+    // Zero pads segment numbers in file names so they sort
+    pub fn number_padding(&mut self) -> &mut Self {
+        self.number_padding = true;
+        self
+    }
+
+    pub fn init_segment(&mut self, name: &str) -> &mut Self {
+        self.init_segment = Some(name.to_string());
+        self
+    }
+
+    pub fn encryption(&mut self, encryption: Encryption) -> &mut Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    pub fn out_dir(&mut self, dir: PathBuf) -> Result<&mut Self, SessionError> {
+        if dir.exists() && !self.force {
+            return Err(InvalidCommandConfig("directory already exists"));
+        }
+        self.out_dir = Some(dir);
+        Ok(self)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::commands::mp4dash::Config;
+
+    #[test]
+    fn dotted_out_dir() {
+        // Package names are titles, which often have dots that aren't extensions
+        for name in ["Mr. Robot", "Show.S01E01", ".Mr. Robot.5a1b4c"].iter() {
+            let dir = PathBuf::from("/nonexistent/out").join(name);
+            assert!(Config::new(vec![]).out_dir(dir).is_ok(), "{}", name);
+        }
+    }
+}
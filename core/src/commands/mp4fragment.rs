@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::commands::{CommandLine, MediaCommandConfig, SessionError, Tool};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::WORK_DIR;
+use crate::error::ConvError;
+
+pub struct Config {
+    file: PathBuf,
+    out_file: Option<PathBuf>,
+    fragment_duration: Option<Duration>,
+    track: Option<String>,
+    can_fail: bool,
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        let mut cmd = CommandLine::tool(Tool::Mp4fragment);
+
+        // Fragments can only start on a keyframe so they're at least this long
+        if let Some(d) = self.fragment_duration {
+            cmd.arg("--fragment-duration")
+                .arg(d.as_millis().to_string());
+        }
+
+        if let Some(t) = &self.track {
+            cmd.arg("--track")
+                .arg(t);
+        }
+
+        cmd.path(&self.file)
+            .path(self.out_path());
+        Ok(cmd)
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        if !self.file.exists() {
+            return Err(InvalidCommandConfig("File does not exist"));
+        }
+        Ok(())
+    }
+
+    fn can_fail(&self) -> bool {
+        self.can_fail
+    }
+
+    fn name(&self) -> String {
+        "Fragment".to_string()
+    }
+
+    fn weight(&self) -> f64 {
+        0.2
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        vec![self.file.clone()]
+    }
+
+    fn outputs(&self) -> Vec<PathBuf> {
+        vec![self.out_path()]
+    }
+}
+
+impl Config {
+    fn out_path(&self) -> PathBuf {
+        self.out_file.clone().unwrap_or({
+            let mut base = WORK_DIR.to_path_buf();
+            let mut stem = self.file.file_stem().unwrap().to_os_string();
+            stem.push("-f.mp4");
+            base.push(stem);
+            base
+        })
+    }
+
+    pub fn new(file: PathBuf) -> Self {
+        Config {
+            file,
+            out_file: None,
+            fragment_duration: None,
+            track: None,
+            can_fail: false,
+        }
+    }
+
+    pub fn can_fail(&mut self) -> &mut Self {
+        self.can_fail = true;
+        self
+    }
+
+    // Only keeps the track with this id, or the first of a type such as "video"
+    pub fn track(&mut self, track: &str) -> &mut Self {
+        self.track = Some(track.to_string());
+        self
+    }
+
+    pub fn out_file(&mut self, out: PathBuf) -> &mut Self {
+        self.out_file = Some(out);
+        self
+    }
+
+    pub fn fragment_duration(&mut self, d: Duration) -> &mut Self {
+        self.fragment_duration = Some(d);
+        self
+    }
+}
\ No newline at end of file
@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::commands::{CommandLine, MediaCommandConfig, SessionError, Tool};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::error::ConvError;
+
+// Grabs a single image for the package, either the attached cover art or a frame from the video
+pub struct Config {
+    file: PathBuf,
+    out_file: PathBuf,
+    track: isize,
+    seek: Option<Duration>,
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        self.validate()?;
+
+        let mut cmd = CommandLine::tool(Tool::Ffmpeg);
+        cmd.arg("-y");
+
+        // Seeking before the input is fast as it jumps to the nearest keyframe
+        if let Some(seek) = self.seek {
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", seek.as_secs_f64()));
+        }
+
+        cmd.arg("-i")
+            .path(&self.file)
+            .arg("-map")
+            .arg(format!("0:{}", self.track))
+            .arg("-frames:v")
+            .arg("1")
+            .path(&self.out_file);
+
+        Ok(cmd)
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        if self.track < 0 {
+            return Err(InvalidCommandConfig("poster track must be a valid stream index"));
+        }
+        Ok(())
+    }
+
+    fn can_fail(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> String {
+        "Poster".to_string()
+    }
+
+    fn weight(&self) -> f64 {
+        0.1
+    }
+}
+
+impl Config {
+    pub fn new(file: PathBuf, out_file: PathBuf, track: isize) -> Self {
+        Config {
+            file,
+            out_file,
+            track,
+            seek: None,
+        }
+    }
+
+    pub fn seek(&mut self, seek: Duration) -> &mut Self {
+        self.seek = Some(seek);
+        self
+    }
+}
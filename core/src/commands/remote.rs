@@ -0,0 +1,461 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{error, info_span};
+use tracing_futures::Instrument;
+use uuid::Uuid;
+
+use crate::commands::{After, CommandLine, Ending, MediaCommandConfig, MediaInfo, Outcome, Progress, Session,
+                      SessionError, SessionEvent, SessionInfoInt, SessionState, Stream, Tool, SHUTTING_DOWN};
+use crate::commands::SessionError::{AlreadyStarted, InvalidCommandConfig};
+use crate::error::ConvError;
+use crate::SETTINGS;
+
+// Sessions run by workers, see SETTINGS.workers. The coordinator hands a session's stages to a worker
+// as a Job, which the worker runs as a session of its own, sending back Reports which the coordinator
+// applies to its session as if it were running the stages itself. What's done once the stages have
+// finished, such as moving the source, is still done by the coordinator.
+// Paths are sent as they are, so workers need the storage mounted where the coordinator has it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    file_title: String,
+    duration: Option<Duration>,
+    frames: Option<u64>,
+    parallelism: Option<usize>,
+    // How long the coordinator waits to hear from the worker before giving up on the session, after
+    // which the worker should stop it too
+    pub lost_after: Duration,
+    // Where stages record what they've finished, so running the same again can skip them
+    #[serde(default)]
+    work_dir: Option<PathBuf>,
+    stages: Vec<JobStage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JobStage {
+    name: String,
+    // Run as the worker has it set up, with its own path and extra arguments
+    tool: String,
+    // Those after the tool's own
+    args: Vec<String>,
+    // Which arguments are keys, so the worker doesn't log them either
+    #[serde(default)]
+    secrets: Vec<usize>,
+    // The stages it waits for, from 0
+    after: Vec<usize>,
+    can_fail: bool,
+    weight: f64,
+    reports_progress: bool,
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+}
+
+impl MediaCommandConfig for JobStage {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        // Workers only run the tools, whatever they're sent
+        let tool = Tool::from_name(&self.tool).ok_or(InvalidCommandConfig("a worker can only run the tools"))?;
+        let mut cmd = CommandLine::tool(tool);
+        let prefix = cmd.args.len();
+        cmd.args(&self.args);
+        cmd.secrets = self.secrets.iter().map(|i| i + prefix).collect();
+        Ok(cmd)
+    }
+
+    fn can_fail(&self) -> bool {
+        self.can_fail
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn reports_progress(&self) -> bool {
+        self.reports_progress
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        self.inputs.clone()
+    }
+
+    fn outputs(&self) -> Vec<PathBuf> {
+        self.outputs.clone()
+    }
+}
+
+impl Job {
+    // The session the worker runs, with what reports on it
+    pub fn session(self) -> Result<(Session, Reporter), ConvError> {
+        let mut stages = self.stages.into_iter();
+        let first = stages.next().ok_or(InvalidCommandConfig("a job needs at least one stage"))?;
+        let info = MediaInfo {
+            file_title: self.file_title,
+            duration: self.duration,
+            frames: self.frames,
+            ..MediaInfo::default()
+        };
+        let mut session = Session::new(self.id, Box::new(first), Arc::new(RwLock::new(info)));
+        for stage in stages {
+            let after = After::Stages(stage.after.clone());
+            session.chain_after(stage, after);
+        }
+        if let Some(n) = self.parallelism {
+            session.parallelism(n);
+        }
+        if let Some(dir) = self.work_dir {
+            session.work_dir(dir);
+        }
+        let (tx, logs) = mpsc::unbounded_channel();
+        session.forward = Some(tx);
+        Ok((session, Reporter { logs, pending: vec![] }))
+    }
+}
+
+// How a worker's session is getting on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Report {
+    state: SessionState,
+    stage: usize,
+    max_stages: usize,
+    done: Vec<bool>,
+    lead: Option<usize>,
+    // How long the lead stage has been running
+    lead_time: Option<Duration>,
+    frame: usize,
+    fps: f64,
+    bitrate: f64,
+    total_size: usize,
+    time: Duration,
+    error: Option<String>,
+    // Lines logged since the last report the coordinator took
+    logs: Vec<(Stream, String)>,
+}
+
+impl Report {
+    pub fn finished(&self) -> bool {
+        !matches!(self.state, SessionState::Pending | SessionState::Running)
+    }
+
+    fn apply(self, s: &mut SessionInfoInt) {
+        s.stage = self.stage;
+        if self.done.len() == s.done.len() {
+            s.done = self.done;
+        }
+        s.lead = self.lead;
+        s.stage_started = self.lead_time.and_then(|t| Instant::now().checked_sub(t));
+        s.frame = self.frame;
+        s.fps = self.fps;
+        s.bitrate = self.bitrate;
+        s.total_size = self.total_size;
+        s.time = self.time;
+        if self.error.is_some() {
+            s.error = self.error;
+        }
+        for (stream, line) in self.logs {
+            s.log(stream, line);
+        }
+    }
+}
+
+// Kept by the worker for each of its sessions, holding on to lines logged until the coordinator has
+// them
+pub struct Reporter {
+    logs: mpsc::UnboundedReceiver<(Stream, String)>,
+    pending: Vec<(Stream, String)>,
+}
+
+impl Reporter {
+    pub fn report(&mut self, session: &Session) -> Report {
+        while let Ok(line) = self.logs.try_recv() {
+            self.pending.push(line);
+        }
+        // Kept well under the coordinator's limit on request bodies, the rest go with the next report
+        let mut size = 0;
+        let logs = self.pending.iter()
+            .take_while(|(_, line)| {
+                size += line.len();
+                size == line.len() || size < 16 * 1024
+            })
+            .cloned()
+            .collect();
+        let s = &*session.info.borrow();
+        Report {
+            state: s.state(),
+            stage: s.stage,
+            max_stages: s.max_stages,
+            done: s.done.clone(),
+            lead: s.lead,
+            lead_time: s.stage_started.map(|t| t.elapsed()),
+            frame: s.frame,
+            fps: s.fps,
+            bitrate: s.bitrate,
+            total_size: s.total_size,
+            time: s.time,
+            error: s.error.clone(),
+            logs,
+        }
+    }
+
+    // The coordinator has taken the last report
+    pub fn sent(&mut self, report: &Report) {
+        self.pending.drain(..report.logs.len().min(self.pending.len()));
+    }
+
+    // Every line logged so far has been sent
+    pub fn caught_up(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// What the coordinator's session hears of the worker
+pub(super) enum Update {
+    Report(Report),
+    // Given up on without waiting to hear from the worker
+    Abandoned,
+}
+
+impl Session {
+    // Starts the session on a worker rather than here, giving the job to hand it
+    pub fn start_remote(&mut self, worker: &str) -> Result<Job, ConvError> {
+        if self.commands.is_empty() {
+            return Err(AlreadyStarted.into());
+        }
+        let after = self.dependencies()?;
+        let stages = self.commands.iter().zip(after).map(|(c, after)| {
+            let line = c.describe()?;
+            let tool = line.tool.ok_or(InvalidCommandConfig("a worker can only run the tools"))?;
+            Ok(JobStage {
+                name: c.name(),
+                tool: tool.name().to_string(),
+                secrets: line.secrets.iter().filter_map(|i| i.checked_sub(line.prefix)).collect(),
+                args: line.args.into_iter().skip(line.prefix).map(text).collect::<Result<_, _>>()?,
+                after,
+                can_fail: c.can_fail(),
+                weight: c.weight(),
+                reports_progress: c.reports_progress(),
+                inputs: c.inputs(),
+                outputs: c.outputs(),
+            })
+        }).collect::<Result<Vec<_>, ConvError>>()?;
+        let job = {
+            let info = self.media_info.read().unwrap();
+            Job {
+                id: self.id,
+                file_title: info.file_title.clone(),
+                duration: info.duration,
+                frames: info.frames,
+                parallelism: self.parallelism,
+                lost_after: lost_after(),
+                work_dir: self.work_dir.clone(),
+                stages,
+            }
+        };
+
+        self.commands.clear();
+        let (tx, reports) = mpsc::unbounded_channel();
+        self.reports = Some(tx);
+        self.worker = Some(worker.to_string());
+        let work_lock = self.work_lock.take();
+        let progress = self.progress.clone();
+        let ending = self.begin();
+        let span = info_span!("session", session_id = %self.id, worker);
+        tokio::spawn(follow(reports, progress, ending, worker.to_string(), work_lock).instrument(span));
+        Ok(job)
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    // Passes on a report from the worker running the session, giving whether the session has been
+    // cancelled. None once the session isn't being run by a worker, which should then stop.
+    pub fn report(&self, report: Report) -> Option<bool> {
+        self.reports.as_ref()?.send(Update::Report(report)).ok()?;
+        Some(*self.cancelled.borrow())
+    }
+
+    // While shutting down the session is given up on straight away rather than waiting to hear the
+    // worker has stopped, which it does once its next report is turned away
+    pub(super) fn abandon(&self) {
+        if let Some(reports) = &self.reports {
+            reports.send(Update::Abandoned).ok();
+        }
+    }
+}
+
+// Arguments go over the wire as JSON strings, which can't hold ones that aren't UTF-8
+fn text(s: OsString) -> Result<String, SessionError> {
+    s.into_string().map_err(|_| InvalidCommandConfig("a worker can't be sent arguments which aren't UTF-8"))
+}
+
+fn lost_after() -> Duration {
+    Duration::from_secs(SETTINGS.workers.as_ref().map_or(60, |w| w.lost_after))
+}
+
+// Applies the worker's reports to the session until it has finished. A worker which goes without
+// reporting for longer than workers.lost_after is taken as gone, along with the session.
+async fn follow(mut reports: mpsc::UnboundedReceiver<Update>, progress: Arc<Progress>, ending: Ending, worker: String,
+                _work_lock: Option<File>) {
+    let lost_after = lost_after();
+    let mut stage = 0;
+    loop {
+        let report = match tokio::time::timeout(lost_after, reports.recv()).await {
+            Ok(Some(Update::Report(report))) => report,
+            Ok(Some(Update::Abandoned)) => return ending.end(Outcome::Interrupted).await,
+            // The session has been removed
+            Ok(None) => return ending.end(Outcome::Interrupted).await,
+            Err(_) => {
+                let msg = format!("Worker {} stopped reporting", worker);
+                error!("{}", msg);
+                progress.update(|s| {
+                    s.log(Stream::Stderr, msg.clone());
+                    s.error = Some(msg);
+                }).await;
+                return ending.end(Outcome::Interrupted).await;
+            }
+        };
+
+        let state = report.state;
+        if report.stage != stage {
+            stage = report.stage;
+            ending.notify(SessionEvent::Stage { id: ending.id, stage, max_stages: report.max_stages });
+        }
+        progress.update(|s| report.apply(s)).await;
+        let outcome = match state {
+            SessionState::Pending | SessionState::Running => continue,
+            SessionState::Complete => Outcome::Completed,
+            SessionState::Failed => Outcome::Failed,
+            // As with sessions run here, they're only cancelled while shutting down to stop them
+            SessionState::Cancelled if SHUTTING_DOWN.load(Ordering::SeqCst) => Outcome::Interrupted,
+            SessionState::Cancelled => Outcome::Cancelled,
+            SessionState::Interrupted => Outcome::Interrupted,
+        };
+        return ending.end(outcome).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::commands::{MediaCommandConfig, SessionInfoInt, Stream, Tool};
+    use crate::commands::remote::{Job, JobStage};
+
+    fn stage(name: &str, tool: &str, after: Vec<usize>) -> JobStage {
+        JobStage {
+            name: name.to_string(),
+            tool: tool.to_string(),
+            args: vec!["-i".to_string(), "in.mkv".to_string(), "--key".to_string(), "ffeeddcc".to_string()],
+            secrets: vec![3],
+            after,
+            can_fail: false,
+            weight: 1.0,
+            reports_progress: true,
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    fn job(stages: Vec<JobStage>) -> Job {
+        Job {
+            id: Uuid::new_v4(),
+            file_title: "movie".to_string(),
+            duration: Some(Duration::from_secs(60)),
+            frames: None,
+            parallelism: Some(2),
+            lost_after: Duration::from_secs(60),
+            work_dir: None,
+            stages,
+        }
+    }
+
+    #[test]
+    fn job_round_trip() {
+        let sent = job(vec![stage("encode", "ffmpeg", vec![]), stage("package", "packager", vec![0])]);
+        let job: Job = serde_json::from_str(&serde_json::to_string(&sent).unwrap()).unwrap();
+        assert_eq!((job.id, job.lost_after), (sent.id, sent.lost_after));
+
+        let (session, _) = job.session().unwrap();
+        assert_eq!(session.stage_names, vec!["encode", "package"]);
+        assert_eq!(session.dependencies().unwrap(), vec![vec![], vec![0]]);
+        assert_eq!(session.parallelism, Some(2));
+        assert_eq!(session.media_info.read().unwrap().duration, Some(Duration::from_secs(60)));
+        // Run as the worker has the tool, with the secret still the key after its own arguments
+        let line = session.commands[1].describe().unwrap();
+        assert_eq!(line.tool, Some(Tool::Packager));
+        assert_eq!(line.args[line.prefix..], ["-i", "in.mkv", "--key", "ffeeddcc"]);
+        assert_eq!(line.secrets, vec![line.prefix + 3]);
+    }
+
+    #[test]
+    fn only_tools() {
+        assert!(stage("shell", "sh", vec![]).describe().is_err());
+        assert!(job(vec![]).session().is_err());
+    }
+
+    #[test]
+    fn report_batches() {
+        let (session, mut reporter) = job(vec![stage("encode", "ffmpeg", vec![])]).session().unwrap();
+        let forward = session.forward.clone().unwrap();
+        for _ in 0..3 {
+            forward.send((Stream::Stderr, "x".repeat(6 * 1024))).unwrap();
+        }
+        let report = reporter.report(&session);
+        assert_eq!(report.logs.len(), 2);
+        reporter.sent(&report);
+        assert!(!reporter.caught_up());
+
+        // Sent again until the coordinator has them
+        assert_eq!(reporter.report(&session).logs.len(), 1);
+        let report = reporter.report(&session);
+        assert_eq!(report.logs.len(), 1);
+        reporter.sent(&report);
+        assert!(reporter.caught_up());
+
+        // A line over the limit still goes, on its own
+        forward.send((Stream::Stdout, "x".repeat(20 * 1024))).unwrap();
+        forward.send((Stream::Stdout, "y".to_string())).unwrap();
+        assert_eq!(reporter.report(&session).logs.len(), 1);
+    }
+
+    #[test]
+    fn apply_report() {
+        let (session, mut reporter) = job(vec![stage("encode", "ffmpeg", vec![]), stage("package", "packager", vec![0])])
+            .session().unwrap();
+        session.forward.clone().unwrap().send((Stream::Stderr, "frame=100".to_string())).unwrap();
+        let mut report = reporter.report(&session);
+        report.stage = 1;
+        report.done = vec![true, false];
+        report.frame = 100;
+        report.error = Some("the packager failed".to_string());
+
+        let mut s = SessionInfoInt::new();
+        s.done = vec![false, false];
+        report.clone().apply(&mut s);
+        assert_eq!((s.stage, s.frame), (1, 100));
+        assert_eq!(s.done, vec![true, false]);
+        assert_eq!(s.error.as_deref(), Some("the packager failed"));
+        assert_eq!(s.stderr.back().map(String::as_str), Some("frame=100"));
+
+        // Which stages are done is only taken from a job with as many stages, and an error stays
+        let mut s = SessionInfoInt::new();
+        s.done = vec![false; 3];
+        s.error = Some("earlier".to_string());
+        report.error = None;
+        report.apply(&mut s);
+        assert_eq!(s.done, vec![false; 3]);
+        assert_eq!(s.error.as_deref(), Some("earlier"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::commands::{CommandLine, long_path, MediaCommandConfig, Tool};
+use crate::commands::mp4dash::{HLS_PLAYLIST, MANIFEST, option_value, Track};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::encryption::{DrmSystem, Encryption};
+use crate::error::ConvError;
+
+// Packages the separately encoded streams with Shaka Packager. Unlike mp4dash it reads the encodes
+// as they are, so they don't need fragmenting first.
+pub struct Config {
+    files: Vec<Track>,
+    out_dir: Option<PathBuf>,
+    mpd_name: String,
+    hls: bool,
+    segment_duration: Option<Duration>,
+    fragment_duration: Option<Duration>,
+    low_latency: bool,
+    encryption: Option<Encryption>,
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        let out_dir = self.out_dir.as_ref().ok_or(InvalidCommandConfig("no output directory was given"))?;
+
+        let mut cmd = CommandLine::tool(Tool::Packager);
+
+        // Each stream is described by a descriptor of comma separated key=value fields
+        for track in &self.files {
+            let kind = track.kind();
+            let mut fields = vec![
+                format!("in={}", long_path(&track.file).to_string_lossy()),
+                format!("stream={}", kind),
+                format!("output={}", long_path(&out_dir.join(track.file.file_name().unwrap())).to_string_lossy()),
+            ];
+            if let Some(l) = &track.language {
+                if kind != "video" {
+                    fields.push(format!("language={}", option_value(l)));
+                }
+            }
+            if let Some(r) = track.role {
+                fields.push(format!("dash_roles={}", r));
+            }
+            // Every frame of the trick play encode is a keyframe, so all of them are kept
+            if track.trick_play {
+                fields.push("trick_play_factor=1".to_string());
+            }
+            cmd.arg(fields.join(","));
+        }
+
+        cmd.arg("--mpd_output")
+            .path(out_dir.join(&self.mpd_name));
+        if self.hls {
+            cmd.arg("--hls_master_playlist_output")
+                .path(out_dir.join(HLS_PLAYLIST));
+        }
+        if let Some(d) = self.segment_duration {
+            cmd.arg("--segment_duration")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+        if let Some(d) = self.fragment_duration {
+            cmd.arg("--fragment_duration")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+        if self.low_latency {
+            cmd.arg("--low_latency_dash_mode");
+        }
+
+        if let Some(e) = &self.encryption {
+            match (&e.key_id, &e.key, &e.key_server) {
+                (Some(id), Some(key), _) => {
+                    cmd.arg("--enable_raw_key_encryption")
+                        .arg("--keys")
+                        .secret(format!("key_id={}:key={}", id, key));
+                    if !e.systems.is_empty() {
+                        let systems: Vec<_> = e.systems.iter().map(|s| match s {
+                            DrmSystem::Widevine => "Widevine",
+                            DrmSystem::PlayReady => "PlayReady",
+                            // ClearKey players read the W3C common PSSH
+                            DrmSystem::ClearKey => "CommonSystem",
+                        }).collect();
+                        cmd.arg("--protection_systems")
+                            .arg(systems.join(","));
+                    }
+                }
+                (_, _, Some(server)) => {
+                    cmd.arg("--enable_widevine_encryption")
+                        .arg("--key_server_url")
+                        .arg(&server.url)
+                        .arg("--content_id")
+                        .arg(&server.content_id)
+                        .arg("--signer")
+                        .arg(&server.signer)
+                        .arg("--aes_signing_key")
+                        .secret(&server.signing_key)
+                        .arg("--aes_signing_iv")
+                        .secret(&server.signing_iv);
+                }
+                _ => return Err(InvalidCommandConfig("encryption needs a key or a key server").into()),
+            }
+            cmd.arg("--protection_scheme")
+                .arg(e.scheme.name());
+        }
+
+        Ok(cmd)
+    }
+
+    fn name(&self) -> String {
+        "Package".to_string()
+    }
+
+    fn weight(&self) -> f64 {
+        0.5
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|t| t.file.clone()).collect()
+    }
+}
+
+impl Config {
+    pub fn new<T>(files: T) -> Self
+        where T: IntoIterator<Item=Track>
+    {
+        Config {
+            files: files.into_iter().collect(),
+            out_dir: None,
+            mpd_name: MANIFEST.to_string(),
+            hls: false,
+            segment_duration: None,
+            fragment_duration: None,
+            low_latency: false,
+            encryption: None,
+        }
+    }
+
+    pub fn mpd_name(&mut self, name: &str) -> &mut Self {
+        self.mpd_name = name.to_string();
+        self
+    }
+
+    // Also writes HLS playlists for the same segments
+    pub fn hls(&mut self) -> &mut Self {
+        self.hls = true;
+        self
+    }
+
+    pub fn segment_duration(&mut self, d: Duration) -> &mut Self {
+        self.segment_duration = Some(d);
+        self
+    }
+
+    pub fn fragment_duration(&mut self, d: Duration) -> &mut Self {
+        self.fragment_duration = Some(d);
+        self
+    }
+
+    // Segments are advertised as available as soon as their first fragment is
+    pub fn low_latency(&mut self) -> &mut Self {
+        self.low_latency = true;
+        self
+    }
+
+    pub fn encryption(&mut self, encryption: Encryption) -> &mut Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    pub fn out_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.out_dir = Some(dir);
+        self
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::commands::{CommandLine, MediaCommandConfig, SessionError, Tool};
+use crate::commands::SessionError::InvalidCommandConfig;
+use crate::vtt;
+use crate::error::ConvError;
+
+pub const SPRITE_PATTERN: &str = "thumbnails-%03d.jpg";
+
+// Produces tiled sprite sheets of evenly spaced frames, used alongside a WebVTT storyboard for scrub
+// previews in the player
+pub struct Config {
+    file: PathBuf,
+    out_dir: PathBuf,
+    track: isize,
+    interval: Duration,
+    width: isize,
+    height: isize,
+    columns: isize,
+    rows: isize,
+    duration: Option<Duration>,
+    start: Option<Duration>,
+}
+
+impl MediaCommandConfig for Config {
+    fn describe(&self) -> Result<CommandLine, ConvError> {
+        self.validate()?;
+
+        let mut cmd = CommandLine::tool(Tool::Ffmpeg);
+        cmd.arg("-y");
+
+        if let Some(start) = self.start {
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", start.as_secs_f64()));
+        }
+
+        cmd.arg("-i")
+            .path(&self.file)
+            .arg("-map")
+            .arg(format!("0:{}", self.track));
+
+        if let Some(d) = self.duration {
+            cmd.arg("-t")
+                .arg(format!("{:.3}", d.as_secs_f64()));
+        }
+
+        cmd.arg("-vf")
+            .arg(format!("fps=1/{:.3},scale={}:{},tile={}x{}",
+                         self.interval.as_secs_f64(), self.width, self.height, self.columns, self.rows))
+            .arg("-q:v")
+            .arg("5")
+            .path(self.out_dir.join(SPRITE_PATTERN));
+
+        Ok(cmd)
+    }
+
+    fn validate(&self) -> Result<(), SessionError> {
+        if self.interval.as_millis() == 0 {
+            return Err(InvalidCommandConfig("thumbnail interval must be positive"));
+        }
+        if self.width < 1 || self.height < 1 || self.columns < 1 || self.rows < 1 {
+            return Err(InvalidCommandConfig("thumbnail dimensions must be positive"));
+        }
+        Ok(())
+    }
+
+    fn can_fail(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> String {
+        "Thumbnails".to_string()
+    }
+
+    fn weight(&self) -> f64 {
+        2.0
+    }
+
+    fn reports_progress(&self) -> bool {
+        // The tiled output's timestamps don't line up with the source's
+        false
+    }
+}
+
+impl Config {
+    pub fn new(file: PathBuf, out_dir: PathBuf, track: isize) -> Self {
+        Config {
+            file,
+            out_dir,
+            track,
+            interval: Duration::from_secs(10),
+            width: 160,
+            height: 90,
+            columns: 10,
+            rows: 10,
+            duration: None,
+            start: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn duration(&mut self, d: Duration) -> &mut Self {
+        self.duration = Some(d);
+        self
+    }
+
+    pub fn start(&mut self, start: Duration) -> &mut Self {
+        self.start = Some(start);
+        self
+    }
+
+    // Sizes the thumbnails to the given width, keeping the source aspect ratio
+    pub fn size_for(&mut self, width: isize, src_width: isize, src_height: isize) -> &mut Self {
+        self.width = width;
+        if src_width > 0 && src_height > 0 {
+            // Round to an even height as required by most encoders
+            self.height = ((width * src_height / src_width) + 1) / 2 * 2;
+        }
+        self
+    }
+
+    // The WebVTT storyboard mapping each interval of the video onto its tile in the sprite sheets
+    pub fn storyboard(&self, duration: Duration) -> String {
+        let per_sheet = (self.columns * self.rows) as u128;
+        let count = (duration.as_millis() + self.interval.as_millis() - 1) / self.interval.as_millis();
+
+        let mut out = String::from("WEBVTT\n");
+        for i in 0..count {
+            let start = Duration::from_millis((i * self.interval.as_millis()) as u64);
+            let end = (start + self.interval).min(duration);
+            let pos = (i % per_sheet) as isize;
+            let sheet = SPRITE_PATTERN.replace("%03d", &format!("{:03}", i / per_sheet + 1));
+
+            write!(out, "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                   vtt::timestamp(start), vtt::timestamp(end), sheet,
+                   pos % self.columns * self.width, pos / self.columns * self.height,
+                   self.width, self.height).unwrap();
+        }
+        out
+    }
+}
@@ -0,0 +1,1293 @@
+use std::fs::File;
+use std::io;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use derive_more::{Display, Error};
+use fs2::FileExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::commands;
+use crate::commands::{After, concat, ffmpeg, ffmpeg_dash, MediaInfo, mp4dash, mp4fragment, poster, Session, shaka, thumbnails};
+use crate::commands::ffmpeg::{AAC, EAC3, WEB_VTT, X264};
+use crate::commands::ffprobe::Stream;
+use crate::encryption::Encryption;
+use crate::error::ConvError;
+use crate::sessions::Sessions;
+use crate::{library, PREVIEW_DIR, queue, reaper, runtime, PROCESSED_DIR, SETTINGS, vtt, WORK_DIR};
+use crate::package::Metadata;
+use crate::queue::{Entry, Request};
+use crate::settings::{Packager, PostProcess};
+
+pub const POSTER: &str = "poster.jpg";
+
+type Stage = Box<dyn commands::MediaCommandConfig + Send + Sync>;
+
+// Per-request tweaks to the encoding parameters, any of these being set forces a video transcode
+#[derive(Serialize, Deserialize, Debug, Default, Clone, ToSchema)]
+pub struct Overrides {
+    pub crf: Option<isize>,
+    pub video_bitrate: Option<isize>,
+    pub audio_bitrate: Option<isize>,
+    pub max_height: Option<isize>,
+    pub encoder: Option<String>,
+    // Also emit a 5.1 rendition alongside the stereo downmix for multichannel sources
+    pub surround: Option<bool>,
+    // Burn the first image based subtitle into the video, as those can't be converted to WebVTT
+    pub burn_subtitles: Option<bool>,
+    // Add a low framerate, keyframe only video rendition for smooth seeking
+    pub trick_play: Option<bool>,
+    // Only convert the first N seconds into the preview directory, for trying out settings
+    pub preview_seconds: Option<u64>,
+    // Trim the output to this range of the source, as seconds or hh:mm:ss.fff
+    pub start: Option<String>,
+    pub end: Option<String>,
+    // Package every chapter separately instead of the whole file
+    pub split_chapters: Option<bool>,
+    // Replaces the configured post_process for this request
+    pub post_process: Option<PostProcess>,
+    // Process again even though a package already exists
+    pub force: Option<bool>,
+    // Replaces the configured packager, so profiles can use a different one
+    pub packager: Option<Packager>,
+    // Replaces the configured cmaf
+    pub cmaf: Option<bool>,
+    // Seconds per segment, shorter segments start playing sooner and seek in finer steps but make
+    // for larger manifests. Encoded video gets a keyframe at each segment boundary.
+    pub segment_duration: Option<f64>,
+    // Seconds per fragment within a segment, so players can start on a segment before all of it has
+    // been fetched. Bento4 can't split segments so uses this as the segment duration when that
+    // isn't set.
+    pub fragment_duration: Option<f64>,
+    // Advertise segments as available once their first fragment is (availabilityTimeOffset), not
+    // supported by Bento4 so refused with it
+    pub low_latency: Option<bool>,
+    // Encrypt the package so it can only be played under DRM
+    pub encryption: Option<Encryption>,
+    // Encrypt the package with the ClearKey key of this name from the settings instead
+    pub clearkey: Option<String>,
+}
+
+impl Overrides {
+    pub(crate) fn video_set(&self) -> bool {
+        self.crf.is_some() || self.video_bitrate.is_some() || self.max_height.is_some() || self.encoder.is_some()
+            || self.burn_subtitles.unwrap_or(false)
+            // Copying video can only cut on keyframes
+            || self.start.is_some()
+    }
+
+    // Checks the user supplied values, returning a message suitable for the client
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(e) = &self.encoder {
+            if ffmpeg::video_encoder_from_name(e).is_none() {
+                return Err(format!("Unknown encoder: {}", e));
+            }
+        }
+        for t in self.start.iter().chain(self.end.iter()) {
+            if vtt::parse_timestamp(t).is_none() {
+                return Err(format!("Invalid timestamp: {}", t));
+            }
+        }
+        if let (Some(start), Some(end)) = (self.start(), self.end()) {
+            if start >= end {
+                return Err("start must be before end".to_string());
+            }
+        }
+        for d in self.segment_duration.iter().chain(self.fragment_duration.iter()) {
+            if !d.is_finite() || *d <= 0.0 {
+                return Err(format!("Invalid segment or fragment duration: {}", d));
+            }
+        }
+        if let (Some(s), Some(f)) = (self.segment_duration, self.fragment_duration) {
+            if f > s {
+                return Err("fragment_duration can't be longer than segment_duration".to_string());
+            }
+        }
+        if self.encryption.is_some() && self.clearkey.is_some() {
+            return Err("Only one of encryption and clearkey can be given".to_string());
+        }
+        if let Some(name) = &self.clearkey {
+            if Encryption::clearkey(name).is_none() {
+                return Err(format!("Unknown ClearKey key: {}", name));
+            }
+        }
+        if let Some(e) = self.encryption() {
+            e.validate(self.packager.unwrap_or(SETTINGS.packager))?;
+        }
+        if self.low_latency.unwrap_or(false) && self.packager.unwrap_or(SETTINGS.packager) == Packager::Bento4 {
+            return Err("low_latency needs the shaka or ffmpeg packager".to_string());
+        }
+        Ok(())
+    }
+
+    // These overrides, with anything they leave unset taken from defaults. Encryption is taken
+    // whole, as encryption and clearkey can't both be given.
+    pub fn or(&self, defaults: &Overrides) -> Overrides {
+        let encrypted = self.encryption.is_some() || self.clearkey.is_some();
+        Overrides {
+            crf: self.crf.or(defaults.crf),
+            video_bitrate: self.video_bitrate.or(defaults.video_bitrate),
+            audio_bitrate: self.audio_bitrate.or(defaults.audio_bitrate),
+            max_height: self.max_height.or(defaults.max_height),
+            encoder: self.encoder.clone().or_else(|| defaults.encoder.clone()),
+            surround: self.surround.or(defaults.surround),
+            burn_subtitles: self.burn_subtitles.or(defaults.burn_subtitles),
+            trick_play: self.trick_play.or(defaults.trick_play),
+            preview_seconds: self.preview_seconds.or(defaults.preview_seconds),
+            start: self.start.clone().or_else(|| defaults.start.clone()),
+            end: self.end.clone().or_else(|| defaults.end.clone()),
+            split_chapters: self.split_chapters.or(defaults.split_chapters),
+            post_process: self.post_process.or(defaults.post_process),
+            force: self.force.or(defaults.force),
+            packager: self.packager.or(defaults.packager),
+            cmaf: self.cmaf.or(defaults.cmaf),
+            segment_duration: self.segment_duration.or(defaults.segment_duration),
+            fragment_duration: self.fragment_duration.or(defaults.fragment_duration),
+            low_latency: self.low_latency.or(defaults.low_latency),
+            encryption: if encrypted { self.encryption.clone() } else { defaults.encryption.clone() },
+            clearkey: if encrypted { self.clearkey.clone() } else { defaults.clearkey.clone() },
+        }
+    }
+
+    // How much of a source of the given length ends up in the package. A source of unknown length
+    // is assumed to be long enough for any trim.
+    pub(crate) fn output_duration(&self, full: Option<Duration>) -> Option<Duration> {
+        let start = self.start().unwrap_or_default();
+        let trimmed = match full {
+            Some(full) => Some(self.end().unwrap_or(full).min(full) - start.min(full)),
+            None => self.end().map(|e| e.checked_sub(start).unwrap_or_default()),
+        };
+        match (trimmed, self.preview_seconds) {
+            (Some(t), Some(p)) => Some(t.min(Duration::from_secs(p))),
+            (None, Some(p)) => Some(Duration::from_secs(p)),
+            (t, None) => t,
+        }
+    }
+
+    // The share of a source of the given length which ends up in the package
+    fn output_share(&self, full: Option<Duration>) -> f64 {
+        match (self.output_duration(full), full) {
+            (Some(o), Some(f)) if f.as_secs_f64() > 0.0 => o.as_secs_f64() / f.as_secs_f64(),
+            _ => 1.0,
+        }
+    }
+
+    pub(crate) fn start(&self) -> Option<Duration> {
+        self.start.as_deref().and_then(vtt::parse_timestamp)
+    }
+
+    pub(crate) fn end(&self) -> Option<Duration> {
+        self.end.as_deref().and_then(vtt::parse_timestamp)
+    }
+
+    fn encryption(&self) -> Option<Encryption> {
+        self.encryption.clone()
+            .or_else(|| self.clearkey.as_deref().and_then(Encryption::clearkey))
+    }
+
+    fn segment_duration(&self) -> Option<Duration> {
+        self.segment_duration.map(Duration::from_secs_f64)
+    }
+
+    fn fragment_duration(&self) -> Option<Duration> {
+        self.fragment_duration.map(Duration::from_secs_f64)
+    }
+}
+
+// The 'business logic' of the main functionality of the API, this method will convert a given video
+// file into a directory containing a dash manifest and all segments. This is achieved by chaining
+// various Configs together into a Session. The session enables reporting of status through some
+// shared memory, and coordinates the list of commands to execute.
+// Multiple files are joined together first, the streams of the first file are assumed to be the same
+// as the rest.
+pub async fn exec_dash_conv(state: Arc<Sessions>, files: Vec<PathBuf>, overrides: &Overrides, owner: Option<String>) -> Result<String, ConvError> {
+    let session = dash_files(&state, Uuid::new_v4(), files.clone(), overrides, owner, None, true).await?;
+    launch(&state, session, Request::Dash { files, overrides: overrides.clone(), name: None, post_process: true }).await
+}
+
+// The session packaging the files, also used to put together sessions taken from the queue
+pub(crate) async fn dash_files(state: &Arc<Sessions>, id: Uuid, files: Vec<PathBuf>, overrides: &Overrides, owner: Option<String>,
+                               name: Option<String>, post: bool) -> Result<Session, ConvError> {
+    let mut info = MediaInfo::get(&files[0]).await?;
+    let size = files.iter().filter_map(|f| f.metadata().ok()).map(|m| m.len()).sum();
+    // The joined copy is written to the work directory before anything else
+    let joined = if files.len() > 1 { size } else { 0 };
+    let mut full = info.duration;
+    for f in &files[1..] {
+        full = full.zip(MediaInfo::get(f).await?.duration).map(|(a, b)| a + b);
+    }
+    check_space(size, overrides.output_share(full), &[(*WORK_DIR, joined)])?;
+
+    let work = work_dir(id, &files[0], overrides)?;
+    let mut session = if files.len() == 1 {
+        dash_session(state, id, work, info, None, files[0].clone(), overrides, owner, name)?
+    } else {
+        info.duration = full;
+        let list = work_file(&work.path, "-concat.txt");
+        concat::write_list(&list, &files)?;
+        let out = work_file(&work.path, "-concat.mkv");
+        let join = concat::Config::new(list, out.clone());
+        dash_session(state, id, work, info, Some(Box::new(join)), out, overrides, owner, name)?
+    };
+    if post {
+        post_process(&mut session, files, overrides);
+    }
+    Ok(session)
+}
+
+// The session exec_dash_conv would start, put together without writing anything or running it
+pub async fn plan_dash_conv(state: Arc<Sessions>, files: Vec<PathBuf>, overrides: &Overrides) -> Result<Session, ConvError> {
+    let id = Uuid::new_v4();
+    let mut info = MediaInfo::get(&files[0]).await?;
+    let work = WorkDir::planned(id);
+    if files.len() == 1 {
+        return dash_session(&state, id, work, info, None, files[0].clone(), overrides, None, None);
+    }
+    let mut full = info.duration;
+    for f in &files[1..] {
+        full = full.zip(MediaInfo::get(f).await?.duration).map(|(a, b)| a + b);
+    }
+    info.duration = full;
+    let list = work_file(&work.path, "-concat.txt");
+    let out = work_file(&work.path, "-concat.mkv");
+    let join = concat::Config::new(list, out.clone());
+    dash_session(&state, id, work, info, Some(Box::new(join)), out, overrides, None, None)
+}
+
+// Where the package for a source will be written
+pub fn package_dir(info: &MediaInfo, overrides: &Overrides) -> PathBuf {
+    let base = if overrides.preview_seconds.is_some() { *PREVIEW_DIR } else { *PROCESSED_DIR };
+    base.join(package_name(info))
+}
+
+// Packages are named by filling in the package template, which may group them into directories with
+// '/'. Each level is sanitised separately and levels left empty are dropped, so a template of
+// {show}/{title} puts films without a show straight in the processed directory.
+pub fn package_name(info: &MediaInfo) -> String {
+    let vars = template_vars(info);
+    let mut rendered = String::new();
+    let mut rest = SETTINGS.package_template.as_str();
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let close = match rest[open..].find('}') {
+            Some(c) => open + c,
+            None => break,
+        };
+        let var = &rest[open + 1..close];
+        rendered.push_str(vars.iter().find(|(k, _)| *k == var).map_or("", |(_, v)| v.as_str()));
+        rest = &rest[close + 1..];
+    }
+    rendered.push_str(rest);
+
+    let name = rendered.split('/')
+        // Leading dots would hide the package, or climb out of the processed directory
+        .map(|level| sanitise_name(level).trim_start_matches('.').trim().to_string())
+        .filter(|level| !level.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    if name.is_empty() {
+        sanitise_name(&info.path.file_stem().unwrap().to_string_lossy())
+    } else {
+        name
+    }
+}
+
+// The values a package template can use, from the source's metadata or else its file name, and empty
+// when neither says
+fn template_vars(info: &MediaInfo) -> Vec<(&'static str, String)> {
+    let stem = info.path.file_stem().unwrap().to_string_lossy().into_owned();
+    let format = &info.raw.format;
+    let parsed = &info.parsed;
+    let tag = |key: &str| format.tag(key).map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    let number = |key: &str| format.tag(key).and_then(|n| n.trim().parse::<u32>().ok());
+    // Numbers are padded so packages sort in order
+    let padded = |n: Option<u32>| n.map_or_else(String::new, |n| format!("{:02}", n));
+    let dir = library::relative_path(&info.path).parent()
+        .map_or_else(String::new, |p| p.to_string_lossy().into_owned());
+    vec![
+        ("title", tag("title").or_else(|| parsed.title.clone()).unwrap_or_else(|| stem.clone())),
+        ("stem", stem),
+        ("dir", dir),
+        ("show", tag("show").or_else(|| parsed.show.clone()).unwrap_or_default()),
+        ("season", padded(number("season_number").or(parsed.season))),
+        ("episode", padded(number("episode_sort").or(parsed.episode))),
+        ("year", tag("date").map(|d| d.chars().take(4).collect()).or_else(|| parsed.year.map(|y| y.to_string())).unwrap_or_default()),
+    ]
+}
+
+// What packaging several files or chapters came to. One which can't be started doesn't stop the
+// rest, so the sessions which were are still answered with.
+pub struct Batch {
+    pub ids: Vec<String>,
+    // What couldn't be started and why, by the media id of the file or the chapter's package name
+    pub failed: Vec<(String, ConvError)>,
+}
+
+impl Batch {
+    // Only when something was started, otherwise the first failure or else that everything had
+    // already been processed
+    fn started(self, name: impl FnOnce() -> String) -> Result<Batch, ConvError> {
+        if !self.ids.is_empty() {
+            return Ok(self);
+        }
+        match self.failed.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Err(AlreadyProcessed { name: name() }.into()),
+        }
+    }
+}
+
+// Packages each chapter of the file separately, named after the chapter. Chapters which already have
+// a package are skipped. The source is never post processed as the chapters finish independently.
+pub async fn exec_dash_chapters(state: Arc<Sessions>, file: PathBuf, overrides: &Overrides, owner: Option<String>) -> Result<Batch, ConvError> {
+    let info = MediaInfo::get(&file).await?;
+    if info.raw.chapters.is_empty() {
+        return Err(ConvError::Probe { path: file, reason: "it has no chapters to split by".to_string() });
+    }
+    // Every chapter runs at once, so between them they need as much as the whole file
+    check_space(file.metadata().map(|m| m.len()).unwrap_or(0), 1.0, &[])?;
+    // Chapters go alongside where the whole file's package would
+    let package = PathBuf::from(package_name(&info));
+    let group = package.parent().map(Path::to_path_buf).unwrap_or_default();
+    let stem = package.file_name().unwrap().to_string_lossy();
+    let base = if overrides.preview_seconds.is_some() { *PREVIEW_DIR } else { *PROCESSED_DIR };
+    // Forced chapters are packaged alongside the old ones and swapped in by dash_session
+    let forced = overrides.force.unwrap_or(false) || overrides.preview_seconds.is_some();
+
+    let mut batch = Batch { ids: vec![], failed: vec![] };
+    for (i, c) in info.raw.chapters.iter().enumerate() {
+        let title = c.tags.as_ref().and_then(|t| t.title.clone()).unwrap_or_default();
+        let name = group.join(sanitise_name(&format!("{} {:02} {}", stem, i + 1, title))).to_string_lossy().into_owned();
+        let package = base.join(&name);
+        if state.writing_to(&package).is_some() || (package.exists() && !forced) {
+            info!("Skipping chapter {} of {:?} as it has already been processed", i + 1, file);
+            continue;
+        }
+
+        let mut o = overrides.clone();
+        o.start = Some(c.start_time.clone());
+        o.end = Some(c.end_time.clone());
+        let id = Uuid::new_v4();
+        let request = Request::Dash { files: vec![file.clone()], overrides: o.clone(), name: Some(name.clone()), post_process: false };
+        let started: Result<_, ConvError> = async {
+            let work = work_dir(id, &file, &o)?;
+            let session = dash_session(&state, id, work, info.clone(), None, file.clone(), &o, owner.clone(), Some(name.clone()))?;
+            launch(&state, session, request).await
+        }.await;
+        match started {
+            Ok(id) => batch.ids.push(id),
+            Err(e) => {
+                error!("Could not start chapter {} of {:?}: {}", i + 1, file, e);
+                batch.failed.push((name, e));
+            }
+        }
+    }
+    batch.started(|| format!("Every chapter of {}", package_name(&info)))
+}
+
+// Packages every file in a directory of an unprocessed directory separately, such as a season of a
+// show. Packages are laid out as the sources are, under the directory's path relative to the
+// unprocessed directory, rather than by the package template. Files which already have a package,
+// or have one being written, are skipped.
+pub async fn exec_dash_dir(state: Arc<Sessions>, dir: &Path, files: Vec<PathBuf>, overrides: &Overrides, owner: Option<String>) -> Result<Batch, ConvError> {
+    let size = files.iter().filter_map(|f| f.metadata().ok()).map(|m| m.len()).sum();
+    check_space(size, 1.0, &[])?;
+    let base = if overrides.preview_seconds.is_some() { *PREVIEW_DIR } else { *PROCESSED_DIR };
+
+    let mut batch = Batch { ids: vec![], failed: vec![] };
+    for file in files {
+        let relative = library::relative_path(&file).with_extension("");
+        let name = relative.iter()
+            .map(|level| sanitise_name(&level.to_string_lossy()).trim_start_matches('.').trim().to_string())
+            .filter(|level| !level.is_empty())
+            .collect::<PathBuf>();
+        let package = base.join(&name);
+        let forced = overrides.force.unwrap_or(false) || overrides.preview_seconds.is_some();
+        if state.writing_to(&package).is_some() || (package.exists() && !forced) {
+            info!("Skipping {:?} as it has already been processed", file);
+            continue;
+        }
+
+        match start_in_dir(&state, &file, name, overrides, owner.clone()).await {
+            Ok(id) => batch.ids.push(id),
+            Err(e) => {
+                error!("Could not start {:?}: {}", file, e);
+                batch.failed.push((commands::media_id(&file), e));
+            }
+        }
+    }
+    batch.started(|| format!("Everything in {}", dir.display()))
+}
+
+async fn start_in_dir(state: &Arc<Sessions>, file: &Path, name: PathBuf, overrides: &Overrides, owner: Option<String>) -> Result<String, ConvError> {
+    // The library has already probed it, so this only fails for a file which has just changed
+    let info = MediaInfo::get(file).await?;
+    let id = Uuid::new_v4();
+    let work = work_dir(id, file, overrides)?;
+    let name = name.to_string_lossy().into_owned();
+    let mut session = dash_session(state, id, work, info, None, file.to_path_buf(), overrides, owner, Some(name.clone()))?;
+    post_process(&mut session, vec![file.to_path_buf()], overrides);
+    let request = Request::Dash { files: vec![file.to_path_buf()], overrides: overrides.clone(), name: Some(name), post_process: true };
+    launch(state, session, request).await
+}
+
+// Intermediate files go in a working directory of the session's own, so sessions never clobber each
+// other. The name replaces the default package directory name.
+// A preparation stage, such as joining or downloading, runs first when given and must produce file.
+fn dash_session(state: &Arc<Sessions>, id: Uuid, work: WorkDir, mut info: MediaInfo, prepare: Option<Stage>,
+                file: PathBuf, overrides: &Overrides, owner: Option<String>, name: Option<String>) -> Result<Session, ConvError> {
+    let WorkDir { path: work, lock, dry_run } = work;
+
+    let start = overrides.start();
+    let end = overrides.end();
+    let preview = overrides.preview_seconds.map(Duration::from_secs);
+    info.duration = overrides.output_duration(info.duration);
+    // Named after the original source rather than file, which may be an intermediate
+    let name = name.unwrap_or_else(|| package_name(&info));
+    let mut replacing = None;
+    let out_dir = if preview.is_some() {
+        // Previews are disposable so they're overwritten each time
+        PREVIEW_DIR.join(name)
+    } else if overrides.force.unwrap_or(false) && PROCESSED_DIR.join(&name).exists() {
+        // Re-processing packages alongside the old package and swaps it in at the end, so the old
+        // one stays playable in the meantime
+        let package = PROCESSED_DIR.join(name);
+        let staged = staging_dir(&package, id);
+        if !dry_run {
+            reaper::staging(&staged);
+        }
+        replacing = Some(package);
+        staged
+    } else {
+        PROCESSED_DIR.join(name)
+    };
+    // The template can group packages into directories which don't exist yet
+    if let Some(parent) = out_dir.parent().filter(|_| !dry_run) {
+        std::fs::create_dir_all(parent).ok();
+    }
+
+    let video_encoder = (info.dash_transcode_required() || overrides.video_set()).then(|| overrides.encoder.as_deref()
+        .and_then(ffmpeg::video_encoder_from_name)
+        .unwrap_or(X264));
+    let hls = overrides.cmaf.unwrap_or(SETTINGS.cmaf);
+    let pipe = SETTINGS.pipe_intermediates;
+    let mut run_together = None;
+    let mut make_dir = false;
+    let mut subtitles = vec![];
+    let stages = match overrides.packager.unwrap_or(SETTINGS.packager) {
+        Packager::Bento4 => {
+            let (mut stages, tracks, _) = split_stages(&info, &file, overrides, video_encoder, &work, true, pipe, dry_run);
+            let mut dash = mp4dash::Config::new(tracks);
+            if preview.is_some() {
+                dash.force();
+            }
+            if hls {
+                dash.hls();
+            }
+            let layout = &SETTINGS.mp4dash;
+            dash.mpd_name(&SETTINGS.manifest_name)
+                .profile(layout.profile)
+                .segment_addressing(layout.segment_addressing);
+            if layout.number_padding {
+                dash.number_padding();
+            }
+            if let Some(i) = &layout.init_segment {
+                dash.init_segment(i);
+            }
+            if let Some(e) = overrides.encryption() {
+                dash.encryption(e);
+            }
+            dash.out_dir(out_dir.clone())?;
+            stages.push((Box::new(dash), After::All));
+            stages
+        }
+        Packager::Shaka => {
+            let (mut stages, tracks, piped) = split_stages(&info, &file, overrides, video_encoder, &work, false, pipe, dry_run);
+            let mut packager = shaka::Config::new(tracks);
+            packager.mpd_name(&SETTINGS.manifest_name);
+            if hls {
+                packager.hls();
+            }
+            if let Some(d) = overrides.segment_duration() {
+                packager.segment_duration(d);
+            }
+            if let Some(d) = overrides.fragment_duration() {
+                packager.fragment_duration(d);
+            }
+            if overrides.low_latency.unwrap_or(false) {
+                packager.low_latency();
+            }
+            if let Some(e) = overrides.encryption() {
+                packager.encryption(e);
+            }
+            packager.out_dir(out_dir.clone());
+            // Unlike mp4dash, it won't create the directory itself
+            make_dir = true;
+            // Reading the pipes as they're written means starting with the encodes, all of which
+            // have to run at once to be read. The subtitles are ordinary files so have to be
+            // finished first, or the packager could read them half written.
+            if let Some(files) = piped {
+                stages.push((Box::new(packager), After::Stages(files)));
+                run_together = Some(stages.len());
+            } else {
+                stages.push((Box::new(packager), After::All));
+            }
+            stages
+        }
+        Packager::Ffmpeg => {
+            let mut dash = ffmpeg_dash_stage(&info, &file, overrides, video_encoder, &out_dir);
+            // Unlike mp4dash, the muxer won't create the directory itself
+            make_dir = true;
+            if hls {
+                dash.hls();
+            }
+            subtitles = dash.subtitles();
+            vec![(Box::new(dash) as Stage, After::All)]
+        }
+    };
+    // Chapter times are relative to the untrimmed source so they'd be misleading on a clip
+    let chapters = (!info.raw.chapters.is_empty() && start.is_none() && end.is_none())
+        .then(|| vtt::chapters(&info.raw.chapters));
+
+    // Prefer embedded cover art, falling back to a frame a tenth of the way in to skip any intros
+    let poster = match info.raw.streams.iter().find(|s| s.is_attached_pic()) {
+        Some(s) => Some(poster::Config::new(file.clone(), out_dir.join(POSTER), s.index)),
+        None => info.raw.streams.iter().find(|s| s.codec_type == "video").map(|s| {
+            let mut c = poster::Config::new(file.clone(), out_dir.join(POSTER), s.index);
+            c.seek(start.unwrap_or_default() + info.duration.unwrap_or_default() / 10);
+            c
+        }),
+    };
+
+    // The storyboard can't be laid out without knowing the length
+    let video = info.raw.streams.iter().find(|s| s.codec_type == "video" && !s.is_attached_pic());
+    let thumbs = video.zip(info.duration).map(|(s, d)| {
+        let mut c = thumbnails::Config::new(file.clone(), out_dir.clone(), s.index);
+        c.size_for(160, s.width.unwrap_or(0), s.height.unwrap_or(0));
+        if let Some(s) = start {
+            c.start(s);
+        }
+        c.duration(d);
+        (c, d)
+    });
+    let storyboard = thumbs.as_ref().map(|(t, d)| t.storyboard(*d));
+    let thumbs = thumbs.map(|(t, _)| t);
+
+    let metadata = Metadata::new(info.path.clone(), video_encoder, overrides.clone(), info.raw.streams.clone());
+
+    let info = Arc::new(RwLock::new(info));
+    // Stages which wait for nothing else still wait for the preparation
+    let offset = prepare.is_some() as usize;
+    let mut stages = stages.into_iter().map(|(s, after)| match after {
+        After::Stages(v) if offset == 1 && v.is_empty() => (s, After::Stages(vec![0])),
+        After::Stages(v) => (s, After::Stages(v.into_iter().map(|i| i + offset).collect())),
+        After::All => (s, After::All),
+    });
+    let mut session = match prepare {
+        Some(prepare) => Session::new(id, prepare, info),
+        None => Session::new(id, stages.next().unwrap().0, info),
+    };
+    session.output(replacing.clone().unwrap_or_else(|| out_dir.clone()));
+    if make_dir {
+        session.make_dir(out_dir.clone());
+    }
+    if let Some(n) = run_together {
+        session.parallelism(n.max(runtime::stage_parallelism()));
+    }
+    for (s, after) in stages {
+        session.chain_after(s, after);
+    }
+    if let Some(poster) = poster {
+        session.chain(poster);
+    }
+    if let Some(thumbs) = thumbs {
+        session.chain(thumbs);
+    }
+    if !subtitles.is_empty() {
+        let out_dir = out_dir.clone();
+        session.on_success(move || ffmpeg_dash::add_subtitles(&out_dir.join(&SETTINGS.manifest_name), &subtitles));
+    }
+    if let Some(chapters) = chapters {
+        let out_dir = out_dir.clone();
+        session.on_success(move || std::fs::write(out_dir.join("chapters.vtt"), chapters));
+    }
+    if let Some(storyboard) = storyboard {
+        let out_dir = out_dir.clone();
+        session.on_success(move || std::fs::write(out_dir.join("thumbnails.vtt"), storyboard));
+    }
+    {
+        let out_dir = out_dir.clone();
+        session.on_success(move || metadata.write(&out_dir));
+    }
+    // Last, so everything else has been written into the staged package
+    if let Some(package) = replacing {
+        session.on_success(move || swap_in(&out_dir, &package));
+    }
+    session.owner(owner)
+        .work_dir(work)
+        .events(state.events.clone());
+    if let Some(lock) = lock {
+        session.lock_work_dir(lock);
+    }
+    Ok(session)
+}
+
+// Encodes each stream to a file of its own, along with the tracks a packager should be given.
+// mp4dash needs the files fragmented by mp4fragment first, which adds a stage for each.
+// Each stage comes with the stages in the list it waits for, so the encodes can run side by side.
+// With pipe the encodes are fragmented by ffmpeg instead, and when the packager doesn't need
+// fragmenting they're written into named pipes. When the pipes were made the stages writing
+// ordinary files, the subtitles, are returned last, in which case the packager has to run alongside
+// the encodes after those. A dry run only assumes the pipes would be made.
+fn split_stages(info: &MediaInfo, file: &Path, overrides: &Overrides, video_encoder: Option<&'static str>,
+                work: &Path, fragmented: bool, pipe: bool, dry_run: bool) -> (Vec<(Stage, After)>, Vec<mp4dash::Track>, Option<Vec<usize>>) {
+    let tmp = |ending: &str| work_file(work, ending);
+    let packaged = |name: &str| if fragmented {
+        tmp(&format!("{}-f.mp4", name))
+    } else {
+        tmp(&format!("{}.mp4", name))
+    };
+    // The pipes made so far, None once they aren't being used
+    let mut piped = (pipe && !fragmented).then(Vec::new);
+    let encoded = |name: &str, c: &mut ffmpeg::Config, piped: &mut Option<Vec<PathBuf>>| {
+        if !pipe {
+            c.out(tmp(&format!("{}.mp4", name)));
+            return;
+        }
+        match overrides.segment_duration().or_else(|| overrides.fragment_duration()) {
+            Some(d) => { c.fragment_duration(d); }
+            None => { c.fragmented(); }
+        }
+        let out = packaged(name);
+        if let Some(pipes) = piped.as_mut().filter(|_| !dry_run) {
+            match make_pipe(&out) {
+                Ok(()) => pipes.push(out.clone()),
+                // Either every encode goes through a pipe or none do
+                Err(e) => {
+                    error!("Could not create a pipe for {:?}, writing files instead: {}", out, e);
+                    pipes.iter().for_each(|p| { std::fs::remove_file(p).ok(); });
+                    *piped = None;
+                }
+            }
+        }
+        c.out(out);
+    };
+    let new_ffmpeg = || {
+        let mut c = ffmpeg::Config::new(file.to_path_buf());
+        if let Some(s) = overrides.start() {
+            c.start(s);
+        }
+        if let Some(e) = overrides.end() {
+            c.end(e);
+        }
+        if let Some(p) = overrides.preview_seconds {
+            c.duration(Duration::from_secs(p));
+        }
+        c
+    };
+
+    let mut vid = new_ffmpeg();
+    if let Some(encoder) = video_encoder {
+        vid.video_encoder(encoder)
+            .colour_8_bit();
+        // An explicit bitrate replaces the default constant quality target
+        match (overrides.crf, overrides.video_bitrate) {
+            (Some(crf), _) => { vid.crf(crf); }
+            (None, None) => { vid.crf(19); }
+            (None, Some(_)) => (),
+        }
+        if let Some(b) = overrides.video_bitrate {
+            vid.video_bitrate(b);
+        }
+        if let Some(h) = overrides.max_height {
+            vid.max_height(h);
+        }
+        if let Some(s) = burnt_subtitle(info, overrides) {
+            vid.burn_subtitle(s);
+        }
+        if let Some(d) = overrides.segment_duration() {
+            vid.keyframe_interval(d);
+        }
+    }
+    vid.audio_disabled()
+        .subtitle_disabled();
+    encoded("-split-vid-0", &mut vid, &mut piped);
+
+    // Video which is kept as it is in an MP4 can be fragmented straight from the source, rather than
+    // copied out first
+    let is_mp4 = file.extension()
+        .map_or(false, |e| e.eq_ignore_ascii_case("mp4") || e.eq_ignore_ascii_case("m4v"));
+    let videos = info.raw.streams.iter().filter(|s| s.codec_type == "video" && !s.is_attached_pic()).count();
+    let passthrough = fragmented && is_mp4 && videos == 1 && video_encoder.is_none()
+        && overrides.start().is_none() && overrides.end().is_none() && overrides.preview_seconds.is_none();
+
+    let audio_streams = wanted_audio_streams(&info.raw.streams, &SETTINGS.audio_languages);
+
+    let audio_bitrate = overrides.audio_bitrate.unwrap_or(256_000);
+    let audios: Vec<_> = audio_streams.iter().map(|s| {
+        let mut aud = new_ffmpeg();
+        aud.video_disabled()
+            .subtitle_disabled()
+            .tracks(once(s.index));
+        if !audio_copyable(s, audio_bitrate) {
+            aud.audio_channels(2)
+                .audio_encoder(AAC)
+                .audio_bitrate(audio_bitrate);
+        }
+        encoded(&format!("-split-aud-{}", s.index), &mut aud, &mut piped);
+        aud
+    }).collect();
+
+    let trick = overrides.trick_play.unwrap_or(false).then(|| {
+        let mut c = new_ffmpeg();
+        c.audio_disabled()
+            .subtitle_disabled()
+            .video_encoder(X264)
+            .fps(1)
+            .gop(1)
+            .crf(28)
+            .max_height(360)
+            .colour_8_bit();
+        encoded("-split-vid-0-trick", &mut c, &mut piped);
+        c
+    });
+
+    let surrounds = surround_streams(&audio_streams, overrides);
+
+    // Passthrough when the source is already Dolby Digital, otherwise encode to E-AC-3 which tops
+    // out at 5.1
+    let surround_audios: Vec<_> = surrounds.iter().map(|s| {
+        let mut aud = new_ffmpeg();
+        aud.video_disabled()
+            .subtitle_disabled()
+            .tracks(once(s.index));
+        encoded(&format!("-split-aud-{}-surround", s.index), &mut aud, &mut piped);
+        if s.codec_name != "ac3" && s.codec_name != "eac3" {
+            aud.audio_encoder(EAC3)
+                .audio_channels(s.channels.unwrap_or(6).min(6))
+                .audio_bitrate(640_000);
+        }
+        aud
+    }).collect();
+
+    let subs: Vec<_> = info.text_subtitles().map(|s| {
+        let mut sub = new_ffmpeg();
+        sub.video_disabled()
+            .audio_disabled()
+            .subtitle_encoder(WEB_VTT)
+            .tracks(once(s.index))
+            .out(tmp(&format!("-split-sub-{}.vtt", s.index)))
+            .can_fail();
+        sub
+    }).collect();
+
+    // Each fragment becomes a segment of the package
+    let fragment = |name: &str| {
+        let mut c = mp4fragment::Config::new(tmp(&format!("{}.mp4", name)));
+        c.out_file(tmp(&format!("{}-f.mp4", name)));
+        if let Some(d) = overrides.segment_duration().or_else(|| overrides.fragment_duration()) {
+            c.fragment_duration(d);
+        }
+        c
+    };
+    let vid_frag = if passthrough {
+        let mut c = mp4fragment::Config::new(file.to_path_buf());
+        c.track("video")
+            .out_file(packaged("-split-vid-0"));
+        if let Some(d) = overrides.segment_duration().or_else(|| overrides.fragment_duration()) {
+            c.fragment_duration(d);
+        }
+        c
+    } else {
+        fragment("-split-vid-0")
+    };
+    let trick_frag = trick.as_ref()
+        .map(|_| fragment("-split-vid-0-trick"));
+    let audio_frags: Vec<_> = audio_streams.iter().map(|s| {
+        let mut c = fragment(&format!("-split-aud-{}", s.index));
+        c.can_fail();
+        c
+    }).chain(surrounds.iter().map(|s| {
+        let mut c = fragment(&format!("-split-aud-{}-surround", s.index));
+        c.can_fail();
+        c
+    })).collect();
+
+    let mut tracks = vec![];
+    if info.raw.streams.iter().any(|s| s.codec_type == "video" && s.index == 0) {
+        tracks.push(mp4dash::Track::new(packaged("-split-vid-0"), None));
+        if trick.is_some() {
+            let mut t = mp4dash::Track::new(packaged("-split-vid-0-trick"), None);
+            t.trick_play();
+            tracks.push(t);
+        }
+    }
+    for s in &audio_streams {
+        let mut t = mp4dash::Track::new(packaged(&format!("-split-aud-{}", s.index)), s.language());
+        // Only label the roles when there is an alternative to choose between
+        if surrounds.iter().any(|a| a.index == s.index) || (s.is_default() && audio_streams.len() > 1) {
+            t.role("main");
+        }
+        tracks.push(t);
+    }
+    for s in &surrounds {
+        let mut t = mp4dash::Track::new(packaged(&format!("-split-aud-{}-surround", s.index)), s.language());
+        t.role("alternate");
+        tracks.push(t);
+    }
+    for s in info.text_subtitles() {
+        let mut t = mp4dash::Track::new(tmp(&format!("-split-sub-{}.vtt", s.index)), s.language());
+        if let Some(r) = subtitle_role(s) {
+            t.role(r);
+        }
+        tracks.push(t);
+    }
+    // A missing audio stream is only skipped when nothing is reading from the other end
+    let mut audios = audios;
+    let mut surround_audios = surround_audios;
+    let piped = piped.is_some();
+    if !piped {
+        audios.iter_mut().chain(surround_audios.iter_mut()).for_each(|a| { a.can_fail(); });
+    }
+
+    let root = || After::Stages(vec![]);
+    let mut stages: Vec<(Stage, After)> = vec![];
+    if !passthrough {
+        stages.push((Box::new(vid), root()));
+    }
+    let first_audio = stages.len();
+    let audio_encodes = first_audio..first_audio + audios.len() + surround_audios.len();
+    stages.extend(audios.into_iter().map(|a| (Box::new(a) as Stage, root())));
+    stages.extend(surround_audios.into_iter().map(|a| (Box::new(a) as Stage, root())));
+    let sub_extracts = stages.len()..stages.len() + subs.len();
+    stages.extend(subs.into_iter().map(|s| (Box::new(s) as Stage, root())));
+    let trick_encode = trick.map(|t| {
+        stages.push((Box::new(t), root()));
+        stages.len() - 1
+    });
+    // Each fragment waits only for its own encode
+    if passthrough {
+        stages.push((Box::new(vid_frag), root()));
+    } else if fragmented && !pipe {
+        stages.push((Box::new(vid_frag), After::Stages(vec![0])));
+    }
+    if fragmented && !pipe {
+        if let Some((t, i)) = trick_frag.zip(trick_encode) {
+            stages.push((Box::new(t), After::Stages(vec![i])));
+        }
+        stages.extend(audio_frags.into_iter().zip(audio_encodes)
+            .map(|(a, i)| (Box::new(a) as Stage, After::Stages(vec![i]))));
+    }
+    (stages, tracks, piped.then(|| sub_extracts.collect()))
+}
+
+// Encodes and packages everything in one ffmpeg run
+fn ffmpeg_dash_stage(info: &MediaInfo, file: &Path, overrides: &Overrides, video_encoder: Option<&'static str>,
+                     out_dir: &Path) -> ffmpeg_dash::Config {
+    let mut dash = ffmpeg_dash::Config::new(file.to_path_buf());
+    dash.mpd_name(&SETTINGS.manifest_name);
+    if let Some(s) = overrides.start() {
+        dash.start(s);
+    }
+    if let Some(e) = overrides.end() {
+        dash.end(e);
+    }
+    if let Some(p) = overrides.preview_seconds {
+        dash.duration(Duration::from_secs(p));
+    }
+
+    if let Some(s) = info.raw.streams.iter().find(|s| s.codec_type == "video" && !s.is_attached_pic()) {
+        let mut vid = ffmpeg_dash::Video::new(s.index);
+        if let Some(encoder) = video_encoder {
+            vid.encoder(encoder)
+                .colour_8_bit();
+            match (overrides.crf, overrides.video_bitrate) {
+                (Some(crf), _) => { vid.crf(crf); }
+                (None, None) => { vid.crf(19); }
+                (None, Some(_)) => (),
+            }
+            if let Some(b) = overrides.video_bitrate {
+                vid.bitrate(b);
+            }
+            if let Some(h) = overrides.max_height {
+                vid.max_height(h);
+            }
+            if let Some(s) = burnt_subtitle(info, overrides) {
+                vid.burn_subtitle(s);
+            }
+        }
+        if overrides.trick_play.unwrap_or(false) {
+            vid.trick_play();
+        }
+        dash.video(vid);
+    }
+
+    let audio_streams = wanted_audio_streams(&info.raw.streams, &SETTINGS.audio_languages);
+    let audio_bitrate = overrides.audio_bitrate.unwrap_or(256_000);
+    for s in &audio_streams {
+        let mut aud = ffmpeg_dash::Audio::new(s.index, s.language());
+        if !audio_copyable(s, audio_bitrate) {
+            aud.encoder(AAC)
+                .channels(2)
+                .bitrate(audio_bitrate);
+        }
+        dash.audio(aud);
+    }
+    for s in surround_streams(&audio_streams, overrides) {
+        let mut aud = ffmpeg_dash::Audio::new(s.index, s.language());
+        if s.codec_name != "ac3" && s.codec_name != "eac3" {
+            aud.encoder(EAC3)
+                .channels(s.channels.unwrap_or(6).min(6))
+                .bitrate(640_000);
+        }
+        dash.audio(aud);
+    }
+
+    for s in info.text_subtitles() {
+        let mut sub = ffmpeg_dash::Subtitle::new(s.index, s.language());
+        if let Some(r) = subtitle_role(s) {
+            sub.role(r);
+        }
+        dash.subtitle(sub);
+    }
+    if let Some(d) = overrides.segment_duration() {
+        dash.segment_duration(d);
+    }
+    if let Some(d) = overrides.fragment_duration() {
+        dash.fragment_duration(d);
+    }
+    if overrides.low_latency.unwrap_or(false) {
+        dash.low_latency();
+    }
+    dash.out_dir(out_dir.to_path_buf());
+    dash
+}
+
+// Stereo AAC within the bitrate is already what the stereo track would be encoded to, so it's copied
+// as is. Streams which don't say what their bitrate is are encoded to be safe.
+fn audio_copyable(s: &Stream, bitrate: isize) -> bool {
+    s.codec_name == "aac"
+        && s.channels.map_or(false, |c| c <= 2)
+        && s.bit_rate().map_or(false, |b| b <= bitrate)
+}
+
+fn surround_streams<'a>(audio_streams: &[&'a Stream], overrides: &Overrides) -> Vec<&'a Stream> {
+    if overrides.surround.unwrap_or(false) {
+        audio_streams.iter().copied().filter(|s| s.channels.unwrap_or(0) > 2).collect()
+    } else {
+        vec![]
+    }
+}
+
+// The first image based subtitle, when asked to burn subtitles in, as those can't be converted to WebVTT
+fn burnt_subtitle(info: &MediaInfo, overrides: &Overrides) -> Option<isize> {
+    if !overrides.burn_subtitles.unwrap_or(false) {
+        return None;
+    }
+    info.bitmap_subtitles().next().map(|s| s.index)
+}
+
+fn subtitle_role(s: &Stream) -> Option<&'static str> {
+    if s.is_forced() {
+        Some("forced-subtitle")
+    } else if s.is_default() {
+        Some("main")
+    } else {
+        None
+    }
+}
+
+// Queues the session to be started once there's room for it. Anything wrong with its commands is
+// reported now rather than when it's started. The request is what the session was put together
+// from, for putting it together again wherever it's taken from the queue.
+pub(crate) async fn launch(state: &Arc<Sessions>, session: Session, request: Request) -> Result<String, ConvError> {
+    session.plan()?;
+    let id = session.id();
+    let entry = Entry::new(id, session.get_owner().map(str::to_string), request);
+    queue::push(state, session, entry).await?;
+    queue::dispatch(state).await;
+    Ok(id.to_string())
+}
+
+// Archives or deletes the sources once the package's manifest has been written. Previews and trimmed
+// packages don't cover the whole source so it is always kept for them.
+fn post_process(session: &mut Session, sources: Vec<PathBuf>, overrides: &Overrides) {
+    let action = overrides.post_process.unwrap_or(SETTINGS.post_process);
+    let partial = overrides.preview_seconds.is_some() || overrides.start.is_some() || overrides.end.is_some();
+    if action == PostProcess::Keep || partial {
+        return;
+    }
+
+    let manifest = session.output_dir().unwrap().join(&SETTINGS.manifest_name);
+    session.on_success(move || {
+        if !manifest.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no manifest was written, keeping the sources"));
+        }
+        for source in &sources {
+            match action {
+                PostProcess::Move => archive(source)?,
+                PostProcess::Delete => std::fs::remove_file(source)?,
+                PostProcess::Keep => (),
+            }
+            info!("Post processed {:?} ({:?})", source, action);
+        }
+        Ok(())
+    });
+}
+
+// A source is never archived over one archived before it with the same name
+fn archive(source: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(&SETTINGS.dirs.archive)?;
+    let dest = SETTINGS.dirs.archive.join(source.file_name().unwrap());
+    match rename_new(source, &dest) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists =>
+            Err(io::Error::new(e.kind(), format!("{:?} has already been archived, keeping the source", dest))),
+        // Renaming fails across filesystems, where it has to be copied instead
+        Err(_) => {
+            let mut out = std::fs::OpenOptions::new().write(true).create_new(true).open(&dest)?;
+            io::copy(&mut File::open(source)?, &mut out)?;
+            std::fs::remove_file(source)
+        }
+    }
+}
+
+// Renames from to to, failing rather than replacing to if it exists. Linking and then unlinking does
+// that without a gap between checking and renaming, filesystems without hard links have to check.
+pub fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::hard_link(from, to) {
+        Ok(()) => std::fs::remove_file(from),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) if to.exists() => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} already exists", to))),
+        Err(_) => std::fs::rename(from, to),
+    }
+}
+
+// Picks the audio streams whose language is in the allow-list. Untagged streams are always kept, and
+// if nothing matches every audio stream is kept rather than producing a silent package.
+pub(crate) fn wanted_audio_streams<'a>(streams: &'a [Stream], languages: &[String]) -> Vec<&'a Stream> {
+    let audio: Vec<_> = streams.iter().filter(|s| s.codec_type == "audio").collect();
+    if languages.is_empty() {
+        return audio;
+    }
+
+    let wanted: Vec<_> = audio.iter().cloned().filter(|s| {
+        match s.language() {
+            Some(l) => languages.contains(&l),
+            None => true
+        }
+    }).collect();
+
+    if wanted.is_empty() { audio } else { wanted }
+}
+
+// Strips characters which are awkward in directory names
+fn sanitise_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || " ._-()".contains(c) { c } else { '_' })
+        .collect()
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "{} has already been processed, set force to process it again", name)]
+pub struct AlreadyProcessed {
+    #[error(not(source))]
+    pub(crate) name: String,
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "{:?} needs {} MB free but only has {} MB", dir, "needed / 1_000_000", "free / 1_000_000")]
+pub struct InsufficientSpace {
+    #[error(not(source))]
+    dir: PathBuf,
+    needed: u64,
+    free: u64,
+}
+
+// Estimates the space a session will need from the size of its sources, share being the fraction of
+// the source which is packaged. Extra needs are added on top, e.g. for joining or downloading.
+fn check_space(size: u64, share: f64, extra: &[(&Path, u64)]) -> Result<(), InsufficientSpace> {
+    let check = &SETTINGS.space_check;
+    if !check.enabled {
+        return Ok(());
+    }
+
+    let output = (size as f64 * share.min(1.0)) as u64;
+    let needs = [
+        // There's no fragmented copy of each stream when ffmpeg fragments them itself
+        (*WORK_DIR, (output as f64 * check.work_factor / if SETTINGS.pipe_intermediates { 2.0 } else { 1.0 }) as u64),
+        (*PROCESSED_DIR, (output as f64 * check.processed_factor) as u64),
+    ];
+
+    // Directories sharing a volume draw on the same free space
+    let mut volumes: Vec<(&Path, u64, u64)> = vec![];
+    for &(dir, needed) in needs.iter().chain(extra) {
+        let free = fs2::available_space(dir).unwrap_or(u64::MAX);
+        match volumes.iter_mut().find(|v| same_volume(v.0, dir)) {
+            Some(v) => v.1 += needed,
+            None => volumes.push((dir, needed, free)),
+        }
+    }
+
+    match volumes.into_iter().find(|&(_, needed, free)| needed > free) {
+        Some((dir, needed, free)) => Err(InsufficientSpace { dir: dir.to_path_buf(), needed, free }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn same_volume(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_volume(a: &Path, b: &Path) -> bool {
+    a == b
+}
+
+#[cfg(unix)]
+fn make_pipe(path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    // An earlier run in the same work dir may have left a file or pipe behind
+    std::fs::remove_file(path).ok();
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_pipe(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "named pipes are only supported on unix"))
+}
+
+// Hidden, so it isn't listed as a package while it's being written
+pub(crate) fn staging_dir(package: &Path, id: Uuid) -> PathBuf {
+    package.with_file_name(format!(".{}.{}", package.file_name().unwrap().to_string_lossy(), id))
+}
+
+// Replaces the package with the staged one. The old package is renamed out of the way first, so it
+// is only missing for the moment between the two renames.
+pub(crate) fn swap_in(staged: &Path, package: &Path) -> io::Result<()> {
+    let _changing = crate::package::CHANGING.lock();
+    let old = package.with_file_name(format!(".{}.old", package.file_name().unwrap().to_string_lossy()));
+    match std::fs::rename(package, &old) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    std::fs::rename(staged, package)?;
+    reaper::staged(staged);
+    std::fs::remove_dir_all(&old).or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+}
+
+struct WorkDir {
+    path: PathBuf,
+    lock: Option<File>,
+    // Only planning, so nothing is written to disk, see plan_dash_conv
+    dry_run: bool,
+}
+
+impl WorkDir {
+    // Where a session would work, without creating anything
+    fn planned(id: Uuid) -> Self {
+        WorkDir { path: WORK_DIR.join(id.to_string()), lock: None, dry_run: true }
+    }
+}
+
+// Created on first use, and removed along with everything in it once the session is done.
+// Named after the source and the options so doing the same again after a failure picks up the
+// intermediates which were finished, see keep_failed_intermediates. Stages are only skipped when
+// they'd run the same command as before, so a change to the settings since writes them again.
+// While a session is using it the directory is locked, and anything else doing the same gets a
+// directory of its own.
+fn work_dir(id: Uuid, source: &Path, overrides: &Overrides) -> io::Result<WorkDir> {
+    let mut hash = Sha256::new();
+    hash.update(commands::path_bytes(source));
+    hash.update(serde_json::to_vec(overrides).unwrap_or_default());
+    let dir = WORK_DIR.join(hex::encode(&hash.finalize()[..16]));
+    std::fs::create_dir_all(&dir)?;
+    let lock = File::create(dir.join(".lock"))
+        .and_then(|f| f.try_lock_exclusive().map(|_| f));
+    match lock {
+        Ok(lock) => Ok(WorkDir { path: dir, lock: Some(lock), dry_run: false }),
+        Err(_) => {
+            let dir = WORK_DIR.join(id.to_string());
+            std::fs::create_dir_all(&dir)?;
+            Ok(WorkDir { path: dir, lock: None, dry_run: false })
+        }
+    }
+}
+
+// Intermediates all share a plain name as the work directory is already per source, which keeps
+// exotic source names away from the tools further down the pipeline
+fn work_file(work: &Path, ending: &str) -> PathBuf {
+    work.join(format!("media{}", ending))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn validate_overrides() {
+        let encryption = json!({"key_id": "0123456789abcdef0123456789abcdef", "key": "0123456789abcdef0123456789abcdef"});
+        let cases = vec![
+            (json!({}), true),
+            (json!({"encoder": "x264"}), true),
+            (json!({"encoder": "nonsense"}), false),
+            (json!({"start": "00:01:00.000", "end": "00:02:00.000"}), true),
+            (json!({"start": "00:02:00.000", "end": "00:01:00.000"}), false),
+            (json!({"start": "soon"}), false),
+            (json!({"segment_duration": 4.0, "fragment_duration": 1.0}), true),
+            (json!({"segment_duration": 1.0, "fragment_duration": 4.0}), false),
+            (json!({"segment_duration": 0.0}), false),
+            (json!({"packager": "shaka", "encryption": encryption}), true),
+            (json!({"packager": "bento4", "encryption": encryption}), true),
+            (json!({"packager": "ffmpeg", "encryption": encryption}), false),
+            (json!({"packager": "shaka", "low_latency": true}), true),
+            (json!({"packager": "ffmpeg", "low_latency": true}), true),
+            (json!({"packager": "bento4", "low_latency": true}), false),
+            (json!({"packager": "bento4", "low_latency": false}), true),
+            (json!({"packager": "shaka", "encryption": {"key_id": "0123456789abcdef0123456789abcdef"}}), false),
+            (json!({"packager": "shaka", "clearkey": "unknown"}), false),
+            // Only one of them, even when the clearkey name would be fine on its own
+            (json!({"packager": "shaka", "encryption": encryption, "clearkey": "default"}), false),
+        ];
+        for (i, (overrides, valid)) in cases.into_iter().enumerate() {
+            let parsed: Overrides = serde_json::from_value(overrides.clone()).unwrap();
+            assert_eq!(parsed.validate().is_ok(), valid, "case {}: {}", i, overrides);
+        }
+    }
+
+    #[test]
+    fn piped_packager_waits_for_subtitles() {
+        let mut info = MediaInfo::default();
+        info.raw.streams = serde_json::from_value(json!([
+            {"index": 0, "codec_name": "h264", "codec_type": "video"},
+            {"index": 1, "codec_name": "aac", "codec_type": "audio", "channels": 2},
+            {"index": 2, "codec_name": "subrip", "codec_type": "subtitle"},
+        ])).unwrap();
+        let (stages, _, piped) = split_stages(&info, Path::new("in.mkv"), &Overrides::default(), Some("libx264"),
+                                              Path::new("work"), false, true, true);
+        let subs: Vec<_> = stages.iter().enumerate()
+            .filter(|(_, (s, _))| s.outputs().iter().any(|o| o.extension() == Some("vtt".as_ref())))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(piped, Some(subs));
+    }
+}
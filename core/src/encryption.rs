@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::settings::Packager;
+use crate::SETTINGS;
+
+// Common encryption (CENC) of a package's media, either with a key given outright or with keys a
+// Widevine key server hands out. Secrets are never serialised, as the overrides end up in the
+// package's metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct Encryption {
+    // 16 bytes each, as hex
+    pub key_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub key: Option<String>,
+    // Only Shaka Packager can fetch keys
+    pub key_server: Option<KeyServer>,
+    #[serde(default)]
+    pub scheme: Scheme,
+    // The DRM systems to signal in the manifest and init segments
+    #[serde(default)]
+    pub systems: Vec<DrmSystem>,
+    // Where PlayReady players get licenses, only used by Bento4
+    pub playready_license_url: Option<String>,
+    // Where ClearKey players get keys, only used by Bento4
+    pub clearkey_license_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct KeyServer {
+    pub url: String,
+    // Identifies the title to the key server, as hex
+    pub content_id: String,
+    pub signer: String,
+    // Left empty when read back after being serialised, which validating refuses
+    #[serde(skip_serializing, default)]
+    pub signing_key: String,
+    #[serde(skip_serializing, default)]
+    pub signing_iv: String,
+}
+
+// What serialising encryption leaves out, for keeping alongside it where it has to be put back
+// together, such as in a shared queue
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Secrets {
+    key: Option<String>,
+    signing_key: Option<String>,
+    signing_iv: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    // AES-CTR, what Widevine and PlayReady have always supported
+    Cenc,
+    // AES-CBC with pattern encryption, which FairPlay needs as well
+    Cbcs,
+}
+
+impl Default for Scheme {
+    fn default() -> Self {
+        Scheme::Cenc
+    }
+}
+
+impl Scheme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scheme::Cenc => "cenc",
+            Scheme::Cbcs => "cbcs",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DrmSystem {
+    Widevine,
+    PlayReady,
+    ClearKey,
+}
+
+impl Encryption {
+    // ClearKey encryption with the key of the given name from the settings
+    pub fn clearkey(name: &str) -> Option<Self> {
+        let pair = SETTINGS.clearkey.keys.get(name)?;
+        Some(Encryption {
+            key_id: Some(pair.key_id.to_lowercase()),
+            key: Some(pair.key.to_lowercase()),
+            systems: vec![DrmSystem::ClearKey],
+            clearkey_license_url: SETTINGS.clearkey.license_url.clone(),
+            ..Encryption::default()
+        })
+    }
+
+    pub fn secrets(&self) -> Secrets {
+        Secrets {
+            key: self.key.clone(),
+            signing_key: self.key_server.as_ref().map(|s| s.signing_key.clone()),
+            signing_iv: self.key_server.as_ref().map(|s| s.signing_iv.clone()),
+        }
+    }
+
+    // Puts back secrets taken with secrets, after the rest has been read back
+    pub fn restore(&mut self, secrets: Secrets) {
+        self.key = secrets.key;
+        if let Some(server) = &mut self.key_server {
+            server.signing_key = secrets.signing_key.unwrap_or_default();
+            server.signing_iv = secrets.signing_iv.unwrap_or_default();
+        }
+    }
+
+    // Checks the user supplied values, returning a message suitable for the client
+    pub fn validate(&self, packager: Packager) -> Result<(), String> {
+        if packager == Packager::Ffmpeg {
+            return Err("Encryption needs the bento4 or shaka packager".to_string());
+        }
+        match (&self.key_id, &self.key, &self.key_server) {
+            (Some(id), Some(key), None) => {
+                if !is_key(id) || !is_key(key) {
+                    return Err("key_id and key must be 32 hex digits".to_string());
+                }
+            }
+            (None, None, Some(server)) => {
+                if packager != Packager::Shaka {
+                    return Err("Keys can only be fetched from a key server by the shaka packager".to_string());
+                }
+                if !server.url.starts_with("https://") && !server.url.starts_with("http://") {
+                    return Err("The key server must be an http or https URL".to_string());
+                }
+                if !is_hex(&server.content_id) || !is_hex(&server.signing_key) || !is_hex(&server.signing_iv) {
+                    return Err("The key server's content_id, signing_key and signing_iv must be hex".to_string());
+                }
+            }
+            _ => return Err("Give either a key_id and key or a key_server".to_string()),
+        }
+        Ok(())
+    }
+}
+
+fn is_hex(v: &str) -> bool {
+    !v.is_empty() && v.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_key(v: &str) -> bool {
+    v.len() == 32 && is_hex(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn server(signing_key: &str) -> Option<KeyServer> {
+        Some(KeyServer {
+            url: "https://keys.example.com".to_string(),
+            content_id: "abcdef".to_string(),
+            signer: "signer".to_string(),
+            signing_key: signing_key.to_string(),
+            signing_iv: "0011".to_string(),
+        })
+    }
+
+    #[test]
+    fn validate() {
+        let key = |id: &str, key: &str| Encryption { key_id: Some(id.to_string()), key: Some(key.to_string()), ..Encryption::default() };
+        let fetched = |signing_key: &str| Encryption { key_server: server(signing_key), ..Encryption::default() };
+        let cases = vec![
+            (key(KEY, KEY), Packager::Bento4, true),
+            (key(KEY, KEY), Packager::Shaka, true),
+            (key(KEY, KEY), Packager::Ffmpeg, false),
+            (key(KEY, "0123"), Packager::Shaka, false),
+            (key("not hex, but 32 characters long!", KEY), Packager::Shaka, false),
+            (Encryption { key_id: Some(KEY.to_string()), ..Encryption::default() }, Packager::Shaka, false),
+            (Encryption::default(), Packager::Shaka, false),
+            (fetched("00ff"), Packager::Shaka, true),
+            (fetched("00ff"), Packager::Bento4, false),
+            // As read back after the signing key was left out
+            (fetched(""), Packager::Shaka, false),
+            (Encryption { key_server: server("00ff"), ..key(KEY, KEY) }, Packager::Shaka, false),
+            (Encryption { key_server: Some(KeyServer { url: "file:///keys".to_string(), ..server("00ff").unwrap() }), ..Encryption::default() }, Packager::Shaka, false),
+        ];
+        for (i, (encryption, packager, valid)) in cases.into_iter().enumerate() {
+            assert_eq!(encryption.validate(packager).is_ok(), valid, "case {}: {:?} with {:?}", i, encryption, packager);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use derive_more::{Display, Error, From};
+
+use crate::commands::SessionError;
+use crate::dash::{AlreadyProcessed, InsufficientSpace};
+use crate::queue::QueueError;
+
+// What can go wrong probing sources and setting up sessions, each reported over the API with a
+// status of its own
+#[derive(Debug, Display, Error, From)]
+pub enum ConvError {
+    #[display(fmt = "Could not read {:?}: {}", path, reason)]
+    #[from(ignore)]
+    Probe {
+        path: PathBuf,
+        reason: String,
+    },
+    #[display(fmt = "Could not run {}: {}", program, source)]
+    #[from(ignore)]
+    Spawn {
+        program: &'static str,
+        source: io::Error,
+    },
+    // stderr is whatever the program wrote before exiting, trimmed
+    #[display(fmt = "{} failed with {}: {}", program, status, stderr)]
+    #[from(ignore)]
+    Exit {
+        program: &'static str,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[display(fmt = "{}", _0)]
+    Invalid(SessionError),
+    #[display(fmt = "{}", _0)]
+    InsufficientSpace(InsufficientSpace),
+    #[display(fmt = "{}", _0)]
+    AlreadyProcessed(AlreadyProcessed),
+    #[display(fmt = "{}", _0)]
+    Queue(QueueError),
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+}
+
+// Over the API each error is answered with a status of its own
+#[cfg(feature = "actix")]
+mod http {
+    use actix_web::{HttpResponse, ResponseError};
+    use actix_web::http::StatusCode;
+    use serde::Serialize;
+
+    use super::ConvError;
+
+    #[derive(Serialize)]
+    struct ErrorBody {
+        error: String,
+    }
+
+    impl ResponseError for ConvError {
+        fn status_code(&self) -> StatusCode {
+            match self {
+                ConvError::Probe { .. } | ConvError::Invalid(_) => StatusCode::BAD_REQUEST,
+                ConvError::InsufficientSpace(_) => StatusCode::INSUFFICIENT_STORAGE,
+                ConvError::AlreadyProcessed(_) => StatusCode::CONFLICT,
+                ConvError::Queue(_) => StatusCode::SERVICE_UNAVAILABLE,
+                ConvError::Spawn { .. } | ConvError::Exit { .. } | ConvError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            HttpResponse::build(self.status_code()).json(ErrorBody { error: self.to_string() })
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// Words release names tack on after the title, nothing after one of these is part of a name
+const RELEASE_TAGS: &[&str] = &[
+    "480p", "576p", "720p", "1080p", "1080i", "2160p", "4k", "uhd", "hdr", "hdr10", "dv",
+    "bluray", "blu-ray", "bdrip", "brrip", "remux", "web", "web-dl", "webdl", "webrip", "hdtv", "dvdrip", "dvd",
+    "x264", "x265", "h264", "h265", "hevc", "avc", "xvid", "divx", "10bit",
+    "aac", "ac3", "dts", "ddp5", "dd5", "atmos", "truehd", "flac",
+    "proper", "repack", "extended", "unrated", "remastered", "internal", "limited",
+];
+
+// What a file's name says about it, in the way scene and library naming conventions lay it out:
+// "Show Name S01E02 Episode Title 1080p", "Show Name 1x02" or "Film Title (1999) 1080p"
+#[derive(Serialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct ParsedName {
+    // The film's title, or the episode's when there is one
+    pub title: Option<String>,
+    pub show: Option<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    pub year: Option<u32>,
+}
+
+pub fn parse(stem: &str) -> ParsedName {
+    let tokens: Vec<String> = stem.split(|c: char| c.is_whitespace() || c == '.' || c == '_')
+        .map(|t| t.trim_matches(|c| "()[]{}".contains(c)).to_string())
+        .filter(|t| !t.is_empty() && t != "-")
+        .collect();
+    let end = tokens.iter()
+        .position(|t| RELEASE_TAGS.contains(&t.to_lowercase().as_str()))
+        .unwrap_or_else(|| tokens.len());
+    let tokens = &tokens[..end];
+
+    let mut parsed = ParsedName::default();
+    match tokens.iter().enumerate().find_map(|(i, t)| episode_marker(t).map(|m| (i, m))) {
+        Some((i, (season, episode))) => {
+            parsed.season = Some(season);
+            parsed.episode = Some(episode);
+            let (show, show_year) = split_year(&tokens[..i]);
+            parsed.show = join(show);
+            parsed.year = show_year;
+            parsed.title = join(&tokens[i + 1..]);
+        }
+        None => {
+            let (title, year) = split_year(tokens);
+            parsed.title = join(title);
+            parsed.year = year;
+        }
+    }
+    parsed
+}
+
+// S01E02 or 1x02
+fn episode_marker(token: &str) -> Option<(u32, u32)> {
+    let lower = token.to_lowercase();
+    let (season, episode) = match lower.strip_prefix('s') {
+        Some(rest) => rest.split_once('e')?,
+        None => lower.split_once('x')?,
+    };
+    let is_number = |n: &str| !n.is_empty() && n.len() <= 3 && n.chars().all(|c| c.is_ascii_digit());
+    if !is_number(season) || !is_number(episode) {
+        return None;
+    }
+    Some((season.parse().ok()?, episode.parse().ok()?))
+}
+
+// Splits off the last year in the name, a title which is only a year like "1917" is left alone
+fn split_year(tokens: &[String]) -> (&[String], Option<u32>) {
+    let year = tokens.iter().enumerate().skip(1).rev()
+        .find_map(|(i, t)| t.parse::<u32>().ok().filter(|y| t.len() == 4 && (1900..2100).contains(y)).map(|y| (i, y)));
+    match year {
+        Some((i, y)) => (&tokens[..i], Some(y)),
+        None => (tokens, None),
+    }
+}
+
+fn join(tokens: &[String]) -> Option<String> {
+    let joined = tokens.join(" ");
+    let joined = joined.trim_matches(|c: char| c == '-' || c.is_whitespace());
+    (!joined.is_empty()).then(|| joined.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filename::{parse, ParsedName};
+
+    #[test]
+    fn episodes() {
+        assert_eq!(parse("The.Show.S01E02.Pilot.Part.Two.1080p.WEB-DL.x264"), ParsedName {
+            title: Some("Pilot Part Two".to_string()),
+            show: Some("The Show".to_string()),
+            season: Some(1),
+            episode: Some(2),
+            year: None,
+        });
+        assert_eq!(parse("Another Show (2019) - 3x10"), ParsedName {
+            title: None,
+            show: Some("Another Show".to_string()),
+            season: Some(3),
+            episode: Some(10),
+            year: Some(2019),
+        });
+    }
+
+    #[test]
+    fn films() {
+        assert_eq!(parse("2001 A Space Odyssey (1968) [2160p]"), ParsedName {
+            title: Some("2001 A Space Odyssey".to_string()),
+            year: Some(1968),
+            ..ParsedName::default()
+        });
+        assert_eq!(parse("1917"), ParsedName {
+            title: Some("1917".to_string()),
+            ..ParsedName::default()
+        });
+    }
+}
@@ -0,0 +1,46 @@
+#![allow(unused_must_use)]
+#![feature(bool_to_option)]
+
+// Probing media, putting together the commands which package it and running them as sessions,
+// without anything to do with serving them over HTTP. The server and the CLI are both built on this.
+
+#[macro_use]
+extern crate lazy_static;
+
+use std::path::Path;
+
+use crate::settings::Settings;
+
+pub mod commands;
+pub mod settings;
+pub mod sessions;
+pub mod dash;
+pub mod mp4;
+pub mod audio;
+pub mod queue;
+pub mod runtime;
+pub mod vtt;
+pub mod metrics;
+pub mod retention;
+pub mod reaper;
+pub mod probe_cache;
+pub mod library;
+pub mod package;
+pub mod filename;
+pub mod encryption;
+pub mod error;
+
+lazy_static! {
+    pub static ref SETTINGS: Settings = Settings::new().unwrap();
+    pub static ref UNPROCESSED_DIRS: Vec<&'static Path> = SETTINGS.dirs.unprocessed.iter().map(|d| d.as_path()).collect();
+    // Where uploads and downloads are saved
+    pub static ref UNPROCESSED_DIR: &'static Path = UNPROCESSED_DIRS.first().expect("dirs.unprocessed needs at least one directory");
+    pub static ref PROCESSED_DIR: &'static Path = Path::new(&(*SETTINGS).dirs.processed);
+    pub static ref PREVIEW_DIR: &'static Path = Path::new(&(*SETTINGS).dirs.preview);
+    // Instances sharing a queue may share storage too, so each keeps to a work directory of its own
+    pub static ref WORK_DIR: &'static Path = match SETTINGS.queue.instance() {
+        Some(instance) => Box::leak(SETTINGS.dirs.work.join(instance).into_boxed_path()),
+        None => Path::new(&(*SETTINGS).dirs.work),
+    };
+    pub static ref LOG_DIR: &'static Path = Path::new(&(*SETTINGS).dirs.logs);
+}
@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use derive_more::{Display, Error};
+use futures::{stream, StreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, info};
+
+use crate::{probe_cache, SETTINGS, UNPROCESSED_DIRS};
+use crate::commands::{self, MediaInfo};
+use crate::error::ConvError;
+use crate::settings::Globs;
+
+const PROBE_CONCURRENCY: usize = 8;
+
+
+// An index of the media in every unprocessed directory, kept up to date by watching them so listings
+// never have to probe. Probes are persisted by the probe cache, so rebuilding the index at startup
+// only probes files which are new or have changed.
+pub struct Library {
+    media: RwLock<HashMap<PathBuf, MediaInfo>>,
+    // Files still being written, indexed once they settle
+    pending: Mutex<HashSet<PathBuf>>,
+    // Files newly indexed or changed since they were
+    added: broadcast::Sender<PathBuf>,
+    filter: Filter,
+}
+
+// Byte identical copies share a fingerprint, so its id can't say which is meant
+#[derive(Debug, Display, Error)]
+#[display(fmt = "{} is the id of several identical files, use one of their path based ids instead: {}", id, "ids.join(\", \")")]
+pub struct Ambiguous {
+    #[error(not(source))]
+    id: String,
+    #[error(not(source))]
+    ids: Vec<String>,
+}
+
+enum Change {
+    Fs(DebouncedEvent),
+    Recheck,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        let (added, _) = broadcast::channel(1024);
+        Library {
+            media: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            added,
+            // Bad globs are reported and ignored rather than stopping the scan
+            filter: Filter::new(&SETTINGS.scan.globs).unwrap_or_else(|e| {
+                error!("Ignoring scan.include and scan.exclude: {}", e);
+                Filter::default()
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PathBuf> {
+        self.added.subscribe()
+    }
+
+    pub fn media(&self) -> Vec<MediaInfo> {
+        self.media.read().unwrap().values().cloned().collect()
+    }
+
+    // Path based ids from before media were fingerprinted are still recognised
+    pub fn get(&self, id: &str) -> Result<Option<MediaInfo>, Ambiguous> {
+        let media = self.media.read().unwrap();
+        if let Some(m) = media.iter().find(|(p, _)| commands::media_id(p) == id).map(|(_, m)| m) {
+            return Ok(Some(m.clone()));
+        }
+        let mut found: Vec<_> = media.values().filter(|m| m.id == id).collect();
+        if found.len() > 1 {
+            found.sort_by(|a, b| a.path.cmp(&b.path));
+            let ids = found.iter().map(|m| commands::media_id(&m.path)).collect();
+            return Err(Ambiguous { id: id.to_string(), ids });
+        }
+        Ok(found.pop().cloned())
+    }
+
+    // Where the media with the given id is now, wherever it has been moved to
+    pub fn path_of(&self, id: &str) -> Result<Option<PathBuf>, Ambiguous> {
+        Ok(self.get(id)?.map(|m| m.path))
+    }
+
+    // Probes the file again rather than using the probe cache, updating the index if it's in it
+    pub async fn refresh(&self, path: &Path) -> Result<MediaInfo, ConvError> {
+        probe_cache::refresh(path).await?;
+        let info = MediaInfo::get(path).await?;
+        if let Some(m) = self.media.write().unwrap().get_mut(path) {
+            *m = info.clone();
+        }
+        Ok(info)
+    }
+
+    async fn add(&self, path: PathBuf) {
+        if !self.is_candidate(&path) {
+            return;
+        }
+        if !is_stable(&path) {
+            debug!("Waiting for {:?} to settle", path);
+            self.media.write().unwrap().remove(&path);
+            self.pending.lock().unwrap().insert(path);
+            return;
+        }
+        self.pending.lock().unwrap().remove(&path);
+        match MediaInfo::get(&path).await {
+            Ok(info) => {
+                debug!("Indexed {:?}", path);
+                self.media.write().unwrap().insert(path.clone(), info);
+                self.added.send(path).ok();
+            }
+            Err(e) => error!("Error getting media for {:?}: {}", path, e),
+        }
+    }
+
+    async fn add_all(&self, files: Vec<PathBuf>) {
+        stream::iter(files)
+            .for_each_concurrent(PROBE_CONCURRENCY, |f| self.add(f))
+            .await;
+    }
+
+    fn remove(&self, path: &Path) {
+        // Removing a directory removes everything that was in it
+        self.media.write().unwrap().retain(|p, _| !p.starts_with(path));
+        self.pending.lock().unwrap().retain(|p| !p.starts_with(path));
+    }
+
+    async fn rescan(&self) {
+        let files: Vec<_> = UNPROCESSED_DIRS.iter()
+            .flat_map(|d| walkdir::WalkDir::new(d).into_iter())
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path())
+            .filter(|p| self.is_candidate(p))
+            .collect();
+        info!("Scanning {} files in {:?}", files.len(), *UNPROCESSED_DIRS);
+
+        let present: HashSet<_> = files.iter().collect();
+        self.media.write().unwrap().retain(|p, _| present.contains(p));
+        self.add_all(files).await;
+    }
+
+    // Only files with a media extension which the scan globs allow are probed. Hidden files are
+    // uploads and downloads still in progress, as are files with the temporary extensions download
+    // clients use.
+    fn is_candidate(&self, path: &Path) -> bool {
+        let has_extension = |list: &[String]| path.extension()
+            .map_or(false, |e| list.iter().any(|l| e.to_string_lossy().eq_ignore_ascii_case(l)));
+        path.is_file()
+            && !path.file_name().map_or(true, |n| n.to_string_lossy().starts_with('.'))
+            && !has_extension(&SETTINGS.scan.temp_extensions)
+            && (SETTINGS.scan.extensions.is_empty() || has_extension(&SETTINGS.scan.extensions))
+            && self.filter.matches(path)
+    }
+
+    async fn recheck(&self) {
+        let pending: Vec<_> = self.pending.lock().unwrap().iter().cloned().collect();
+        if !pending.is_empty() {
+            self.add_all(pending).await;
+        }
+    }
+}
+
+// The unprocessed directory a path is in
+pub(crate) fn root_of(path: &Path) -> Option<&'static Path> {
+    UNPROCESSED_DIRS.iter().copied().find(|d| path.starts_with(d))
+}
+
+// A path relative to the unprocessed directory it's in, which is what globs are matched against
+pub fn relative_path(path: &Path) -> &Path {
+    root_of(path).and_then(|d| path.strip_prefix(d).ok()).unwrap_or(path)
+}
+
+// Which files include and exclude globs let through
+#[derive(Default)]
+pub struct Filter {
+    // None when there are no include globs, so everything is
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    pub fn new(globs: &Globs) -> Result<Self, globset::Error> {
+        Ok(Filter {
+            include: Some(&globs.include).filter(|i| !i.is_empty()).map(|i| glob_set(i)).transpose()?,
+            exclude: glob_set(&globs.exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let relative = relative_path(path);
+        self.include.as_ref().map_or(true, |i| i.is_match(relative)) && !self.exclude.is_match(relative)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    patterns.iter()
+        .try_fold(GlobSetBuilder::new(), |mut b, p| {
+            b.add(Glob::new(p)?);
+            Ok(b)
+        })?
+        .build()
+}
+
+// A file is stable once it hasn't been modified for a while. Uploads and fetches are written under a
+// hidden name and renamed once complete, so they're never seen half written.
+fn is_stable(path: &Path) -> bool {
+    path.metadata()
+        .and_then(|m| m.modified())
+        .map(|m| SystemTime::now().duration_since(m).unwrap_or_default() >= Duration::from_secs(SETTINGS.scan.stable_seconds))
+        .unwrap_or(false)
+}
+
+// Builds the index then follows changes to the directory for as long as the server runs
+pub async fn run(library: Arc<Library>) {
+    library.rescan().await;
+    save_probes().await;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    // The watcher reports on a std channel, so a thread forwards its events to us. Events are
+    // debounced so a file being copied in is only picked up once the copy pauses.
+    std::thread::spawn(move || {
+        let (watch_tx, watch_rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::watcher(watch_tx, Duration::from_secs(2)) {
+            Ok(w) => w,
+            Err(e) => return error!("Could not watch {:?}, new files won't be noticed: {}", *UNPROCESSED_DIRS, e),
+        };
+        for dir in UNPROCESSED_DIRS.iter() {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
+                error!("Could not watch {:?}, new files won't be noticed: {}", dir, e);
+            }
+        }
+        for event in watch_rx {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+    });
+
+    // Files still being written are looked at again periodically, as one which has stopped changing
+    // produces no more events
+    let recheck = tokio::time::interval(Duration::from_secs(SETTINGS.scan.stable_seconds.max(1)))
+        .map(|_| Change::Recheck);
+    let mut changes = stream::select(rx.map(Change::Fs), recheck);
+    while let Some(change) = changes.next().await {
+        let event = match change {
+            Change::Fs(e) => e,
+            Change::Recheck => {
+                library.recheck().await;
+                continue;
+            }
+        };
+        debug!(?event, "Library changed");
+        match event {
+            DebouncedEvent::Create(p) | DebouncedEvent::Write(p) => library.add_all(vec![p]).await,
+            DebouncedEvent::Remove(p) => library.remove(&p),
+            DebouncedEvent::Rename(from, to) => {
+                library.remove(&from);
+                // Everything inside a directory moved in is new to us
+                let files = walkdir::WalkDir::new(&to).into_iter()
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.into_path())
+                    .collect();
+                library.add_all(files).await;
+            }
+            // Events were missed, so the index can't be trusted
+            DebouncedEvent::Rescan => library.rescan().await,
+            DebouncedEvent::Error(e, p) => error!("Error watching {:?}: {}", p, e),
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Chmod(_) => (),
+        }
+        save_probes().await;
+    }
+}
+
+async fn save_probes() {
+    tokio::task::spawn_blocking(probe_cache::save).await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambiguous_ids() {
+        let library = Library::new();
+        for path in &["/a/film.mkv", "/b/film.mkv"] {
+            let info = MediaInfo { id: "same".to_string(), path: PathBuf::from(path), ..MediaInfo::default() };
+            library.media.write().unwrap().insert(PathBuf::from(path), info);
+        }
+
+        assert!(library.get("same").is_err());
+        let by_path = commands::media_id(Path::new("/b/film.mkv"));
+        assert_eq!(library.path_of(&by_path).unwrap(), Some(PathBuf::from("/b/film.mkv")));
+        assert!(library.get("other").unwrap().is_none());
+    }
+}
@@ -0,0 +1,20 @@
+use prometheus::{Histogram, IntCounter, IntGauge};
+
+lazy_static! {
+    pub static ref SESSIONS_STARTED: IntCounter = prometheus::register_int_counter!(
+        "streamin_sessions_started_total", "Sessions started").unwrap();
+    pub static ref SESSIONS_COMPLETED: IntCounter = prometheus::register_int_counter!(
+        "streamin_sessions_completed_total", "Sessions which finished successfully").unwrap();
+    pub static ref SESSIONS_FAILED: IntCounter = prometheus::register_int_counter!(
+        "streamin_sessions_failed_total", "Sessions which failed").unwrap();
+    pub static ref SESSIONS_ACTIVE: IntGauge = prometheus::register_int_gauge!(
+        "streamin_sessions_active", "Sessions currently running").unwrap();
+    pub static ref SESSIONS_QUEUED: IntGauge = prometheus::register_int_gauge!(
+        "streamin_sessions_queued", "Sessions waiting to start").unwrap();
+    pub static ref SESSION_DURATION: Histogram = prometheus::register_histogram!(
+        "streamin_session_duration_seconds", "Wall clock time taken by successful sessions",
+        prometheus::exponential_buckets(30.0, 2.0, 12).unwrap()).unwrap();
+    pub static ref ENCODE_FPS: Histogram = prometheus::register_histogram!(
+        "streamin_encode_fps", "Average frames per second of finished ffmpeg stages",
+        prometheus::exponential_buckets(1.0, 2.0, 12).unwrap()).unwrap();
+}
@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::commands::{ffmpeg, MediaInfo, Session};
+use crate::commands::ffmpeg::{AAC, X264, X264_NVENC};
+use crate::dash::{self, AlreadyProcessed, Overrides};
+use crate::error::ConvError;
+use crate::queue::Request;
+use crate::sessions::Sessions;
+use crate::{PREVIEW_DIR, SETTINGS};
+
+// Where the MP4 for a source will be written, named like its package would be
+pub fn mp4_path(info: &MediaInfo, overrides: &Overrides) -> PathBuf {
+    let base = if overrides.preview_seconds.is_some() { *PREVIEW_DIR } else { SETTINGS.dirs.mp4.as_path() };
+    base.join(dash::package_name(info) + ".mp4")
+}
+
+// Checks the overrides which make sense for a single MP4, returning a message suitable for the client
+pub fn validate(overrides: &Overrides) -> Result<(), String> {
+    overrides.validate()?;
+    if let Some(e) = &overrides.encoder {
+        match ffmpeg::video_encoder_from_name(e) {
+            Some(X264) | Some(X264_NVENC) => (),
+            _ => return Err(format!("{} doesn't encode H.264, which plain MP4s are kept to", e)),
+        }
+    }
+    if overrides.split_chapters.unwrap_or(false) {
+        return Err("Chapters can only be split when packaging".to_string());
+    }
+    if overrides.encryption.is_some() || overrides.clearkey.is_some() {
+        return Err("Only packages can be encrypted".to_string());
+    }
+    Ok(())
+}
+
+// Writes the source out as a single progressive MP4 of H.264 video and AAC audio, for downloading
+// and for players which can't play DASH. Streams already in those codecs are copied. The file is
+// written under a hidden name and renamed into place once finished, so a forced redo leaves the
+// old one in place until then.
+pub async fn exec_mp4_conv(state: Arc<Sessions>, file: PathBuf, overrides: &Overrides, owner: Option<String>) -> Result<String, ConvError> {
+    let session = mp4_session(&state, Uuid::new_v4(), file.clone(), overrides, owner).await?;
+    dash::launch(&state, session, Request::Mp4 { file, overrides: overrides.clone() }).await
+}
+
+pub(crate) async fn mp4_session(state: &Arc<Sessions>, id: Uuid, file: PathBuf, overrides: &Overrides,
+                                owner: Option<String>) -> Result<Session, ConvError> {
+    let mut info = MediaInfo::get(&file).await?;
+    let out = mp4_path(&info, overrides);
+    if out.exists() && overrides.preview_seconds.is_none() && !overrides.force.unwrap_or(false) {
+        return Err(AlreadyProcessed { name: out.file_name().unwrap().to_string_lossy().into_owned() }.into());
+    }
+    std::fs::create_dir_all(out.parent().unwrap())?;
+
+    let staged = out.with_file_name(format!(".{}.{}.mp4", out.file_stem().unwrap().to_string_lossy(), id));
+    let mut c = ffmpeg::Config::new(file);
+    c.out(staged.clone())
+        .faststart()
+        .subtitle_disabled();
+    if let Some(s) = overrides.start() {
+        c.start(s);
+    }
+    if let Some(e) = overrides.end() {
+        c.end(e);
+    }
+    if let Some(p) = overrides.preview_seconds {
+        c.duration(Duration::from_secs(p));
+    }
+
+    let video = info.raw.streams.iter().find(|s| s.codec_type == "video" && !s.is_attached_pic());
+    match video {
+        Some(v) => {
+            c.tracks(Some(v.index));
+            if info.dash_transcode_required() || overrides.video_set() {
+                let encoder = overrides.encoder.as_deref()
+                    .and_then(ffmpeg::video_encoder_from_name)
+                    .unwrap_or(X264);
+                c.video_encoder(encoder)
+                    .colour_8_bit();
+                match (overrides.crf, overrides.video_bitrate) {
+                    (Some(crf), _) => { c.crf(crf); }
+                    (None, None) => { c.crf(19); }
+                    (None, Some(_)) => (),
+                }
+                if let Some(b) = overrides.video_bitrate {
+                    c.video_bitrate(b);
+                }
+                if let Some(h) = overrides.max_height {
+                    c.max_height(h);
+                }
+            }
+        }
+        None => { c.video_disabled(); }
+    }
+
+    let audio = dash::wanted_audio_streams(&info.raw.streams, &SETTINGS.audio_languages);
+    if audio.is_empty() {
+        c.audio_disabled();
+    } else {
+        c.tracks(audio.iter().map(|s| s.index));
+        // Copying is all or nothing, as the codec is chosen for every stream at once
+        if overrides.audio_bitrate.is_some() || audio.iter().any(|s| s.codec_name != "aac") {
+            c.audio_encoder(AAC)
+                .audio_channels(2)
+                .audio_bitrate(overrides.audio_bitrate.unwrap_or(256_000));
+        }
+    }
+
+    info.duration = overrides.output_duration(info.duration);
+    let mut session = Session::new(id, Box::new(c), Arc::new(RwLock::new(info)));
+    session.output(out.clone())
+        .on_success(move || std::fs::rename(&staged, &out))
+        .owner(owner)
+        .events(state.events.clone());
+    Ok(session)
+}
+
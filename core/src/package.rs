@@ -0,0 +1,251 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::commands::ffprobe::Stream;
+use crate::commands::{mp4dash, Tool};
+use crate::dash::Overrides;
+use crate::{retention, SETTINGS};
+
+pub const METADATA: &str = "metadata.json";
+
+lazy_static! {
+    // Held while a package is swapped for a new one or removed, so neither happens partway through
+    // the other
+    pub(crate) static ref CHANGING: Mutex<()> = Mutex::new(());
+    static ref TOOLS: Tools = Tools {
+        ffmpeg: tool_version(Tool::Ffmpeg, "-version"),
+        mp4dash: tool_version(Tool::Mp4dash, "--version"),
+        packager: tool_version(Tool::Packager, "--version"),
+    };
+}
+
+// A record of how a package was made, written into it once everything else has succeeded
+#[derive(Serialize, Debug)]
+pub struct Metadata {
+    source: PathBuf,
+    // None when the video was copied rather than encoded
+    video_encoder: Option<&'static str>,
+    overrides: Overrides,
+    streams: Vec<Stream>,
+    // Seconds since the epoch
+    started: u64,
+    finished: u64,
+    processing_seconds: u64,
+    tools: &'static Tools,
+    // What the manifest was called, so the package is still found after manifest_name changes
+    manifest: String,
+}
+
+// The part of the metadata needed to find the manifest again
+#[derive(Deserialize)]
+struct Recorded {
+    manifest: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Tools {
+    ffmpeg: Option<String>,
+    mp4dash: Option<String>,
+    packager: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(source: PathBuf, video_encoder: Option<&'static str>, overrides: Overrides, streams: Vec<Stream>) -> Self {
+        Metadata {
+            source,
+            video_encoder,
+            overrides,
+            streams,
+            started: epoch_secs(SystemTime::now()),
+            finished: 0,
+            processing_seconds: 0,
+            tools: &TOOLS,
+            manifest: SETTINGS.manifest_name.clone(),
+        }
+    }
+
+    pub fn write(mut self, dir: &Path) -> io::Result<()> {
+        self.finished = epoch_secs(SystemTime::now());
+        self.processing_seconds = self.finished.saturating_sub(self.started);
+        std::fs::write(dir.join(METADATA), serde_json::to_vec_pretty(&self)?)
+    }
+}
+
+// The first line the tool prints about itself, e.g. "ffmpeg version 4.3.1 Copyright ..."
+fn tool_version(tool: Tool, arg: &str) -> Option<String> {
+    let out = Command::new(tool.path()).arg(arg).output().ok()?;
+    String::from_utf8_lossy(&out.stdout).lines()
+        .chain(String::from_utf8_lossy(&out.stderr).lines())
+        .map(|l| l.trim().to_string())
+        .find(|l| !l.is_empty())
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// The package's manifest, as named in its metadata. Packages being written, or made before the name
+// was recorded, have whichever .mpd is in them, the one named by manifest_name first.
+pub fn manifest(dir: &Path) -> Option<PathBuf> {
+    let recorded = std::fs::read(dir.join(METADATA)).ok()
+        .and_then(|bytes| serde_json::from_slice::<Recorded>(&bytes).ok())
+        .and_then(|r| r.manifest);
+    if let Some(name) = recorded {
+        return Some(dir.join(name));
+    }
+    let current = dir.join(&SETTINGS.manifest_name);
+    if current.is_file() {
+        return Some(current);
+    }
+    std::fs::read_dir(dir).ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.is_file() && p.extension().map_or(false, |e| e == "mpd"))
+}
+
+// Every package under dir, relative to it. Packages may be grouped into directories of their own by
+// the package template, so a directory is taken to be a package when it has a manifest. Empty ones
+// are left out, they're sessions which haven't written anything yet. Hidden directories are packages
+// being staged.
+pub fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut packages = vec![];
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let children: Vec<_> = std::fs::read_dir(dir.join(&relative))?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir() && !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| relative.join(e.file_name()))
+            .collect();
+        if !relative.as_os_str().is_empty() && is_package(&dir.join(&relative)) {
+            packages.push(relative);
+        } else {
+            pending.extend(children);
+        }
+    }
+    Ok(packages)
+}
+
+pub fn is_package(dir: &Path) -> bool {
+    manifest(dir).is_some()
+}
+
+// Removes the package with the given name from under dir, along with any directories it was grouped
+// into which are left empty
+pub fn remove(dir: &Path, name: &Path) -> io::Result<()> {
+    std::fs::remove_dir_all(dir.join(name))?;
+    for parent in name.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+        // Fails once a directory still has something in it
+        if std::fs::remove_dir(dir.join(parent)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// What a package contains, read back from its manifest
+#[derive(Serialize, Debug, Default, ToSchema)]
+pub struct PackageInfo {
+    // The manifest's file name, which is manifest_name unless that has changed since
+    manifest: String,
+    #[schema(value_type = Option<Object>)]
+    duration: Option<Duration>,
+    renditions: Vec<Rendition>,
+    audio_languages: Vec<String>,
+    subtitle_languages: Vec<String>,
+    // Whether there's an HLS playlist alongside the manifest
+    hls: bool,
+    // Bytes, including the poster and thumbnails
+    size: u64,
+    // Seconds since the epoch
+    created: Option<u64>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Rendition {
+    kind: String,
+    codecs: Option<String>,
+    bandwidth: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    lang: Option<String>,
+}
+
+// None when the package has no readable manifest
+pub fn read(dir: &Path) -> Option<PackageInfo> {
+    let manifest = manifest(dir)?;
+    let xml = std::fs::read_to_string(&manifest).ok()?;
+    let doc = roxmltree::Document::parse(&xml).ok()?;
+
+    let mut info = PackageInfo {
+        manifest: manifest.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        duration: doc.root_element().attribute("mediaPresentationDuration").and_then(parse_duration),
+        hls: dir.join(mp4dash::HLS_PLAYLIST).exists(),
+        size: retention::dir_size(dir),
+        created: manifest.metadata().ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+        ..PackageInfo::default()
+    };
+
+    for set in doc.descendants().filter(|n| n.has_tag_name("AdaptationSet")) {
+        let mime = set.attribute("mimeType");
+        let lang = set.attribute("lang").map(|l| l.to_string());
+        for rep in set.children().filter(|n| n.has_tag_name("Representation")) {
+            // Representations may override what their set says
+            let mime = rep.attribute("mimeType").or(mime).unwrap_or_default();
+            let kind = set.attribute("contentType")
+                .unwrap_or_else(|| mime.split('/').next().unwrap_or_default());
+            let kind = if kind == "text" || mime.contains("ttml") { "subtitle" } else { kind };
+            let languages = match kind {
+                "audio" => Some(&mut info.audio_languages),
+                "subtitle" => Some(&mut info.subtitle_languages),
+                _ => None,
+            };
+            if let (Some(languages), Some(l)) = (languages, &lang) {
+                if !languages.contains(l) {
+                    languages.push(l.clone());
+                }
+            }
+            info.renditions.push(Rendition {
+                kind: kind.to_string(),
+                codecs: rep.attribute("codecs").or_else(|| set.attribute("codecs")).map(|c| c.to_string()),
+                bandwidth: rep.attribute("bandwidth").and_then(|b| b.parse().ok()),
+                width: rep.attribute("width").and_then(|w| w.parse().ok()),
+                height: rep.attribute("height").and_then(|h| h.parse().ok()),
+                lang: lang.clone(),
+            });
+        }
+    }
+    Some(info)
+}
+
+// Parses the ISO 8601 durations manifests use, e.g. PT1H2M3.40S
+fn parse_duration(d: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    let mut time = false;
+    let mut num = String::new();
+    for c in d.strip_prefix('P')?.chars() {
+        match c {
+            'T' => time = true,
+            '0'..='9' | '.' => num.push(c),
+            unit => {
+                let v: f64 = std::mem::take(&mut num).parse().ok()?;
+                secs += v * match (unit, time) {
+                    ('D', false) => 86400.0,
+                    ('H', true) => 3600.0,
+                    ('M', true) => 60.0,
+                    ('S', true) => 1.0,
+                    _ => return None,
+                };
+            }
+        }
+    }
+    Some(Duration::from_secs_f64(secs))
+}
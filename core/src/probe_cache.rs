@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::commands::{self, ffprobe};
+use crate::commands::ffprobe::FFProbeResponse;
+use crate::error::ConvError;
+use crate::SETTINGS;
+
+// ffprobe results for local files, keyed by media id. An entry is only used while the file's size and
+// modification time are unchanged.
+#[derive(Serialize, Deserialize, Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+    size: u64,
+    modified: Duration,
+    probe: FFProbeResponse,
+    #[serde(default)]
+    fingerprint: Option<String>,
+}
+
+lazy_static! {
+    static ref CACHE: RwLock<Cache> = RwLock::new(load());
+}
+
+fn load() -> Cache {
+    match std::fs::read(&SETTINGS.probe_cache) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            error!("Ignoring unreadable probe cache: {}", e);
+            Cache::default()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Cache::default(),
+        Err(e) => {
+            error!("Could not read the probe cache: {}", e);
+            Cache::default()
+        }
+    }
+}
+
+// Writes the cache out if anything has changed since it was last saved
+pub fn save() {
+    let mut cache = CACHE.write().unwrap();
+    if !cache.dirty {
+        return;
+    }
+    let res = serde_json::to_vec(&*cache).map_err(io::Error::from).and_then(|bytes| {
+        // Written alongside and renamed over so a crash can't leave half a cache
+        let tmp = SETTINGS.probe_cache.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, &SETTINGS.probe_cache)
+    });
+    match res {
+        Ok(()) => cache.dirty = false,
+        Err(e) => error!("Could not save the probe cache: {}", e),
+    }
+}
+
+fn stamp(file: &Path) -> Option<(u64, Duration)> {
+    file.metadata().ok()
+        .and_then(|m| Some((m.len(), m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?)))
+}
+
+// Probes the file, or returns the cached result when the file hasn't changed. Anything which isn't a
+// local file, such as a URL, is always probed.
+pub async fn probe(file: &Path) -> Result<FFProbeResponse, ConvError> {
+    let key = commands::media_id(file);
+    let (size, modified) = match stamp(file) {
+        Some(s) => s,
+        None => return ffprobe::get_info(file).await,
+    };
+
+    if let Some(e) = CACHE.read().unwrap().entries.get(&key) {
+        if e.size == size && e.modified == modified {
+            return Ok(e.probe.clone());
+        }
+    }
+
+    let probe = ffprobe::get_info(file).await?;
+    let mut cache = CACHE.write().unwrap();
+    cache.entries.insert(key, Entry { size, modified, probe: probe.clone(), fingerprint: None });
+    cache.dirty = true;
+    Ok(probe)
+}
+
+// Probes the file whether or not it has changed, replacing what was cached
+pub async fn refresh(file: &Path) -> Result<FFProbeResponse, ConvError> {
+    CACHE.write().unwrap().entries.remove(&commands::media_id(file));
+    probe(file).await
+}
+
+// The file's fingerprint, kept alongside its probe so files don't have to be read on every startup
+pub fn fingerprint(file: &Path) -> io::Result<String> {
+    let key = commands::media_id(file);
+    let stamp = stamp(file);
+    if let Some(e) = CACHE.read().unwrap().entries.get(&key) {
+        if Some((e.size, e.modified)) == stamp {
+            if let Some(f) = &e.fingerprint {
+                return Ok(f.clone());
+            }
+        }
+    }
+
+    let fingerprint = commands::fingerprint(file)?;
+    let mut cache = CACHE.write().unwrap();
+    if let Some(e) = cache.entries.get_mut(&key).filter(|e| Some((e.size, e.modified)) == stamp) {
+        e.fingerprint = Some(fingerprint.clone());
+        cache.dirty = true;
+    }
+    Ok(fingerprint)
+}
+
+// Forgets the probe cached under key, the path based media id, or every probe when not given.
+// Written out straight away.
+pub fn forget(key: Option<&str>) {
+    {
+        let mut cache = CACHE.write().unwrap();
+        match key {
+            Some(key) => {
+                cache.entries.remove(key);
+            }
+            None => {
+                info!("Clearing {} cached probes", cache.entries.len());
+                cache.entries.clear();
+            }
+        }
+        cache.dirty = true;
+    }
+    save();
+}
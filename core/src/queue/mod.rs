@@ -0,0 +1,599 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use derive_more::{Display, Error};
+use futures::StreamExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::audio::{self, AudioFormat};
+use crate::commands::{self, Session, SessionEvent};
+use crate::commands::remote::Job;
+use crate::dash::{self, Overrides};
+use crate::encryption::Secrets;
+use crate::error::ConvError;
+use crate::mp4;
+use crate::sessions::Sessions;
+use crate::settings::QueueBackend;
+use crate::{metrics, runtime, SETTINGS};
+
+#[cfg(feature = "redis-queue")]
+mod redis;
+
+// What a queued session was asked for, enough for any instance sharing the queue to put the session
+// together again and run it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Request {
+    // Joined first when there are several files. Chapters and the files of a directory are queued
+    // separately under their own names, and without post processing for chapters.
+    Dash { files: Vec<PathBuf>, overrides: Overrides, name: Option<String>, post_process: bool },
+    Mp4 { file: PathBuf, overrides: Overrides },
+    Audio { file: PathBuf, format: AudioFormat, tracks: Option<Vec<isize>>, overrides: Overrides },
+}
+
+impl Request {
+    fn overrides_mut(&mut self) -> &mut Overrides {
+        match self {
+            Request::Dash { overrides, .. } | Request::Mp4 { overrides, .. } | Request::Audio { overrides, .. } => overrides,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub id: Uuid,
+    pub owner: Option<String>,
+    pub request: Request,
+    // The request's encryption keys, which serialising its overrides leaves out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secrets: Option<Secrets>,
+}
+
+impl Entry {
+    pub fn new(id: Uuid, owner: Option<String>, mut request: Request) -> Self {
+        let secrets = request.overrides_mut().encryption.as_ref().map(|e| e.secrets());
+        Entry { id, owner, request, secrets }
+    }
+
+    // The request as it was made, keys and all
+    pub fn request(self) -> Request {
+        let mut request = self.request;
+        if let (Some(e), Some(secrets)) = (request.overrides_mut().encryption.as_mut(), self.secrets) {
+            e.restore(secrets);
+        }
+        request
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "The queue can't be used: {}", reason)]
+pub struct QueueError {
+    reason: String,
+}
+
+// Where queued sessions wait, see SETTINGS.queue. Entries are taken oldest first, and stay held for
+// the instance which took them until it's done with the session, so a backend kept outside the
+// process can put back what an instance had taken when it stopped part way, see recover.
+pub trait Backend: Send + Sync {
+    fn push(&self, entry: &Entry) -> Result<(), QueueError>;
+    fn take(&self) -> Result<Option<Entry>, QueueError>;
+    // Takes out an entry which is still waiting, giving whether it was
+    fn withdraw(&self, id: Uuid) -> Result<bool, QueueError>;
+    // The instance has finished with a session it took
+    fn done(&self, id: Uuid) -> Result<(), QueueError>;
+    // The ids of the sessions waiting, oldest first
+    fn waiting(&self) -> Result<Vec<Uuid>, QueueError>;
+    // How many sessions are waiting
+    fn count(&self) -> Result<usize, QueueError>;
+    // Puts what this instance had taken back at the front, giving how many there were
+    fn recover(&self) -> Result<usize, QueueError>;
+    // Whether other instances take sessions from it too
+    fn shared(&self) -> bool;
+}
+
+// Lost along with the process, and only seen by it
+#[derive(Default)]
+pub struct Memory {
+    waiting: Mutex<VecDeque<Entry>>,
+}
+
+impl Backend for Memory {
+    fn push(&self, entry: &Entry) -> Result<(), QueueError> {
+        self.waiting.lock().unwrap().push_back(entry.clone());
+        Ok(())
+    }
+
+    fn take(&self) -> Result<Option<Entry>, QueueError> {
+        Ok(self.waiting.lock().unwrap().pop_front())
+    }
+
+    fn withdraw(&self, id: Uuid) -> Result<bool, QueueError> {
+        let mut waiting = self.waiting.lock().unwrap();
+        let before = waiting.len();
+        waiting.retain(|e| e.id != id);
+        Ok(waiting.len() < before)
+    }
+
+    fn done(&self, _id: Uuid) -> Result<(), QueueError> {
+        Ok(())
+    }
+
+    fn waiting(&self) -> Result<Vec<Uuid>, QueueError> {
+        Ok(self.waiting.lock().unwrap().iter().map(|e| e.id).collect())
+    }
+
+    fn count(&self) -> Result<usize, QueueError> {
+        Ok(self.waiting.lock().unwrap().len())
+    }
+
+    fn recover(&self) -> Result<usize, QueueError> {
+        Ok(0)
+    }
+
+    fn shared(&self) -> bool {
+        false
+    }
+}
+
+// The backend the config asks for. The command line tools keep to one in memory, so only the server
+// takes from a shared queue.
+pub fn configured() -> Box<dyn Backend> {
+    match SETTINGS.queue.backend {
+        QueueBackend::Memory => Box::new(Memory::default()),
+        #[cfg(feature = "redis-queue")]
+        QueueBackend::Redis => match redis::Redis::new(&SETTINGS.queue) {
+            Ok(r) => Box::new(r),
+            // Checked along with the rest of the config, so only a URL which can't be parsed
+            Err(e) => panic!("{}", e),
+        },
+        #[cfg(not(feature = "redis-queue"))]
+        QueueBackend::Redis => panic!("queue.backend is redis but this build doesn't include it"),
+    }
+}
+
+// Queues a session put together here, holding it until it's started
+pub async fn push(state: &Arc<Sessions>, session: Session, entry: Entry) -> Result<(), QueueError> {
+    let id = entry.id;
+    // Held here before it's in the queue, so it's never mistaken for a session queued elsewhere
+    let _dispatching = state.dispatching.lock().await;
+    // Inserted before starting so the created event can be matched to its owner
+    state.sessions.write().insert(id, session);
+    if let Err(e) = ask(state, move |q| q.push(&entry)).await {
+        state.sessions.write().remove(&id);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// New sessions are queued and started in order as there's room for them, up to max_sessions at once,
+// which can be changed at runtime.
+// Pausing the queue leaves the running sessions to finish but starts nothing new, for draining the
+// server before an upgrade.
+// With workers configured they take sessions from the same queue, see claim, and max_sessions only
+// limits those run here.
+// A shared queue is also taken from by other instances. Sessions queued elsewhere are put together
+// again from their requests here before starting, and those queued here but taken by another
+// instance are dropped, to be followed on the instance running them, leaving only their ids.
+pub async fn dispatch(state: &Arc<Sessions>) {
+    let _dispatching = state.dispatching.lock().await;
+    if state.queue.shared() {
+        forget_taken(state).await;
+    }
+    if state.paused.load(Ordering::SeqCst) || commands::SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return;
+    }
+    if SETTINGS.workers.as_ref().map_or(false, |w| !w.local) {
+        return;
+    }
+    let max_sessions = runtime::max_sessions();
+    loop {
+        let active = state.sessions.read().values()
+            .filter(|s| !s.is_queued() && !s.is_remote() && s.get_info().running())
+            .count() + state.resuming.load(Ordering::SeqCst);
+        if max_sessions != 0 && active >= max_sessions {
+            return;
+        }
+        let entry = match ask(state, |q| q.take()).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Could not take a session from the queue: {}", e);
+                return;
+            }
+        };
+        let id = entry.id;
+        let started = {
+            let mut sessions = state.sessions.write();
+            match sessions.get_mut(&id) {
+                Some(session) if session.is_queued() => match session.start() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Could not start session {}: {}", id, e);
+                        sessions.remove(&id);
+                        false
+                    }
+                },
+                // Cancelled while it was waiting
+                Some(_) => false,
+                None => {
+                    state.resuming.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(resume(state.clone(), entry));
+                    true
+                }
+            }
+        };
+        if !started {
+            done(state, id).await;
+        }
+    }
+}
+
+// Starts the next queued session on the worker asking for one, giving the job for it to run. None
+// when there's nothing to start.
+pub async fn claim(state: &Arc<Sessions>, worker: &str) -> Option<Job> {
+    if state.paused.load(Ordering::SeqCst) || commands::SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return None;
+    }
+    loop {
+        let dispatching = state.dispatching.lock().await;
+        let entry = match ask(state, |q| q.take()).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Could not take a session from the queue: {}", e);
+                return None;
+            }
+        };
+        let id = entry.id;
+        let local = {
+            let mut sessions = state.sessions.write();
+            match sessions.get_mut(&id) {
+                Some(session) if session.is_queued() => match session.start_remote(worker) {
+                    Ok(job) => {
+                        info!("Session {} started on worker {}", id, worker);
+                        return Some(job);
+                    }
+                    Err(e) => {
+                        error!("Could not start session {} on worker {}: {}", id, worker, e);
+                        sessions.remove(&id);
+                        true
+                    }
+                },
+                Some(_) => true,
+                None => false,
+            }
+        };
+        drop(dispatching);
+        if local {
+            done(state, id).await;
+            continue;
+        }
+
+        let session = match rebuild(state, entry).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Could not put session {} together again: {}", id, e);
+                done(state, id).await;
+                continue;
+            }
+        };
+        {
+            let mut sessions = state.sessions.write();
+            // Inserted before starting so the created event can be matched to its owner
+            let session = sessions.entry(id).or_insert(session);
+            match session.start_remote(worker) {
+                Ok(job) => {
+                    info!("Session {} started on worker {}", id, worker);
+                    return Some(job);
+                }
+                Err(e) => {
+                    error!("Could not start session {} on worker {}: {}", id, worker, e);
+                    sessions.remove(&id);
+                }
+            }
+        }
+        done(state, id).await;
+    }
+}
+
+// Starts a session queued by another instance, or before a restart, once it's been put together here
+async fn resume(state: Arc<Sessions>, entry: Entry) {
+    let id = entry.id;
+    let rebuilt = rebuild(&state, entry).await;
+    state.resuming.fetch_sub(1, Ordering::SeqCst);
+    let session = match rebuilt {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not put session {} together again: {}", id, e);
+            done(&state, id).await;
+            return;
+        }
+    };
+    {
+        let mut sessions = state.sessions.write();
+        let session = sessions.entry(id).or_insert(session);
+        match session.start() {
+            Ok(()) => return,
+            Err(e) => {
+                error!("Could not start session {}: {}", id, e);
+                sessions.remove(&id);
+            }
+        }
+    }
+    done(&state, id).await;
+}
+
+// The session the request was for, as the instance which queued it had it
+async fn rebuild(state: &Arc<Sessions>, entry: Entry) -> Result<Session, ConvError> {
+    let (id, owner) = (entry.id, entry.owner.clone());
+    let request = entry.request();
+    info!("Putting together session {} from the queue", id);
+    match request {
+        Request::Dash { files, overrides, name, post_process } =>
+            dash::dash_files(state, id, files, &overrides, owner, name, post_process).await,
+        Request::Mp4 { file, overrides } => mp4::mp4_session(state, id, file, &overrides, owner).await,
+        Request::Audio { file, format, tracks, overrides } =>
+            audio::audio_session(state, id, file, format, tracks.as_deref(), &overrides, owner).await,
+    }
+}
+
+// Sessions queued here which another instance sharing the queue has taken. Only called while
+// dispatching, so nothing is queued part way through.
+async fn forget_taken(state: &Arc<Sessions>) {
+    let waiting: HashSet<Uuid> = match ask(state, |q| q.waiting()).await {
+        Ok(waiting) => waiting.into_iter().collect(),
+        Err(e) => {
+            error!("Could not look at the queue: {}", e);
+            return;
+        }
+    };
+    let mut elsewhere = state.elsewhere.write();
+    state.sessions.write().retain(|id, s| {
+        let taken = s.is_queued() && !waiting.contains(id);
+        if taken {
+            info!("Session {} was taken by another instance", id);
+            elsewhere.insert(*id, s.get_owner().map(str::to_string));
+        }
+        !taken
+    });
+}
+
+async fn done(state: &Arc<Sessions>, id: Uuid) {
+    if let Err(e) = ask(state, move |q| q.done(id)).await {
+        error!("Could not mark session {} as done in the queue: {}", id, e);
+    }
+}
+
+// Queued sessions can be given up on without being started, so other instances don't take them
+pub async fn withdraw(state: &Arc<Sessions>, id: Uuid) {
+    if let Err(e) = ask(state, move |q| q.withdraw(id)).await {
+        error!("Could not take session {} out of the queue: {}", id, e);
+    }
+}
+
+// A shared backend is asked on the blocking pool, so waiting on it holds nothing else up
+async fn ask<T, F>(state: &Arc<Sessions>, f: F) -> Result<T, QueueError>
+    where T: Send + 'static,
+          F: FnOnce(&dyn Backend) -> Result<T, QueueError> + Send + 'static
+{
+    if !state.queue.shared() {
+        return f(&*state.queue);
+    }
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || f(&*state.queue)).await
+        .map_err(|e| QueueError { reason: e.to_string() })?
+}
+
+// Starts queued sessions as the running ones finish, after putting back those this instance had
+// taken when it last stopped. A shared queue is also looked at every queue.poll_interval, for
+// sessions queued by other instances.
+pub async fn run(state: Arc<Sessions>) {
+    match ask(&state, |q| q.recover()).await {
+        Ok(0) => (),
+        Ok(n) => info!("Put {} sessions which were running when last stopped back in the queue", n),
+        Err(e) => error!("Could not put back the sessions running when last stopped: {}", e),
+    }
+    dispatch(&state).await;
+
+    let poll_interval = Duration::from_secs(SETTINGS.queue.poll_interval);
+    let mut events = state.events.subscribe();
+    loop {
+        let event = if state.queue.shared() {
+            match tokio::time::timeout(poll_interval, events.next()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    dispatch(&state).await;
+                    continue;
+                }
+            }
+        } else {
+            events.next().await
+        };
+        let id = match event {
+            None => return,
+            Some(Ok(SessionEvent::Created { .. })) | Some(Ok(SessionEvent::Stage { .. })) | Some(Ok(SessionEvent::Progress(_))) => continue,
+            // Interrupted by stopping, so run again once started again
+            Some(Ok(SessionEvent::Interrupted { .. })) if commands::SHUTTING_DOWN.load(Ordering::SeqCst) => None,
+            Some(Ok(SessionEvent::Completed { id })) | Some(Ok(SessionEvent::Failed { id }))
+            | Some(Ok(SessionEvent::Cancelled { id })) | Some(Ok(SessionEvent::Interrupted { id })) => Some(id),
+            // Events missed while lagging may have been sessions finishing
+            Some(Err(_)) => None,
+        };
+        if let Some(id) = id {
+            done(&state, id).await;
+        }
+        dispatch(&state).await;
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QueueStatus {
+    paused: bool,
+    // Whether other instances take sessions from the queue too
+    shared: bool,
+    // 0 when there's no limit
+    max_sessions: usize,
+    running: usize,
+    // In the order they'll be started, including those queued by other instances
+    #[schema(value_type = Vec<String>)]
+    queued: Vec<Uuid>,
+}
+
+// Sets the queued sessions gauge. Counted when the metrics are asked for rather than as sessions
+// are queued, as other instances sharing the queue change it too.
+pub async fn count(state: &Arc<Sessions>) {
+    match ask(state, |q| q.count()).await {
+        Ok(n) => metrics::SESSIONS_QUEUED.set(n as i64),
+        Err(e) => error!("Could not count the queue: {}", e),
+    }
+}
+
+pub async fn status(state: &Arc<Sessions>) -> QueueStatus {
+    let waiting = ask(state, |q| q.waiting()).await.unwrap_or_else(|e| {
+        error!("Could not look at the queue: {}", e);
+        vec![]
+    });
+    let sessions = state.sessions.read();
+    QueueStatus {
+        paused: state.paused.load(Ordering::SeqCst),
+        shared: state.queue.shared(),
+        max_sessions: runtime::max_sessions(),
+        running: sessions.values().filter(|s| !s.is_queued() && s.get_info().running()).count(),
+        queued: waiting.into_iter().filter(|id| sessions.get(id).map_or(true, |s| s.is_queued())).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+
+    use tokio::process::Command;
+    use uuid::Uuid;
+
+    use crate::commands::{CommandLine, MediaCommandConfig, MediaInfo, Session, SessionState, Tool};
+    use crate::dash::Overrides;
+    use crate::encryption::{Encryption, KeyServer};
+    use crate::error::ConvError;
+    use crate::queue::{Backend, claim, dispatch, Entry, Memory, push, Request};
+    use crate::sessions::Sessions;
+
+    // Stands in for a stage, which is never run
+    struct Probe;
+
+    impl MediaCommandConfig for Probe {
+        fn describe(&self) -> Result<CommandLine, ConvError> {
+            let mut cmd = CommandLine::tool(Tool::Ffprobe);
+            cmd.arg("-version");
+            Ok(cmd)
+        }
+
+        fn build(&self) -> Result<Command, ConvError> {
+            panic!("the stage was run")
+        }
+    }
+
+    fn entry(id: Uuid) -> Entry {
+        Entry::new(id, None, Request::Mp4 { file: PathBuf::from("in/movie.mkv"), overrides: Overrides::default() })
+    }
+
+    async fn queued(state: &Arc<Sessions>) -> Uuid {
+        let id = Uuid::new_v4();
+        let session = Session::new(id, Box::new(Probe), Arc::new(RwLock::new(MediaInfo::default())));
+        push(state, session, entry(id)).await.unwrap();
+        id
+    }
+
+    fn state_of(state: &Sessions, id: Uuid) -> SessionState {
+        state.sessions.read()[&id].get_info().state()
+    }
+
+    #[test]
+    fn memory_order() {
+        let q = Memory::default();
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for &id in &ids {
+            q.push(&entry(id)).unwrap();
+        }
+        assert_eq!(q.waiting().unwrap(), ids);
+        assert!(q.withdraw(ids[1]).unwrap());
+        assert!(!q.withdraw(ids[1]).unwrap());
+        assert_eq!(q.waiting().unwrap(), vec![ids[0], ids[2]]);
+        assert_eq!(q.take().unwrap().map(|e| e.id), Some(ids[0]));
+        assert_eq!(q.take().unwrap().map(|e| e.id), Some(ids[2]));
+        assert!(q.take().unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn claim_skips_cancelled() {
+        let state = Arc::new(Sessions::new());
+        let cancelled = queued(&state).await;
+        let waiting = queued(&state).await;
+        state.sessions.write().get_mut(&cancelled).unwrap().cancel();
+
+        assert_eq!(claim(&state, "worker").await.map(|j| j.id), Some(waiting));
+        assert!(claim(&state, "worker").await.is_none());
+        assert_eq!(state_of(&state, cancelled), SessionState::Cancelled);
+        assert!(state.sessions.read()[&waiting].is_remote());
+    }
+
+    #[actix_rt::test]
+    async fn dispatch_skips_cancelled() {
+        let state = Arc::new(Sessions::new());
+        let cancelled = queued(&state).await;
+        state.sessions.write().get_mut(&cancelled).unwrap().cancel();
+
+        // Starting it would run the stage
+        dispatch(&state).await;
+        assert!(state.queue.waiting().unwrap().is_empty());
+        assert_eq!(state_of(&state, cancelled), SessionState::Cancelled);
+    }
+
+    fn round_trip(encryption: Encryption) -> Encryption {
+        let overrides = Overrides { encryption: Some(encryption), ..Overrides::default() };
+        let request = Request::Mp4 { file: PathBuf::from("in/movie.mkv"), overrides };
+        let json = serde_json::to_string(&Entry::new(Uuid::new_v4(), None, request)).unwrap();
+        match serde_json::from_str::<Entry>(&json).unwrap().request() {
+            Request::Mp4 { overrides, .. } => overrides.encryption.unwrap(),
+            r => panic!("came back as {:?}", r),
+        }
+    }
+
+    #[test]
+    fn encrypted_entry() {
+        let raw = round_trip(Encryption {
+            key_id: Some("00112233445566778899aabbccddeeff".to_string()),
+            key: Some("ffeeddccbbaa99887766554433221100".to_string()),
+            ..Encryption::default()
+        });
+        assert_eq!(raw.key.as_deref(), Some("ffeeddccbbaa99887766554433221100"));
+
+        let server = round_trip(Encryption {
+            key_server: Some(KeyServer {
+                url: "https://license.example.com".to_string(),
+                content_id: "0123".to_string(),
+                signer: "test".to_string(),
+                signing_key: "1ae8".to_string(),
+                signing_iv: "d58c".to_string(),
+            }),
+            ..Encryption::default()
+        }).key_server.unwrap();
+        assert_eq!((server.signing_key.as_str(), server.signing_iv.as_str()), ("1ae8", "d58c"));
+    }
+
+    #[test]
+    fn secrets_only_in_entry() {
+        let overrides = Overrides {
+            encryption: Some(Encryption { key: Some("ffeeddccbbaa99887766554433221100".to_string()), ..Encryption::default() }),
+            ..Overrides::default()
+        };
+        // Metadata serialises the overrides alone, which must keep leaving the key out
+        assert!(!serde_json::to_string(&overrides).unwrap().contains("ffeeddcc"));
+    }
+}
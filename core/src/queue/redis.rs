@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::error;
+use redis::{Client, Commands, Connection, RedisResult};
+use uuid::Uuid;
+
+use crate::queue::{Backend, Entry, QueueError};
+use crate::settings::Queue;
+
+// A Redis which stops answering is given up on rather than holding up the queue
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// A queue shared by every instance using the same Redis and prefix. The ids of waiting sessions are
+// kept in a list, newest first, and each instance moves those it takes to a list of its own until
+// it's done with them, so whatever it had taken when it stopped can be put back. Entries are kept
+// separately by id, as JSON.
+// Requests are kept as they were made, including any encryption keys in their overrides, so Redis
+// needs keeping as private as the config.
+pub struct Redis {
+    client: Client,
+    // Made on first use, and again after it breaks
+    connection: Mutex<Option<Connection>>,
+    waiting: String,
+    taken: String,
+    entries: String,
+}
+
+impl Redis {
+    pub fn new(config: &Queue) -> Result<Self, QueueError> {
+        let url = config.url.as_deref().ok_or_else(|| failed("queue.url isn't set"))?;
+        let instance = config.instance().ok_or_else(|| failed("queue.instance isn't set"))?;
+        Ok(Redis {
+            client: Client::open(url).map_err(failed)?,
+            connection: Mutex::new(None),
+            waiting: format!("{}:waiting", config.prefix),
+            taken: format!("{}:taken:{}", config.prefix, instance),
+            entries: format!("{}:entries", config.prefix),
+        })
+    }
+
+    fn with<T, F: FnOnce(&mut Connection) -> RedisResult<T>>(&self, f: F) -> Result<T, QueueError> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            let c = self.client.get_connection_with_timeout(TIMEOUT).map_err(failed)?;
+            c.set_read_timeout(Some(TIMEOUT)).map_err(failed)?;
+            c.set_write_timeout(Some(TIMEOUT)).map_err(failed)?;
+            *connection = Some(c);
+        }
+        let result = f(connection.as_mut().unwrap());
+        if let Err(e) = &result {
+            if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() {
+                *connection = None;
+            }
+        }
+        result.map_err(failed)
+    }
+
+    // Gone from the sessions this instance has taken, along with its entry
+    fn forget(&self, id: &str) -> Result<(), QueueError> {
+        self.with(|c| redis::pipe().atomic()
+            .lrem(&self.taken, 1, id).ignore()
+            .hdel(&self.entries, id).ignore()
+            .query(c))
+    }
+}
+
+impl Backend for Redis {
+    fn push(&self, entry: &Entry) -> Result<(), QueueError> {
+        let json = serde_json::to_string(entry).map_err(failed)?;
+        let id = entry.id.to_string();
+        self.with(|c| redis::pipe().atomic()
+            .hset(&self.entries, &id, json).ignore()
+            .lpush(&self.waiting, &id).ignore()
+            .query(c))
+    }
+
+    fn take(&self) -> Result<Option<Entry>, QueueError> {
+        loop {
+            let (id, json): (Option<String>, Option<String>) = self.with(|c| {
+                let id: Option<String> = c.rpoplpush(&self.waiting, &self.taken)?;
+                match id {
+                    Some(id) => Ok((Some(id.clone()), c.hget(&self.entries, id)?)),
+                    None => Ok((None, None)),
+                }
+            })?;
+            let id = match id {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            match json.as_deref().map(serde_json::from_str::<Entry>) {
+                Some(Ok(entry)) => return Ok(Some(entry)),
+                // Withdrawn as it was being taken
+                None => (),
+                Some(Err(e)) => error!("Dropping session {} from the queue as it can't be read: {}", id, e),
+            }
+            self.forget(&id)?;
+        }
+    }
+
+    fn withdraw(&self, id: Uuid) -> Result<bool, QueueError> {
+        let id = id.to_string();
+        let removed: usize = self.with(|c| c.lrem(&self.waiting, 1, &id))?;
+        if removed > 0 {
+            self.with(|c| c.hdel::<_, _, ()>(&self.entries, &id))?;
+        }
+        Ok(removed > 0)
+    }
+
+    fn done(&self, id: Uuid) -> Result<(), QueueError> {
+        self.forget(&id.to_string())
+    }
+
+    fn waiting(&self) -> Result<Vec<Uuid>, QueueError> {
+        let ids: Vec<String> = self.with(|c| c.lrange(&self.waiting, 0, -1))?;
+        Ok(ids.iter().rev().filter_map(|id| Uuid::parse_str(id).ok()).collect())
+    }
+
+    fn count(&self) -> Result<usize, QueueError> {
+        self.with(|c| c.llen(&self.waiting))
+    }
+
+    fn recover(&self) -> Result<usize, QueueError> {
+        let ids: Vec<String> = self.with(|c| c.lrange(&self.taken, 0, -1))?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        // The list is newest first, so the first taken ends up next to be taken again
+        self.with(|c| redis::pipe().atomic()
+            .rpush(&self.waiting, &ids).ignore()
+            .del(&self.taken).ignore()
+            .query::<()>(c))?;
+        Ok(ids.len())
+    }
+
+    fn shared(&self) -> bool {
+        true
+    }
+}
+
+fn failed<E: ToString>(e: E) -> QueueError {
+    QueueError { reason: e.to_string() }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::{LOG_DIR, PROCESSED_DIR, SETTINGS, UNPROCESSED_DIR, WORK_DIR};
+
+const PIDS: &str = ".pids";
+
+lazy_static! {
+    // Set when running as a worker, see worker
+    static ref WORKER: RwLock<Option<String>> = RwLock::new(None);
+}
+
+// Each running command has a file here named after its pid, holding its command line so a pid which
+// has since been reused by something else isn't mistaken for ours. Workers keep theirs apart, as
+// they may well share the work directory with the coordinator.
+fn pid_dir() -> PathBuf {
+    match &*WORKER.read().unwrap() {
+        Some(name) => WORK_DIR.join(PIDS).join(format!("worker-{}", name)),
+        None => WORK_DIR.join(PIDS),
+    }
+}
+
+// Staged packages this instance is writing, named as they are. Instances sharing a queue may share
+// the processed directory, so each only cleans up the staged packages it recorded here.
+fn staged_dir() -> PathBuf {
+    WORK_DIR.join(".staged")
+}
+
+pub fn record(pid: u32) {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let res = std::fs::create_dir_all(pid_dir())
+        .and_then(|_| std::fs::write(pid_dir().join(pid.to_string()), cmdline));
+    if let Err(e) = res {
+        error!("Could not record pid {}: {}", pid, e);
+    }
+}
+
+pub fn forget(pid: u32) {
+    ignore_missing(std::fs::remove_file(pid_dir().join(pid.to_string())))
+        .unwrap_or_else(|e| error!("Could not remove the record of pid {}: {}", pid, e));
+}
+
+pub fn staging(staged: &Path) {
+    let name = staged.file_name().unwrap_or_default();
+    let res = std::fs::create_dir_all(staged_dir())
+        .and_then(|_| std::fs::write(staged_dir().join(name), b""));
+    if let Err(e) = res {
+        error!("Could not record staged package {:?}: {}", staged, e);
+    }
+}
+
+// The staged package has been swapped in, or never existed
+pub fn staged(staged: &Path) {
+    let name = staged.file_name().unwrap_or_default();
+    ignore_missing(std::fs::remove_file(staged_dir().join(name)))
+        .unwrap_or_else(|e| error!("Could not remove the record of staged package {:?}: {}", staged, e));
+}
+
+// Sessions don't survive a restart, so whatever the last run left behind is cleaned up: commands it
+// started, the intermediates they were writing, and half finished uploads and package swaps
+pub fn reap() {
+    kill_orphans();
+    clean_processed_dir();
+    clean_work_dir();
+    clean_uploads();
+    clean_logs();
+}
+
+// Runs as a worker of the given name, killing whatever commands it started when it last ran. The
+// rest is left to the coordinator, as it's the coordinator's sessions the worker's were.
+pub fn worker(name: &str) {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    *WORKER.write().unwrap() = Some(name);
+    kill_orphans();
+}
+
+fn kill_orphans() {
+    let entries = match std::fs::read_dir(pid_dir()) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_str().and_then(|p| p.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let recorded = std::fs::read(entry.path()).unwrap_or_default();
+        let current = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+        if !recorded.is_empty() && recorded == current {
+            info!("Killing orphaned command {} ({})", pid, String::from_utf8_lossy(&recorded).replace('\0', " ").trim());
+            kill(pid);
+        }
+        forget(pid);
+    }
+}
+
+#[cfg(unix)]
+fn kill(pid: u32) {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        error!("Could not kill {}: {}", pid, io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}
+
+fn clean_work_dir() {
+    let entries = match std::fs::read_dir(*WORK_DIR) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        // Holding workers' records, ours have been dealt with
+        if entry.file_name() == PIDS {
+            continue;
+        }
+        info!("Removing stale intermediates {:?}", entry.path());
+        remove(&entry.path());
+    }
+}
+
+// Staged packages are removed, unless a swap was interrupted between its renames in which case the
+// old package is put back. Staged packages sit next to their package, which may be grouped into
+// directories by the package template.
+// When the processed directory is shared, only the staged packages this instance recorded are
+// touched, and an old package is only put back when one of them was being swapped in for it.
+fn clean_processed_dir() {
+    let own: Option<HashSet<String>> = SETTINGS.queue.instance().map(|_| {
+        std::fs::read_dir(staged_dir()).into_iter().flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect()
+    });
+    let mut dirs = vec![PROCESSED_DIR.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        clean_staged(&dir, &mut dirs, own.as_ref());
+    }
+}
+
+fn clean_staged(dir: &Path, dirs: &mut Vec<PathBuf>, own: Option<&HashSet<String>>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let names: Vec<String> = entries.filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    let ours = |name: &str| own.map_or(true, |own| own.contains(name));
+    for name in &names {
+        let path = dir.join(name);
+        if !name.starts_with('.') {
+            // Could be a grouping directory with packages of its own
+            dirs.push(path);
+            continue;
+        }
+        let package = name.strip_suffix(".old").map(|p| &p[1..]);
+        match package {
+            Some(package) if own.is_some() && !names.iter().any(|n| n.starts_with(&format!(".{}.", package)) && ours(n)) => (),
+            Some(package) if !dir.join(package).exists() => {
+                let package = dir.join(package);
+                info!("Restoring {:?} from an interrupted swap", package);
+                if let Err(e) = std::fs::rename(&path, &package) {
+                    error!("Could not restore {:?}: {}", package, e);
+                }
+            }
+            _ if ours(name) => {
+                info!("Removing stale staged package {:?}", path);
+                remove(&path);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn clean_uploads() {
+    let entries = match std::fs::read_dir(*UNPROCESSED_DIR) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && name.ends_with(".part") {
+            info!("Removing incomplete upload {:?}", entry.path());
+            remove(&entry.path());
+        }
+    }
+}
+
+// Sessions are only kept until the server stops, so their logs can't be looked up through them
+// afterwards. They're still kept on disk for a while to look into what went wrong.
+fn clean_logs() {
+    if SETTINGS.session_log_days == 0 {
+        return;
+    }
+    let entries = match std::fs::read_dir(*LOG_DIR) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let max_age = Duration::from_secs(SETTINGS.session_log_days * 24 * 60 * 60);
+    for entry in entries.filter_map(|e| e.ok()) {
+        // Instances sharing the directory are still writing to theirs
+        let old = entry.metadata().ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|m| m.elapsed().ok())
+            .map_or(false, |age| age > max_age);
+        if old && entry.path().extension().map_or(false, |e| e == "log") {
+            remove(&entry.path());
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    let res = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    if let Err(e) = ignore_missing(res) {
+        error!("Could not remove {:?}: {}", path, e);
+    }
+}
+
+fn ignore_missing(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use log::{error, info};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::sessions::Sessions;
+use crate::{package, PROCESSED_DIR, SETTINGS};
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Expired {
+    name: String,
+    size: u64,
+    // Seconds since the epoch
+    modified: u64,
+    reason: &'static str,
+}
+
+struct Package {
+    name: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+// Packages which the retention policy would remove now, oldest first. Packages still being written
+// are never included.
+pub fn plan(state: &Sessions) -> Vec<Expired> {
+    let retention = match &SETTINGS.retention {
+        Some(r) => r,
+        None => return vec![],
+    };
+
+    let busy: Vec<_> = state.sessions.read().values()
+        .filter(|s| s.get_info().running())
+        .filter_map(|s| s.output_dir().map(|o| o.to_path_buf()))
+        .collect();
+    let mut packages: Vec<_> = match package::list(*PROCESSED_DIR) {
+        Ok(names) => names.into_iter()
+            .map(|n| (PROCESSED_DIR.join(&n), n))
+            .filter(|(path, _)| !busy.contains(path))
+            .filter_map(|(path, name)| Some(Package {
+                name: name.to_str()?.to_string(),
+                size: dir_size(&path),
+                modified: path.metadata().ok()?.modified().ok()?,
+            }))
+            .collect(),
+        Err(e) => {
+            error!("Could not list packages: {}", e);
+            return vec![];
+        }
+    };
+    packages.sort_by_key(|p| p.modified);
+
+    let now = SystemTime::now();
+    let mut total: u64 = packages.iter().map(|p| p.size).sum();
+    packages.into_iter().filter_map(|p| {
+        let too_old = retention.max_age_days
+            .map_or(false, |d| now.duration_since(p.modified).unwrap_or_default() > Duration::from_secs(d * 24 * 60 * 60));
+        let too_big = retention.max_total_size.map_or(false, |m| total > m);
+        let reason = match (too_old, too_big) {
+            (true, _) => "max_age_days",
+            (false, true) => "max_total_size",
+            (false, false) => return None,
+        };
+        total -= p.size;
+        Some(Expired {
+            name: p.name,
+            size: p.size,
+            modified: p.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            reason,
+        })
+    }).collect()
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir).into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+// Prunes expired packages periodically, when a retention policy is configured
+pub async fn run(state: Arc<Sessions>) {
+    let retention = match &SETTINGS.retention {
+        Some(r) => r,
+        None => return,
+    };
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(retention.interval.max(60)));
+    while ticks.next().await.is_some() {
+        for p in plan(&state) {
+            // A session may have started replacing it since the plan, and one which is about to
+            // swap in its package waits until this one is gone
+            let _changing = package::CHANGING.lock();
+            if let Some(id) = state.writing_to(&PROCESSED_DIR.join(&p.name)) {
+                info!("Keeping package {} as session {} is replacing it", p.name, id);
+                continue;
+            }
+            match package::remove(*PROCESSED_DIR, Path::new(&p.name)) {
+                Ok(()) => info!("Removed package {} ({})", p.name, p.reason),
+                Err(e) => error!("Could not remove package {}: {}", p.name, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::StreamExt;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::dash::Overrides;
+use crate::sessions::Sessions;
+use crate::settings::{Settings, SettingsError, CONFIG_FILE};
+use crate::{queue, SETTINGS};
+
+// Settings changed through the API while the server is running, which take the place of those in
+// the config until they're reset. They're kept in SETTINGS.runtime_settings so they survive restarts.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, ToSchema)]
+pub struct RuntimeSettings {
+    pub max_sessions: Option<usize>,
+    pub niceness: Option<i32>,
+    pub default_profile: Option<String>,
+}
+
+lazy_static! {
+    static ref RUNTIME: RwLock<RuntimeSettings> = RwLock::new(load());
+    // The config as last loaded, of which only the settings in reload are used
+    static ref CONFIG: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::new().unwrap()));
+}
+
+// Settings which take effect when the config is reloaded. The rest are read once at startup and
+// need a restart to change.
+const RELOADED: [&str; 8] = ["profiles", "default_profile", "webhooks", "notifiers", "media_server",
+    "max_sessions", "stage_parallelism", "niceness"];
+
+pub fn config() -> Arc<Settings> {
+    CONFIG.read().unwrap().clone()
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Reloaded {
+    reloaded: &'static [&'static str],
+    // Settings which changed but only take effect after a restart
+    restart_needed: Vec<String>,
+}
+
+// Reads the config again. Nothing changes when it can't be read.
+pub fn reload() -> Result<Reloaded, String> {
+    let new = Settings::new().map_err(|e| e.to_string())?;
+    let problems = new.profile_problems();
+    if !problems.is_empty() {
+        return Err(SettingsError::Invalid(problems).to_string());
+    }
+    let mut restart_needed = changed(&SETTINGS, &new);
+    restart_needed.retain(|name| !RELOADED.contains(&name.as_str()));
+    for name in &restart_needed {
+        warn!("{} changed in the config, which only takes effect after a restart", name);
+    }
+    *CONFIG.write().unwrap() = Arc::new(new);
+    info!("Config reloaded");
+    Ok(Reloaded { reloaded: &RELOADED, restart_needed })
+}
+
+// The top level settings which differ between the two, as the config gave them rather than with
+// defaults filled in, so a setting added to the config with its default value counts as changed
+fn changed(old: &Settings, new: &Settings) -> Vec<String> {
+    let mut names: Vec<_> = old.raw.keys().chain(new.raw.keys())
+        .filter(|name| old.raw.get(*name) != new.raw.get(*name))
+        .cloned()
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Reloads the config whenever the file changes
+pub async fn watch(state: Arc<Sessions>) {
+    let file = Path::new(CONFIG_FILE);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    // As for the library, the watcher reports on a std channel. The directory is watched rather than
+    // the file as editors often replace the file rather than writing to it.
+    std::thread::spawn(move || {
+        let (watch_tx, watch_rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::watcher(watch_tx, Duration::from_secs(1)) {
+            Ok(w) => w,
+            Err(e) => return error!("Could not watch {:?}, it won't be reloaded on changes: {}", file, e),
+        };
+        let dir = file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            return error!("Could not watch {:?}, it won't be reloaded on changes: {}", file, e);
+        }
+        for event in watch_rx {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+    });
+
+    while let Some(event) = rx.next().await {
+        let changed = match event {
+            DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Rename(_, p) => p,
+            _ => continue,
+        };
+        if changed.file_name() != file.file_name() {
+            continue;
+        }
+        match reload() {
+            Ok(_) => queue::dispatch(&state).await,
+            Err(e) => error!("Could not reload the config, keeping the current one: {}", e),
+        }
+    }
+}
+
+fn load() -> RuntimeSettings {
+    match std::fs::read(&SETTINGS.runtime_settings) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            error!("Ignoring unreadable runtime settings: {}", e);
+            RuntimeSettings::default()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => RuntimeSettings::default(),
+        Err(e) => {
+            error!("Could not read the runtime settings: {}", e);
+            RuntimeSettings::default()
+        }
+    }
+}
+
+pub fn save(settings: &RuntimeSettings) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(settings)?;
+    // Written alongside and renamed over so a crash can't leave half the file
+    let tmp = SETTINGS.runtime_settings.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, &SETTINGS.runtime_settings)
+}
+
+pub fn max_sessions() -> usize {
+    RUNTIME.read().unwrap().max_sessions.unwrap_or_else(|| config().max_sessions)
+}
+
+pub fn niceness() -> i32 {
+    RUNTIME.read().unwrap().niceness.unwrap_or_else(|| config().niceness)
+}
+
+pub fn default_profile() -> Option<String> {
+    RUNTIME.read().unwrap().default_profile.clone().or_else(|| config().default_profile.clone())
+}
+
+pub fn stage_parallelism() -> usize {
+    config().stage_parallelism
+}
+
+// The request's overrides, with anything they leave unset taken from the default profile
+pub fn with_defaults(overrides: &Overrides) -> Overrides {
+    match default_profile().and_then(|p| config().profiles.get(&p).cloned()) {
+        Some(defaults) => overrides.or(&defaults),
+        None => overrides.clone(),
+    }
+}
+
+fn validate(settings: &RuntimeSettings) -> Result<(), String> {
+    if let Some(n) = settings.niceness {
+        // Raising priority needs privileges the server shouldn't have
+        if !(0..=19).contains(&n) {
+            return Err("niceness must be from 0 to 19".to_string());
+        }
+    }
+    if let Some(p) = &settings.default_profile {
+        match config().profiles.get(p) {
+            Some(o) => o.validate().map_err(|e| format!("The profile {} is invalid: {}", p, e))?,
+            None => return Err(format!("Unknown profile: {}", p)),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Current {
+    max_sessions: usize,
+    niceness: i32,
+    default_profile: Option<String>,
+    // What has been changed from the config
+    overridden: RuntimeSettings,
+}
+
+pub fn current() -> Current {
+    Current {
+        max_sessions: max_sessions(),
+        niceness: niceness(),
+        default_profile: default_profile(),
+        overridden: RUNTIME.read().unwrap().clone(),
+    }
+}
+
+// Changes the settings given, leaving the rest as they are, giving what they now are. They're only
+// kept once saved.
+pub fn update(changes: &RuntimeSettings) -> Result<RuntimeSettings, String> {
+    validate(changes)?;
+    let mut runtime = RUNTIME.write().unwrap();
+    let updated = RuntimeSettings {
+        max_sessions: changes.max_sessions.or(runtime.max_sessions),
+        niceness: changes.niceness.or(runtime.niceness),
+        default_profile: changes.default_profile.clone().or_else(|| runtime.default_profile.clone()),
+    };
+    *runtime = updated.clone();
+    info!("Runtime settings changed: {:?}", updated);
+    Ok(updated)
+}
+
+// Goes back to the settings in the config
+pub fn reset() {
+    *RUNTIME.write().unwrap() = RuntimeSettings::default();
+    info!("Runtime settings reset to the config");
+}
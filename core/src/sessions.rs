@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+use parking_lot::RwLock;
+use tokio::sync::broadcast;
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+
+use crate::commands::{Session, SessionEvent};
+use crate::queue::{self, Backend};
+
+// Every session since startup, and those waiting to start
+pub struct Sessions {
+    pub sessions: RwLock<HashMap<Uuid, Session>>,
+    pub events: broadcast::Sender<SessionEvent>,
+    // Sessions waiting to start, see queue::dispatch
+    pub queue: Box<dyn Backend>,
+    pub paused: AtomicBool,
+    // Sessions taken from the queue which are being put together before starting, see queue::resume
+    pub resuming: AtomicUsize,
+    // Held while taking from or adding to the queue, see queue::dispatch
+    pub dispatching: AsyncMutex<()>,
+    // Sessions queued here which another instance sharing the queue took, with their owners, so
+    // asking after them here can be answered
+    pub elsewhere: RwLock<HashMap<Uuid, Option<String>>>,
+}
+
+impl Sessions {
+    // With a queue of its own in memory
+    pub fn new() -> Self {
+        Sessions::with_queue(Box::new(queue::Memory::default()))
+    }
+
+    pub fn with_queue(queue: Box<dyn Backend>) -> Self {
+        // Slow subscribers skip events rather than holding anything up
+        let (events, _) = broadcast::channel(256);
+        Sessions {
+            sessions: RwLock::new(HashMap::new()),
+            events,
+            queue,
+            paused: AtomicBool::new(false),
+            resuming: AtomicUsize::new(0),
+            dispatching: AsyncMutex::new(()),
+            elsewhere: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // The running session writing a package to dir, if any
+    pub fn writing_to(&self, dir: &Path) -> Option<Uuid> {
+        self.sessions.read().values()
+            .find(|s| s.get_info().running() && s.output_dir() == Some(dir))
+            .map(|s| s.id())
+    }
+}
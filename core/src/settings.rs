@@ -0,0 +1,1069 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use config::{Config, ConfigError, Environment, File, Value};
+use derive_more::{Display, From};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::commands::{find_program, mp4dash, Tool};
+use crate::dash::Overrides;
+
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    #[serde(default = "default_host")]
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub listen: Listen,
+    // Serve HTTPS on every listener when set
+    pub tls: Option<Tls>,
+    // The gRPC interface, in builds with the grpc feature
+    pub grpc: Option<Grpc>,
+    // Hands sessions to workers, instances of this started with --worker, see commands::remote
+    pub workers: Option<Workers>,
+    // Used when started with --worker
+    #[serde(default)]
+    pub worker: Worker,
+    // Where sessions wait to start, see queue
+    #[serde(default)]
+    pub queue: Queue,
+    pub dirs: Dirs,
+    // ISO 639-2 codes of the audio streams to keep, empty keeps everything
+    #[serde(default)]
+    pub audio_languages: Vec<String>,
+    #[serde(default)]
+    pub webhooks: Webhooks,
+    pub media_server: Option<MediaServer>,
+    #[serde(default)]
+    pub notifiers: Notifiers,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub cors: Cors,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub upload: Upload,
+    #[serde(default)]
+    pub fetch: Fetch,
+    // What happens to a source once it has been packaged
+    #[serde(default)]
+    pub post_process: PostProcess,
+    // What turns the streams into a DASH package
+    #[serde(default)]
+    pub packager: Packager,
+    // What the manifest in each package is called, whichever packager writes it
+    #[serde(default = "default_manifest_name")]
+    pub manifest_name: String,
+    #[serde(default)]
+    pub mp4dash: Mp4Dash,
+    #[serde(default)]
+    pub clearkey: ClearKey,
+    // Also write an HLS playlist referencing the same fMP4 segments as the manifest, so one copy
+    // of each package serves both
+    #[serde(default)]
+    pub cmaf: bool,
+    // Where packages go under the processed directory, see dash::package_name
+    #[serde(default = "default_package_template")]
+    pub package_template: String,
+    // Intermediate files are always removed after success, this keeps them when a session fails so
+    // a retry can skip the stages which finished
+    #[serde(default = "default_true")]
+    pub keep_failed_intermediates: bool,
+    // Encodes are written fragmented for the packager rather than fragmented afterwards, and
+    // straight into pipes which Shaka Packager reads from, so far less is written to the work dir
+    #[serde(default)]
+    pub pipe_intermediates: bool,
+    #[serde(default)]
+    pub space_check: SpaceCheck,
+    pub retention: Option<Retention>,
+    // Output lines kept in memory for each stream of a session, the full log is in dirs.logs
+    #[serde(default = "default_session_log_lines")]
+    pub session_log_lines: usize,
+    // Days the logs of sessions from before the server last started are kept, 0 keeps them forever
+    #[serde(default = "default_session_log_days")]
+    pub session_log_days: u64,
+    // Seconds an encode may go without reporting progress before it's killed, 0 never kills
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+    // Stages of a session which don't depend on each other, like encoding each stream, run at the
+    // same time up to this many
+    #[serde(default = "default_stage_parallelism")]
+    pub stage_parallelism: usize,
+    // Sessions run at once, the rest wait in the queue. 0 runs every session straight away.
+    #[serde(default)]
+    pub max_sessions: usize,
+    // Added to the niceness of every command, so encodes give way to everything else on the machine
+    #[serde(default)]
+    pub niceness: i32,
+    // The profile whose overrides fill in whatever a request leaves unset
+    pub default_profile: Option<String>,
+    // Where settings changed through the API are kept, see runtime
+    #[serde(default = "default_runtime_settings")]
+    pub runtime_settings: PathBuf,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    // Seconds to wait for ffprobe before treating a file as unreadable
+    #[serde(default = "default_probe_timeout")]
+    pub probe_timeout: u64,
+    // Where ffprobe results are kept between runs
+    #[serde(default = "default_probe_cache")]
+    pub probe_cache: PathBuf,
+    // Where Swagger UI's files are, served with the API docs
+    pub swagger_ui: Option<PathBuf>,
+    // Named sets of overrides
+    #[serde(default)]
+    pub profiles: HashMap<String, Overrides>,
+    #[serde(default)]
+    pub auto_process: AutoProcess,
+    #[serde(default)]
+    pub scan: Scan,
+    #[serde(default)]
+    pub tools: Tools,
+    // Each top level setting as the config gave it, so a reload can tell which changed
+    #[serde(skip)]
+    pub(crate) raw: HashMap<String, Value>,
+}
+
+// Where the external programs are, each is looked for on the PATH unless a path is given
+#[derive(Debug, Deserialize)]
+pub struct Tools {
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: PathBuf,
+    #[serde(default = "default_ffprobe")]
+    pub ffprobe: PathBuf,
+    #[serde(default = "default_mp4fragment")]
+    pub mp4fragment: PathBuf,
+    #[serde(default = "default_mp4dash")]
+    pub mp4dash: PathBuf,
+    #[serde(default = "default_packager")]
+    pub packager: PathBuf,
+    // Runs mp4dash when it's Bento4's Python script, see mp4dash::script
+    #[serde(default = "default_python")]
+    pub python: PathBuf,
+    #[serde(default)]
+    pub extra_args: ExtraArgs,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Tools {
+            ffmpeg: default_ffmpeg(),
+            ffprobe: default_ffprobe(),
+            mp4fragment: default_mp4fragment(),
+            mp4dash: default_mp4dash(),
+            packager: default_packager(),
+            python: default_python(),
+            extra_args: ExtraArgs::default(),
+        }
+    }
+}
+
+impl Tools {
+    pub fn path(&self, tool: Tool) -> &Path {
+        match tool {
+            Tool::Ffmpeg => &self.ffmpeg,
+            Tool::Ffprobe => &self.ffprobe,
+            Tool::Mp4fragment => &self.mp4fragment,
+            Tool::Mp4dash => &self.mp4dash,
+            Tool::Packager => &self.packager,
+        }
+    }
+
+    pub fn extra_args(&self, tool: Tool) -> &[String] {
+        match tool {
+            Tool::Ffmpeg => &self.extra_args.ffmpeg,
+            Tool::Ffprobe => &self.extra_args.ffprobe,
+            Tool::Mp4fragment => &self.extra_args.mp4fragment,
+            Tool::Mp4dash => &self.extra_args.mp4dash,
+            Tool::Packager => &self.extra_args.packager,
+        }
+    }
+}
+
+// Passed to every run of each tool before anything else
+#[derive(Debug, Deserialize, Default)]
+pub struct ExtraArgs {
+    #[serde(default)]
+    pub ffmpeg: Vec<String>,
+    #[serde(default)]
+    pub ffprobe: Vec<String>,
+    #[serde(default)]
+    pub mp4fragment: Vec<String>,
+    #[serde(default)]
+    pub mp4dash: Vec<String>,
+    #[serde(default)]
+    pub packager: Vec<String>,
+}
+
+// Keys for ClearKey encryption, which players get from the server's license endpoint
+#[derive(Debug, Deserialize, Default)]
+pub struct ClearKey {
+    // By the name requests and profiles pick them with
+    #[serde(default)]
+    pub keys: HashMap<String, ClearKeyPair>,
+    // Where players should ask for keys, the public URL of /clearkey/license
+    pub license_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClearKeyPair {
+    // 16 bytes each, as hex
+    pub key_id: String,
+    pub key: String,
+}
+
+// How Bento4 lays out packages
+#[derive(Debug, Deserialize, Default)]
+pub struct Mp4Dash {
+    #[serde(default)]
+    pub profile: DashProfile,
+    // Ignored by the on-demand profile, which has a single file per stream
+    #[serde(default)]
+    pub segment_addressing: SegmentAddressing,
+    // seg-00001.m4s rather than seg-1.m4s
+    #[serde(default)]
+    pub number_padding: bool,
+    // Name of each stream's initialisation segment, mp4dash uses init.mp4 by default
+    pub init_segment: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DashProfile {
+    // Many small segment files per stream
+    Live,
+    // One file per stream, fetched in byte ranges
+    OnDemand,
+}
+
+impl Default for DashProfile {
+    fn default() -> Self {
+        DashProfile::Live
+    }
+}
+
+// How the manifest refers to segments
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentAddressing {
+    // A template along with the timing of every segment
+    Timeline,
+    // A template with the number of each segment
+    Template,
+    // Every segment's URL
+    List,
+}
+
+impl Default for SegmentAddressing {
+    fn default() -> Self {
+        SegmentAddressing::Timeline
+    }
+}
+
+// How the unprocessed directory is indexed
+#[derive(Debug, Deserialize)]
+pub struct Scan {
+    // Files are left alone until they've gone this many seconds without being modified
+    #[serde(default = "default_stable_seconds")]
+    pub stable_seconds: u64,
+    // Extensions of partial downloads, which are never indexed
+    #[serde(default = "default_temp_extensions")]
+    pub temp_extensions: Vec<String>,
+    // Extensions of the files worth probing, empty probes everything
+    #[serde(default = "default_scan_extensions")]
+    pub extensions: Vec<String>,
+    #[serde(flatten)]
+    pub globs: Globs,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Scan {
+            stable_seconds: default_stable_seconds(),
+            temp_extensions: default_temp_extensions(),
+            extensions: default_scan_extensions(),
+            globs: Globs::default(),
+        }
+    }
+}
+
+// Globs matched against paths relative to the unprocessed directory, everything is included when
+// there are none. See library::Filter.
+#[derive(Debug, Deserialize, Default)]
+pub struct Globs {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+// Packages files as they're added to the unprocessed directory
+#[derive(Debug, Deserialize, Default)]
+pub struct AutoProcess {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub globs: Globs,
+    pub profile: Option<String>,
+}
+
+// What happens to running sessions when the server is stopped
+#[derive(Debug, Deserialize)]
+pub struct Shutdown {
+    #[serde(default)]
+    pub mode: ShutdownMode,
+    // Seconds to wait for stages to finish when draining, anything still running is then killed
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            mode: ShutdownMode::default(),
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownMode {
+    // Kill running commands straight away
+    Kill,
+    // Let running stages finish but start no more
+    Drain,
+}
+
+impl Default for ShutdownMode {
+    fn default() -> Self {
+        ShutdownMode::Kill
+    }
+}
+
+// Failed stages are run again before the session is failed, waiting backoff seconds before the first
+// retry and doubling the wait for each one after
+#[derive(Debug, Deserialize)]
+pub struct Retry {
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 0,
+            backoff: default_retry_backoff(),
+        }
+    }
+}
+
+// Oldest packages are pruned from the processed directory once either limit is passed
+#[derive(Debug, Deserialize)]
+pub struct Retention {
+    // Bytes
+    pub max_total_size: Option<u64>,
+    pub max_age_days: Option<u64>,
+    // Seconds between prunes
+    #[serde(default = "default_retention_interval")]
+    pub interval: u64,
+}
+
+// Sessions are refused up front when the space they'll need, estimated as multiples of the source's
+// size, isn't free
+#[derive(Debug, Deserialize)]
+pub struct SpaceCheck {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // The split streams plus their fragmented copies
+    #[serde(default = "default_work_factor")]
+    pub work_factor: f64,
+    #[serde(default = "default_processed_factor")]
+    pub processed_factor: f64,
+}
+
+impl Default for SpaceCheck {
+    fn default() -> Self {
+        SpaceCheck {
+            enabled: true,
+            work_factor: default_work_factor(),
+            processed_factor: default_processed_factor(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Packager {
+    // Each stream is encoded separately then fragmented and packaged by mp4fragment and mp4dash
+    Bento4,
+    // A single ffmpeg run encodes and packages everything, so Bento4 doesn't need to be installed
+    Ffmpeg,
+    // Each stream is encoded separately then packaged by Shaka Packager's packager
+    Shaka,
+}
+
+impl Default for Packager {
+    fn default() -> Self {
+        Packager::Bento4
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PostProcess {
+    Keep,
+    // Into dirs.archive
+    Move,
+    Delete,
+}
+
+impl Default for PostProcess {
+    fn default() -> Self {
+        PostProcess::Keep
+    }
+}
+
+// Limits on files sent to the upload endpoint, the extensions also apply to fetched sources
+#[derive(Debug, Deserialize)]
+pub struct Upload {
+    // Bytes per file
+    #[serde(default = "default_upload_max_size")]
+    pub max_size: u64,
+    // Lowercase file extensions which may be uploaded
+    #[serde(default = "default_upload_extensions")]
+    pub extensions: Vec<String>,
+}
+
+// Which URLs sources may be fetched from. The server fetches them itself, so without limits anyone
+// who can process could reach whatever the server can.
+#[derive(Debug, Deserialize, Default)]
+pub struct Fetch {
+    // Only these hosts when any are given
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    // Hosts with loopback, private or link-local addresses are refused unless this is set
+    #[serde(default)]
+    pub allow_private: bool,
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        Upload {
+            max_size: default_upload_max_size(),
+            extensions: default_upload_extensions(),
+        }
+    }
+}
+
+// JSON logs carry the request and session ids as fields for log aggregators
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Webhooks {
+    // Each URL receives a POST when a session finishes
+    #[serde(default)]
+    pub urls: Vec<String>,
+    // When set the body is signed with HMAC-SHA256, sent in the X-Streamin-Signature header
+    pub secret: Option<String>,
+}
+
+// API keys accepted on the API, the API is open when none are configured
+#[derive(Debug, Deserialize, Default)]
+pub struct Auth {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    // Sessions are owned by the key's user, keys without one share their sessions
+    pub user: Option<String>,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+}
+
+// Read keys may only list and watch, process keys may also start and cancel sessions. Admin keys
+// can see and cancel everyone's sessions, and pause the queue. Each scope includes the ones before it.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Process,
+    Admin,
+}
+
+// Browser origins allowed to call the API, "*" allows any. CORS is off when empty.
+#[derive(Debug, Deserialize, Default)]
+pub struct Cors {
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Listen {
+    // Further addresses to listen on, as host:port
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    pub unix_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Grpc {
+    // As host:port, separate from the HTTP listeners as it's served by its own server
+    pub address: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Workers {
+    // Also run sessions here, up to max_sessions, rather than leaving every one to the workers
+    #[serde(default = "default_true")]
+    pub local: bool,
+    // Seconds a worker may go without reporting before its session is taken as interrupted
+    #[serde(default = "default_lost_after")]
+    pub lost_after: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Worker {
+    // An admin key, when the coordinator has keys configured
+    pub api_key: Option<String>,
+    // How the coordinator shows this worker, the host's name by default
+    pub name: Option<String>,
+    // Seconds between asking the coordinator for sessions while it has none
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+impl Default for Worker {
+    fn default() -> Self {
+        Worker {
+            api_key: None,
+            name: None,
+            poll_interval: default_poll_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Queue {
+    #[serde(default)]
+    pub backend: QueueBackend,
+    // Where Redis is, as redis://host:port/db or unix:///path/to/redis.sock
+    pub url: Option<String>,
+    // Put before every key, so separate setups can use the same Redis
+    #[serde(default = "default_queue_prefix")]
+    pub prefix: String,
+    // Which of the instances sharing the queue this is, the host's name by default. Each needs a
+    // name of its own, as it's what the sessions an instance has taken are kept under.
+    pub instance: Option<String>,
+    // Seconds between looking for sessions queued by other instances
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+impl Queue {
+    // This instance's name among those sharing the queue, None when it isn't shared
+    pub fn instance(&self) -> Option<String> {
+        match self.backend {
+            QueueBackend::Memory => None,
+            QueueBackend::Redis => self.instance.clone().or_else(host_name),
+        }
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            backend: QueueBackend::default(),
+            url: None,
+            prefix: default_queue_prefix(),
+            instance: None,
+            poll_interval: default_poll_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    // In the process, lost when it stops
+    Memory,
+    // Shared by every instance using the same Redis and prefix, in builds with the redis-queue feature
+    Redis,
+}
+
+impl Default for QueueBackend {
+    fn default() -> Self {
+        QueueBackend::Memory
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dirs {
+    // One directory or a list of them, uploads and downloads go in the first
+    #[serde(deserialize_with = "one_or_many")]
+    pub unprocessed: Vec<PathBuf>,
+    pub processed: PathBuf,
+    #[serde(default = "default_preview_dir")]
+    pub preview: PathBuf,
+    #[serde(default = "default_archive_dir")]
+    pub archive: PathBuf,
+    // Single MP4s written by the remux mode
+    #[serde(default = "default_mp4_dir")]
+    pub mp4: PathBuf,
+    // Audio extracted by the audio_extract mode, a directory for each source
+    #[serde(default = "default_audio_dir")]
+    pub audio: PathBuf,
+    // Intermediate files, which can be several times the size of the source
+    #[serde(default = "default_work_dir")]
+    pub work: PathBuf,
+    // A log file per session with everything its commands wrote
+    #[serde(default = "default_logs_dir")]
+    pub logs: PathBuf,
+}
+
+fn default_ffmpeg() -> PathBuf {
+    PathBuf::from(Tool::Ffmpeg.name())
+}
+
+fn default_ffprobe() -> PathBuf {
+    PathBuf::from(Tool::Ffprobe.name())
+}
+
+fn default_mp4fragment() -> PathBuf {
+    PathBuf::from(Tool::Mp4fragment.name())
+}
+
+fn default_mp4dash() -> PathBuf {
+    PathBuf::from(Tool::Mp4dash.name())
+}
+
+fn default_packager() -> PathBuf {
+    PathBuf::from(Tool::Packager.name())
+}
+
+// Windows installs have no python3, and whichever Python is installed last is python
+#[cfg(windows)]
+fn default_python() -> PathBuf {
+    PathBuf::from("python")
+}
+
+#[cfg(not(windows))]
+fn default_python() -> PathBuf {
+    PathBuf::from("python3")
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_upload_max_size() -> u64 {
+    100 * 1024 * 1024 * 1024
+}
+
+fn default_upload_extensions() -> Vec<String> {
+    ["mkv", "mp4", "m4v", "mov", "avi", "ts", "webm"].iter().map(|e| e.to_string()).collect()
+}
+
+fn default_retention_interval() -> u64 {
+    60 * 60
+}
+
+fn default_session_log_lines() -> usize {
+    1000
+}
+
+fn default_session_log_days() -> u64 {
+    30
+}
+
+fn default_stable_seconds() -> u64 {
+    30
+}
+
+fn default_temp_extensions() -> Vec<String> {
+    ["part", "!qB", "crdownload", "partial", "tmp"].iter().map(|e| e.to_string()).collect()
+}
+
+fn default_scan_extensions() -> Vec<String> {
+    ["mkv", "mp4", "m4v", "mov", "avi", "ts", "m2ts", "webm", "wmv", "mpg", "mpeg", "flv"].iter().map(|e| e.to_string()).collect()
+}
+
+fn default_probe_cache() -> PathBuf {
+    PathBuf::from("./probe-cache.json")
+}
+
+fn default_runtime_settings() -> PathBuf {
+    PathBuf::from("./runtime-settings.json")
+}
+
+fn default_probe_timeout() -> u64 {
+    60
+}
+
+fn default_drain_timeout() -> u64 {
+    5 * 60
+}
+
+fn default_retry_backoff() -> u64 {
+    30
+}
+
+fn default_stall_timeout() -> u64 {
+    15 * 60
+}
+
+fn default_stage_parallelism() -> usize {
+    2
+}
+
+fn default_work_factor() -> f64 {
+    2.0
+}
+
+fn default_processed_factor() -> f64 {
+    1.0
+}
+
+fn default_lost_after() -> u64 {
+    60
+}
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+fn default_queue_prefix() -> String {
+    "streamin-conv".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_scope() -> Scope {
+    Scope::Read
+}
+
+fn default_preview_dir() -> PathBuf {
+    PathBuf::from("./preview")
+}
+
+fn default_archive_dir() -> PathBuf {
+    PathBuf::from("./archive")
+}
+
+fn default_mp4_dir() -> PathBuf {
+    PathBuf::from("./mp4")
+}
+
+fn default_audio_dir() -> PathBuf {
+    PathBuf::from("./audio")
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(p) => vec![p],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+fn default_manifest_name() -> String {
+    mp4dash::MANIFEST.to_string()
+}
+
+fn default_package_template() -> String {
+    "{stem}".to_string()
+}
+
+fn default_work_dir() -> PathBuf {
+    std::env::temp_dir().join("streamin-conv")
+}
+
+fn default_logs_dir() -> PathBuf {
+    PathBuf::from("./logs")
+}
+
+#[cfg(not(test))]
+pub const CONFIG_FILE: &str = "config.yaml";
+// Tests run from core, and use the example config
+#[cfg(test)]
+pub const CONFIG_FILE: &str = "../config.yaml";
+
+lazy_static! {
+    // Settings given on the command line, which take the place of the config's and the environment's
+    static ref OVERRIDES: RwLock<Vec<(&'static str, String)>> = RwLock::new(vec![]);
+}
+
+// Must come before anything reads SETTINGS to apply to it
+pub fn set_override(key: &'static str, value: String) {
+    OVERRIDES.write().unwrap().push((key, value));
+}
+
+// Settings without defaults, looked for before reading the rest so they're all reported at once
+const REQUIRED: [&str; 3] = ["port", "dirs.unprocessed", "dirs.processed"];
+
+// Everything wrong with the config, so it can all be fixed before trying again
+#[derive(Debug, Display, From)]
+pub enum SettingsError {
+    #[display(fmt = "Could not read the config: {}", _0)]
+    Config(ConfigError),
+    #[display(fmt = "The config has problems:{}", "list(_0)")]
+    #[from(ignore)]
+    Invalid(Vec<String>),
+}
+
+fn list(problems: &[String]) -> String {
+    problems.iter().map(|p| format!("\n  - {}", p)).collect()
+}
+
+impl Settings {
+    pub fn new() -> Result<Self, SettingsError> {
+        let mut s = Config::new();
+
+        // Start off by merging in the "default" configuration file
+        s.merge(File::with_name(CONFIG_FILE))?;
+
+        // Add in settings from the environment (with a prefix of APP)
+        // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
+        s.merge(Environment::with_prefix("streamin"))?;
+        for (key, value) in OVERRIDES.read().unwrap().iter() {
+            s.set(key, value.as_str())?;
+        }
+
+        let missing: Vec<_> = REQUIRED.iter()
+            .filter(|k| s.get::<Value>(k).is_err())
+            .map(|k| format!("{} is missing", k))
+            .collect();
+        if !missing.is_empty() {
+            return Err(SettingsError::Invalid(missing));
+        }
+
+        let raw = s.clone().try_into()?;
+        // You can deserialize (and thus freeze) the entire configuration as
+        let mut settings: Settings = s.try_into()?;
+        settings.raw = raw;
+        Ok(settings)
+    }
+
+    // Checks the settings against the machine we're running on before anything is started. The work
+    // and log dirs are created if they don't exist.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let mut problems = self.profile_problems();
+
+        if self.dirs.unprocessed.is_empty() {
+            problems.push("dirs.unprocessed needs at least one directory".to_string());
+        }
+        for dir in &self.dirs.unprocessed {
+            if let Err(e) = std::fs::read_dir(dir) {
+                problems.push(format!("dirs.unprocessed {:?} can't be read: {}", dir, e));
+            }
+        }
+        if let Err(e) = writable(&self.dirs.processed) {
+            problems.push(format!("dirs.processed {:?} can't be written to: {}", self.dirs.processed, e));
+        }
+        for (name, dir) in [("dirs.work", &self.dirs.work), ("dirs.logs", &self.dirs.logs)].iter() {
+            if let Err(e) = std::fs::create_dir_all(dir).and_then(|_| writable(dir)) {
+                problems.push(format!("{} {:?} can't be written to: {}", name, dir, e));
+            }
+        }
+        // Packages written among the sources would be taken for sources themselves
+        if let Ok(processed) = self.dirs.processed.canonicalize() {
+            for dir in &self.dirs.unprocessed {
+                if dir.canonicalize().map_or(false, |d| d == processed) {
+                    problems.push(format!("dirs.processed is the same directory as dirs.unprocessed {:?}", dir));
+                }
+            }
+        }
+
+        if let Some(grpc) = &self.grpc {
+            if !cfg!(feature = "grpc") {
+                problems.push("grpc is set but this build doesn't include it, build with --features grpc".to_string());
+            }
+            if let Err(e) = grpc.address.to_socket_addrs() {
+                problems.push(format!("grpc.address {} isn't a host:port: {}", grpc.address, e));
+            }
+        }
+        if self.workers.as_ref().map_or(false, |w| w.lost_after == 0) {
+            problems.push("workers.lost_after needs to be at least 1".to_string());
+        }
+        if self.worker.poll_interval == 0 {
+            problems.push("worker.poll_interval needs to be at least 1".to_string());
+        }
+        if self.queue.backend == QueueBackend::Redis {
+            if !cfg!(feature = "redis-queue") {
+                problems.push("queue.backend is redis but this build doesn't include it, build with --features redis-queue".to_string());
+            }
+            match &self.queue.url {
+                None => problems.push("queue.url is needed for the redis backend".to_string()),
+                #[cfg(feature = "redis-queue")]
+                Some(url) => if let Err(e) = redis::Client::open(url.as_str()) {
+                    problems.push(format!("queue.url {} can't be used: {}", url, e));
+                },
+                #[cfg(not(feature = "redis-queue"))]
+                Some(_) => (),
+            }
+            match self.queue.instance() {
+                None => problems.push("queue.instance is needed as the host's name can't be found".to_string()),
+                // It names the instance's work directory
+                Some(i) if !plain_name(&i) => problems.push(format!("queue.instance {} can only be letters, digits, '-', '_' and '.'", i)),
+                Some(_) => (),
+            }
+        }
+        if self.queue.poll_interval == 0 {
+            problems.push("queue.poll_interval needs to be at least 1".to_string());
+        }
+
+        for tool in self.tools_needed() {
+            let path = self.tools.path(tool);
+            if find_program(path).is_none() {
+                problems.push(format!("{} can't be found at tools.{}, {:?}", tool.name(), tool.name(), path));
+            }
+            if tool == Tool::Mp4dash && mp4dash::script(&self.tools).is_some() && find_program(&self.tools.python).is_none() {
+                problems.push(format!("mp4dash is a Python script but Python can't be found at tools.python, {:?}", self.tools.python));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+
+    // Problems with the profiles, which unlike the rest are checked again when the config is reloaded
+    pub fn profile_problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut names: Vec<_> = self.profiles.keys().collect();
+        names.sort();
+        for name in names {
+            if let Err(e) = self.profiles[name].validate() {
+                problems.push(format!("The profile {} is invalid: {}", name, e));
+            }
+        }
+        for (setting, profile) in [("default_profile", &self.default_profile), ("auto_process.profile", &self.auto_process.profile)].iter() {
+            if let Some(p) = profile {
+                if !self.profiles.contains_key(p) {
+                    problems.push(format!("{} is {}, which isn't one of the profiles", setting, p));
+                }
+            }
+        }
+        problems
+    }
+
+    // The tools sessions may run, given the packagers which are configured
+    fn tools_needed(&self) -> Vec<Tool> {
+        let mut tools = vec![Tool::Ffmpeg, Tool::Ffprobe];
+        let packagers = std::iter::once(self.packager).chain(self.profiles.values().filter_map(|o| o.packager));
+        for packager in packagers {
+            let needed: &[Tool] = match packager {
+                Packager::Bento4 => &[Tool::Mp4fragment, Tool::Mp4dash],
+                Packager::Shaka => &[Tool::Packager],
+                Packager::Ffmpeg => &[],
+            };
+            for t in needed {
+                if !tools.contains(t) {
+                    tools.push(*t);
+                }
+            }
+        }
+        tools
+    }
+}
+
+fn writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".streamin-conv-{}", Uuid::new_v4()));
+    std::fs::File::create(&probe)?;
+    std::fs::remove_file(probe)
+}
+
+fn plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+// What worker.name and queue.instance default to. HOSTNAME is often set by the shell without being
+// exported, so on Linux it's also read from where it's kept.
+pub fn host_name() -> Option<String> {
+    std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")).ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+// A Jellyfin or Plex server to rescan once a title has been processed
+#[derive(Debug, Deserialize)]
+pub struct MediaServer {
+    pub kind: MediaServerKind,
+    pub url: String,
+    pub token: String,
+    // Plex library section to refresh, all sections when unset
+    pub section: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaServerKind {
+    Jellyfin,
+    Plex,
+}
+
+// Chat and push services told when sessions finish, each is enabled by being configured
+#[derive(Debug, Deserialize, Default)]
+pub struct Notifiers {
+    pub discord: Option<Discord>,
+    pub telegram: Option<Telegram>,
+    pub gotify: Option<Gotify>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Discord {
+    pub webhook_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Telegram {
+    pub bot_token: String,
+    pub chat_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Gotify {
+    pub url: String,
+    pub token: String,
+    pub priority: Option<i64>,
+}
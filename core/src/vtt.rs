@@ -0,0 +1,57 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::commands::ffprobe::Chapter;
+
+// Formats a duration as a WebVTT cue timestamp, hh:mm:ss.ttt
+pub fn timestamp(d: Duration) -> String {
+    let ms = d.as_millis();
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+// Parses either plain seconds or a colon separated [[hh:]mm:]ss.fff timestamp
+pub fn parse_timestamp(t: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in t.split(':') {
+        let v: f64 = part.parse().ok()?;
+        if v < 0.0 {
+            return None;
+        }
+        secs = secs * 60.0 + v;
+    }
+    secs.is_finite().then(|| Duration::from_secs_f64(secs))
+}
+
+// Builds a WebVTT chapters track, untitled chapters are numbered
+pub fn chapters(chapters: &[Chapter]) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for (i, c) in chapters.iter().enumerate() {
+        let start = c.start_time.parse().map(Duration::from_secs_f64).unwrap_or_default();
+        let end = c.end_time.parse().map(Duration::from_secs_f64).unwrap_or_default();
+        let title = c.tags.as_ref()
+            .and_then(|t| t.title.clone())
+            .unwrap_or_else(|| format!("Chapter {}", i + 1));
+
+        write!(out, "\n{}\n{} --> {}\n{}\n", i + 1, timestamp(start), timestamp(end), title).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::vtt::{parse_timestamp, timestamp};
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(Duration::from_millis(3_723_004)), "01:02:03.004");
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("90.5"), Some(Duration::from_millis(90_500)));
+        assert_eq!(parse_timestamp("01:02:03.004000000"), Some(Duration::from_millis(3_723_004)));
+        assert_eq!(parse_timestamp("-1"), None);
+    }
+}
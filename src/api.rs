@@ -0,0 +1,95 @@
+use actix_web::{dev, FromRequest, HttpRequest, Scope, web};
+use futures::future::{ready, Ready};
+
+use crate::{media, probe_cache, queue, retention, runtime, worker};
+
+// The API is served under each version's prefix. Changes to what it takes or gives back which
+// would break clients are only made in the newest version, handlers telling which one they're
+// serving with ApiVersion.
+pub const V1: &str = "/api/v1";
+// The paths from before there were versions, which keep behaving as they did for existing clients
+pub const LEGACY: &str = "/api/conv";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    Legacy,
+    V1,
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::Legacy];
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::Legacy => LEGACY,
+            ApiVersion::V1 => V1,
+        }
+    }
+
+    // The version a request's path is under, with the rest of the path after the prefix. Taken from
+    // the path as routing sees it, percent-encoding decoded, so it can't differ from the route run.
+    // The whole path, as what Path::path gives shrinks as scopes are matched.
+    pub fn of(path: &dev::Path<dev::Url>) -> Option<(ApiVersion, &str)> {
+        ApiVersion::ALL.iter().find_map(|v| {
+            let rest = path.get_ref().path().strip_prefix(v.prefix())?;
+            (rest.is_empty() || rest.starts_with('/')).then(|| (*v, rest))
+        })
+    }
+}
+
+impl FromRequest for ApiVersion {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(ApiVersion::of(req.match_info())
+            .map(|(v, _)| v)
+            .ok_or_else(|| actix_web::error::ErrorNotFound("Not an API path")))
+    }
+}
+
+// Every API route, under the version's prefix
+pub fn scope(version: ApiVersion) -> Scope {
+    web::scope(version.prefix())
+        .service(media::unprocessed)
+        .service(media::search)
+        .service(media::media_detail)
+        .service(media::processed)
+        .service(media::delete_processed)
+        .service(media::process)
+        .service(media::plan)
+        .service(media::upload)
+        .service(media::fetch)
+        .service(media::get_session)
+        .service(media::cancel_session)
+        .service(media::session_logs)
+        .service(media::all_sessions)
+        .service(media::session_events)
+        .service(retention::preview)
+        .service(queue::get_queue)
+        .service(queue::pause)
+        .service(queue::resume)
+        .service(runtime::get_settings)
+        .service(runtime::update_settings)
+        .service(runtime::reset_settings)
+        .service(runtime::reload_config)
+        .service(probe_cache::invalidate)
+        .service(worker::claim)
+        .service(worker::report)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::ApiVersion;
+
+    #[test]
+    fn within_scope() {
+        let mut req = TestRequest::with_uri("/api/v1/fetch").to_srv_request();
+        // As routing leaves it once the version's scope has matched
+        req.match_info_mut().skip(super::V1.len() as u16);
+        assert_eq!(ApiVersion::of(req.match_info()), Some((ApiVersion::V1, "/fetch")));
+    }
+}
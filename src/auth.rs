@@ -0,0 +1,112 @@
+use actix_web::dev::{Path, RequestHead, ServiceRequest, Url};
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::http::{header, Method};
+use actix_web::HttpMessage;
+use subtle::ConstantTimeEq;
+
+use crate::api::ApiVersion;
+use crate::settings::{ApiKey, Scope};
+use crate::SETTINGS;
+
+// Relative to the API prefix, which is the same for every version
+// Workers are handed the commands to run, so are trusted as admins are
+const ADMIN_PREFIXES: [&str; 4] = ["/queue/", "/settings", "/config/", "/worker/"];
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+// Checks the caller may use the route, attaching the matching key to the request.
+// Everything is open when no keys are configured.
+pub fn authorise(req: &ServiceRequest) -> Result<(), actix_web::Error> {
+    let scope = match required_scope(req.method(), req.match_info()) {
+        Some(scope) => scope,
+        None => return Ok(()),
+    };
+
+    let key = check(presented_key(req.head()), scope)?;
+    if let Some(key) = key {
+        req.extensions_mut().insert::<ApiKey>(key.clone());
+    }
+    Ok(())
+}
+
+// The configured key matching the one given, when it allows scope. None when no keys are configured.
+pub fn check(given: Option<&str>, scope: Scope) -> Result<Option<&'static ApiKey>, actix_web::Error> {
+    let keys = &SETTINGS.auth.keys;
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    let given = given.ok_or_else(|| ErrorUnauthorized("Missing API key"))?;
+    // Every key is compared, in constant time, so how long it takes doesn't give away how much of a
+    // key was right or which it was
+    let key = keys.iter()
+        .fold(None, |found, k| if bool::from(k.key.as_bytes().ct_eq(given.as_bytes())) { Some(k) } else { found })
+        .ok_or_else(|| ErrorUnauthorized("Invalid API key"))?;
+
+    match scope {
+        Scope::Admin if key.scope < Scope::Admin => Err(ErrorForbidden("API key isn't an admin key")),
+        scope if key.scope < scope => Err(ErrorForbidden("API key is read-only")),
+        _ => Ok(Some(key)),
+    }
+}
+
+// Whether the caller may see and control a session belonging to owner. Without auth everyone can.
+pub fn can_access(key: Option<&ApiKey>, owner: Option<&str>) -> bool {
+    match key {
+        Some(key) => key.scope == Scope::Admin || key.user.as_deref() == owner,
+        None => true,
+    }
+}
+
+// Accepts either "Authorization: Bearer <key>" or "X-Api-Key: <key>"
+fn presented_key(head: &RequestHead) -> Option<&str> {
+    if let Some(key) = head.headers.get(API_KEY_HEADER) {
+        return key.to_str().ok();
+    }
+    head.headers.get(header::AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// None for paths outside the API. Going by the decoded path routes are matched against, as
+// "/%61pi/v1/..." reaches the same handler as "/api/v1/...".
+fn required_scope(method: &Method, path: &Path<Url>) -> Option<Scope> {
+    let (_, path) = ApiVersion::of(path)?;
+    Some(match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => Scope::Read,
+        // Pausing the queue and changing settings affects everyone's sessions
+        _ if ADMIN_PREFIXES.iter().any(|p| path.starts_with(p)) => Scope::Admin,
+        _ => Scope::Process,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::Method;
+    use actix_web::test::TestRequest;
+
+    use crate::settings::Scope;
+
+    use super::required_scope;
+
+    fn scope_of(method: Method, uri: &str) -> Option<Scope> {
+        let req = TestRequest::with_uri(uri).method(method).to_srv_request();
+        required_scope(req.method(), req.match_info())
+    }
+
+    #[test]
+    fn encoded_paths() {
+        assert_eq!(scope_of(Method::POST, "/api/v1/process"), Some(Scope::Process));
+        assert_eq!(scope_of(Method::POST, "/%61pi/v1/process"), Some(Scope::Process));
+        assert_eq!(scope_of(Method::POST, "/api/%76%31/process"), Some(Scope::Process));
+        assert_eq!(scope_of(Method::GET, "/%61pi/conv/session"), Some(Scope::Read));
+
+        assert_eq!(scope_of(Method::POST, "/api/v1/queue/pause"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::POST, "/api/v1/%71ueue/pause"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::POST, "/api/v1/%73ettings"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::DELETE, "/%61pi/conv/%73ettings"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::POST, "/api/v1/%77orker/claim"), Some(Scope::Admin));
+
+        assert_eq!(scope_of(Method::POST, "/metrics"), None);
+        assert_eq!(scope_of(Method::POST, "/apis/v1/process"), None);
+    }
+}
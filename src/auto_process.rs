@@ -0,0 +1,92 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast::RecvError;
+use tracing::{error, info, warn};
+
+use crate::{dash, runtime, SETTINGS};
+use crate::library::{Filter, Library};
+use crate::commands::MediaInfo;
+use crate::media::Sessions;
+
+// Packages new files as they appear in the unprocessed directories, one at a time so a large drop doesn't start
+// every session at once
+pub async fn run(library: Arc<Library>, state: Arc<Sessions>) {
+    let auto = &SETTINGS.auto_process;
+    if !auto.enabled {
+        return;
+    }
+    // Only warned about here, as the config may be reloaded with the profile fixed
+    if let Err(e) = overrides(auto.profile.as_deref()) {
+        error!("Files won't be auto processed until the config is fixed: {}", e);
+    }
+    let filter = match Filter::new(&auto.globs) {
+        Ok(f) => f,
+        Err(e) => return error!("Auto processing is off, bad glob: {}", e),
+    };
+
+    let mut added = library.subscribe();
+    loop {
+        let file = match added.recv().await {
+            Ok(f) => f,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Auto processing missed {} new files", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if !filter.matches(&file) {
+            continue;
+        }
+        let overrides = match overrides(auto.profile.as_deref()) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Not auto processing {:?}: {}", file, e);
+                continue;
+            }
+        };
+        let info = match MediaInfo::get(&file).await {
+            Ok(i) => i,
+            Err(e) => {
+                error!("Could not auto process {:?}: {}", file, e);
+                continue;
+            }
+        };
+        let package = dash::package_dir(&info, &overrides);
+        if package.exists() || state.writing_to(&package).is_some() {
+            continue;
+        }
+
+        info!("Auto processing {:?}", file);
+        match dash::exec_dash_conv(state.clone(), vec![file.clone()], &overrides, None).await {
+            Ok(id) => wait_for(&state, &id).await,
+            Err(e) => error!("Could not auto process {:?}: {}", file, e),
+        }
+    }
+}
+
+// Looked up for each file, so profiles and the default profile follow reloads and runtime settings
+fn overrides(profile: Option<&str>) -> Result<dash::Overrides, String> {
+    let overrides = match profile {
+        Some(p) => runtime::config().profiles.get(p).cloned().ok_or_else(|| format!("the profile {} doesn't exist", p))?,
+        None => dash::Overrides::default(),
+    };
+    let overrides = runtime::with_defaults(&overrides);
+    overrides.validate().map_err(|e| format!("the profile is invalid: {}", e))?;
+    Ok(overrides)
+}
+
+async fn wait_for(state: &Sessions, id: &str) {
+    let id = match uuid::Uuid::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+    loop {
+        let running = state.sessions.read().get(&id).map_or(false, |s| s.get_info().running());
+        if !running {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, stream, StreamExt};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::commands::SessionEvent;
+use crate::dash::{self, Overrides};
+use crate::media::Sessions;
+use crate::runtime;
+use crate::settings::{self, PostProcess};
+use crate::worker;
+
+pub const USAGE: &str = "Usage: streamin-conv [convert <file> [--profile <name>] [--out <dir>] | --worker <coordinator url>]
+With no command the server is started.";
+
+pub enum Command {
+    // Packages a file as the API would, without starting the server, for scripts and for checking
+    // an install works
+    Convert { file: PathBuf, profile: Option<String> },
+    // Runs sessions for another instance rather than serving the API, see worker
+    Worker { coordinator: String },
+}
+
+// The command given, None to run the server. Settings given as options are applied straight away,
+// so this has to come before anything reads SETTINGS.
+pub fn command() -> Result<Option<Command>, String> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => Ok(None),
+        Some("convert") => {
+            let mut file = None;
+            let mut profile = None;
+            while let Some(arg) = args.next() {
+                let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+                match arg.as_str() {
+                    "--profile" => profile = Some(value()?),
+                    "--out" => settings::set_override("dirs.processed", value()?),
+                    _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                    _ if file.is_none() => file = Some(PathBuf::from(arg)),
+                    _ => return Err("Only one file can be converted".to_string()),
+                }
+            }
+            let file = file.ok_or("convert needs a file")?;
+            Ok(Some(Command::Convert { file, profile }))
+        }
+        Some("--worker") => {
+            let coordinator = args.next().ok_or("--worker needs the coordinator's URL")?;
+            if let Some(extra) = args.next() {
+                return Err(format!("Unexpected {}", extra));
+            }
+            Ok(Some(Command::Worker { coordinator }))
+        }
+        Some("-h") | Some("--help") | Some("help") => {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        Some(other) => Err(format!("Unknown command {}", other)),
+    }
+}
+
+// Runs the command, giving the exit code
+pub async fn run(command: Command) -> i32 {
+    // Logs go to stderr along with the progress, keeping stdout for the package's path
+    tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env()).with_writer(io::stderr).init();
+    let res = match command {
+        Command::Convert { file, profile } => convert(file, profile).await,
+        Command::Worker { coordinator } => worker::run(coordinator).await,
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+async fn convert(file: PathBuf, profile: Option<String>) -> Result<(), String> {
+    let file = file.canonicalize().map_err(|e| format!("{:?} can't be read: {}", file, e))?;
+    let overrides = match &profile {
+        Some(p) => runtime::config().profiles.get(p).cloned().ok_or_else(|| format!("Unknown profile: {}", p))?,
+        None => Overrides::default(),
+    };
+    let mut overrides = runtime::with_defaults(&overrides);
+    // The file was given by hand rather than picked up from the unprocessed directories, so is
+    // left where it is whatever the profile says
+    overrides.post_process = Some(PostProcess::Keep);
+    overrides.validate()?;
+
+    let state = Arc::new(Sessions::new());
+    let events = state.events.subscribe();
+    let id = dash::exec_dash_conv(state.clone(), vec![file], &overrides, None).await.map_err(|e| e.to_string())?;
+    let id = Uuid::parse_str(&id).map_err(|e| e.to_string())?;
+    let stages = state.sessions.read()[&id].stage_names().to_vec();
+
+    // Events are only looked at to tell when it's finished, the progress is shown every second
+    let events = events.filter_map(|e| future::ready(e.ok())).map(Some);
+    let ticks = tokio::time::interval(Duration::from_secs(1)).map(|_| None);
+    let mut updates = stream::select(events, ticks);
+    while let Some(update) = updates.next().await {
+        let info = state.sessions.read()[&id].get_info();
+        let stage = stages.get(info.stage().saturating_sub(1)).map_or("", String::as_str);
+        // A stage which can't tell how far through it is would look stalled
+        let percent = if info.indeterminate() { "busy".to_string() } else { format!("{:.1}%", info.percent_complete()) };
+        eprint!("\r[{}/{}] {:<24} {:>6}", info.stage(), info.max_stages(), stage, percent);
+        io::stderr().flush();
+        match update {
+            Some(SessionEvent::Completed { id: done }) if done == id => {
+                eprintln!();
+                let sessions = state.sessions.read();
+                if let Some(out) = sessions[&id].output_dir() {
+                    println!("{}", out.display());
+                }
+                return Ok(());
+            }
+            Some(SessionEvent::Failed { id: done }) if done == id => {
+                eprintln!();
+                return Err(format!("Failed: {}", info.error().unwrap_or("see the session's log in dirs.logs")));
+            }
+            Some(SessionEvent::Cancelled { id: done }) | Some(SessionEvent::Interrupted { id: done }) if done == id => {
+                eprintln!();
+                return Err("Stopped before it finished".to_string());
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
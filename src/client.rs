@@ -0,0 +1,15 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::client::{Client, Connector};
+
+// An HTTP client able to talk to HTTPS endpoints using the bundled root certificates
+pub fn new() -> Client {
+    let mut config = rustls::ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    Client::builder()
+        .connector(Connector::new().rustls(Arc::new(config)).finish())
+        .timeout(Duration::from_secs(30))
+        .finish()
+}
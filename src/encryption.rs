@@ -0,0 +1,55 @@
+use actix_web::{HttpResponse, post, web};
+use serde::{Deserialize, Serialize};
+
+use crate::SETTINGS;
+
+pub use streamin_core::encryption::*;
+
+// A license request from a ClearKey player, as in the W3C Encrypted Media Extensions spec
+#[derive(Deserialize)]
+struct LicenseReq {
+    // Base64url key ids
+    kids: Vec<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Serialize)]
+struct License {
+    keys: Vec<JsonWebKey>,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Serialize)]
+struct JsonWebKey {
+    kty: &'static str,
+    kid: String,
+    k: String,
+}
+
+// Hands out the ClearKey keys in the settings. Players can't be given API keys so this is outside
+// the API, which is fine for what ClearKey is for.
+#[post("/clearkey/license")]
+pub async fn clearkey_license(body: web::Bytes) -> Result<HttpResponse, actix_web::Error> {
+    // Players don't always say the body is JSON
+    let req: LicenseReq = serde_json::from_slice(&body).map_err(actix_web::error::ErrorBadRequest)?;
+    let b64 = |bytes: Vec<u8>| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let keys: Vec<_> = req.kids.iter().filter_map(|kid| {
+        let id = base64::decode_config(kid, base64::URL_SAFE_NO_PAD).ok()?;
+        let pair = SETTINGS.clearkey.keys.values()
+            .find(|k| hex::decode(&k.key_id).map_or(false, |k| k == id))?;
+        Some(JsonWebKey {
+            kty: "oct",
+            kid: b64(id),
+            k: b64(hex::decode(&pair.key).ok()?),
+        })
+    }).collect();
+    if keys.is_empty() {
+        return Err(actix_web::error::ErrorNotFound("None of the keys are known"));
+    }
+    Ok(HttpResponse::Ok().json(License {
+        keys,
+        kind: req.kind.clone().unwrap_or_else(|| "temporary".to_string()),
+    }))
+}
@@ -0,0 +1,101 @@
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+use actix_web::error::{ErrorBadGateway, ErrorBadRequest, ErrorForbidden};
+use actix_web::http::header;
+use actix_web::web;
+use futures::TryStreamExt;
+use url::Url;
+
+use crate::{client, SETTINGS};
+
+// Redirects followed before giving up on a download
+const MAX_REDIRECTS: usize = 5;
+
+// Downloads url into file, which mustn't exist yet. Every URL on the way, redirects included, is
+// checked and then connected to at the address which was checked, so neither a redirect nor the host
+// resolving differently a second time can reach anywhere check wouldn't allow.
+pub async fn download(url: &str, file: &Path) -> Result<(), actix_web::Error> {
+    let client = client::new();
+    let mut url = Url::parse(url).map_err(|e| ErrorBadRequest(format!("The URL is invalid: {}", e)))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = check(&url).await?;
+        let mut res = client.get(url.as_str())
+            .address(addr)
+            .send()
+            .await
+            .map_err(|e| ErrorBadGateway(format!("Could not fetch {}: {}", url, e)))?;
+        if res.status().is_redirection() {
+            let location = res.headers().get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or_else(|| ErrorBadGateway(format!("{} redirected without a location", url)))?;
+            url = url.join(location).map_err(|e| ErrorBadGateway(format!("{} redirected to an invalid URL: {}", url, e)))?;
+            continue;
+        }
+        if !res.status().is_success() {
+            return Err(ErrorBadGateway(format!("{} answered {}", url, res.status())));
+        }
+
+        let mut f = web::block({
+            let file = file.to_path_buf();
+            move || std::fs::OpenOptions::new().write(true).create_new(true).open(file)
+        }).await?;
+        while let Some(chunk) = res.try_next().await.map_err(|e| ErrorBadGateway(format!("Could not fetch {}: {}", url, e)))? {
+            f = web::block(move || f.write_all(&chunk).map(|_| f)).await?;
+        }
+        return Ok(());
+    }
+    Err(ErrorBadGateway(format!("Gave up fetching after {} redirects", MAX_REDIRECTS)))
+}
+
+// Only http and https URLs, to hosts which are allowed, giving the address to connect to
+async fn check(url: &Url) -> Result<SocketAddr, actix_web::Error> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ErrorBadRequest("Only http and https URLs can be fetched"));
+    }
+    // IPv6 addresses are in brackets in URLs
+    let host = url.host_str().ok_or_else(|| ErrorBadRequest("The URL has no host"))?
+        .trim_start_matches('[').trim_end_matches(']').to_string();
+    let limits = &SETTINGS.fetch;
+    let allowed = limits.allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(&host));
+    if !limits.allowed_hosts.is_empty() && !allowed {
+        return Err(ErrorForbidden(format!("{} isn't one of fetch.allowed_hosts", host)));
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = web::block(move || (host.as_str(), port).to_socket_addrs().map(|a| a.collect::<Vec<_>>()))
+        .await
+        .map_err(|e| ErrorBadRequest(format!("The URL's host can't be found: {}", e)))?;
+    if !allowed && !limits.allow_private && addrs.iter().any(|a| is_private(a.ip())) {
+        return Err(ErrorForbidden("Sources can't be fetched from private addresses, see fetch in the config"));
+    }
+    addrs.into_iter().next().ok_or_else(|| ErrorBadRequest("The URL's host has no addresses"))
+}
+
+// Whatever isn't on the public internet, so can only be reached from where the server is
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation()
+                // Shared address space, used by carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4() {
+                // Mapped and compatible addresses reach the IPv4 address, :: and ::1 aside
+                if !ip.is_loopback() && !ip.is_unspecified() {
+                    return is_private(IpAddr::V4(v4));
+                }
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                // Unique local, fc00::/7, and link-local, fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
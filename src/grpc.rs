@@ -0,0 +1,223 @@
+use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use futures::{future, stream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth;
+use crate::commands::{MediaInfo, SessionEvent, SessionInfo};
+use crate::library::Library;
+use crate::media::{self, ProcessReq, Sessions, Started};
+use crate::settings::{ApiKey, Scope};
+use crate::SETTINGS;
+
+use pb::conv_server::{Conv, ConvServer};
+
+mod pb {
+    tonic::include_proto!("conv.v1");
+}
+
+// The gRPC interface from proto/conv.proto, served alongside the HTTP API when grpc is configured.
+// It goes through the same code as the HTTP API so the two can't drift apart.
+pub async fn serve(state: Arc<Sessions>, library: Arc<Library>) {
+    let grpc = match &SETTINGS.grpc {
+        Some(grpc) => grpc,
+        None => return,
+    };
+    // Settings::validate has made sure it resolves
+    let addr = match grpc.address.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        Some(addr) => addr,
+        None => return error!("grpc.address {} can't be resolved", grpc.address),
+    };
+    info!("Serving gRPC on {}", addr);
+    let service = ConvServer::new(Service { state, library });
+    if let Err(e) = Server::builder().add_service(service).serve(addr).await {
+        error!("The gRPC server stopped: {}", e);
+    }
+}
+
+struct Service {
+    state: Arc<Sessions>,
+    library: Arc<Library>,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<pb::SessionEvent, Status>> + Send + Sync>>;
+
+#[tonic::async_trait]
+impl Conv for Service {
+    async fn list_media(&self, request: Request<pb::ListMediaRequest>) -> Result<Response<pb::ListMediaResponse>, Status> {
+        authorise(&request, Scope::Read)?;
+        let mut media = media::unprocessed_media(&self.library);
+        media.sort_by(|a, b| a.file_title.cmp(&b.file_title));
+        Ok(Response::new(pb::ListMediaResponse {
+            media: media.into_iter().map(pb::Media::from).collect(),
+        }))
+    }
+
+    async fn start_session(&self, request: Request<pb::StartSessionRequest>) -> Result<Response<pb::StartSessionResponse>, Status> {
+        let key = authorise(&request, Scope::Process)?;
+        let req: ProcessReq = serde_json::from_str(&request.get_ref().process_json)
+            .map_err(|e| Status::invalid_argument(format!("process_json is invalid: {}", e)))?;
+        let mut res = pb::StartSessionResponse::default();
+        match media::start(req, key, &self.state, &self.library).await.map_err(status)? {
+            Started::Session(id) => res.session_ids = vec![id],
+            Started::Sessions(batch) => {
+                res.session_ids = batch.ids;
+                res.failed = batch.failed.into_iter().map(|(id, e)| pb::NotStarted { id, error: e.to_string() }).collect();
+            }
+            Started::Running(id) => {
+                res.session_ids = vec![id];
+                res.already_running = true;
+            }
+            Started::Refreshed(media) => res.refreshed = media.into_iter().map(pb::Media::from).collect(),
+        }
+        Ok(Response::new(res))
+    }
+
+    type WatchSessionsStream = EventStream;
+
+    async fn watch_sessions(&self, request: Request<pb::WatchSessionsRequest>) -> Result<Response<EventStream>, Status> {
+        let key = authorise(&request, Scope::Read)?;
+        let req = request.into_inner();
+        let only = match req.session_id.as_str() {
+            "" => None,
+            id => Some(Uuid::parse_str(id).map_err(|_| Status::invalid_argument("session_id isn't a session id"))?),
+        };
+        if let Some(id) = only {
+            if !visible(&self.state, &id, only, key) {
+                return Err(Status::not_found("No such session"));
+            }
+        }
+
+        let lifecycle_state = self.state.clone();
+        let lifecycle = self.state.events.subscribe()
+            .filter_map(|e| future::ready(e.ok()))
+            .filter(move |e| future::ready(visible(&lifecycle_state, &e.id(), only, key)))
+            .map(|e| vec![e]);
+
+        // A single session's progress is sent even once it has finished, which ends the stream
+        let progress_state = self.state.clone();
+        let interval = Duration::from_secs(if req.interval_seconds == 0 { 5 } else { req.interval_seconds });
+        let progress = tokio::time::interval(interval)
+            .map(move |_| {
+                progress_state.sessions.read()
+                    .iter()
+                    .filter(|(id, s)| only == Some(**id) || (only.is_none() && auth::can_access(key, s.get_owner())))
+                    .map(|(_, s)| s.get_info())
+                    .filter(|i| only.is_some() || i.running())
+                    .map(SessionEvent::Progress)
+                    .collect::<Vec<_>>()
+            });
+
+        let events = stream::select(lifecycle, progress)
+            .flat_map(stream::iter)
+            .scan(false, move |done, e| {
+                if *done {
+                    return future::ready(None);
+                }
+                *done = only.is_some() && finished(&e);
+                future::ready(Some(e))
+            })
+            .map(|e| Ok(pb::SessionEvent::from(e)));
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+// As auth::authorise, with the key in the metadata rather than the headers
+fn authorise<T>(request: &Request<T>, scope: Scope) -> Result<Option<&'static ApiKey>, Status> {
+    let metadata = request.metadata();
+    let given = match metadata.get("x-api-key") {
+        Some(key) => key.to_str().ok(),
+        None => metadata.get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim),
+    };
+    auth::check(given, scope).map_err(status)
+}
+
+fn visible(state: &Sessions, id: &Uuid, only: Option<Uuid>, key: Option<&ApiKey>) -> bool {
+    only.map_or(true, |o| o == *id) && state.sessions.read()
+        .get(id)
+        .map_or(false, |s| auth::can_access(key, s.get_owner()))
+}
+
+fn finished(e: &SessionEvent) -> bool {
+    match e {
+        SessionEvent::Created { .. } | SessionEvent::Stage { .. } => false,
+        SessionEvent::Progress(info) => !info.running(),
+        _ => true,
+    }
+}
+
+// The code closest to the status the HTTP API would have answered with
+fn status(e: actix_web::Error) -> Status {
+    let message = e.to_string();
+    match e.as_response_error().status_code() {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::INSUFFICIENT_STORAGE => Status::resource_exhausted(message),
+        _ => Status::internal(message),
+    }
+}
+
+impl From<MediaInfo> for pb::Media {
+    fn from(m: MediaInfo) -> Self {
+        pb::Media {
+            id: m.id,
+            file_title: m.file_title,
+            meta_title: m.meta_title.unwrap_or_default(),
+            video_codec: m.video_codec.unwrap_or_default(),
+            audio_codec: m.audio_codec.unwrap_or_default(),
+            duration_seconds: m.duration.map_or(0.0, |d| d.as_secs_f64()),
+            width: m.width.unwrap_or_default() as i64,
+            height: m.height.unwrap_or_default() as i64,
+            size: m.size,
+            modified: m.modified,
+        }
+    }
+}
+
+impl From<SessionEvent> for pb::SessionEvent {
+    fn from(e: SessionEvent) -> Self {
+        let mut event = pb::SessionEvent {
+            session_id: e.id().to_string(),
+            kind: e.name().to_string(),
+            ..Default::default()
+        };
+        match e {
+            SessionEvent::Stage { stage, max_stages, .. } => {
+                event.stage = stage as u64;
+                event.max_stages = max_stages as u64;
+            }
+            SessionEvent::Progress(info) => event.progress = Some(pb::Progress::from(&info)),
+            _ => (),
+        }
+        event
+    }
+}
+
+impl From<&SessionInfo> for pb::Progress {
+    fn from(info: &SessionInfo) -> Self {
+        pb::Progress {
+            // As the HTTP API names it
+            state: serde_json::to_value(info.state()).ok()
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_default(),
+            percent_complete: info.percent_complete(),
+            stage: info.stage() as u64,
+            max_stages: info.max_stages() as u64,
+            indeterminate: info.indeterminate(),
+            error: info.error().unwrap_or_default().to_string(),
+        }
+    }
+}
@@ -5,24 +5,52 @@
 extern crate lazy_static;
 
 use std::io;
-use std::path::Path;
+use std::iter::once;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-use actix_web::{App, get, HttpResponse, HttpServer, web};
+use actix_cors::Cors;
+use actix_web::{App, get, HttpResponse, HttpServer};
+use actix_web::dev::Service;
+use actix_web::http::{header, HeaderValue};
+use actix_web::middleware::Condition;
+use actix_web::web::Data;
+use futures::future::{self, Either};
 use serde_json::json;
+use tracing::{info, info_span};
+use tracing_futures::Instrument;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
+// The rest is in streamin-core, used through the same paths as when it was all one crate
+use streamin_core::{audio, commands, dash, error, filename, library, mp4, package, reaper, sessions, settings};
+use streamin_core::{PROCESSED_DIR, SETTINGS, UNPROCESSED_DIR, UNPROCESSED_DIRS};
+
+use crate::api::ApiVersion;
+use crate::library::Library;
 use crate::media::Sessions;
-use crate::settings::Settings;
+use crate::settings::{LogFormat, Settings, ShutdownMode};
 
-mod commands;
-mod settings;
 mod media;
-mod dash;
-
-lazy_static! {
-    static ref SETTINGS: Settings = Settings::new().unwrap();
-    static ref UNPROCESSED_DIR: &'static Path = Path::new(&(*SETTINGS).dirs.unprocessed);
-    static ref PROCESSED_DIR: &'static Path = Path::new(&(*SETTINGS).dirs.processed);
-}
+mod queue;
+mod runtime;
+mod client;
+mod fetch;
+mod notifiers;
+mod metrics;
+mod tls;
+mod auth;
+mod retention;
+mod probe_cache;
+mod auto_process;
+mod encryption;
+mod api;
+mod cli;
+mod openapi;
+mod worker;
+#[cfg(feature = "grpc")]
+mod grpc;
 
 #[get("/")]
 async fn index() -> Result<HttpResponse, actix_web::Error> {
@@ -31,25 +59,161 @@ async fn index() -> Result<HttpResponse, actix_web::Error> {
     })))
 }
 
+const REQUEST_ID: &str = "x-request-id";
+
+// RUST_LOG filters as before, log records from dependencies are forwarded too
+fn init_logging() {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match SETTINGS.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+// Stops the running sessions before the server stops, so no commands outlive us. The server keeps
+// answering in the meantime, so sessions can be followed as they drain and workers hear they're
+// given up on.
+async fn shutdown(state: &Sessions) {
+    commands::SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    // Queued sessions will never start so aren't waited for
+    let running = || state.sessions.read().values().any(|s| s.get_info().running() && !s.is_queued());
+    let wait = |timeout: Duration| async move {
+        let deadline = Instant::now() + timeout;
+        while running() && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(250)).await;
+        }
+    };
+
+    if SETTINGS.shutdown.mode == ShutdownMode::Drain && running() {
+        info!("Waiting up to {}s for running stages to finish", SETTINGS.shutdown.drain_timeout);
+        wait(Duration::from_secs(SETTINGS.shutdown.drain_timeout)).await;
+    }
+    for s in state.sessions.write().values_mut().filter(|s| s.get_info().running()) {
+        info!(session_id = %s.id(), "Interrupting session");
+        s.cancel();
+    }
+    // Time for the sessions to kill their commands and record themselves as interrupted
+    wait(Duration::from_secs(10)).await;
+}
+
+// Resolves on Ctrl-C, or on SIGTERM as sent by service managers and docker stop
+async fn stopped() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            future::select(Box::pin(actix_web::rt::signal::ctrl_c()), Box::pin(term.recv())).await;
+            return;
+        }
+    }
+    actix_web::rt::signal::ctrl_c().await.ok();
+}
+
+fn cors() -> Cors {
+    let cors = SETTINGS.cors.allowed_origins.iter()
+        .fold(Cors::default(), |cors, origin| match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        });
+    cors.allowed_methods(vec!["GET", "POST", "DELETE"])
+        .allowed_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE, header::HeaderName::from_static("x-api-key")])
+        // The process endpoint returns the new session in Location
+        .expose_headers(vec![header::LOCATION])
+        .max_age(3600)
+}
+
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    env_logger::init();
-    std::fs::read_dir(*UNPROCESSED_DIR).expect("unprocessed dirs");
-    std::fs::read_dir(*PROCESSED_DIR).expect("processed dirs");
+    let command = cli::command().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+    // Before anything reads SETTINGS, which would panic on a config that can't be read
+    if let Err(e) = Settings::new().and_then(|s| s.validate()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(command) = command {
+        std::process::exit(cli::run(command).await);
+    }
+    init_logging();
+    reaper::reap();
 
-    let state = web::Data::new(Sessions::new());
+    let state = Arc::new(Sessions::with_queue(queue::configured()));
+    let library = Arc::new(Library::new());
+    actix_web::rt::spawn(library::run(library.clone()));
+    actix_web::rt::spawn(auto_process::run(library.clone(), state.clone()));
+    let shutdown_state = state.clone();
+    actix_web::rt::spawn(notifiers::run(state.clone()));
+    actix_web::rt::spawn(retention::run(state.clone()));
+    actix_web::rt::spawn(queue::run(state.clone()));
+    actix_web::rt::spawn(runtime::watch(state.clone()));
+    #[cfg(feature = "grpc")]
+    actix_web::rt::spawn(grpc::serve(state.clone(), library.clone()));
 
-    HttpServer::new(move || {
+    let (state, library) = (Data::from(state), Data::from(library));
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
-            .service(media::unprocessed)
-            .service(media::processed)
-            .service(media::process)
-            .service(media::get_session)
-            .service(media::all_sessions)
+            .app_data(library.clone())
+            .wrap_fn(|req, srv| match auth::authorise(&req) {
+                Ok(()) => Either::Left(srv.call(req)),
+                Err(e) => Either::Right(future::err(e)),
+            })
+            // Outside auth so preflight requests, which never carry a key, are answered
+            .wrap(Condition::new(!SETTINGS.cors.allowed_origins.is_empty(), cors()))
+            // Everything logged while handling a request, including the sessions it starts, carries
+            // the request id. Callers may supply their own to correlate with their logs.
+            .wrap_fn(|req, srv| {
+                let request_id = req.headers().get(REQUEST_ID)
+                    .and_then(|id| id.to_str().ok())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                let span = info_span!("request", %request_id, method = %req.method(), path = %req.path());
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    if let Ok(id) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID), id);
+                    }
+                    Ok(res)
+                }.instrument(span)
+            })
+            .service(api::scope(ApiVersion::V1))
+            .service(api::scope(ApiVersion::Legacy))
+            .service(encryption::clearkey_license)
+            .service(metrics::metrics)
+            .service(openapi::spec)
+            .service(openapi::docs)
+            .service(openapi::docs_asset)
             .service(index)
-    })
-        .bind("0.0.0.0:8090")?
-        .run()
-        .await
+    });
+
+    let tls = SETTINGS.tls.as_ref().map(tls::server_config).transpose()?;
+    let addrs = once(format!("{}:{}", SETTINGS.host, SETTINGS.port)).chain(SETTINGS.listen.addresses.iter().cloned());
+    for addr in addrs {
+        server = match &tls {
+            Some(config) => server.bind_rustls(addr, config.clone())?,
+            None => server.bind(addr)?,
+        };
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &SETTINGS.listen.unix_socket {
+        // A socket left over from a previous run would stop us binding
+        if socket.exists() {
+            std::fs::remove_file(socket)?;
+        }
+        server = server.bind_uds(socket)?;
+    }
+
+    // Signals are handled here rather than by the server, which would stop before the sessions
+    let server = server.disable_signals().run();
+    let handle = server.clone();
+    actix_web::rt::spawn(async move {
+        stopped().await;
+        info!("Stopping");
+        shutdown(&shutdown_state).await;
+        handle.stop(true).await;
+    });
+    server.await
 }
@@ -1,39 +1,73 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::error::Error;
-use std::fs::DirEntry;
 use std::io;
-use std::path::Path;
-use std::sync::RwLock;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{get, HttpResponse, post};
+use actix_multipart::Multipart;
+use actix_web::{delete, get, HttpResponse, post};
 use actix_web::web;
-use actix_web::web::Data;
+use actix_web::web::{Bytes, Data, ReqData};
+use futures::{future, stream, StreamExt, TryStreamExt};
 use derive_more::{Display, Error};
-use log::{debug, error};
-use rayon::prelude::*;
+use tracing::error;
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-use crate::{commands, dash, PROCESSED_DIR, UNPROCESSED_DIR};
-use crate::commands::{MediaInfo, Session};
+use crate::{audio, auth, commands, dash, mp4, package, queue, runtime, PROCESSED_DIR, SETTINGS, UNPROCESSED_DIR, UNPROCESSED_DIRS};
+use crate::api::ApiVersion;
+use crate::dash::{Batch, Overrides};
+use crate::package::PackageInfo;
+use crate::library::{self, Library};
+use crate::audio::AudioFormat;
+use crate::commands::{ffprobe, MediaInfo, PlannedStage, SessionEvent, SessionInfo, SessionState};
 use crate::media::UserError::NotFound;
+use crate::retention::Expired;
+use crate::settings::{ApiKey, PostProcess, Scope};
 
-pub struct Sessions {
-    pub(crate) sessions: RwLock<HashMap<Uuid, Session>>
+pub use crate::sessions::Sessions;
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ProcessReq {
+    // A media id, or the path based id of a directory to package everything in it
+    id: Option<String>,
+    // Several sources to be joined in order, e.g. a film split over two discs
+    ids: Option<Vec<String>>,
+    mode: Option<Mode>,
+    // What audio_extract writes, and the audio streams it extracts by index, those in the
+    // configured languages when not given
+    audio_format: Option<AudioFormat>,
+    tracks: Option<Vec<isize>>,
+    // From before there were modes, false with no mode given is refused
+    dash: Option<bool>,
+    #[serde(flatten)]
+    overrides: Overrides,
 }
 
-impl Sessions {
-    pub fn new() -> Self {
-        Sessions {
-            sessions: RwLock::new(HashMap::new())
-        }
-    }
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    // Packages the sources for adaptive streaming
+    Dash,
+    // Writes a single progressive MP4 of H.264 and AAC, see dirs.mp4
+    Remux,
+    // Writes each audio stream to a file of its own, see dirs.audio
+    AudioExtract,
+    // Probes the sources again, ignoring the probe cache, and returns what was found
+    ProbeRefresh,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ProcessReq {
-    id: String,
-    dash: Option<bool>,
+impl ProcessReq {
+    fn mode(&self) -> Result<Mode, actix_web::Error> {
+        match (self.mode, self.dash) {
+            (Some(m), _) => Ok(m),
+            (None, Some(false)) => Err(actix_web::error::ErrorBadRequest("dash is false and no mode was given")),
+            (None, _) => Ok(Mode::Dash),
+        }
+    }
 }
 
 #[derive(Debug, Display, Error)]
@@ -51,109 +85,854 @@ fn log_not_found<T>(e: T) -> actix_web::Error
     actix_web::error::ErrorNotFound(NotFound)
 }
 
-#[post("/api/conv/process")]
-pub async fn process(req: web::Json<ProcessReq>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
-    // We return NotFoundError in most cases to avoid information leakage
-    let res = base64::decode(&req.id)
-        .map_err(log_not_found)?;
+// Sessions queued here may have been taken by another instance sharing the queue, which is then the
+// only one which can answer for them
+fn missing_session(state: &Sessions, id: &Uuid, key: Option<&ApiKey>) -> actix_web::Error {
+    match state.elsewhere.read().get(id) {
+        Some(owner) if auth::can_access(key, owner.as_deref()) =>
+            actix_web::error::ErrorConflict("The session is being run by another instance sharing the queue"),
+        _ => log_not_found(NotFound),
+    }
+}
 
-    let canonical = Path::new(std::str::from_utf8(&res)
-        .map_err(log_not_found)?)
+// Finds the file a media id refers to, ensuring it exists in an unprocessed directory. Files the library
+// hasn't indexed yet can be referred to by their path based id.
+fn resolve_unprocessed(library: &Library, id: &str) -> Result<PathBuf, actix_web::Error> {
+    // We return NotFoundError in most cases to avoid information leakage
+    let canonical = library.path_of(id).map_err(actix_web::error::ErrorConflict)?
+        .or_else(|| commands::media_path(id))
+        .ok_or_else(|| actix_web::error::ErrorNotFound(NotFound))?
         .canonicalize().map_err(log_not_found)?;
 
-    let dir = *UNPROCESSED_DIR;
-    if canonical.starts_with(dir.canonicalize()?) && canonical.exists() {
-        if let Some(true) = req.dash {
-            return Ok(HttpResponse::Created().header("Location", dash::exec_dash_conv(state, canonical)).finish());
-        };
+    for dir in UNPROCESSED_DIRS.iter() {
+        if canonical.starts_with(dir.canonicalize()?) && canonical.exists() {
+            return Ok(canonical);
+        }
     }
 
     Err(actix_web::error::ErrorNotFound(NotFound))
 }
 
-#[derive(Serialize)]
-struct Items<T> {
-    items: Vec<T>
+#[utoipa::path(post, path = "/api/v1/process", tag = "sessions", request_body = ProcessReq, responses(
+    (status = 201, description = "Started, or for a directory or splitting by chapters one for each package along with any which couldn't be", body = Created),
+    (status = 200, description = "Already underway, or with probe_refresh what was found", body = Created),
+    (status = 400, description = "The request or the source is invalid"),
+    (status = 403, description = "post_process delete was asked for without an admin key"),
+    (status = 404, description = "No such media"),
+    (status = 409, description = "Already processed, or an id is shared by identical files so one of their path based ids is needed"),
+    (status = 507, description = "Not enough space to process it"),
+))]
+#[post("/process")]
+pub async fn process(req: web::Json<ProcessReq>, key: Option<ReqData<ApiKey>>, version: ApiVersion, state: Data<Sessions>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    match start(req.into_inner(), key.as_deref(), &state, &library).await? {
+        Started::Session(id) => Ok(HttpResponse::Created().header("Location", id.as_str()).json(created(&state, &id, version)?)),
+        Started::Running(id) => Ok(HttpResponse::Ok().header("Location", id.as_str()).json(created(&state, &id, version)?)),
+        Started::Sessions(batch) => {
+            let items = batch.ids.iter().map(|id| created(&state, id, version)).collect::<Result<Vec<_>, _>>()?;
+            let failed = batch.failed.into_iter().map(|(id, e)| NotStarted { id, error: e.to_string() }).collect();
+            Ok(HttpResponse::Created().json(CreatedBatch { items, failed }))
+        }
+        Started::Refreshed(items) => Ok(HttpResponse::Ok().json(Items { items })),
+    }
+}
+
+// Deleting sources can't be undone, so asking for it rather than the configured post_process takes
+// an admin key. Profiles are set up by admins so may ask for it.
+fn check_post_process(overrides: &Overrides, key: Option<&ApiKey>) -> Result<(), actix_web::Error> {
+    let admin = key.map_or(true, |k| k.scope == Scope::Admin);
+    if overrides.post_process == Some(PostProcess::Delete) && !admin {
+        return Err(actix_web::error::ErrorForbidden("Only admin keys can have sources deleted"));
+    }
+    Ok(())
+}
+
+// What processing a request came to, for each API to answer with in its own way
+pub(crate) enum Started {
+    Session(String),
+    // One for each package, from a directory or splitting by chapters
+    Sessions(Batch),
+    // Already underway, so nothing new was started
+    Running(String),
+    // Probe refreshes, which don't start a session
+    Refreshed(Vec<MediaInfo>),
+}
+
+pub(crate) async fn start(mut req: ProcessReq, key: Option<&ApiKey>, state: &Arc<Sessions>, library: &Library) -> Result<Started, actix_web::Error> {
+    check_post_process(&req.overrides, key)?;
+    let owner = key.and_then(|k| k.user.clone());
+    req.overrides = runtime::with_defaults(&req.overrides);
+    let files = req.id.iter()
+        .chain(req.ids.iter().flatten())
+        .map(|id| resolve_unprocessed(library, id))
+        .collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("No media ids given"));
+    }
+
+    let mode = req.mode()?;
+    match mode {
+        Mode::Dash => process_dash(&req, files, owner, state, library).await,
+        Mode::Remux => process_mp4(&req, files, owner, state).await,
+        Mode::AudioExtract => process_audio(&req, files, owner, state).await,
+        Mode::ProbeRefresh => {
+            let mut items = vec![];
+            for f in &files {
+                items.push(library.refresh(f).await?);
+            }
+            Ok(Started::Refreshed(items))
+        }
+    }
+}
+
+async fn process_dash(req: &ProcessReq, files: Vec<PathBuf>, owner: Option<String>, state: &Arc<Sessions>, library: &Library) -> Result<Started, actix_web::Error> {
+    req.overrides.validate().map_err(actix_web::error::ErrorBadRequest)?;
+
+    if let Some(dir) = files.iter().find(|f| f.is_dir()) {
+        if files.len() > 1 || req.overrides.split_chapters.unwrap_or(false) {
+            return Err(actix_web::error::ErrorBadRequest("A directory can only be processed on its own"));
+        }
+        // Only what the library lists, so the scan settings apply
+        let mut files: Vec<_> = library.media().into_iter()
+            .map(|m| m.path)
+            .filter(|p| p.canonicalize().map_or(false, |p| p.starts_with(dir)))
+            .collect();
+        if files.is_empty() {
+            return Err(actix_web::error::ErrorBadRequest("The directory has no media in it"));
+        }
+        files.sort();
+        let batch = dash::exec_dash_dir(state.clone(), dir, files, &req.overrides, owner).await?;
+        return Ok(Started::Sessions(batch));
+    }
+
+    if req.overrides.split_chapters.unwrap_or(false) {
+        if files.len() > 1 {
+            return Err(actix_web::error::ErrorBadRequest("Only a single file can be split by chapters"));
+        }
+        let batch = dash::exec_dash_chapters(state.clone(), files.into_iter().next().unwrap(), &req.overrides, owner).await?;
+        return Ok(Started::Sessions(batch));
+    }
+    // Asking again for something already underway gives back the running session, rather than
+    // a second one writing to the same place
+    let info = MediaInfo::get(&files[0]).await?;
+    let package = dash::package_dir(&info, &req.overrides);
+    if req.overrides.preview_seconds.is_none() {
+        if let Some(id) = state.writing_to(&package) {
+            return Ok(Started::Running(id.to_string()));
+        }
+        if package.exists() && !req.overrides.force.unwrap_or(false) {
+            return Err(actix_web::error::ErrorConflict(format!(
+                "Already processed as {}, set force to process it again", dash::package_name(&info))));
+        }
+    }
+    let id = dash::exec_dash_conv(state.clone(), files, &req.overrides, owner).await?;
+    Ok(Started::Session(id))
+}
+
+async fn process_mp4(req: &ProcessReq, files: Vec<PathBuf>, owner: Option<String>, state: &Arc<Sessions>) -> Result<Started, actix_web::Error> {
+    mp4::validate(&req.overrides).map_err(actix_web::error::ErrorBadRequest)?;
+    if files.len() > 1 {
+        return Err(actix_web::error::ErrorBadRequest("Only a single file can be remuxed"));
+    }
+    let file = files.into_iter().next().unwrap();
+
+    let info = MediaInfo::get(&file).await?;
+    if let Some(id) = state.writing_to(&mp4::mp4_path(&info, &req.overrides)) {
+        return Ok(Started::Running(id.to_string()));
+    }
+    let id = mp4::exec_mp4_conv(state.clone(), file, &req.overrides, owner).await?;
+    Ok(Started::Session(id))
+}
+
+async fn process_audio(req: &ProcessReq, files: Vec<PathBuf>, owner: Option<String>, state: &Arc<Sessions>) -> Result<Started, actix_web::Error> {
+    audio::validate(&req.overrides).map_err(actix_web::error::ErrorBadRequest)?;
+    if files.len() > 1 {
+        return Err(actix_web::error::ErrorBadRequest("Audio can only be extracted from a single file"));
+    }
+    let file = files.into_iter().next().unwrap();
+
+    let info = MediaInfo::get(&file).await?;
+    if let Some(id) = state.writing_to(&audio::audio_dir(&info, &req.overrides)) {
+        return Ok(Started::Running(id.to_string()));
+    }
+    let format = req.audio_format.unwrap_or_default();
+    let id = audio::exec_audio_conv(state.clone(), file, format, req.tracks.as_deref(), &req.overrides, owner).await?;
+    Ok(Started::Session(id))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Plan {
+    media: MediaInfo,
+    #[schema(value_type = Option<String>)]
+    output: Option<PathBuf>,
+    stages: Vec<PlannedStage>,
+}
+
+// The stages processing would run with the same request, without running them or writing anything
+#[utoipa::path(post, path = "/api/v1/plan", tag = "sessions", request_body = ProcessReq, responses(
+    (status = 200, body = Plan),
+    (status = 400, description = "The request or the source is invalid"),
+    (status = 404, description = "No such media"),
+    (status = 409, description = "Already processed, or an id is shared by identical files so one of their path based ids is needed"),
+))]
+#[post("/plan")]
+pub async fn plan(mut req: web::Json<ProcessReq>, state: Data<Sessions>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    req.overrides = runtime::with_defaults(&req.overrides);
+    let files = req.id.iter()
+        .chain(req.ids.iter().flatten())
+        .map(|id| resolve_unprocessed(&library, id))
+        .collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("No media ids given"));
+    }
+    if req.mode()? != Mode::Dash {
+        return Err(actix_web::error::ErrorBadRequest("Only dash processing can be planned"));
+    }
+    req.overrides.validate().map_err(actix_web::error::ErrorBadRequest)?;
+    if req.overrides.split_chapters.unwrap_or(false) {
+        return Err(actix_web::error::ErrorBadRequest("Splitting by chapters can't be planned"));
+    }
+
+    let info = MediaInfo::get(&files[0]).await?;
+    let package = dash::package_dir(&info, &req.overrides);
+    if req.overrides.preview_seconds.is_none() && package.exists() && !req.overrides.force.unwrap_or(false) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Already processed as {}, set force to process it again", dash::package_name(&info))));
+    }
+
+    let session = dash::plan_dash_conv(state.into_inner(), files, &req.overrides).await?;
+    let stages = session.plan()?;
+    Ok(HttpResponse::Ok().json(Plan {
+        media: session.media_info(),
+        output: session.output_dir().map(Path::to_path_buf),
+        stages,
+    }))
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct FetchReq {
+    url: String,
+    // Name to save the source as, taken from the URL when not given
+    file_name: Option<String>,
+    #[serde(flatten)]
+    overrides: Overrides,
+}
+
+// Downloads a source into UNPROCESSED_DIR then packages it, answering once the download is done. As
+// with uploads it's written under a hidden name and renamed into place once complete.
+#[utoipa::path(post, path = "/api/v1/fetch", tag = "sessions", request_body = FetchReq, responses(
+    (status = 201, body = Created),
+    (status = 400, description = "The request is invalid"),
+    (status = 403, description = "post_process delete was asked for without an admin key, or the URL's host isn't allowed"),
+    (status = 409, description = "A file of that name already exists"),
+    (status = 502, description = "The source couldn't be downloaded"),
+))]
+#[post("/fetch")]
+pub async fn fetch(mut req: web::Json<FetchReq>, key: Option<ReqData<ApiKey>>, version: ApiVersion, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    check_post_process(&req.overrides, key.as_deref())?;
+    req.overrides = runtime::with_defaults(&req.overrides);
+    req.overrides.validate().map_err(actix_web::error::ErrorBadRequest)?;
+
+    let url_name = req.url.split(['?', '#']).next().unwrap().rsplit('/').next();
+    let name = req.file_name.as_deref().or(url_name)
+        .and_then(upload_file_name)
+        .filter(|n| allowed_extension(n))
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("A file_name with an allowed extension is needed"))?;
+    let dest = UNPROCESSED_DIR.join(&name);
+    if dest.exists() {
+        return Err(actix_web::error::ErrorConflict(format!("{} already exists", name)));
+    }
+
+    let part = Part(UNPROCESSED_DIR.join(format!(".{}.{}.part", name, Uuid::new_v4())));
+    crate::fetch::download(&req.url, &part.0).await?;
+    dash::rename_new(&part.0, &dest).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => actix_web::error::ErrorConflict(format!("{} already exists", name)),
+        _ => e.into(),
+    })?;
+
+    let owner = key.and_then(|k| k.user.clone());
+    let id = dash::exec_dash_conv(state.clone().into_inner(), vec![dest], &req.overrides, owner).await?;
+    Ok(HttpResponse::Created().header("Location", id.as_str()).json(created(&state, &id, version)?))
+}
+
+// Describes a newly started session so clients needn't dig the id out of the Location header
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Created {
+    #[schema(value_type = String)]
+    id: Uuid,
+    media: MediaInfo,
+    stages: Vec<String>,
+    links: Links,
+}
+
+// The sessions started for a directory or chapters, and those which couldn't be
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CreatedBatch {
+    items: Vec<Created>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<NotStarted>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct NotStarted {
+    // The media id of the file, or the name of the chapter's package
+    id: String,
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Links {
+    session: String,
+    // Path of the manifest relative to the processed directory, which only exists once the session
+    // has succeeded. Previews aren't kept there so have none.
+    manifest: Option<String>,
+}
+
+fn created(state: &Sessions, id: &str, version: ApiVersion) -> Result<Created, actix_web::Error> {
+    let id = Uuid::parse_str(id).map_err(actix_web::error::ErrorInternalServerError)?;
+    let sessions = state.sessions.read();
+    let session = sessions.get(&id).ok_or_else(|| actix_web::error::ErrorInternalServerError("Session went missing"))?;
+
+    let manifest = session.output_dir()
+        .and_then(|o| o.strip_prefix(*PROCESSED_DIR).ok())
+        .map(|p| format!("{}/{}", p.to_string_lossy(), SETTINGS.manifest_name));
+    Ok(Created {
+        id,
+        media: session.media_info(),
+        stages: session.stage_names().to_vec(),
+        links: Links {
+            session: format!("{}/session/{}", version.prefix(), id),
+            manifest,
+        },
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+#[aliases(MediaItems = Items<MediaInfo>, IdItems = Items<String>, ProcessedItems = Items<ProcessedMedia>, ExpiredItems = Items<Expired>)]
+pub(crate) struct Items<T> {
+    pub(crate) items: Vec<T>
+}
+
+// A slice of a longer list, with the full length so clients can page through it
+#[derive(Serialize, ToSchema)]
+#[aliases(MediaPage = Page<MediaInfo>, SessionPage = Page<SessionInfo>, LogPage = Page<String>, SearchPage = Page<SearchResult>)]
+pub(crate) struct Page<T> {
+    pub(crate) items: Vec<T>,
+    pub(crate) total: usize,
+}
+
+impl<T> Page<T> {
+    fn new(items: Vec<T>, offset: Option<usize>, limit: Option<usize>) -> Self {
+        let total = items.len();
+        let items = items.into_iter()
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        Page { items, total }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionSort {
+    Created,
+    Started,
+    Finished,
+    FileName,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionsReq {
+    // Comma separated, e.g. failed,cancelled
+    state: Option<String>,
+    sort: Option<SessionSort>,
+    // Newest first, or alphabetical for names, unless asked otherwise
+    order: Option<Order>,
+    limit: Option<usize>,
+    offset: Option<usize>,
 }
 
-#[get("/api/conv/session")]
-pub async fn all_sessions(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
-    let sessions: Vec<_> = state.sessions
+#[utoipa::path(get, path = "/api/v1/session", tag = "sessions", params(SessionsReq), responses(
+    (status = 200, body = SessionPage),
+))]
+#[get("/session")]
+pub async fn all_sessions(query: web::Query<SessionsReq>, key: Option<ReqData<ApiKey>>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let states = query.state.as_deref()
+        .map(|s| s.split(',')
+            .map(|s| serde_json::from_value::<SessionState>(serde_json::Value::String(s.trim().to_string())))
+            .collect::<Result<Vec<_>, _>>())
+        .transpose()
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("Unknown session state: {}", e)))?;
+
+    let mut sessions: Vec<_> = state.sessions
         .read()
-        .unwrap()
         .iter()
+        .filter(|s| auth::can_access(key.as_deref(), s.1.get_owner()))
         .map(|s| s.1.get_info())
+        .filter(|i| states.as_ref().map_or(true, |s| s.contains(&i.state())))
         .collect();
 
-    Ok(HttpResponse::Ok().json(Items { items: sessions }))
+    let sort = query.sort.unwrap_or(SessionSort::Created);
+    match sort {
+        SessionSort::Created => sessions.sort_by_key(|i| i.created()),
+        SessionSort::Started => sessions.sort_by_key(|i| i.started()),
+        SessionSort::Finished => sessions.sort_by_key(|i| i.finished()),
+        SessionSort::FileName => sessions.sort_by(|a, b| a.file_name().cmp(b.file_name())),
+    }
+    let default_order = if sort == SessionSort::FileName { Order::Asc } else { Order::Desc };
+    if query.order.unwrap_or(default_order) == Order::Desc {
+        sessions.reverse();
+    }
+
+    Ok(HttpResponse::Ok().json(Page::new(sessions, query.offset, query.limit)))
 }
 
-#[get("/api/conv/session/{id}")]
-pub async fn get_session(web::Path(id): web::Path<String>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
-    println!("{}", id);
+#[utoipa::path(get, path = "/api/v1/session/{id}", tag = "sessions", params(("id" = String, Path, description = "The session id")), responses(
+    (status = 200, body = SessionInfo),
+    (status = 404, description = "No such session"),
+    (status = 409, description = "The session was taken by another instance sharing the queue"),
+))]
+#[get("/session/{id}")]
+pub async fn get_session(web::Path(id): web::Path<String>, key: Option<ReqData<ApiKey>>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
     let id = Uuid::parse_str(id.as_str()).map_err(log_not_found)?;
-    println!("{}", id);
 
-    let sessions = state.sessions.read().unwrap();
-    let session = sessions.get(&id).ok_or_else(|| log_not_found(NotFound))?;
+    let sessions = state.sessions.read();
+    let session = sessions.get(&id)
+        .filter(|s| auth::can_access(key.as_deref(), s.get_owner()))
+        .ok_or_else(|| missing_session(&state, &id, key.as_deref()))?;
     Ok(HttpResponse::Ok().json(session.get_info()))
 }
 
-#[get("/api/conv/unprocessed")]
-pub async fn unprocessed() -> Result<HttpResponse, actix_web::Error> {
-    Ok(HttpResponse::Ok().json(Items { items: get_media_infos(*UNPROCESSED_DIR) }))
+// Stops a running session, only its owner or an admin may do so
+#[utoipa::path(post, path = "/api/v1/session/{id}/cancel", tag = "sessions", params(("id" = String, Path, description = "The session id")), responses(
+    (status = 202, description = "The session is being stopped"),
+    (status = 404, description = "No such session"),
+    (status = 409, description = "The session has already finished, or was taken by another instance sharing the queue"),
+))]
+#[post("/session/{id}/cancel")]
+pub async fn cancel_session(web::Path(id): web::Path<String>, key: Option<ReqData<ApiKey>>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let id = Uuid::parse_str(id.as_str()).map_err(log_not_found)?;
+
+    let queued = {
+        let mut sessions = state.sessions.write();
+        let session = sessions.get_mut(&id)
+            .filter(|s| auth::can_access(key.as_deref(), s.get_owner()))
+            .ok_or_else(|| missing_session(&state, &id, key.as_deref()))?;
+        if !session.get_info().running() {
+            return Err(actix_web::error::ErrorConflict("Session has already finished"));
+        }
+        let queued = session.is_queued();
+        session.cancel();
+        queued
+    };
+    // So no other instance sharing the queue starts it
+    if queued {
+        queue::withdraw(&state, id).await;
+    }
+    Ok(HttpResponse::Accepted().finish())
 }
 
-#[derive(Serialize)]
-struct ProcessedMedia {
-    file_name: String
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LogsReq {
+    // Only the last lines, overrides offset
+    tail: Option<usize>,
+    offset: Option<usize>,
+    limit: Option<usize>,
 }
 
-#[get("/api/conv/processed")]
-pub async fn processed() -> Result<HttpResponse, actix_web::Error> {
-    Ok(HttpResponse::Ok().json(Items {
-        items: processed_files()?
-            .map(|f| f.file_name())
-            .map(|f| ProcessedMedia { file_name: f.to_str().unwrap().to_string() })
-            .collect()
+#[utoipa::path(get, path = "/api/v1/session/{id}/logs", tag = "sessions", params(("id" = String, Path, description = "The session id"), LogsReq), responses(
+    (status = 200, body = LogPage),
+    (status = 404, description = "No such session"),
+    (status = 409, description = "The session was taken by another instance sharing the queue"),
+))]
+#[get("/session/{id}/logs")]
+pub async fn session_logs(web::Path(id): web::Path<String>, query: web::Query<LogsReq>, key: Option<ReqData<ApiKey>>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let id = Uuid::parse_str(id.as_str()).map_err(log_not_found)?;
+    let visible = state.sessions.read().get(&id)
+        .map_or(false, |s| auth::can_access(key.as_deref(), s.get_owner()));
+    if !visible {
+        return Err(missing_session(&state, &id, key.as_deref()));
+    }
+
+    let query = query.into_inner();
+    let (items, total) = web::block(move || {
+        state.sessions.read().get(&id).map(|s| s.logs(query.offset, query.tail, query.limit)).ok_or(NotFound)
+    }).await?;
+    Ok(HttpResponse::Ok().json(Page { items, total }))
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsReq {
+    // Seconds between progress updates
+    interval: Option<u64>,
+}
+
+// Server-sent events for every session, with the progress of running sessions sent periodically
+#[utoipa::path(get, path = "/api/v1/events", tag = "sessions", params(EventsReq), responses(
+    (status = 200, description = "Server-sent events named after the kind of event", body = SessionEvent, content_type = "text/event-stream"),
+))]
+#[get("/events")]
+pub async fn session_events(query: web::Query<EventsReq>, key: Option<ReqData<ApiKey>>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let key = key.map(ReqData::into_inner);
+    let lifecycle_key = key.clone();
+    let lifecycle_state = state.clone();
+    let lifecycle = state.events.subscribe()
+        .filter_map(|e| future::ready(e.ok()))
+        .filter(move |e| future::ready(lifecycle_state.sessions.read()
+            .get(&e.id())
+            .map_or(false, |s| auth::can_access(lifecycle_key.as_ref(), s.get_owner()))))
+        .map(|e| vec![e]);
+
+    let progress_state = state.clone();
+    let progress = tokio::time::interval(Duration::from_secs(query.interval.unwrap_or(5).max(1)))
+        .map(move |_| {
+            progress_state.sessions.read()
+                .values()
+                .filter(|s| auth::can_access(key.as_ref(), s.get_owner()))
+                .map(|s| s.get_info())
+                .filter(|i| i.running())
+                .map(SessionEvent::Progress)
+                .collect::<Vec<_>>()
+        });
+
+    let body = stream::select(lifecycle, progress)
+        .flat_map(stream::iter)
+        .map(|e| {
+            let data = serde_json::to_string(&e)?;
+            Ok::<_, actix_web::Error>(Bytes::from(format!("event: {}\ndata: {}\n\n", e.name(), data)))
+        });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(body))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct MediaDetail {
+    #[serde(flatten)]
+    media: MediaInfo,
+    // As ffprobe reports them
+    #[schema(value_type = Vec<Object>)]
+    streams: Vec<ffprobe::Stream>,
+    #[schema(value_type = Vec<Object>)]
+    chapters: Vec<ffprobe::Chapter>,
+}
+
+// Everything known about a source, including every stream ffprobe found, for choosing tracks
+#[utoipa::path(get, path = "/api/v1/media/{id}", tag = "media", params(("id" = String, Path, description = "The media id")), responses(
+    (status = 200, body = MediaDetail),
+    (status = 404, description = "No such media"),
+    (status = 409, description = "The id is shared by identical files, so one of their path based ids is needed"),
+))]
+#[get("/media/{id}")]
+pub async fn media_detail(web::Path(id): web::Path<String>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    let media = library.get(&id).map_err(actix_web::error::ErrorConflict)?.ok_or_else(|| log_not_found(NotFound))?;
+    Ok(HttpResponse::Ok().json(MediaDetail {
+        streams: media.raw.streams.clone(),
+        chapters: media.raw.chapters.clone(),
+        media,
     }))
 }
 
-fn get_media_infos(dir: &Path) -> Vec<MediaInfo> {
-    // Get the names of all the processed files
-    let processed_files: HashSet<_> = processed_files().map(|f|
-        f.map(|f|
-            f.path()
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned()
-        ).collect()
-    ).unwrap_or_default();
-    // Splits the files into a parallel iterator and runs ffprobe on each media file, ignoring any invalid files
-    // This will not panic unless directories are deleted during execution
-    walkdir::WalkDir::new(dir).into_iter().par_bridge()
-        .filter_map(|e| e.ok())
-        .filter(|e| !processed_files.contains(e.path()
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .split('-')
-            .next()
-            .unwrap()
-        )).filter_map(|entry| {
-            debug!("{:?}", entry);
-            commands::MediaInfo::get(entry.path()).map_err(|e| {
-                error!("Error getting media for {:?}: {}", entry, e);
-                e
-            }).ok()
-        }).collect()
-}
-
-fn processed_files() -> Result<impl Iterator<Item=DirEntry>, io::Error> {
-    Ok(std::fs::read_dir(*PROCESSED_DIR)?
-        .filter_map(|f| f.ok())
-        .filter(|f| f.path().is_dir()))
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaSort {
+    Name,
+    Size,
+    Duration,
+    Mtime,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UnprocessedReq {
+    sort: Option<MediaSort>,
+    // Alphabetical for names, largest, longest or newest first otherwise, unless asked otherwise
+    order: Option<Order>,
+    // Comma separated field:value pairs which must all match, e.g. codec:h264,name:pilot
+    filter: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[utoipa::path(get, path = "/api/v1/unprocessed", tag = "media", params(UnprocessedReq), responses(
+    (status = 200, body = MediaPage),
+    (status = 400, description = "The filter is invalid"),
+))]
+#[get("/unprocessed")]
+pub async fn unprocessed(query: web::Query<UnprocessedReq>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    let filters = query.filter.as_deref()
+        .map(|f| f.split(',').map(MediaFilter::parse).collect::<Result<Vec<_>, _>>())
+        .transpose()
+        .map_err(actix_web::error::ErrorBadRequest)?
+        .unwrap_or_default();
+
+    let mut media: Vec<_> = unprocessed_media(&library).into_iter()
+        .filter(|m| filters.iter().all(|f| f.matches(m)))
+        .collect();
+
+    let sort = query.sort.unwrap_or(MediaSort::Name);
+    match sort {
+        MediaSort::Name => media.sort_by(|a, b| a.file_title.cmp(&b.file_title)),
+        MediaSort::Size => media.sort_by_key(|m| m.size),
+        MediaSort::Duration => media.sort_by_key(|m| m.duration),
+        MediaSort::Mtime => media.sort_by_key(|m| m.modified),
+    }
+    let default_order = if sort == MediaSort::Name { Order::Asc } else { Order::Desc };
+    if query.order.unwrap_or(default_order) == Order::Desc {
+        media.reverse();
+    }
+
+    Ok(HttpResponse::Ok().json(Page::new(media, query.offset, query.limit)))
+}
+
+enum MediaFilter {
+    // Either the video or the audio codec
+    Codec(String),
+    VideoCodec(String),
+    AudioCodec(String),
+    // Part of the file or metadata title
+    Name(String),
+}
+
+impl MediaFilter {
+    fn parse(filter: &str) -> Result<Self, String> {
+        let (field, value) = filter.split_once(':')
+            .ok_or_else(|| format!("Filters are field:value, got {}", filter))?;
+        let value = value.trim().to_lowercase();
+        Ok(match field.trim() {
+            "codec" => MediaFilter::Codec(value),
+            "video_codec" => MediaFilter::VideoCodec(value),
+            "audio_codec" => MediaFilter::AudioCodec(value),
+            "name" => MediaFilter::Name(value),
+            f => return Err(format!("Unknown filter field {}", f)),
+        })
+    }
+
+    fn matches(&self, media: &MediaInfo) -> bool {
+        let is = |codec: &Option<String>, value: &str| codec.as_deref().map_or(false, |c| c.eq_ignore_ascii_case(value));
+        match self {
+            MediaFilter::Codec(c) => is(&media.video_codec, c) || is(&media.audio_codec, c),
+            MediaFilter::VideoCodec(c) => is(&media.video_codec, c),
+            MediaFilter::AudioCodec(c) => is(&media.audio_codec, c),
+            MediaFilter::Name(n) => media.file_title.to_lowercase().contains(n.as_str())
+                || media.meta_title.as_ref().map_or(false, |t| t.to_lowercase().contains(n.as_str())),
+        }
+    }
+}
+
+// Streams each file in the form into UNPROCESSED_DIR, responding with the ids of the new media.
+// Files are written under a hidden name and renamed once complete so they aren't listed early.
+#[utoipa::path(post, path = "/api/v1/upload", tag = "media", request_body(content = String, content_type = "multipart/form-data", description = "One or more files"), responses(
+    (status = 201, description = "The ids of the new media", body = IdItems),
+    (status = 409, description = "A file of that name already exists"),
+    (status = 413, description = "A file is larger than the upload limit"),
+    (status = 415, description = "A file's extension isn't allowed"),
+))]
+#[post("/upload")]
+pub async fn upload(mut payload: Multipart) -> Result<HttpResponse, actix_web::Error> {
+    let mut ids = vec![];
+    while let Some(mut field) = payload.try_next().await? {
+        let name = field.content_disposition()
+            .and_then(|cd| cd.get_filename().and_then(upload_file_name))
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("Each file needs a name"))?;
+        if !allowed_extension(&name) {
+            return Err(actix_web::error::ErrorUnsupportedMediaType(format!("{} is not an allowed file type", name)));
+        }
+
+        let path = UNPROCESSED_DIR.join(&name);
+        if path.exists() {
+            return Err(actix_web::error::ErrorConflict(format!("{} already exists", name)));
+        }
+        // Named for this upload alone so uploads of the same file at once don't write over each other
+        let part = Part(UNPROCESSED_DIR.join(format!(".{}.{}.part", name, Uuid::new_v4())));
+        let mut f = web::block({
+            let part = part.0.clone();
+            move || std::fs::OpenOptions::new().write(true).create_new(true).open(part)
+        }).await?;
+
+        let mut size = 0;
+        while let Some(chunk) = field.try_next().await? {
+            size += chunk.len() as u64;
+            if size > SETTINGS.upload.max_size {
+                return Err(actix_web::error::ErrorPayloadTooLarge(format!("{} is larger than the upload limit", name)));
+            }
+            f = web::block(move || f.write_all(&chunk).map(|_| f)).await?;
+        }
+        drop(f);
+        // Whichever upload of the same name finishes first is kept
+        dash::rename_new(&part.0, &path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => actix_web::error::ErrorConflict(format!("{} already exists", name)),
+            _ => e.into(),
+        })?;
+        // The same id the library gives the file once it has indexed it
+        ids.push(commands::fingerprint(&path).unwrap_or_else(|_| commands::media_id(&path)));
+    }
+
+    Ok(HttpResponse::Created().json(Items { items: ids }))
+}
+
+// An upload or download being written, removed unless it's finished and renamed into place
+struct Part(PathBuf);
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("Could not remove incomplete file {:?}: {}", self.0, e);
+            }
+        }
+    }
+}
+
+fn allowed_extension(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| SETTINGS.upload.extensions.iter().any(|a| a.eq_ignore_ascii_case(e)))
+}
+
+// Only the final component of the client's name is used so uploads can't escape the directory
+fn upload_file_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    (!name.starts_with('.')).then(|| name.to_string())
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ProcessedMedia {
+    file_name: String,
+    // Path of the poster image relative to the processed directory
+    poster: Option<String>,
+    #[serde(flatten)]
+    info: Option<PackageInfo>,
+}
+
+#[utoipa::path(get, path = "/api/v1/processed", tag = "media", responses(
+    (status = 200, body = ProcessedItems),
+))]
+#[get("/processed")]
+pub async fn processed() -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(Items { items: web::block(processed_media).await? }))
+}
+
+fn processed_media() -> Result<Vec<ProcessedMedia>, io::Error> {
+    Ok(package::list(*PROCESSED_DIR)?.into_iter()
+        .map(|name| {
+            let dir = PROCESSED_DIR.join(&name);
+            let file_name = name.to_string_lossy().into_owned();
+            let poster = dir.join(dash::POSTER).exists()
+                .then(|| format!("{}/{}", file_name, dash::POSTER));
+            let info = package::read(&dir);
+            ProcessedMedia { file_name, poster, info }
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchReq {
+    q: String,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub(crate) enum SearchResult {
+    Unprocessed(MediaInfo),
+    Processed(ProcessedMedia),
+}
+
+// Case insensitive search of everything in the library, every word of the query has to appear in the
+// file name, metadata title or path. Unprocessed media comes first, each part sorted by name.
+#[utoipa::path(get, path = "/api/v1/search", tag = "media", params(SearchReq), responses(
+    (status = 200, body = SearchPage),
+    (status = 400, description = "Nothing to search for"),
+))]
+#[get("/search")]
+pub async fn search(query: web::Query<SearchReq>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    let terms: Vec<_> = query.q.split_whitespace().map(|t| t.to_lowercase()).collect();
+    if terms.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Nothing to search for"));
+    }
+    let matches = |fields: &[&str]| {
+        let haystack = fields.join("\n").to_lowercase();
+        terms.iter().all(|t| haystack.contains(t.as_str()))
+    };
+
+    let mut sources: Vec<_> = unprocessed_media(&library).into_iter()
+        .filter(|m| {
+            let path = library::relative_path(&m.path).to_string_lossy();
+            matches(&[&m.file_title, m.meta_title.as_deref().unwrap_or(""), &path])
+        })
+        .collect();
+    sources.sort_by(|a, b| a.file_title.cmp(&b.file_title));
+    let mut packages: Vec<_> = web::block(processed_media).await?.into_iter()
+        .filter(|p| matches(&[&p.file_name]))
+        .collect();
+    packages.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    let results = sources.into_iter().map(SearchResult::Unprocessed)
+        .chain(packages.into_iter().map(SearchResult::Processed))
+        .collect();
+    Ok(HttpResponse::Ok().json(Page::new(results, query.offset, query.limit)))
+}
+
+// Removes a package from PROCESSED_DIR, refusing while a session is still writing to it. Names are
+// as listed, so may have several levels when the package template groups packages.
+#[utoipa::path(delete, path = "/api/v1/processed/{name}", tag = "media", params(("name" = String, Path, description = "As listed, may have several levels")), responses(
+    (status = 204, description = "The package was removed"),
+    (status = 404, description = "No such package"),
+    (status = 409, description = "The package is still being written"),
+))]
+#[delete("/processed/{name:.+}")]
+pub async fn delete_processed(web::Path(name): web::Path<String>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let dir = resolve_processed(&name)?;
+
+    let busy = state.sessions.read().values()
+        .filter(|s| s.get_info().running())
+        .filter_map(|s| s.output_dir().and_then(|o| o.canonicalize().ok()))
+        .any(|o| o == dir);
+    if busy {
+        return Err(actix_web::error::ErrorConflict("The package is still being written"));
+    }
+
+    web::block(move || package::remove(*PROCESSED_DIR, Path::new(&name))).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Ensures a package name refers to one of the packages under PROCESSED_DIR, as package::list would
+// give it, without going through them all
+fn resolve_processed(name: &str) -> Result<PathBuf, actix_web::Error> {
+    let name = Path::new(name);
+    // list doesn't look inside packages or hidden directories
+    let listed = name.components().all(|c| matches!(c, Component::Normal(c) if !c.to_string_lossy().starts_with('.')))
+        && !name.ancestors().skip(1).any(|p| !p.as_os_str().is_empty() && package::is_package(&PROCESSED_DIR.join(p)));
+    if !listed {
+        return Err(actix_web::error::ErrorNotFound(NotFound));
+    }
+
+    let canonical = PROCESSED_DIR.join(name).canonicalize().map_err(log_not_found)?;
+    if canonical.starts_with(PROCESSED_DIR.canonicalize()?) && canonical.is_dir() && package::is_package(&canonical) {
+        return Ok(canonical);
+    }
+
+    Err(actix_web::error::ErrorNotFound(NotFound))
+}
+
+// Indexed media which hasn't been packaged yet
+pub(crate) fn unprocessed_media(library: &Library) -> Vec<MediaInfo> {
+    let packages: HashSet<_> = package::list(*PROCESSED_DIR).unwrap_or_default().into_iter().collect();
+    library.media().into_iter()
+        .filter(|m| !packages.contains(Path::new(&dash::package_name(m))))
+        .collect()
 }
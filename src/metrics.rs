@@ -0,0 +1,19 @@
+use actix_web::{get, HttpResponse};
+use actix_web::web::Data;
+use prometheus::{Encoder, TextEncoder};
+
+use crate::media::Sessions;
+use crate::queue;
+
+#[get("/metrics")]
+pub async fn metrics(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    queue::count(&state).await;
+    let mut buf = vec![];
+    let encoder = TextEncoder::new();
+    encoder.encode(&prometheus::gather(), &mut buf)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf))
+}
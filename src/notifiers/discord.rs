@@ -0,0 +1,33 @@
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde_json::json;
+
+use crate::notifiers::{check, Notification, Notifier};
+use crate::settings;
+
+pub struct Discord {
+    webhook_url: String,
+}
+
+impl Discord {
+    pub fn new(settings: &settings::Discord) -> Self {
+        Discord {
+            webhook_url: settings.webhook_url.clone(),
+        }
+    }
+}
+
+impl Notifier for Discord {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            check(client.post(&self.webhook_url)
+                .send_json(&json!({ "content": n.message() }))
+                .await)
+        }.boxed_local()
+    }
+}
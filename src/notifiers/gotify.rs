@@ -0,0 +1,42 @@
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde_json::json;
+
+use crate::notifiers::{check, Notification, Notifier};
+use crate::settings;
+
+pub struct Gotify {
+    url: String,
+    token: String,
+    priority: i64,
+}
+
+impl Gotify {
+    pub fn new(settings: &settings::Gotify) -> Self {
+        Gotify {
+            url: settings.url.trim_end_matches('/').to_string(),
+            token: settings.token.clone(),
+            priority: settings.priority.unwrap_or(5),
+        }
+    }
+}
+
+impl Notifier for Gotify {
+    fn name(&self) -> &'static str {
+        "Gotify"
+    }
+
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            check(client.post(format!("{}/message", self.url))
+                .header("X-Gotify-Key", self.token.as_str())
+                .send_json(&json!({
+                    "title": "streamin-conv",
+                    "message": n.message(),
+                    "priority": self.priority,
+                }))
+                .await)
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,52 @@
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+
+use crate::notifiers::{check, Notification, Notifier};
+use crate::settings::{self, MediaServerKind};
+
+// Asks a Jellyfin or Plex server to rescan its library whenever a session completes
+pub struct MediaServer {
+    kind: MediaServerKind,
+    url: String,
+    token: String,
+    section: Option<String>,
+}
+
+impl MediaServer {
+    pub fn new(settings: &settings::MediaServer) -> Self {
+        MediaServer {
+            kind: settings.kind,
+            url: settings.url.trim_end_matches('/').to_string(),
+            token: settings.token.clone(),
+            section: settings.section.clone(),
+        }
+    }
+}
+
+impl Notifier for MediaServer {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            MediaServerKind::Jellyfin => "Jellyfin",
+            MediaServerKind::Plex => "Plex",
+        }
+    }
+
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            // Nothing new to find when the session failed
+            if !n.succeeded() {
+                return Ok(());
+            }
+
+            let req = match self.kind {
+                MediaServerKind::Jellyfin => client.post(format!("{}/Library/Refresh", self.url))
+                    .header("X-Emby-Token", self.token.as_str()),
+                MediaServerKind::Plex => client.get(format!("{}/library/sections/{}/refresh", self.url,
+                                                            self.section.as_deref().unwrap_or("all")))
+                    .header("X-Plex-Token", self.token.as_str()),
+            };
+            check(req.send().await)
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use actix_web::client::{Client, ClientResponse, SendRequestError};
+use futures::future::LocalBoxFuture;
+use log::{error, info};
+use serde::Serialize;
+use tokio::stream::StreamExt;
+use uuid::Uuid;
+
+use crate::{client, runtime};
+use crate::settings::Settings;
+use crate::commands::SessionEvent;
+use crate::media::Sessions;
+
+mod discord;
+mod gotify;
+mod media_server;
+mod telegram;
+mod webhook;
+
+#[derive(Serialize, Debug)]
+pub struct Notification {
+    id: Uuid,
+    file_name: String,
+    source: String,
+    output: Option<String>,
+    // Length of the media in seconds, when known
+    duration: Option<f64>,
+    state: &'static str,
+}
+
+impl Notification {
+    pub fn succeeded(&self) -> bool {
+        self.state == "completed"
+    }
+
+    // A one line summary for chat style notifications
+    pub fn message(&self) -> String {
+        if self.succeeded() {
+            format!("Finished converting {}", self.file_name)
+        } else {
+            format!("Failed to convert {}", self.file_name)
+        }
+    }
+}
+
+// Something to tell when a session finishes. The client is shared between notifiers and isn't Send,
+// hence the local future.
+pub trait Notifier {
+    fn name(&self) -> &'static str;
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+fn configured(settings: &Settings) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = vec![];
+    if !settings.webhooks.urls.is_empty() {
+        notifiers.push(Box::new(webhook::Webhook::new(&settings.webhooks)));
+    }
+    if let Some(s) = &settings.media_server {
+        notifiers.push(Box::new(media_server::MediaServer::new(s)));
+    }
+    if let Some(s) = &settings.notifiers.discord {
+        notifiers.push(Box::new(discord::Discord::new(s)));
+    }
+    if let Some(s) = &settings.notifiers.telegram {
+        notifiers.push(Box::new(telegram::Telegram::new(s)));
+    }
+    if let Some(s) = &settings.notifiers.gotify {
+        notifiers.push(Box::new(gotify::Gotify::new(s)));
+    }
+    notifiers
+}
+
+// Listens for sessions finishing and passes a summary to every configured notifier. They're looked
+// up for each session so reloading the config changes them.
+pub async fn run(state: Arc<Sessions>) {
+    let client = client::new();
+    let mut events = state.events.subscribe();
+    while let Some(e) = events.next().await {
+        let (id, finished) = match e {
+            Ok(SessionEvent::Completed { id }) => (id, "completed"),
+            Ok(SessionEvent::Failed { id }) => (id, "failed"),
+            _ => continue,
+        };
+
+        let n = match state.sessions.read().get(&id) {
+            Some(s) => {
+                let info = s.media_info();
+                Notification {
+                    id,
+                    file_name: info.file_title.clone(),
+                    source: info.path.to_string_lossy().into_owned(),
+                    output: s.output_dir().map(|o| o.to_string_lossy().into_owned()),
+                    duration: info.duration.map(|d| d.as_secs_f64()),
+                    state: finished,
+                }
+            }
+            None => continue,
+        };
+
+        for notifier in &configured(&runtime::config()) {
+            match notifier.notify(&client, &n).await {
+                Ok(()) => info!("{} notified of session {}", notifier.name(), id),
+                Err(e) => error!("{} notification failed for session {}: {}", notifier.name(), id, e),
+            }
+        }
+    }
+}
+
+// Treats any non 2xx response as a failure
+fn check<S>(res: Result<ClientResponse<S>, SendRequestError>) -> Result<(), String> {
+    let res = res.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("responded with {}", res.status()));
+    }
+    Ok(())
+}
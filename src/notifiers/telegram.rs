@@ -0,0 +1,35 @@
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde_json::json;
+
+use crate::notifiers::{check, Notification, Notifier};
+use crate::settings;
+
+pub struct Telegram {
+    bot_token: String,
+    chat_id: String,
+}
+
+impl Telegram {
+    pub fn new(settings: &settings::Telegram) -> Self {
+        Telegram {
+            bot_token: settings.bot_token.clone(),
+            chat_id: settings.chat_id.clone(),
+        }
+    }
+}
+
+impl Notifier for Telegram {
+    fn name(&self) -> &'static str {
+        "Telegram"
+    }
+
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            check(client.post(format!("https://api.telegram.org/bot{}/sendMessage", self.bot_token))
+                .send_json(&json!({ "chat_id": self.chat_id, "text": n.message() }))
+                .await)
+        }.boxed_local()
+    }
+}
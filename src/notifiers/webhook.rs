@@ -0,0 +1,55 @@
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::notifiers::{check, Notification, Notifier};
+use crate::settings::Webhooks;
+
+// POSTs the notification as JSON to every configured URL
+pub struct Webhook {
+    urls: Vec<String>,
+    secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(settings: &Webhooks) -> Self {
+        Webhook {
+            urls: settings.urls.clone(),
+            secret: settings.secret.clone(),
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "Webhook"
+    }
+
+    fn notify<'a>(&'a self, client: &'a Client, n: &'a Notification) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            let body = serde_json::to_vec(n).unwrap();
+            let mut errors = vec![];
+            for url in &self.urls {
+                let mut req = client.post(url)
+                    .content_type("application/json");
+                if let Some(secret) = &self.secret {
+                    req = req.header("X-Streamin-Signature", format!("sha256={}", sign(secret, &body)));
+                }
+
+                if let Err(e) = check(req.send_body(body.clone()).await) {
+                    errors.push(format!("{}: {}", url, e));
+                }
+            }
+
+            if errors.is_empty() { Ok(()) } else { Err(errors.join(", ")) }
+        }.boxed_local()
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
@@ -0,0 +1,159 @@
+use actix_web::{get, HttpResponse, web};
+use actix_web::error::ErrorNotFound;
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+use crate::{audio, commands, dash, encryption, filename, media, package, probe_cache, queue, retention, runtime, settings, worker, SETTINGS};
+
+// Every route under /api/v1/ and the types they take and give back. Routes added in api::scope
+// need adding here too, with a utoipa::path alongside the route.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        media::process,
+        media::plan,
+        media::fetch,
+        media::all_sessions,
+        media::get_session,
+        media::cancel_session,
+        media::session_logs,
+        media::session_events,
+        media::media_detail,
+        media::unprocessed,
+        media::upload,
+        media::processed,
+        media::search,
+        media::delete_processed,
+        retention::preview,
+        probe_cache::invalidate,
+        queue::get_queue,
+        queue::pause,
+        queue::resume,
+        runtime::get_settings,
+        runtime::update_settings,
+        runtime::reset_settings,
+        runtime::reload_config,
+        worker::claim,
+        worker::report,
+    ),
+    components(schemas(
+        media::ProcessReq,
+        media::Mode,
+        media::FetchReq,
+        media::Created,
+        media::Links,
+        media::Plan,
+        media::MediaDetail,
+        media::ProcessedMedia,
+        media::SearchResult,
+        media::CreatedBatch,
+        media::NotStarted,
+        media::MediaItems,
+        media::IdItems,
+        media::ProcessedItems,
+        media::ExpiredItems,
+        media::MediaPage,
+        media::SessionPage,
+        media::LogPage,
+        media::SearchPage,
+        media::SessionSort,
+        media::MediaSort,
+        media::Order,
+        commands::MediaInfo,
+        commands::PlannedStage,
+        commands::SessionInfo,
+        commands::SessionState,
+        commands::SessionDetail,
+        commands::SessionLog,
+        commands::SessionEvent,
+        dash::Overrides,
+        audio::AudioFormat,
+        encryption::Encryption,
+        encryption::KeyServer,
+        encryption::Scheme,
+        encryption::DrmSystem,
+        settings::Packager,
+        settings::PostProcess,
+        filename::ParsedName,
+        package::PackageInfo,
+        package::Rendition,
+        retention::Expired,
+        queue::QueueStatus,
+        runtime::Current,
+        runtime::RuntimeSettings,
+        runtime::Reloaded,
+        worker::ClaimReq,
+        worker::Reported,
+    )),
+    modifiers(&Auth),
+    // Keys are only needed when some are configured
+    security((), ("api_key" = []), ("bearer" = [])),
+    tags(
+        (name = "sessions", description = "Processing media and following along"),
+        (name = "media", description = "What there is to process and what has been"),
+        (name = "admin", description = "Needs an admin key when keys are configured"),
+        (name = "workers", description = "Used by workers, which need an admin key when keys are configured"),
+    ),
+)]
+struct ApiDoc;
+
+// The ways auth::authorise accepts keys
+struct Auth;
+
+impl Modify for Auth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))));
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+lazy_static! {
+    static ref SPEC: String = ApiDoc::openapi().to_pretty_json().unwrap();
+}
+
+// Swagger UI's files are served from swagger_ui rather than a CDN, so the page only runs what the
+// admin put there and works offline
+const DOCS: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>streamin-conv API</title>
+  <link rel="stylesheet" href="/api/docs/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="/api/docs/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+// What the page needs, nothing else in the directory is served
+const ASSETS: [(&str, &str); 2] = [
+    ("swagger-ui.css", "text/css; charset=utf-8"),
+    ("swagger-ui-bundle.js", "application/javascript; charset=utf-8"),
+];
+
+// All of them are outside the API so can be read without a key
+#[get("/api/openapi.json")]
+pub async fn spec() -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().content_type("application/json").body(SPEC.as_str()))
+}
+
+#[get("/api/docs")]
+pub async fn docs() -> Result<HttpResponse, actix_web::Error> {
+    if SETTINGS.swagger_ui.is_none() {
+        return Err(ErrorNotFound("The API docs need swagger_ui set, see config.yaml. The spec is at /api/openapi.json."));
+    }
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(DOCS))
+}
+
+#[get("/api/docs/{file}")]
+pub async fn docs_asset(web::Path(file): web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
+    let dir = SETTINGS.swagger_ui.as_ref().ok_or_else(|| ErrorNotFound("Not found"))?;
+    let (name, content_type) = ASSETS.iter().find(|(name, _)| *name == file).ok_or_else(|| ErrorNotFound("Not found"))?;
+    let path = dir.join(name);
+    let body = web::block(move || std::fs::read(path)).await?;
+    Ok(HttpResponse::Ok().content_type(*content_type).body(body))
+}
@@ -0,0 +1,37 @@
+use std::io;
+
+use actix_web::{delete, HttpResponse, web};
+use actix_web::web::Data;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::commands;
+use crate::library::Library;
+
+pub use streamin_core::probe_cache::*;
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct InvalidateReq {
+    // A single media id, everything is dropped when not given
+    id: Option<String>,
+}
+
+// Forgets cached probes so the files are probed again on next use
+#[utoipa::path(delete, path = "/api/v1/probe-cache", tag = "media", params(InvalidateReq), responses(
+    (status = 204, description = "The probes were forgotten"),
+    (status = 409, description = "The id is shared by identical files, so one of their path based ids is needed"),
+))]
+#[delete("/probe-cache")]
+pub async fn invalidate(query: web::Query<InvalidateReq>, library: Data<Library>) -> Result<HttpResponse, actix_web::Error> {
+    // The cache is keyed by path
+    let key = match &query.id {
+        Some(id) => Some(library.path_of(id).map_err(actix_web::error::ErrorConflict)?.map_or_else(|| id.clone(), |p| commands::media_id(&p))),
+        None => None,
+    };
+    web::block(move || {
+        forget(key.as_deref());
+        Ok::<_, io::Error>(())
+    }).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::{get, HttpResponse, post};
+use actix_web::web::Data;
+use log::info;
+
+use crate::media::Sessions;
+
+pub use streamin_core::queue::*;
+
+#[utoipa::path(get, path = "/api/v1/queue", tag = "admin", responses(
+    (status = 200, body = QueueStatus),
+))]
+#[get("/queue")]
+pub async fn get_queue(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(status(&state).await))
+}
+
+#[utoipa::path(post, path = "/api/v1/queue/pause", tag = "admin", responses(
+    (status = 200, body = QueueStatus),
+))]
+#[post("/queue/pause")]
+pub async fn pause(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    if !state.paused.swap(true, Ordering::SeqCst) {
+        info!("Queue paused, running sessions will finish but no more will start");
+    }
+    Ok(HttpResponse::Ok().json(status(&state).await))
+}
+
+#[utoipa::path(post, path = "/api/v1/queue/resume", tag = "admin", responses(
+    (status = 200, body = QueueStatus),
+))]
+#[post("/queue/resume")]
+pub async fn resume(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    if state.paused.swap(false, Ordering::SeqCst) {
+        info!("Queue resumed");
+    }
+    dispatch(&state).await;
+    Ok(HttpResponse::Ok().json(status(&state).await))
+}
@@ -0,0 +1,15 @@
+use actix_web::{get, HttpResponse};
+use actix_web::web::Data;
+
+use crate::media::{Items, Sessions};
+
+pub use streamin_core::retention::*;
+
+// What the next prune would remove, without removing anything
+#[utoipa::path(get, path = "/api/v1/retention", tag = "media", responses(
+    (status = 200, body = ExpiredItems),
+))]
+#[get("/retention")]
+pub async fn preview(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(Items { items: plan(&state) }))
+}
@@ -0,0 +1,53 @@
+use actix_web::{delete, get, HttpResponse, post, web};
+use actix_web::web::Data;
+
+use crate::media::Sessions;
+use crate::queue;
+
+pub use streamin_core::runtime::*;
+
+#[utoipa::path(post, path = "/api/v1/config/reload", tag = "admin", responses(
+    (status = 200, body = Reloaded),
+    (status = 400, description = "The config can't be read, so nothing changed"),
+))]
+#[post("/config/reload")]
+pub async fn reload_config(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let reloaded = reload().map_err(actix_web::error::ErrorBadRequest)?;
+    queue::dispatch(&state).await;
+    Ok(HttpResponse::Ok().json(reloaded))
+}
+
+#[utoipa::path(get, path = "/api/v1/settings", tag = "admin", responses(
+    (status = 200, body = Current),
+))]
+#[get("/settings")]
+pub async fn get_settings() -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(current()))
+}
+
+// Changes the settings given, leaving the rest as they are. Running sessions carry on as they were,
+// the changes apply to what starts next.
+#[utoipa::path(post, path = "/api/v1/settings", tag = "admin", request_body = RuntimeSettings, responses(
+    (status = 200, body = Current),
+    (status = 400, description = "A setting is invalid"),
+))]
+#[post("/settings")]
+pub async fn update_settings(req: web::Json<RuntimeSettings>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let updated = update(&req).map_err(actix_web::error::ErrorBadRequest)?;
+    web::block(move || save(&updated)).await?;
+    // A higher limit may have made room for queued sessions
+    queue::dispatch(&state).await;
+    Ok(HttpResponse::Ok().json(current()))
+}
+
+// Goes back to the settings in the config
+#[utoipa::path(delete, path = "/api/v1/settings", tag = "admin", responses(
+    (status = 200, body = Current),
+))]
+#[delete("/settings")]
+pub async fn reset_settings(state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    reset();
+    web::block(|| save(&RuntimeSettings::default())).await?;
+    queue::dispatch(&state).await;
+    Ok(HttpResponse::Ok().json(current()))
+}
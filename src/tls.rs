@@ -0,0 +1,29 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use rustls::{NoClientAuth, ServerConfig};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+use crate::settings::Tls;
+
+// Loads the PEM encoded certificate chain and private key, the key may be either PKCS#8 or RSA
+pub fn server_config(tls: &Tls) -> io::Result<ServerConfig> {
+    let invalid = |what: &str, path: &Path| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} in {:?}", what, path));
+
+    let chain = certs(&mut BufReader::new(File::open(&tls.cert)?))
+        .map_err(|_| invalid("certificate", &tls.cert))?;
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&tls.key)?))
+        .map_err(|_| invalid("private key", &tls.key))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(&tls.key)?))
+            .map_err(|_| invalid("private key", &tls.key))?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| invalid("private key", &tls.key))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(config)
+}
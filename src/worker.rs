@@ -0,0 +1,242 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpResponse, post, web};
+use actix_web::client::{Client, ClientRequest};
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use futures::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{client, queue, reaper, runtime, settings, SETTINGS};
+use crate::commands::SHUTTING_DOWN;
+use crate::commands::remote::{Job, Report, Reporter};
+use crate::error::ConvError;
+use crate::media::Sessions;
+
+// Workers are other instances started with --worker, which run sessions this one hands them. They ask
+// for sessions with claim and send back how each is getting on with report, see commands::remote.
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ClaimReq {
+    // The worker's name, shown with the sessions it runs
+    worker: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Reported {
+    // The session has been cancelled, so the worker should stop it
+    cancel: bool,
+}
+
+#[utoipa::path(post, path = "/api/v1/worker/claim", tag = "workers", request_body = ClaimReq, responses(
+    (status = 200, description = "The job to run, a session's stages"),
+    (status = 204, description = "Nothing is waiting to start"),
+    (status = 404, description = "Workers aren't enabled"),
+))]
+#[post("/worker/claim")]
+pub async fn claim(req: web::Json<ClaimReq>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    if SETTINGS.workers.is_none() {
+        return Err(actix_web::error::ErrorNotFound("Workers aren't enabled, see workers in the config"));
+    }
+    Ok(match queue::claim(&state, &req.worker).await {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[utoipa::path(post, path = "/api/v1/worker/session/{id}/report", tag = "workers", params(("id" = String, Path, description = "The session id")), responses(
+    (status = 200, body = Reported),
+    (status = 404, description = "The session isn't being run by a worker, so should be stopped"),
+))]
+#[post("/worker/session/{id}/report")]
+pub async fn report(web::Path(id): web::Path<String>, req: web::Json<Report>, state: Data<Sessions>) -> Result<HttpResponse, actix_web::Error> {
+    let not_found = || actix_web::error::ErrorNotFound("The session isn't being run by a worker");
+    let id = Uuid::parse_str(&id).map_err(|_| not_found())?;
+    let cancel = state.sessions.read()
+        .get(&id)
+        .and_then(|s| s.report(req.into_inner()))
+        .ok_or_else(not_found)?;
+    Ok(HttpResponse::Ok().json(Reported { cancel }))
+}
+
+// Runs sessions for the coordinator at the URL until stopped. Sessions are asked for while there's
+// room for them under max_sessions, and each is reported on every second until the coordinator has
+// heard it finish.
+pub async fn run(coordinator: String) -> Result<(), String> {
+    let name = SETTINGS.worker.name.clone()
+        .or_else(settings::host_name)
+        .unwrap_or_else(|| "worker".to_string());
+    let coordinator = coordinator.trim_end_matches('/').to_string();
+    let state = Arc::new(Sessions::new());
+    let client = Rc::new(client::new());
+    let poll_interval = Duration::from_secs(SETTINGS.worker.poll_interval);
+    info!("Working for {} as {}", coordinator, name);
+    reaper::worker(&name);
+
+    let mut events = state.events.subscribe();
+    let mut stopped = Box::pin(crate::stopped());
+    let mut failing = false;
+    loop {
+        let running = state.sessions.read().values().filter(|s| s.get_info().running()).count();
+        // A worker can't tell how many sessions it can take at once, so takes one unless told
+        if running < runtime::max_sessions().max(1) {
+            match next(&client, &coordinator, &name).await {
+                Ok(Some(job)) => {
+                    failing = false;
+                    let id = job.id;
+                    let lost_after = job.lost_after;
+                    match start(job, &state) {
+                        Ok(reporter) => {
+                            info!(session_id = %id, "Started a session for the coordinator");
+                            actix_web::rt::spawn(follow(id, reporter, lost_after, state.clone(), client.clone(), coordinator.clone()));
+                        }
+                        // The coordinator takes it as lost once it hears nothing
+                        Err(e) => error!(session_id = %id, "Could not start the session: {}", e),
+                    }
+                    // There may be room for another
+                    continue;
+                }
+                Ok(None) => failing = false,
+                // Logged once, rather than each time the coordinator is asked while it's down
+                Err(e) if !failing => {
+                    error!("Could not ask {} for a session, will keep trying: {}", coordinator, e);
+                    failing = true;
+                }
+                Err(_) => (),
+            }
+        }
+
+        // Woken early by a session finishing, which makes room for another
+        let wait = future::select(tokio::time::delay_for(poll_interval), events.next());
+        if let future::Either::Right(_) = future::select(wait, &mut stopped).await {
+            break;
+        }
+    }
+
+    // Stopping as the server does when killing, the coordinator then hears the sessions were interrupted
+    info!("Stopping, interrupting the running sessions");
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    for s in state.sessions.write().values_mut().filter(|s| s.get_info().running()) {
+        s.cancel();
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !state.sessions.read().is_empty() && Instant::now() < deadline {
+        tokio::time::delay_for(Duration::from_millis(250)).await;
+    }
+    Ok(())
+}
+
+fn start(job: Job, state: &Sessions) -> Result<Reporter, ConvError> {
+    let id = job.id;
+    let (mut session, reporter) = job.session()?;
+    session.events(state.events.clone());
+    session.start()?;
+    state.sessions.write().insert(id, session);
+    Ok(reporter)
+}
+
+// Reports on the session every second until the coordinator has heard it finish. A session the
+// coordinator no longer wants is stopped, as is one it hasn't heard about for lost_after, as it will
+// have given up on it and may have given it to someone else.
+async fn follow(id: Uuid, mut reporter: Reporter, lost_after: Duration, state: Arc<Sessions>, client: Rc<Client>,
+                coordinator: String) {
+    let url = format!("{}/api/v1/worker/session/{}/report", coordinator, id);
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    let mut wanted = true;
+    let mut cancelled = false;
+    let mut failing = false;
+    let mut heard = Instant::now();
+    while ticks.next().await.is_some() {
+        let latest = match state.sessions.read().get(&id) {
+            Some(s) => reporter.report(s),
+            None => break,
+        };
+        if !wanted {
+            if latest.finished() {
+                break;
+            }
+            continue;
+        }
+        let cancel = match send(&client, &url, &latest).await {
+            Ok(cancel) => cancel,
+            Err(e) if heard.elapsed() < lost_after => {
+                if !failing {
+                    error!(session_id = %id, "Could not report to the coordinator, will keep trying: {}", e);
+                    failing = true;
+                }
+                continue;
+            }
+            Err(e) => {
+                error!(session_id = %id, "Could not report to the coordinator for {:?}, stopping the session: {}", lost_after, e);
+                wanted = false;
+                if let Some(s) = state.sessions.write().get_mut(&id) {
+                    s.cancel();
+                }
+                continue;
+            }
+        };
+        failing = false;
+        heard = Instant::now();
+        reporter.sent(&latest);
+        if latest.finished() && reporter.caught_up() {
+            break;
+        }
+        match cancel {
+            Some(false) => continue,
+            Some(true) if cancelled => continue,
+            Some(true) => {
+                info!(session_id = %id, "Cancelled by the coordinator");
+                cancelled = true;
+            }
+            None => {
+                info!(session_id = %id, "The coordinator has given up on the session, stopping it");
+                wanted = false;
+            }
+        }
+        if let Some(s) = state.sessions.write().get_mut(&id) {
+            s.cancel();
+        }
+    }
+    state.sessions.write().remove(&id);
+}
+
+// Asks the coordinator for a session to run
+async fn next(client: &Client, coordinator: &str, name: &str) -> Result<Option<Job>, String> {
+    let url = format!("{}/api/v1/worker/claim", coordinator);
+    let mut res = authorised(client.post(url))
+        .send_json(&ClaimReq { worker: name.to_string() })
+        .await
+        .map_err(|e| e.to_string())?;
+    match res.status() {
+        // Jobs list every argument of every stage, so can be far bigger than the usual limit
+        StatusCode::OK => res.json().limit(1 << 22).await.map(Some).map_err(|e| e.to_string()),
+        StatusCode::NO_CONTENT => Ok(None),
+        status => Err(format!("{} answered {}", coordinator, status)),
+    }
+}
+
+// Whether the coordinator has cancelled the session, None when it no longer wants it
+async fn send(client: &Client, url: &str, latest: &Report) -> Result<Option<bool>, String> {
+    let mut res = authorised(client.post(url))
+        .send_json(latest)
+        .await
+        .map_err(|e| e.to_string())?;
+    match res.status() {
+        StatusCode::OK => res.json::<Reported>().await.map(|r| Some(r.cancel)).map_err(|e| e.to_string()),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(format!("the coordinator answered {}", status)),
+    }
+}
+
+fn authorised(req: ClientRequest) -> ClientRequest {
+    match &SETTINGS.worker.api_key {
+        Some(key) => req.header("X-Api-Key", key.as_str()),
+        None => req,
+    }
+}